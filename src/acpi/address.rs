@@ -0,0 +1,142 @@
+//! Provides the Generic Address Structure, which ACPI uses to describe where a register lives - in memory,
+//! in the I/O port space, or elsewhere.
+
+use core::{fmt, ptr};
+
+use arch::x86_64::port;
+use memory::layout;
+
+/// The register lives in the physical memory address space.
+pub const ADDRESS_SPACE_SYSTEM_MEMORY: u8 = 0;
+
+/// The register lives in the I/O port address space.
+pub const ADDRESS_SPACE_SYSTEM_IO: u8 = 1;
+
+/// The register lives in PCI configuration space.
+pub const ADDRESS_SPACE_PCI_CONFIG: u8 = 2;
+
+/// The access size is undefined (legacy); use the bit width to decide.
+pub const ACCESS_SIZE_UNDEFINED: u8 = 0;
+pub const ACCESS_SIZE_BYTE: u8 = 1;
+pub const ACCESS_SIZE_WORD: u8 = 2;
+pub const ACCESS_SIZE_DWORD: u8 = 3;
+pub const ACCESS_SIZE_QWORD: u8 = 4;
+
+/// The Generic Address Structure, which describes the location of a register.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    /// The address space the register lives in (ADDRESS_SPACE_*).
+    pub address_space: u8,
+
+    /// The size of the register, in bits.
+    pub bit_width: u8,
+
+    /// The bit offset of the register at the given address.
+    pub bit_offset: u8,
+
+    /// The size of the access to use (ACCESS_SIZE_*).
+    pub access_size: u8,
+
+    /// The address of the register in the given address space.
+    pub address: u64
+}
+
+impl GenericAddress {
+    /// Creates a generic address for a register in the I/O port space, as described by the older,
+    /// 32-bit block fields in the FADT. Blocks of 32 bytes or more are too wide for the bit width, which is
+    /// left at 0, so their length has to come from elsewhere.
+    pub fn system_io(port: u32, byte_length: u8) -> GenericAddress {
+        GenericAddress {
+            address_space: ADDRESS_SPACE_SYSTEM_IO,
+            bit_width: byte_length.checked_mul(8).unwrap_or(0),
+            bit_offset: 0,
+            access_size: ACCESS_SIZE_UNDEFINED,
+            address: port as u64
+        }
+    }
+
+    /// True if this structure actually points at something; firmware zeroes out unused registers.
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+
+    /// Obtains the width of a single access to this register, in bytes.
+    fn access_width(&self) -> usize {
+        match self.access_size {
+            ACCESS_SIZE_BYTE => 1,
+            ACCESS_SIZE_WORD => 2,
+            ACCESS_SIZE_DWORD => 4,
+            ACCESS_SIZE_QWORD => 8,
+            _ => match self.bit_width {
+                0 ..= 8 => 1,
+                9 ..= 16 => 2,
+                17 ..= 32 => 4,
+                _ => 8
+            }
+        }
+    }
+
+    /// Obtains the virtual address a register in system memory is reached at (or 0 for other address spaces, which
+    /// don't need one). Returns None if the kernel doesn't map it's physical address.
+    fn mapped_address(&self) -> Option<u64> {
+        match self.address_space {
+            ADDRESS_SPACE_SYSTEM_MEMORY => layout::phys_to_virt(self.address),
+            _ => Some(0)
+        }
+    }
+
+    /// Reads the register. Returns None if the register is in an address space (or part of one) we can't access.
+    /// UNSAFE: Reading hardware registers can have side effects.
+    pub unsafe fn read(&self) -> Option<u64> {
+        let memory = self.mapped_address()?;
+        let value = match (self.address_space, self.access_width()) {
+            (ADDRESS_SPACE_SYSTEM_IO, 1) => port::inb(self.address as u16) as u64,
+            (ADDRESS_SPACE_SYSTEM_IO, 2) => port::inw(self.address as u16) as u64,
+            (ADDRESS_SPACE_SYSTEM_IO, 4) => port::inl(self.address as u16) as u64,
+            (ADDRESS_SPACE_SYSTEM_MEMORY, 1) => ptr::read_volatile(memory as *const u8) as u64,
+            (ADDRESS_SPACE_SYSTEM_MEMORY, 2) => ptr::read_volatile(memory as *const u16) as u64,
+            (ADDRESS_SPACE_SYSTEM_MEMORY, 4) => ptr::read_volatile(memory as *const u32) as u64,
+            (ADDRESS_SPACE_SYSTEM_MEMORY, 8) => ptr::read_volatile(memory as *const u64),
+            _ => return None
+        };
+
+        Some(value >> self.bit_offset)
+    }
+
+    /// Writes the register. Returns false if the register is in an address space (or part of one) we can't access.
+    /// UNSAFE: Writing hardware registers can have arbitrary side effects (like turning the machine off).
+    pub unsafe fn write(&self, value: u64) -> bool {
+        let value = value << self.bit_offset;
+        let memory = match self.mapped_address() { Some(memory) => memory, None => return false };
+
+        match (self.address_space, self.access_width()) {
+            (ADDRESS_SPACE_SYSTEM_IO, 1) => port::outb(self.address as u16, value as u8),
+            (ADDRESS_SPACE_SYSTEM_IO, 2) => port::outw(self.address as u16, value as u16),
+            (ADDRESS_SPACE_SYSTEM_IO, 4) => port::outl(self.address as u16, value as u32),
+            (ADDRESS_SPACE_SYSTEM_MEMORY, 1) => ptr::write_volatile(memory as *mut u8, value as u8),
+            (ADDRESS_SPACE_SYSTEM_MEMORY, 2) => ptr::write_volatile(memory as *mut u16, value as u16),
+            (ADDRESS_SPACE_SYSTEM_MEMORY, 4) => ptr::write_volatile(memory as *mut u32, value as u32),
+            (ADDRESS_SPACE_SYSTEM_MEMORY, 8) => ptr::write_volatile(memory as *mut u64, value),
+            _ => return false
+        }
+
+        true
+    }
+}
+
+impl fmt::Display for GenericAddress {
+    /// Formats the register as it's address space and address, followed by it's width in bits.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.address_space {
+            ADDRESS_SPACE_SYSTEM_MEMORY => f.write_str("memory")?,
+            ADDRESS_SPACE_SYSTEM_IO => f.write_str("port")?,
+            ADDRESS_SPACE_PCI_CONFIG => f.write_str("PCI")?,
+            space => write!(f, "space {}", space)?
+        }
+
+        write!(f, " 0x{:x} ({} bits", { self.address }, self.bit_width)?;
+        if self.bit_offset != 0 { write!(f, " from bit {}", self.bit_offset)?; }
+        f.write_str(")")
+    }
+}
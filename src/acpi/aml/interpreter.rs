@@ -0,0 +1,1793 @@
+//! Provides the AML interpreter, which both loads tables (running their top-level term lists to build the
+//! namespace) and evaluates control methods. It works directly on the bytecode with no intermediate tree;
+//! nested terms are handled by recursion, so deep AML needs a reasonable amount of stack.
+
+use core::{cmp, mem, str};
+use core::cmp::Ordering;
+
+use arch::x86_64::port;
+use hpet;
+use pci::PciAddress;
+use acpi::SDTHeader;
+use super::{AmlError, definition_block};
+use super::opcodes::*;
+use super::parser::{Stream, NameString};
+use super::namespace::{Namespace, NodeId, NodeKind, Builtin, ROOT};
+use super::value::*;
+use super::region::{self, Region, UnitAccess, SPACE_PCI_CONFIG, SPACE_SYSTEM_MEMORY};
+
+/// The deepest method calls may nest before we give up on the firmware.
+const MAX_CALL_DEPTH: usize = 16;
+
+/// The most iterations a single While loop may run for before we assume it will never finish.
+const MAX_LOOP_ITERATIONS: usize = 1 << 20;
+
+/// The value of the Revision operator.
+const INTERPRETER_REVISION: u64 = 1;
+
+/// The number of arguments and locals a method has.
+const ARG_COUNT: usize = 7;
+const LOCAL_COUNT: usize = 8;
+
+/// The interfaces we claim to support when the firmware asks through \_OSI. Firmware tends to only enable
+/// its modern code paths for recent versions of Windows, so we claim those like everybody else does.
+const SUPPORTED_INTERFACES: [&'static [u8]; 12] = [
+    b"Windows 2000", b"Windows 2001", b"Windows 2001 SP1", b"Windows 2001 SP2", b"Windows 2006",
+    b"Windows 2009", b"Windows 2012", b"Windows 2015", b"Module Device", b"Processor Device",
+    b"3.0 Thermal Model", b"Extended Address Space Descriptor"
+];
+
+/// The resource template end tag, which ConcatRes has to strip from it's first operand.
+const END_TAG: u8 = 0x79;
+
+/// The longest path LoadTable can be given to load a table under, or store it's parameter to.
+const MAX_PATH_LENGTH: usize = 128;
+
+/// The most digits an integer can have (in decimal).
+const MAX_DIGITS: usize = 20;
+
+/// The state of a single method invocation (or table load).
+struct Frame {
+    /// The scope names are resolved relative to and created in.
+    scope: NodeId,
+
+    args: [AmlValue; ARG_COUNT],
+    locals: [AmlValue; LOCAL_COUNT]
+}
+
+impl Frame {
+    /// Creates a frame with no arguments in the given scope.
+    fn new(scope: NodeId) -> Frame {
+        Frame {
+            scope: scope,
+            args: [AmlValue::Uninitialized; ARG_COUNT],
+            locals: [AmlValue::Uninitialized; LOCAL_COUNT]
+        }
+    }
+}
+
+/// What should happen after a term has been executed.
+#[derive(Debug, Clone, Copy)]
+enum Flow {
+    Next,
+    Return(AmlValue),
+    Break,
+    Continue
+}
+
+/// Somewhere a value can be stored.
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    /// The null name; the value is thrown away.
+    None,
+
+    Local(usize),
+    Arg(usize),
+    Node(NodeId),
+
+    /// An element of a package, or a byte of a buffer or string, from Index; buffers and strings are always
+    /// writable copies in the arena, shared with whatever held them.
+    Element(AmlValue, usize),
+
+    /// The Debug object, which prints whatever is stored to it.
+    Debug,
+
+    /// A name which doesn't exist (only for CondRefOf).
+    Unresolved
+}
+
+/// Where the fields being created by a field list live.
+#[derive(Debug, Clone, Copy)]
+enum FieldSource {
+    Region(NodeId),
+    Index(NodeId, NodeId)
+}
+
+/// Loads tables into, and evaluates objects from, a namespace.
+pub struct Interpreter<'a> {
+    namespace: &'a mut Namespace,
+
+    /// The current method call depth.
+    depth: usize
+}
+
+impl<'a> Interpreter<'a> {
+    /// Creates an interpreter operating on the given namespace.
+    pub fn new(namespace: &'a mut Namespace) -> Interpreter<'a> {
+        Interpreter { namespace: namespace, depth: 0 }
+    }
+
+    /// Loads a definition block (the body of a DSDT or SSDT), adding everything it defines to the namespace.
+    pub fn load_table(&mut self, aml: &'static [u8]) -> Result<(), AmlError> {
+        self.load_table_in(aml, ROOT)
+    }
+
+    /// Loads a definition block with names relative to the given scope. Load and LoadTable can load tables
+    /// while methods are running (or another table is loading), so whether we were loading is put back after.
+    fn load_table_in(&mut self, aml: &'static [u8], scope: NodeId) -> Result<(), AmlError> {
+        let mut stream = Stream::new(aml);
+        let mut frame = Frame::new(scope);
+        let loading = self.namespace.loading;
+
+        self.namespace.loading = true;
+        let result = self.execute_term_list(&mut stream, aml.len(), &mut frame);
+        self.namespace.loading = loading;
+        self.namespace.mark_persistent();
+
+        result.map(|_| ())
+    }
+
+    /// Evaluates the given node: methods are invoked with the given arguments, and anything else is read.
+    pub fn invoke(&mut self, node: NodeId, args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+        match self.namespace.node(node).kind {
+            NodeKind::Method { code, .. } => {
+                if args.len() > ARG_COUNT { return Err(AmlError::TypeMismatch); }
+                if self.depth >= MAX_CALL_DEPTH { return Err(AmlError::TooDeep); }
+
+                // Names the method creates go underneath the method itself.
+                let mut frame = Frame::new(node);
+                frame.args[.. args.len()].copy_from_slice(args);
+
+                let mut stream = Stream::new(code);
+
+                self.depth += 1;
+                let result = self.execute_term_list(&mut stream, code.len(), &mut frame);
+                self.depth -= 1;
+
+                match result? {
+                    Flow::Return(value) => Ok(value),
+                    _ => Ok(AmlValue::Uninitialized)
+                }
+            },
+            NodeKind::Builtin(Builtin::Osi) => self.osi(args.get(0).cloned().unwrap_or(AmlValue::Uninitialized)),
+            _ => self.read_node(node)
+        }
+    }
+
+    /// Obtains the element of a package at the given index. Names in the package are references to the node
+    /// they name.
+    pub fn package_element(&mut self, package: AmlValue, index: usize) -> Result<AmlValue, AmlError> {
+        let (elements, count) = match package {
+            AmlValue::Package { elements, count } => (elements, count),
+            _ => return Err(AmlError::TypeMismatch)
+        };
+
+        if index >= count { return Err(AmlError::IndexOutOfBounds); }
+
+        match self.namespace.element(elements, index) {
+            AmlValue::Name { name, scope } => {
+                let node = self.resolve(scope, &Stream::new(name).name_string()?)?;
+
+                self.namespace.set_element(elements, index, AmlValue::Reference(node));
+                Ok(AmlValue::Reference(node))
+            },
+            element => Ok(element)
+        }
+    }
+
+    /// Converts a value to an integer, reading from the node if it's a reference.
+    pub fn to_integer(&mut self, value: AmlValue) -> Result<u64, AmlError> {
+        match value {
+            AmlValue::Integer(value) => Ok(value),
+            AmlValue::Buffer { data, length } => {
+                let width = if self.namespace.narrow_integers { 4 } else { 8 };
+                let mut result = 0;
+
+                for index in 0 .. if length < width { length } else { width } {
+                    result |= (self.namespace.buffer_byte(data, index) as u64) << (index * 8);
+                }
+
+                Ok(result)
+            },
+            AmlValue::String { .. } => Ok(self.namespace.truncate(parse_integer(self.bytes(value), 16))),
+            AmlValue::Reference(node) => match self.read_node(node)? {
+                AmlValue::Reference(_) => Err(AmlError::TypeMismatch),
+                value => self.to_integer(value)
+            },
+            _ => Err(AmlError::TypeMismatch)
+        }
+    }
+
+    /// Executes terms until the end position, or until one of them changes the flow of control.
+    fn execute_term_list(&mut self, stream: &mut Stream, end: usize, frame: &mut Frame) -> Result<Flow, AmlError> {
+        while !stream.at_end(end) {
+            match self.execute_term(stream, end, frame)? {
+                Flow::Next => {},
+                flow => return Ok(flow)
+            }
+        }
+
+        Ok(Flow::Next)
+    }
+
+    /// Executes the term list of a scope-like object (a Scope, Device, etc.) in that object's scope.
+    fn execute_scoped(&mut self, stream: &mut Stream, end: usize, frame: &mut Frame, scope: NodeId)
+        -> Result<Flow, AmlError> {
+
+        let saved = frame.scope;
+
+        frame.scope = scope;
+        let flow = self.execute_term_list(stream, end, frame);
+        frame.scope = saved;
+
+        stream.seek(end);
+        flow
+    }
+
+    /// Executes a single term: either a statement or named object definition, or an expression whose
+    /// result is thrown away. The list end is the end of the term list it's in, which an If's Else can't be past.
+    fn execute_term(&mut self, stream: &mut Stream, list_end: usize, frame: &mut Frame) -> Result<Flow, AmlError> {
+        let start = stream.position();
+
+        match stream.byte()? {
+            NAME_OP => {
+                let name = stream.name_string()?;
+                let value = self.evaluate(stream, frame)?;
+
+                self.create(frame.scope, &name, NodeKind::Name(value))?;
+            },
+            ALIAS_OP => {
+                let source = stream.name_string()?;
+                let alias = stream.name_string()?;
+                let target = self.resolve(frame.scope, &source)?;
+
+                self.create(frame.scope, &alias, NodeKind::Alias(target))?;
+            },
+            SCOPE_OP => {
+                let end = stream.pkg_end()?;
+                let name = stream.name_string()?;
+                let scope = self.resolve(frame.scope, &name)?;
+
+                return self.execute_scoped(stream, end, frame, scope);
+            },
+            METHOD_OP => {
+                let end = stream.pkg_end()?;
+                let name = stream.name_string()?;
+                let flags = stream.byte()?;
+                let code = stream.slice(stream.position(), end)?;
+
+                self.create(frame.scope, &name, NodeKind::Method {
+                    code: code,
+                    arg_count: flags & 0b111,
+                    serialized: flags & 0b1000 != 0
+                })?;
+
+                stream.seek(end);
+            },
+            EXTERNAL_OP => {
+                let name = stream.name_string()?;
+                stream.byte()?;
+                stream.byte()?;
+
+                // The parent may only be defined by a table we haven't loaded, which is fine.
+                if self.namespace.resolve(frame.scope, &name).is_none() {
+                    self.create(frame.scope, &name, NodeKind::External).ok();
+                }
+            },
+            IF_OP => {
+                let end = stream.pkg_end()?;
+                let predicate = self.evaluate_integer(stream, frame)? != 0;
+
+                if predicate {
+                    match self.execute_term_list(stream, end, frame)? {
+                        Flow::Next => {},
+                        flow => return Ok(flow)
+                    }
+                }
+
+                stream.seek(end);
+
+                // An Else past the end of the list belongs to an If enclosing this one.
+                if !stream.at_end(list_end) && stream.peek()? == ELSE_OP {
+                    stream.byte()?;
+                    let else_end = stream.pkg_end()?;
+
+                    if !predicate {
+                        match self.execute_term_list(stream, else_end, frame)? {
+                            Flow::Next => {},
+                            flow => return Ok(flow)
+                        }
+                    }
+
+                    stream.seek(else_end);
+                }
+            },
+            WHILE_OP => {
+                let end = stream.pkg_end()?;
+                let predicate_start = stream.position();
+                let mut iterations = 0;
+
+                loop {
+                    stream.seek(predicate_start);
+                    if self.evaluate_integer(stream, frame)? == 0 { break; }
+
+                    match self.execute_term_list(stream, end, frame)? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Next | Flow::Continue => {}
+                    }
+
+                    iterations += 1;
+                    if iterations >= MAX_LOOP_ITERATIONS { return Err(AmlError::LoopTimeout); }
+                }
+
+                stream.seek(end);
+            },
+            RETURN_OP => return Ok(Flow::Return(self.evaluate(stream, frame)?)),
+            BREAK_OP => return Ok(Flow::Break),
+            CONTINUE_OP => return Ok(Flow::Continue),
+            NOOP_OP | BREAK_POINT_OP => {},
+            NOTIFY_OP => {
+                let target = self.super_name(stream, frame, true)?;
+                let value = self.evaluate_integer(stream, frame)?;
+
+                // Whoever evaluated the method picks the notifications up afterwards (see aml::take_notification).
+                let reference = |value: AmlValue| match value { AmlValue::Reference(node) => Some(node), _ => None };
+                let node = match target {
+                    Target::Node(node) => Some(node),
+                    Target::Local(index) => reference(frame.locals[index]),
+                    Target::Arg(index) => reference(frame.args[index]),
+                    _ => None
+                };
+
+                if let Some(node) = node { self.namespace.notify(node, value); }
+            },
+            CREATE_BIT_FIELD_OP => self.create_buffer_field(stream, frame, 1, Some(1))?,
+            CREATE_BYTE_FIELD_OP => self.create_buffer_field(stream, frame, 8, Some(8))?,
+            CREATE_WORD_FIELD_OP => self.create_buffer_field(stream, frame, 8, Some(16))?,
+            CREATE_DWORD_FIELD_OP => self.create_buffer_field(stream, frame, 8, Some(32))?,
+            CREATE_QWORD_FIELD_OP => self.create_buffer_field(stream, frame, 8, Some(64))?,
+            EXT_OP_PREFIX => match stream.byte()? {
+                EXT_MUTEX_OP => {
+                    let name = stream.name_string()?;
+                    stream.byte()?;
+
+                    self.create(frame.scope, &name, NodeKind::Mutex)?;
+                },
+                EXT_EVENT_OP => {
+                    let name = stream.name_string()?;
+
+                    self.create(frame.scope, &name, NodeKind::Event)?;
+                },
+                EXT_OP_REGION_OP => {
+                    let name = stream.name_string()?;
+                    let space = stream.byte()?;
+                    let offset = self.evaluate_integer(stream, frame)?;
+                    let length = self.evaluate_integer(stream, frame)?;
+
+                    self.create(frame.scope, &name, NodeKind::OperationRegion {
+                        space: space,
+                        offset: offset,
+                        length: length
+                    })?;
+                },
+                EXT_DATA_REGION_OP => {
+                    let name = stream.name_string()?;
+                    let (signature, oem_id, oem_table_id) = self.table_key(stream, frame)?;
+                    let (address, header) = self.namespace.find_table(&signature, &oem_id, &oem_table_id)
+                        .ok_or(AmlError::TableNotFound)?;
+
+                    self.create(frame.scope, &name, NodeKind::OperationRegion {
+                        space: SPACE_SYSTEM_MEMORY,
+                        offset: address,
+                        length: header.length as u64
+                    })?;
+                },
+                EXT_FIELD_OP => {
+                    let end = stream.pkg_end()?;
+                    let name = stream.name_string()?;
+                    let region = self.resolve(frame.scope, &name)?;
+                    let flags = stream.byte()?;
+
+                    self.parse_field_list(stream, end, frame.scope, FieldSource::Region(region), flags)?;
+                },
+                EXT_INDEX_FIELD_OP => {
+                    let end = stream.pkg_end()?;
+                    let index_name = stream.name_string()?;
+                    let data_name = stream.name_string()?;
+                    let index = self.resolve(frame.scope, &index_name)?;
+                    let data = self.resolve(frame.scope, &data_name)?;
+                    let flags = stream.byte()?;
+
+                    self.parse_field_list(stream, end, frame.scope, FieldSource::Index(index, data), flags)?;
+                },
+                EXT_BANK_FIELD_OP => {
+                    // TODO: Bank fields aren't supported yet; none of the firmware we run on uses them.
+                    let end = stream.pkg_end()?;
+                    stream.seek(end);
+                },
+                EXT_DEVICE_OP => {
+                    let end = stream.pkg_end()?;
+                    let name = stream.name_string()?;
+                    let device = self.create(frame.scope, &name, NodeKind::Device)?;
+
+                    return self.execute_scoped(stream, end, frame, device);
+                },
+                EXT_PROCESSOR_OP => {
+                    let end = stream.pkg_end()?;
+                    let name = stream.name_string()?;
+                    let id = stream.byte()?;
+                    let block_address = stream.dword()?;
+                    let block_length = stream.byte()?;
+
+                    let processor = self.create(frame.scope, &name, NodeKind::Processor {
+                        id: id,
+                        block_address: block_address,
+                        block_length: block_length
+                    })?;
+
+                    return self.execute_scoped(stream, end, frame, processor);
+                },
+                EXT_POWER_RES_OP => {
+                    let end = stream.pkg_end()?;
+                    let name = stream.name_string()?;
+                    stream.byte()?;
+                    stream.word()?;
+
+                    let resource = self.create(frame.scope, &name, NodeKind::PowerResource)?;
+
+                    return self.execute_scoped(stream, end, frame, resource);
+                },
+                EXT_THERMAL_ZONE_OP => {
+                    let end = stream.pkg_end()?;
+                    let name = stream.name_string()?;
+                    let zone = self.create(frame.scope, &name, NodeKind::ThermalZone)?;
+
+                    return self.execute_scoped(stream, end, frame, zone);
+                },
+                EXT_CREATE_FIELD_OP => self.create_buffer_field(stream, frame, 1, None)?,
+                _ => {
+                    stream.seek(start);
+                    self.evaluate(stream, frame)?;
+                }
+            },
+            _ => {
+                stream.seek(start);
+                self.evaluate(stream, frame)?;
+            }
+        }
+
+        Ok(Flow::Next)
+    }
+
+    /// Creates the fields in a Field or IndexField field list.
+    fn parse_field_list(&mut self, stream: &mut Stream, end: usize, scope: NodeId, source: FieldSource, mut flags: u8)
+        -> Result<(), AmlError> {
+
+        let mut bit_offset = 0;
+
+        while !stream.at_end(end) {
+            match stream.peek()? {
+                // ReservedField: skip the given number of bits.
+                0x00 => {
+                    stream.byte()?;
+                    bit_offset += stream.raw_pkg_length()? as u64;
+                },
+                // AccessField: change the access type for the following fields.
+                0x01 => {
+                    stream.byte()?;
+                    let access_type = stream.byte()?;
+                    stream.byte()?;
+
+                    flags = (flags & !0xF) | (access_type & 0xF);
+                },
+                // ConnectField, for GPIO/serial bus regions, which we don't support anyway.
+                0x02 => {
+                    stream.byte()?;
+
+                    if stream.peek()? == BUFFER_OP {
+                        stream.byte()?;
+                        let buffer_end = stream.pkg_end()?;
+                        stream.seek(buffer_end);
+                    } else {
+                        stream.name_string()?;
+                    }
+                },
+                // ExtendedAccessField: as AccessField, with an attribute and length we don't need.
+                0x03 => {
+                    stream.byte()?;
+                    let access_type = stream.byte()?;
+                    stream.byte()?;
+                    stream.byte()?;
+
+                    flags = (flags & !0xF) | (access_type & 0xF);
+                },
+                _ => {
+                    let name = stream.name_seg()?;
+                    let bit_length = stream.raw_pkg_length()? as u64;
+
+                    let kind = match source {
+                        FieldSource::Region(region) => NodeKind::Field {
+                            region: region,
+                            bit_offset: bit_offset,
+                            bit_length: bit_length,
+                            flags: flags
+                        },
+                        FieldSource::Index(index, data) => NodeKind::IndexField {
+                            index: index,
+                            data: data,
+                            bit_offset: bit_offset,
+                            bit_length: bit_length,
+                            flags: flags
+                        }
+                    };
+
+                    self.namespace.add(scope, name, kind)?;
+                    bit_offset += bit_length;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles the CreateXField operators; `index_bits` is the size of the units the index is in, and
+    /// `bit_length` the size of the field, if it's fixed.
+    fn create_buffer_field(&mut self, stream: &mut Stream, frame: &mut Frame, index_bits: u64, bit_length: Option<u64>)
+        -> Result<(), AmlError> {
+
+        // Fields share the buffer with whatever holds it, so we need to know where it came from.
+        let (source, value) = self.source(stream, frame)?;
+        let index = self.evaluate_integer(stream, frame)?;
+        let bit_length = match bit_length {
+            Some(length) => length,
+            None => self.evaluate_integer(stream, frame)?
+        };
+        let name = stream.name_string()?;
+
+        let buffer = self.make_writable(source, value, frame)?;
+        self.create(frame.scope, &name, NodeKind::BufferField {
+            buffer: buffer,
+            bit_offset: index * index_bits,
+            bit_length: bit_length
+        })?;
+
+        Ok(())
+    }
+
+    /// Evaluates an operand which might be modified through whatever it evaluates to, like the buffer of a
+    /// buffer field, giving where it came from (if it's a SuperName) along with it's value.
+    fn source(&mut self, stream: &mut Stream, frame: &mut Frame) -> Result<(Target, AmlValue), AmlError> {
+        if self.is_super_name_start(stream)? {
+            let target = self.super_name(stream, frame, true)?;
+            Ok((target, self.read_target(target, frame)?))
+        } else {
+            Ok((Target::None, self.evaluate(stream, frame)?))
+        }
+    }
+
+    /// Ensures a buffer or string lives in the arena (so it can be modified), replacing the value held by the
+    /// source with the copy.
+    fn make_writable(&mut self, source: Target, value: AmlValue, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        let (data, length, string) = match value {
+            AmlValue::Buffer { data: BufferData::Arena(_), .. } | AmlValue::String { data: BufferData::Arena(_), .. } =>
+                return Ok(value),
+            AmlValue::Buffer { data, length } => (data, length, false),
+            AmlValue::String { data, length } => (data, length, true),
+            _ => return Err(AmlError::TypeMismatch)
+        };
+
+        let persistent = match source {
+            Target::Node(node) => self.namespace.loading || self.namespace.is_persistent(node),
+            _ => self.namespace.loading
+        };
+
+        // Strings keep their null terminator.
+        let offset = self.namespace.copy_buffer(data, length + string as usize, persistent)?;
+        let copy = if string {
+            AmlValue::String { data: BufferData::Arena(offset), length: length }
+        } else {
+            AmlValue::Buffer { data: BufferData::Arena(offset), length: length }
+        };
+
+        match source {
+            Target::Local(index) => frame.locals[index] = copy,
+            Target::Arg(index) => frame.args[index] = copy,
+            Target::Node(node) => if let NodeKind::Name(_) = self.namespace.node(node).kind {
+                self.namespace.node_mut(node).kind = NodeKind::Name(copy);
+            },
+            Target::Element(AmlValue::Package { elements, .. }, index) =>
+                self.namespace.set_element(elements, index, copy),
+            _ => {}
+        }
+
+        Ok(copy)
+    }
+
+    /// Evaluates a term and converts the result to an integer.
+    fn evaluate_integer(&mut self, stream: &mut Stream, frame: &mut Frame) -> Result<u64, AmlError> {
+        let value = self.evaluate(stream, frame)?;
+        self.to_integer(value)
+    }
+
+    /// Evaluates a single TermArg, returning it's value.
+    fn evaluate(&mut self, stream: &mut Stream, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        let start = stream.position();
+        let op = stream.byte()?;
+
+        match op {
+            ZERO_OP => Ok(AmlValue::Integer(0)),
+            ONE_OP => Ok(AmlValue::Integer(1)),
+            ONES_OP => Ok(AmlValue::Integer(self.namespace.truncate(!0))),
+            BYTE_PREFIX => Ok(AmlValue::Integer(stream.byte()? as u64)),
+            WORD_PREFIX => Ok(AmlValue::Integer(stream.word()? as u64)),
+            DWORD_PREFIX => Ok(AmlValue::Integer(stream.dword()? as u64)),
+            QWORD_PREFIX => Ok(AmlValue::Integer(stream.qword()?)),
+            STRING_PREFIX => Ok(AmlValue::string(stream.string()?)),
+            BUFFER_OP => {
+                let end = stream.pkg_end()?;
+                let length = self.evaluate_integer(stream, frame)? as usize;
+                let data = stream.slice(stream.position(), end)?;
+
+                stream.seek(end);
+                Ok(AmlValue::Buffer { data: BufferData::Aml(data), length: length })
+            },
+            PACKAGE_OP | VAR_PACKAGE_OP => {
+                let end = stream.pkg_end()?;
+                let count = if op == PACKAGE_OP {
+                    stream.byte()? as usize
+                } else {
+                    self.evaluate_integer(stream, frame)? as usize
+                };
+
+                self.build_package(stream, end, count, frame)
+            },
+            LOCAL0_OP ..= LOCAL7_OP => Ok(frame.locals[(op - LOCAL0_OP) as usize]),
+            ARG0_OP ..= ARG6_OP => match frame.args[(op - ARG0_OP) as usize] {
+                // Arguments passed by reference (RefOf) read through to what they refer to.
+                AmlValue::Reference(node) => self.read_reference(node),
+                value => Ok(value)
+            },
+            op if is_name_string_start(op) => {
+                stream.seek(start);
+
+                let name = stream.name_string()?;
+                let node = self.resolve(frame.scope, &name)?;
+
+                self.call_or_read(stream, frame, node)
+            },
+            STORE_OP => {
+                let value = self.evaluate(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                self.store(target, value, frame)?;
+                Ok(value)
+            },
+            COPY_OBJECT_OP => {
+                let value = self.evaluate(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                match target {
+                    Target::Node(node) => self.namespace.node_mut(node).kind = NodeKind::Name(value),
+                    target => self.store(target, value, frame)?
+                }
+
+                Ok(value)
+            },
+            REF_OF_OP => match self.super_name(stream, frame, true)? {
+                Target::Node(node) => Ok(AmlValue::Reference(node)),
+                _ => Err(AmlError::Unsupported)
+            },
+            ADD_OP => self.binary(stream, frame, |a, b| Some(a.wrapping_add(b))),
+            SUBTRACT_OP => self.binary(stream, frame, |a, b| Some(a.wrapping_sub(b))),
+            MULTIPLY_OP => self.binary(stream, frame, |a, b| Some(a.wrapping_mul(b))),
+            MOD_OP => self.binary(stream, frame, |a, b| a.checked_rem(b)),
+            SHIFT_LEFT_OP => self.binary(stream, frame, |a, b| Some(if b >= 64 { 0 } else { a << b })),
+            SHIFT_RIGHT_OP => self.binary(stream, frame, |a, b| Some(if b >= 64 { 0 } else { a >> b })),
+            AND_OP => self.binary(stream, frame, |a, b| Some(a & b)),
+            NAND_OP => self.binary(stream, frame, |a, b| Some(!(a & b))),
+            OR_OP => self.binary(stream, frame, |a, b| Some(a | b)),
+            NOR_OP => self.binary(stream, frame, |a, b| Some(!(a | b))),
+            XOR_OP => self.binary(stream, frame, |a, b| Some(a ^ b)),
+            NOT_OP => self.unary(stream, frame, |a| !a),
+            FIND_SET_LEFT_BIT_OP => self.unary(stream, frame, |a| {
+                if a == 0 { 0 } else { 64 - a.leading_zeros() as u64 }
+            }),
+            FIND_SET_RIGHT_BIT_OP => self.unary(stream, frame, |a| {
+                if a == 0 { 0 } else { a.trailing_zeros() as u64 + 1 }
+            }),
+            DIVIDE_OP => {
+                let dividend = self.evaluate_integer(stream, frame)?;
+                let divisor = self.evaluate_integer(stream, frame)?;
+                let remainder_target = self.super_name(stream, frame, true)?;
+                let quotient_target = self.super_name(stream, frame, true)?;
+
+                if divisor == 0 { return Err(AmlError::DivideByZero); }
+
+                let quotient = AmlValue::Integer(dividend / divisor);
+                self.store(remainder_target, AmlValue::Integer(dividend % divisor), frame)?;
+                self.store(quotient_target, quotient, frame)?;
+
+                Ok(quotient)
+            },
+            INCREMENT_OP | DECREMENT_OP => {
+                let target = self.super_name(stream, frame, true)?;
+                let value = self.read_target(target, frame)?;
+                let value = self.to_integer(value)?;
+
+                let result = if op == INCREMENT_OP { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                let result = AmlValue::Integer(self.namespace.truncate(result));
+
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            LAND_OP | LOR_OP => {
+                let a = self.evaluate_integer(stream, frame)? != 0;
+                let b = self.evaluate_integer(stream, frame)? != 0;
+
+                Ok(self.logical(if op == LAND_OP { a && b } else { a || b }))
+            },
+            LNOT_OP => {
+                // LNotEqual and friends are encoded as LNot applied to the opposite comparison, so they
+                // need no special handling.
+                let value = self.evaluate_integer(stream, frame)?;
+                Ok(self.logical(value == 0))
+            },
+            LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                let a = self.evaluate(stream, frame)?;
+                let b = self.evaluate(stream, frame)?;
+                let ordering = self.compare(a, b)?;
+
+                Ok(self.logical(match op {
+                    LEQUAL_OP => ordering == Ordering::Equal,
+                    LGREATER_OP => ordering == Ordering::Greater,
+                    _ => ordering == Ordering::Less
+                }))
+            },
+            TO_INTEGER_OP => {
+                let value = self.evaluate(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                // Unlike implicit conversions, explicit ones treat strings as decimal unless prefixed.
+                let prefixed = self.byte_at(value, 0) == b'0' && self.byte_at(value, 1) | 0x20 == b'x';
+                let result = match value {
+                    AmlValue::String { .. } if prefixed => parse_integer(self.bytes(value).skip(2), 16),
+                    AmlValue::String { .. } => parse_integer(self.bytes(value), 10),
+                    value => self.to_integer(value)?
+                };
+                let result = AmlValue::Integer(self.namespace.truncate(result));
+
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            TO_BUFFER_OP => {
+                let value = self.evaluate(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                let result = match value {
+                    AmlValue::Buffer { .. } => value,
+                    // The null terminator becomes part of the buffer.
+                    AmlValue::String { data, length } => AmlValue::Buffer { data: data, length: length + 1 },
+                    value => {
+                        let integer = self.to_integer(value)?;
+                        self.integer_buffer(integer)?
+                    }
+                };
+
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            TO_DECIMAL_STRING_OP | TO_HEX_STRING_OP => {
+                let value = self.evaluate(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                let result = self.convert_to_string(value, if op == TO_HEX_STRING_OP { 16 } else { 10 })?;
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            TO_STRING_OP => {
+                let value = self.evaluate(stream, frame)?;
+                let limit = self.evaluate_integer(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                // The string ends at the first null in the buffer, or after the given number of bytes (Ones for
+                // no limit).
+                let source = self.implicit_buffer(value)?;
+                let length = self.bytes(source).take(limit as usize).take_while(|&byte| byte != 0).count();
+
+                let result = self.new_string(length)?;
+                self.copy_bytes(source, 0, result, length);
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            MID_OP => {
+                let value = self.evaluate(stream, frame)?;
+                let index = self.evaluate_integer(stream, frame)?;
+                let length = self.evaluate_integer(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                // Whatever's past the end of the source is left out, so starting past it gives an empty result.
+                let source = self.implicit_buffer(value)?;
+                let total = self.byte_length(source)?;
+                let start = cmp::min(index, total as u64) as usize;
+                let length = cmp::min(length, (total - start) as u64) as usize;
+
+                let result = match source {
+                    AmlValue::String { .. } => self.new_string(length)?,
+                    _ => {
+                        let offset = self.namespace.allocate(length, self.namespace.loading)?;
+                        AmlValue::Buffer { data: BufferData::Arena(offset), length: length }
+                    }
+                };
+
+                self.copy_bytes(source, start, result, length);
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            CONCAT_OP => {
+                let a = self.evaluate(stream, frame)?;
+                let b = self.evaluate(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                let result = self.concatenate(a, b, false)?;
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            CONCAT_RES_OP => {
+                let a = self.evaluate(stream, frame)?;
+                let b = self.evaluate(stream, frame)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                let result = self.concatenate(a, b, true)?;
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            SIZE_OF_OP => {
+                let target = self.super_name(stream, frame, true)?;
+
+                match self.read_target(target, frame)? {
+                    AmlValue::String { length, .. } | AmlValue::Buffer { length, .. } =>
+                        Ok(AmlValue::Integer(length as u64)),
+                    AmlValue::Package { count, .. } => Ok(AmlValue::Integer(count as u64)),
+                    _ => Err(AmlError::TypeMismatch)
+                }
+            },
+            OBJECT_TYPE_OP => {
+                let target = self.super_name(stream, frame, true)?;
+
+                let code = match target {
+                    Target::Node(node) => self.node_type(node),
+                    target => self.read_target(target, frame)?.type_code()
+                };
+
+                Ok(AmlValue::Integer(code))
+            },
+            INDEX_OP => {
+                let source = self.evaluate(stream, frame)?;
+                let index = self.evaluate_integer(stream, frame)? as usize;
+                let target = self.super_name(stream, frame, true)?;
+
+                let element = self.element(source, index)?;
+
+                self.store(target, element, frame)?;
+                Ok(element)
+            },
+            DEREF_OF_OP => match self.evaluate(stream, frame)? {
+                AmlValue::Reference(node) => self.read_node(node),
+                value => Ok(value)
+            },
+            MATCH_OP => {
+                let package = self.evaluate(stream, frame)?;
+                let first_op = stream.byte()?;
+                let first = self.evaluate_integer(stream, frame)?;
+                let second_op = stream.byte()?;
+                let second = self.evaluate_integer(stream, frame)?;
+                let start_index = self.evaluate_integer(stream, frame)? as usize;
+
+                let count = match package {
+                    AmlValue::Package { count, .. } => count,
+                    _ => return Err(AmlError::TypeMismatch)
+                };
+
+                for index in start_index .. count {
+                    // Elements which aren't integers never match.
+                    let element = match self.package_element(package, index)? {
+                        AmlValue::Integer(value) => value,
+                        _ => continue
+                    };
+
+                    if match_integer(first_op, element, first) && match_integer(second_op, element, second) {
+                        return Ok(AmlValue::Integer(index as u64));
+                    }
+                }
+
+                Ok(AmlValue::Integer(self.namespace.truncate(!0)))
+            },
+            EXT_OP_PREFIX => self.evaluate_extended(stream, frame),
+            op => Err(AmlError::InvalidOpcode(op))
+        }
+    }
+
+    /// Builds a package from it's initializer list, evaluating the elements once, in the frame building it (so
+    /// LocalN and ArgN elements get the values they have there). Elements past the end of the list are
+    /// uninitialized, and names become references to the node they name.
+    fn build_package(&mut self, stream: &mut Stream, end: usize, count: usize, frame: &mut Frame)
+        -> Result<AmlValue, AmlError> {
+
+        let elements = self.namespace.allocate_elements(count, self.namespace.loading)?;
+        let mut index = 0;
+
+        while !stream.at_end(end) {
+            let element = if is_name_string_start(stream.peek()?) {
+                let start = stream.position();
+                let name = stream.name_string()?;
+
+                match self.namespace.resolve(frame.scope, &name) {
+                    Some(node) => AmlValue::Reference(node),
+                    None => AmlValue::Name { name: stream.slice(start, stream.position())?, scope: frame.scope }
+                }
+            } else {
+                self.evaluate(stream, frame)?
+            };
+
+            // Initializers past the size of the package are thrown away.
+            if index < count { self.namespace.set_element(elements, index, element); }
+            index += 1;
+        }
+
+        stream.seek(end);
+        Ok(AmlValue::Package { elements: elements, count: count })
+    }
+
+    /// Evaluates the extended opcodes which produce values.
+    fn evaluate_extended(&mut self, stream: &mut Stream, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        match stream.byte()? {
+            EXT_COND_REF_OF_OP => {
+                let source = self.super_name(stream, frame, false)?;
+                let target = self.super_name(stream, frame, true)?;
+
+                match source {
+                    Target::Node(node) => {
+                        self.store(target, AmlValue::Reference(node), frame)?;
+                        Ok(self.logical(true))
+                    },
+                    _ => Ok(self.logical(false))
+                }
+            },
+            EXT_REVISION_OP => Ok(AmlValue::Integer(INTERPRETER_REVISION)),
+            EXT_TIMER_OP => {
+                // TODO: This should be a monotonic 100ns counter, which needs a clock source.
+                Ok(AmlValue::Integer(0))
+            },
+            EXT_ACQUIRE_OP => {
+                // We only ever run one method at a time, so mutexes are always free.
+                self.super_name(stream, frame, true)?;
+                stream.word()?;
+
+                Ok(AmlValue::Integer(0))
+            },
+            EXT_RELEASE_OP | EXT_SIGNAL_OP | EXT_RESET_OP => {
+                self.super_name(stream, frame, true)?;
+                Ok(AmlValue::Uninitialized)
+            },
+            EXT_WAIT_OP => {
+                self.super_name(stream, frame, true)?;
+                self.evaluate_integer(stream, frame)?;
+
+                Ok(AmlValue::Integer(0))
+            },
+            EXT_SLEEP_OP => {
+                let milliseconds = self.evaluate_integer(stream, frame)?;
+                delay(milliseconds.saturating_mul(1000));
+
+                Ok(AmlValue::Uninitialized)
+            },
+            EXT_STALL_OP => {
+                let microseconds = self.evaluate_integer(stream, frame)?;
+                delay(microseconds);
+
+                Ok(AmlValue::Uninitialized)
+            },
+            EXT_FROM_BCD_OP => self.unary(stream, frame, |mut bcd| {
+                let mut value = 0;
+                let mut scale = 1;
+
+                while bcd != 0 {
+                    value += (bcd & 0xF) * scale;
+                    scale *= 10;
+                    bcd >>= 4;
+                }
+
+                value
+            }),
+            EXT_TO_BCD_OP => self.unary(stream, frame, |mut value| {
+                let mut bcd = 0;
+                let mut shift = 0;
+
+                while value != 0 && shift < 64 {
+                    bcd |= (value % 10) << shift;
+                    value /= 10;
+                    shift += 4;
+                }
+
+                bcd
+            }),
+            EXT_LOAD_OP => {
+                let name = stream.name_string()?;
+                let target = self.super_name(stream, frame, true)?;
+
+                let (offset, length) = match self.namespace.node(self.resolve(frame.scope, &name)?).kind {
+                    NodeKind::OperationRegion { space: SPACE_SYSTEM_MEMORY, offset, length } => (offset, length),
+                    _ => return Err(AmlError::Unsupported)
+                };
+
+                // UNSAFE: Safe, as far as we trust the firmware to describe it's own memory.
+                let memory = unsafe { region::system_memory(offset, length)? };
+
+                // Tables which don't check out just aren't loaded, and the AML is told so.
+                // UNSAFE: Safe, as the region's memory is mapped for good.
+                let aml = table_in(memory).and_then(|table| unsafe { definition_block(table, b"SSDT") });
+
+                let loaded = match aml {
+                    Ok(aml) => {
+                        self.load_table(aml)?;
+                        true
+                    },
+                    Err(_) => false
+                };
+
+                let result = self.logical(loaded);
+                self.store(target, result, frame)?;
+                Ok(result)
+            },
+            EXT_LOAD_TABLE_OP => {
+                let (signature, oem_id, oem_table_id) = self.table_key(stream, frame)?;
+                let root_path = self.evaluate(stream, frame)?;
+                let parameter_path = self.evaluate(stream, frame)?;
+                let parameter = self.evaluate(stream, frame)?;
+
+                let header = match self.namespace.find_table(&signature, &oem_id, &oem_table_id) {
+                    Some((_, header)) => header,
+                    None => return Ok(AmlValue::Integer(0))
+                };
+
+                // The table is loaded under the root path (or the root), which the parameter path is relative to.
+                let scope = self.path_node(ROOT, root_path)?.unwrap_or(ROOT);
+                // UNSAFE: Safe, as the tables the namespace knows about stay mapped.
+                let aml = unsafe { definition_block(header, &signature)? };
+
+                self.load_table_in(aml, scope)?;
+
+                if let Some(node) = self.path_node(scope, parameter_path)? {
+                    self.write_node(node, parameter)?;
+                }
+
+                Ok(self.logical(true))
+            },
+            EXT_FATAL_OP => {
+                let kind = stream.byte()?;
+                let code = stream.dword()?;
+                let argument = self.evaluate_integer(stream, frame)?;
+
+                Err(AmlError::Fatal { kind: kind, code: code, argument: argument })
+            },
+            EXT_DEBUG_OP => Err(AmlError::Unsupported),
+            op => Err(AmlError::InvalidExtendedOpcode(op))
+        }
+    }
+
+    /// Evaluates a binary integer operator (with a target), storing and returning the result.
+    fn binary(&mut self, stream: &mut Stream, frame: &mut Frame, op: fn(u64, u64) -> Option<u64>)
+        -> Result<AmlValue, AmlError> {
+
+        let a = self.evaluate_integer(stream, frame)?;
+        let b = self.evaluate_integer(stream, frame)?;
+        let target = self.super_name(stream, frame, true)?;
+
+        let result = op(a, b).ok_or(AmlError::DivideByZero)?;
+        let result = AmlValue::Integer(self.namespace.truncate(result));
+
+        self.store(target, result, frame)?;
+        Ok(result)
+    }
+
+    /// Evaluates a unary integer operator (with a target), storing and returning the result.
+    fn unary(&mut self, stream: &mut Stream, frame: &mut Frame, op: fn(u64) -> u64) -> Result<AmlValue, AmlError> {
+        let value = self.evaluate_integer(stream, frame)?;
+        let target = self.super_name(stream, frame, true)?;
+
+        let result = AmlValue::Integer(self.namespace.truncate(op(value)));
+
+        self.store(target, result, frame)?;
+        Ok(result)
+    }
+
+    /// The logical value for the given boolean, at the width integers are in the loaded tables.
+    fn logical(&self, value: bool) -> AmlValue {
+        if value { AmlValue::Integer(self.namespace.truncate(!0)) } else { AmlValue::Integer(0) }
+    }
+
+    /// Compares two values for the logical comparison operators; the second value is converted to the
+    /// type of the first.
+    fn compare(&mut self, a: AmlValue, b: AmlValue) -> Result<Ordering, AmlError> {
+        match a {
+            AmlValue::String { .. } | AmlValue::Buffer { .. } => {
+                let (a_length, b_length) = (self.byte_length(a)?, self.byte_length(b)?);
+
+                for index in 0 .. if a_length < b_length { a_length } else { b_length } {
+                    let (a_byte, b_byte) = (self.byte_at(a, index), self.byte_at(b, index));
+                    if a_byte != b_byte { return Ok(a_byte.cmp(&b_byte)); }
+                }
+
+                Ok(a_length.cmp(&b_length))
+            },
+            a => {
+                let a = self.to_integer(a)?;
+                let b = self.to_integer(b)?;
+
+                Ok(a.cmp(&b))
+            }
+        }
+    }
+
+    /// The number of bytes in a string or buffer.
+    fn byte_length(&self, value: AmlValue) -> Result<usize, AmlError> {
+        match value {
+            AmlValue::String { length, .. } | AmlValue::Buffer { length, .. } => Ok(length),
+            _ => Err(AmlError::TypeMismatch)
+        }
+    }
+
+    /// A single byte of a string or buffer; past the end, it's zero.
+    fn byte_at(&self, value: AmlValue, index: usize) -> u8 {
+        match value {
+            AmlValue::String { data, length } | AmlValue::Buffer { data, length } if index < length =>
+                self.namespace.buffer_byte(data, index),
+            _ => 0
+        }
+    }
+
+    /// The bytes of a string or buffer (none, for anything else).
+    fn bytes<'b>(&'b self, value: AmlValue) -> impl Iterator<Item = u8> + 'b {
+        (0 .. self.byte_length(value).unwrap_or(0)).map(move |index| self.byte_at(value, index))
+    }
+
+    /// The element of a package, or byte of a buffer or string, at the given index.
+    fn element(&mut self, source: AmlValue, index: usize) -> Result<AmlValue, AmlError> {
+        match source {
+            AmlValue::Package { .. } => self.package_element(source, index),
+            AmlValue::Buffer { length, .. } | AmlValue::String { length, .. } if index < length =>
+                Ok(AmlValue::Integer(self.byte_at(source, index) as u64)),
+            AmlValue::Buffer { .. } | AmlValue::String { .. } => Err(AmlError::IndexOutOfBounds),
+            _ => Err(AmlError::TypeMismatch)
+        }
+    }
+
+    /// Creates a temporary buffer holding the given integer.
+    fn integer_buffer(&mut self, value: u64) -> Result<AmlValue, AmlError> {
+        let length = if self.namespace.narrow_integers { 4 } else { 8 };
+        let offset = self.namespace.allocate(length, self.namespace.loading)?;
+
+        for index in 0 .. length {
+            self.namespace.set_buffer_byte(offset, index, (value >> (index * 8)) as u8);
+        }
+
+        Ok(AmlValue::Buffer { data: BufferData::Arena(offset), length: length })
+    }
+
+    /// Concatenates two integers or buffers into a new buffer, or anything onto a string into a new string; for
+    /// resource templates, the end tag of the first is dropped, and the checksum of the resulting end tag is zeroed.
+    fn concatenate(&mut self, a: AmlValue, b: AmlValue, resource: bool) -> Result<AmlValue, AmlError> {
+        // Integers are concatenated as integers, and everything else as bytes.
+        let (a, b) = match a {
+            AmlValue::Integer(a) => {
+                let b = self.to_integer(b)?;
+                (self.integer_buffer(a)?, self.integer_buffer(b)?)
+            },
+            AmlValue::Buffer { .. } => match b {
+                AmlValue::Integer(b) => (a, self.integer_buffer(b)?),
+                AmlValue::String { .. } | AmlValue::Buffer { .. } => (a, b),
+                _ => return Err(AmlError::TypeMismatch)
+            },
+            AmlValue::String { length: a_length, .. } => {
+                let b = self.convert_to_string(b, 16)?;
+                let b_length = self.byte_length(b)?;
+
+                let result = self.new_string(a_length + b_length)?;
+                self.copy_bytes(a, 0, result, a_length);
+                for index in 0 .. b_length {
+                    let byte = self.byte_at(b, index);
+                    self.write_bytes(result, a_length + index, &[byte]);
+                }
+
+                return Ok(result);
+            },
+            _ => return Err(AmlError::TypeMismatch)
+        };
+
+        let mut a_length = self.byte_length(a)?;
+        let b_length = self.byte_length(b)?;
+
+        if resource && a_length >= 2 && self.byte_at(a, a_length - 2) == END_TAG {
+            a_length -= 2;
+        }
+
+        let length = a_length + b_length;
+        let offset = self.namespace.allocate(length, self.namespace.loading)?;
+
+        for index in 0 .. a_length {
+            let byte = self.byte_at(a, index);
+            self.namespace.set_buffer_byte(offset, index, byte);
+        }
+
+        for index in 0 .. b_length {
+            let byte = self.byte_at(b, index);
+            self.namespace.set_buffer_byte(offset, a_length + index, byte);
+        }
+
+        if resource && length >= 2 && self.namespace.buffer_byte(BufferData::Arena(offset), length - 2) == END_TAG {
+            self.namespace.set_buffer_byte(offset, length - 1, 0);
+        }
+
+        Ok(AmlValue::Buffer { data: BufferData::Arena(offset), length: length })
+    }
+
+    /// Allocates a string of the given length in the arena, zeroed, with room for it's null terminator.
+    fn new_string(&mut self, length: usize) -> Result<AmlValue, AmlError> {
+        let offset = self.namespace.allocate(length + 1, self.namespace.loading)?;
+
+        Ok(AmlValue::String { data: BufferData::Arena(offset), length: length })
+    }
+
+    /// Copies bytes from a string or buffer, starting at the given index, to the start of one in the arena.
+    fn copy_bytes(&mut self, source: AmlValue, start: usize, destination: AmlValue, length: usize) {
+        let offset = match destination {
+            AmlValue::String { data: BufferData::Arena(offset), .. }
+                | AmlValue::Buffer { data: BufferData::Arena(offset), .. } => offset,
+            _ => return
+        };
+
+        for index in 0 .. length {
+            let byte = self.byte_at(source, start + index);
+            self.namespace.set_buffer_byte(offset, index, byte);
+        }
+    }
+
+    /// Converts an integer to a buffer, for the operators which take buffers or strings; anything else stays
+    /// as it is.
+    fn implicit_buffer(&mut self, value: AmlValue) -> Result<AmlValue, AmlError> {
+        match value {
+            AmlValue::Integer(integer) => self.integer_buffer(integer),
+            AmlValue::Buffer { .. } | AmlValue::String { .. } => Ok(value),
+            _ => Err(AmlError::TypeMismatch)
+        }
+    }
+
+    /// Converts a value to a string for ToDecimalString (radix 10) or ToHexString (16), as ACPICA does: integers
+    /// become their digits (in hex, as many as an integer is wide), buffers the value of each byte, separated by
+    /// commas (each with 0x in front in hex), and strings stay as they are.
+    fn convert_to_string(&mut self, value: AmlValue, radix: u64) -> Result<AmlValue, AmlError> {
+        let mut digits = [0; MAX_DIGITS];
+
+        match value {
+            AmlValue::String { .. } => Ok(value),
+            AmlValue::Integer(integer) => {
+                let width = match radix {
+                    16 if self.namespace.narrow_integers => 8,
+                    16 => 16,
+                    _ => 1
+                };
+                let start = integer_digits(integer, radix, width, &mut digits);
+
+                let result = self.new_string(MAX_DIGITS - start)?;
+                self.write_bytes(result, 0, &digits[start ..]);
+                Ok(result)
+            },
+            AmlValue::Buffer { length, .. } => {
+                let prefix: &[u8] = if radix == 16 { b"0x" } else { b"" };
+                let width = if radix == 16 { 2 } else { 1 };
+
+                // The string is written straight into the arena, so it's length has to be worked out first.
+                let mut total = length.saturating_sub(1);
+                for index in 0 .. length {
+                    total += prefix.len() + MAX_DIGITS - integer_digits(self.byte_at(value, index) as u64, radix,
+                        width, &mut digits);
+                }
+
+                let result = self.new_string(total)?;
+                let mut position = 0;
+
+                for index in 0 .. length {
+                    if index > 0 { position = self.write_bytes(result, position, b","); }
+
+                    let start = integer_digits(self.byte_at(value, index) as u64, radix, width, &mut digits);
+                    position = self.write_bytes(result, position, prefix);
+                    position = self.write_bytes(result, position, &digits[start ..]);
+                }
+
+                Ok(result)
+            },
+            _ => Err(AmlError::TypeMismatch)
+        }
+    }
+
+    /// Writes bytes into a string or buffer in the arena at the given position, returning the position after
+    /// them.
+    fn write_bytes(&mut self, destination: AmlValue, position: usize, bytes: &[u8]) -> usize {
+        if let AmlValue::String { data: BufferData::Arena(offset), .. }
+            | AmlValue::Buffer { data: BufferData::Arena(offset), .. } = destination {
+
+            for (index, &byte) in bytes.iter().enumerate() {
+                self.namespace.set_buffer_byte(offset, position + index, byte);
+            }
+        }
+
+        position + bytes.len()
+    }
+
+    /// Evaluates the signature, OEM ID and OEM table ID strings DataTableRegion and LoadTable find tables by,
+    /// padded with zeros to the size of the header's fields.
+    fn table_key(&mut self, stream: &mut Stream, frame: &mut Frame) -> Result<([u8; 4], [u8; 6], [u8; 8]), AmlError> {
+        let mut key = ([0; 4], [0; 6], [0; 8]);
+
+        let signature = self.evaluate(stream, frame)?;
+        self.copy_string(signature, &mut key.0)?;
+        let oem_id = self.evaluate(stream, frame)?;
+        self.copy_string(oem_id, &mut key.1)?;
+        let oem_table_id = self.evaluate(stream, frame)?;
+        self.copy_string(oem_table_id, &mut key.2)?;
+
+        Ok(key)
+    }
+
+    /// Copies a string into the given bytes, which it has to fit in.
+    fn copy_string(&self, value: AmlValue, bytes: &mut [u8]) -> Result<(), AmlError> {
+        match value {
+            AmlValue::String { length, .. } if length <= bytes.len() => {},
+            _ => return Err(AmlError::TypeMismatch)
+        }
+
+        for (byte, value) in bytes.iter_mut().zip(self.bytes(value)) {
+            *byte = value;
+        }
+
+        Ok(())
+    }
+
+    /// Finds the node a path in a string (like LoadTable's root path) names, relative to the given scope; an
+    /// empty string names nothing.
+    fn path_node(&self, scope: NodeId, path: AmlValue) -> Result<Option<NodeId>, AmlError> {
+        let mut bytes = [0; MAX_PATH_LENGTH];
+        let length = self.byte_length(path)?;
+
+        if length == 0 { return Ok(None); }
+        if length > MAX_PATH_LENGTH { return Err(AmlError::InvalidNameString); }
+
+        self.copy_string(path, &mut bytes)?;
+        let path = str::from_utf8(&bytes[.. length]).map_err(|_| AmlError::InvalidNameString)?;
+
+        self.namespace.lookup(scope, path).map(Some).ok_or(AmlError::NameNotFound)
+    }
+
+    /// Implements \_OSI, returning Ones if we support the given interface.
+    fn osi(&mut self, interface: AmlValue) -> Result<AmlValue, AmlError> {
+        match interface {
+            AmlValue::String { .. } => {},
+            _ => return Err(AmlError::TypeMismatch)
+        }
+
+        let supported = SUPPORTED_INTERFACES.iter()
+            .any(|supported| self.bytes(interface).eq(supported.iter().cloned()));
+
+        Ok(self.logical(supported))
+    }
+
+    /// Resolves a name, failing if it doesn't exist.
+    fn resolve(&self, scope: NodeId, name: &NameString) -> Result<NodeId, AmlError> {
+        self.namespace.resolve(scope, name).ok_or(AmlError::NameNotFound)
+    }
+
+    /// Creates a new node for the given name.
+    fn create(&mut self, scope: NodeId, name: &NameString, kind: NodeKind) -> Result<NodeId, AmlError> {
+        let (parent, seg) = self.namespace.resolve_for_create(scope, name)?;
+        self.namespace.add(parent, seg, kind)
+    }
+
+    /// Handles a name which appears as a TermArg: methods are invoked (parsing their arguments from the
+    /// stream), and anything else is read.
+    fn call_or_read(&mut self, stream: &mut Stream, frame: &mut Frame, node: NodeId) -> Result<AmlValue, AmlError> {
+        let arg_count = match self.namespace.node(node).kind {
+            NodeKind::Method { arg_count, .. } => arg_count as usize,
+            NodeKind::Builtin(Builtin::Osi) => 1,
+            _ => return self.read_node(node)
+        };
+
+        let mut args = [AmlValue::Uninitialized; ARG_COUNT];
+        for arg in &mut args[.. arg_count] {
+            *arg = self.evaluate(stream, frame)?;
+        }
+
+        self.invoke(node, &args[.. arg_count])
+    }
+
+    /// Reads through a reference; references to objects with no value stay references.
+    fn read_reference(&mut self, node: NodeId) -> Result<AmlValue, AmlError> {
+        match self.namespace.node(node).kind {
+            NodeKind::Method { .. } => Ok(AmlValue::Reference(node)),
+            _ => self.read_node(node)
+        }
+    }
+
+    /// Reads the value of a node: the value of a name, the contents of a field, or the result of a method
+    /// which takes no arguments. Other objects evaluate to a reference to themselves.
+    fn read_node(&mut self, node: NodeId) -> Result<AmlValue, AmlError> {
+        match self.namespace.node(node).kind {
+            NodeKind::Name(value) => Ok(value),
+            NodeKind::Field { region, bit_offset, bit_length, flags } => {
+                let region = self.region(region)?;
+
+                // UNSAFE: Safe, as far as we trust the firmware to describe it's own hardware.
+                unsafe { region.read_field(bit_offset, bit_length, flags) }.map(AmlValue::Integer)
+            },
+            NodeKind::IndexField { index, data, bit_offset, bit_length, flags } => {
+                region::read_units(bit_offset, bit_length, flags, |offset| {
+                    self.write_node(index, AmlValue::Integer(offset))?;
+                    let value = self.read_node(data)?;
+                    self.to_integer(value)
+                }).map(AmlValue::Integer)
+            },
+            NodeKind::BufferField { buffer, bit_offset, bit_length } => {
+                let (data, length) = match buffer {
+                    AmlValue::Buffer { data, length } => (data, length),
+                    _ => return Err(AmlError::TypeMismatch)
+                };
+
+                if bit_length > 64 || bit_offset + bit_length > length as u64 * 8 {
+                    return Err(AmlError::IndexOutOfBounds);
+                }
+
+                let mut value = 0;
+                for bit in 0 .. bit_length {
+                    let position = bit_offset + bit;
+                    let byte = self.namespace.buffer_byte(data, (position / 8) as usize);
+
+                    value |= (((byte >> (position % 8)) & 1) as u64) << bit;
+                }
+
+                Ok(AmlValue::Integer(value))
+            },
+            NodeKind::Method { arg_count: 0, .. } => self.invoke(node, &[]),
+            _ => Ok(AmlValue::Reference(node))
+        }
+    }
+
+    /// Writes a value to a node.
+    fn write_node(&mut self, node: NodeId, value: AmlValue) -> Result<(), AmlError> {
+        match self.namespace.node(node).kind {
+            NodeKind::Name(AmlValue::Integer(_)) => {
+                // Integers stay integers (the other conversions are rarely relied on).
+                let value = self.to_integer(value)?;
+                self.namespace.node_mut(node).kind = NodeKind::Name(AmlValue::Integer(value));
+            },
+            NodeKind::Name(_) => self.namespace.node_mut(node).kind = NodeKind::Name(value),
+            NodeKind::Field { region, bit_offset, bit_length, flags } => {
+                let value = self.to_integer(value)?;
+                let region = self.region(region)?;
+
+                // UNSAFE: Safe, as far as we trust the firmware to describe it's own hardware.
+                unsafe { region.write_field(bit_offset, bit_length, flags, value)?; }
+            },
+            NodeKind::IndexField { index, data, bit_offset, bit_length, flags } => {
+                let value = self.to_integer(value)?;
+
+                region::write_units(bit_offset, bit_length, flags, value, |access| match access {
+                    UnitAccess::Read(offset) => {
+                        self.write_node(index, AmlValue::Integer(offset))?;
+                        let value = self.read_node(data)?;
+                        self.to_integer(value)
+                    },
+                    UnitAccess::Write(offset, unit) => {
+                        self.write_node(index, AmlValue::Integer(offset))?;
+                        self.write_node(data, AmlValue::Integer(unit)).map(|_| 0)
+                    }
+                })?;
+            },
+            NodeKind::BufferField { buffer, bit_offset, bit_length } => {
+                let value = self.to_integer(value)?;
+                let (offset, length) = match buffer {
+                    AmlValue::Buffer { data: BufferData::Arena(offset), length } => (offset, length),
+                    _ => return Err(AmlError::TypeMismatch)
+                };
+
+                if bit_length > 64 || bit_offset + bit_length > length as u64 * 8 {
+                    return Err(AmlError::IndexOutOfBounds);
+                }
+
+                for bit in 0 .. bit_length {
+                    let position = bit_offset + bit;
+                    let index = (position / 8) as usize;
+                    let byte = self.namespace.buffer_byte(BufferData::Arena(offset), index) & !(1 << (position % 8));
+
+                    let set = (((value >> bit) & 1) as u8) << (position % 8);
+
+                    self.namespace.set_buffer_byte(offset, index, byte | set);
+                }
+            },
+            // Storing to an uninitialized external just defines it.
+            NodeKind::External => self.namespace.node_mut(node).kind = NodeKind::Name(value),
+            _ => return Err(AmlError::TypeMismatch)
+        }
+
+        Ok(())
+    }
+
+    /// Works out where an operation region lives.
+    fn region(&mut self, node: NodeId) -> Result<Region, AmlError> {
+        match self.namespace.node(node).kind {
+            NodeKind::OperationRegion { space, offset, .. } => {
+                let pci = if space == SPACE_PCI_CONFIG { Some(self.pci_address(node)) } else { None };
+
+                Ok(Region { space: space, offset: offset, pci: pci })
+            },
+            _ => Err(AmlError::TypeMismatch)
+        }
+    }
+
+    /// Works out which PCI function a configuration space region belongs to: the device is given by the
+    /// _ADR of the enclosing device, and the bus and segment group by the _BBN and _SEG of the host bridge
+    /// above it.
+    fn pci_address(&mut self, region: NodeId) -> PciAddress {
+        let device = match self.namespace.enclosing_device(region) {
+            Some(device) => device,
+            None => return PciAddress::new(0, 0, 0)
+        };
+
+        let address = self.child_integer(device, *b"_ADR").unwrap_or(0);
+
+        let mut bus = 0;
+        let mut segment = 0;
+        let mut current = device;
+        while current != ROOT {
+            if let Some(number) = self.child_integer(current, *b"_BBN") {
+                bus = number;
+                segment = self.child_integer(current, *b"_SEG").unwrap_or(0);
+                break;
+            }
+
+            current = self.namespace.node(current).parent;
+        }
+
+        PciAddress { segment: segment as u16, bus: bus as u8, device: (address >> 16) as u8, function: address as u8 }
+    }
+
+    /// Evaluates the given child of a node as an integer, if it exists.
+    fn child_integer(&mut self, node: NodeId, name: [u8; 4]) -> Option<u64> {
+        let child = self.namespace.child(node, name)?;
+        let value = self.invoke(child, &[]).ok()?;
+
+        self.to_integer(value).ok()
+    }
+
+    /// The ObjectType code of a node.
+    fn node_type(&mut self, node: NodeId) -> u64 {
+        match self.namespace.node(node).kind {
+            NodeKind::Scope | NodeKind::External | NodeKind::Alias(_) => TYPE_UNINITIALIZED,
+            NodeKind::Device => TYPE_DEVICE,
+            NodeKind::Processor { .. } => TYPE_PROCESSOR,
+            NodeKind::PowerResource => TYPE_POWER_RESOURCE,
+            NodeKind::ThermalZone => TYPE_THERMAL_ZONE,
+            NodeKind::Method { .. } | NodeKind::Builtin(_) => TYPE_METHOD,
+            NodeKind::Name(value) => value.type_code(),
+            NodeKind::OperationRegion { .. } => TYPE_OPERATION_REGION,
+            NodeKind::Field { .. } | NodeKind::IndexField { .. } => TYPE_FIELD_UNIT,
+            NodeKind::BufferField { .. } => TYPE_BUFFER_FIELD,
+            NodeKind::Mutex => TYPE_MUTEX,
+            NodeKind::Event => TYPE_EVENT
+        }
+    }
+
+    /// True if the next term is a SuperName which refers to somewhere a value is held.
+    fn is_super_name_start(&self, stream: &Stream) -> Result<bool, AmlError> {
+        let op = stream.peek()?;
+
+        Ok(is_name_string_start(op) || (op >= LOCAL0_OP && op <= ARG6_OP))
+    }
+
+    /// Parses a SuperName or Target. If `must_exist` is false, names which don't resolve give
+    /// Target::Unresolved rather than an error.
+    fn super_name(&mut self, stream: &mut Stream, frame: &mut Frame, must_exist: bool) -> Result<Target, AmlError> {
+        let op = stream.peek()?;
+
+        match op {
+            ZERO_OP => {
+                stream.byte()?;
+                Ok(Target::None)
+            },
+            LOCAL0_OP ..= LOCAL7_OP => {
+                stream.byte()?;
+                Ok(Target::Local((op - LOCAL0_OP) as usize))
+            },
+            ARG0_OP ..= ARG6_OP => {
+                stream.byte()?;
+                Ok(Target::Arg((op - ARG0_OP) as usize))
+            },
+            EXT_OP_PREFIX if stream.peek_second()? == EXT_DEBUG_OP => {
+                stream.byte()?;
+                stream.byte()?;
+                Ok(Target::Debug)
+            },
+            op if is_name_string_start(op) => {
+                let name = stream.name_string()?;
+
+                match self.namespace.resolve(frame.scope, &name) {
+                    Some(node) => Ok(Target::Node(node)),
+                    None if !must_exist => Ok(Target::Unresolved),
+                    None => Err(AmlError::NameNotFound)
+                }
+            },
+            INDEX_OP => {
+                stream.byte()?;
+                self.index_target(stream, frame)
+            },
+            // Anything else (RefOf, DerefOf) has to produce a reference.
+            _ => match self.evaluate(stream, frame)? {
+                AmlValue::Reference(node) => Ok(Target::Node(node)),
+                _ => Err(AmlError::Unsupported)
+            }
+        }
+    }
+
+    /// Parses the operands of an Index used as a SuperName, giving a target referring to the element, so a store
+    /// to it (like `Store(x, Index(Local0, 1))`) changes the package, buffer or string itself. Like Index as a
+    /// TermArg, it also stores the element to it's own target.
+    fn index_target(&mut self, stream: &mut Stream, frame: &mut Frame) -> Result<Target, AmlError> {
+        let (source, value) = self.source(stream, frame)?;
+        let index = self.evaluate_integer(stream, frame)? as usize;
+        let result = self.super_name(stream, frame, true)?;
+
+        let container = match value {
+            AmlValue::Package { count, .. } if index < count => value,
+            AmlValue::Buffer { length, .. } | AmlValue::String { length, .. } if index < length =>
+                self.make_writable(source, value, frame)?,
+            AmlValue::Package { .. } | AmlValue::Buffer { .. } | AmlValue::String { .. } =>
+                return Err(AmlError::IndexOutOfBounds),
+            _ => return Err(AmlError::TypeMismatch)
+        };
+
+        let element = self.element(container, index)?;
+        self.store(result, element, frame)?;
+
+        Ok(Target::Element(container, index))
+    }
+
+    /// Reads the value held by a target.
+    fn read_target(&mut self, target: Target, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+        match target {
+            Target::Local(index) => Ok(frame.locals[index]),
+            Target::Arg(index) => match frame.args[index] {
+                AmlValue::Reference(node) => self.read_reference(node),
+                value => Ok(value)
+            },
+            Target::Node(node) => self.read_node(node),
+            Target::Element(container, index) => self.element(container, index),
+            _ => Ok(AmlValue::Uninitialized)
+        }
+    }
+
+    /// Stores a value to a target.
+    fn store(&mut self, target: Target, value: AmlValue, frame: &mut Frame) -> Result<(), AmlError> {
+        match target {
+            Target::None => Ok(()),
+            Target::Local(index) => {
+                frame.locals[index] = value;
+                Ok(())
+            },
+            // Arguments holding references store through the reference.
+            Target::Arg(index) => match frame.args[index] {
+                AmlValue::Reference(node) => self.write_node(node, value),
+                _ => {
+                    frame.args[index] = value;
+                    Ok(())
+                }
+            },
+            Target::Node(node) => self.write_node(node, value),
+            Target::Element(container, index) => match container {
+                AmlValue::Package { elements, .. } => {
+                    self.namespace.set_element(elements, index, value);
+                    Ok(())
+                },
+                AmlValue::Buffer { data: BufferData::Arena(offset), .. }
+                    | AmlValue::String { data: BufferData::Arena(offset), .. } => {
+
+                    let byte = self.to_integer(value)? as u8;
+                    self.namespace.set_buffer_byte(offset, index, byte);
+                    Ok(())
+                },
+                _ => Err(AmlError::TypeMismatch)
+            },
+            Target::Debug => {
+                println!("[AML] {:?}", value);
+                Ok(())
+            },
+            Target::Unresolved => Err(AmlError::NameNotFound)
+        }
+    }
+}
+
+/// Evaluates one of the Match comparisons (MTR, MEQ, MLE, MLT, MGE, MGT) of a package element against an operand.
+fn match_integer(op: u8, element: u64, operand: u64) -> bool {
+    match op {
+        0 => true,
+        1 => element == operand,
+        2 => element <= operand,
+        3 => element < operand,
+        4 => element >= operand,
+        5 => element > operand,
+        _ => false
+    }
+}
+
+/// Writes the digits of an integer in the given radix (in capitals) to the end of `digits`, padded with zeros to
+/// at least `width` digits, returning where they start.
+fn integer_digits(mut value: u64, radix: u64, width: usize, digits: &mut [u8; MAX_DIGITS]) -> usize {
+    let mut start = MAX_DIGITS;
+
+    while value != 0 || MAX_DIGITS - start < width {
+        start -= 1;
+        digits[start] = b"0123456789ABCDEF"[(value % radix) as usize];
+        value /= radix;
+    }
+
+    start
+}
+
+/// The table whose header is at the start of the given memory, if all of it is there.
+fn table_in(memory: &'static [u8]) -> Result<&'static SDTHeader, AmlError> {
+    if memory.len() < mem::size_of::<SDTHeader>() { return Err(AmlError::InvalidTable); }
+
+    // UNSAFE: Safe, as the memory is big enough for the header, which can be at any alignment.
+    let table = unsafe { &*(memory.as_ptr() as *const SDTHeader) };
+    if table.length as usize > memory.len() { return Err(AmlError::InvalidTable); }
+
+    Ok(table)
+}
+
+/// Parses an integer from the bytes of a string in the given radix, stopping at the first invalid character.
+fn parse_integer<I: Iterator<Item = u8>>(bytes: I, radix: u32) -> u64 {
+    let mut value = 0u64;
+
+    for byte in bytes {
+        match (byte as char).to_digit(radix) {
+            Some(digit) => value = value.wrapping_mul(radix as u64).wrapping_add(digit as u64),
+            None => break
+        }
+    }
+
+    value
+}
+
+/// Busy-waits for at least the given number of microseconds, on the HPET's main counter if there is one. Without
+/// one it's roughly, on writes to the POST port, which take about a microsecond each.
+fn delay(microseconds: u64) {
+    if let Some(start) = hpet::nanoseconds() {
+        let end = start.saturating_add(microseconds.saturating_mul(1000));
+
+        while hpet::nanoseconds().map_or(false, |now| now < end) { }
+        return;
+    }
+
+    for _ in 0 .. microseconds {
+        // UNSAFE: Safe, as nothing listens to the POST port.
+        unsafe { port::outb(0x80, 0); }
+    }
+}
@@ -0,0 +1,229 @@
+//! Provides an interpreter for AML, the bytecode in the DSDT and SSDTs which describes the devices on the
+//! machine and how to control them. Loading the tables builds the ACPI namespace, which can then be
+//! queried by path (e.g. evaluate("\\_S5", &[]) for the S5 sleep type values).
+
+mod opcodes;
+mod parser;
+mod value;
+mod namespace;
+mod region;
+mod interpreter;
+pub mod resource;
+pub mod routing;
+
+#[cfg(test)]
+mod tests;
+
+pub use self::value::{AmlValue, BufferData};
+pub use self::namespace::{NodeId, NodeKind, ROOT};
+
+use core::{mem, slice};
+
+use spin::Mutex;
+
+use super::{ACPI, FADT, SDTHeader};
+use self::namespace::Namespace;
+use self::interpreter::Interpreter;
+
+/// The reasons loading or evaluating AML can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmlError {
+    /// The bytecode ended in the middle of something.
+    UnexpectedEnd,
+
+    /// An opcode we don't know (or which can't appear where it did).
+    InvalidOpcode(u8),
+
+    /// An extended opcode (after 0x5B) we don't know.
+    InvalidExtendedOpcode(u8),
+
+    /// A malformed NameString, or one which goes above the root.
+    InvalidNameString,
+
+    /// A name which doesn't exist in the namespace.
+    NameNotFound,
+
+    /// There's no room left for more nodes in the namespace.
+    NamespaceFull,
+
+    /// There's no room left in the buffer arena.
+    OutOfMemory,
+
+    /// A value of the wrong type for the operation.
+    TypeMismatch,
+
+    DivideByZero,
+    IndexOutOfBounds,
+
+    /// A field in an operation region whose address space we can't access.
+    UnsupportedRegionSpace(u8),
+
+    /// A field in a system memory operation region at a physical address the kernel doesn't map.
+    UnmappedRegion(u64),
+
+    /// Something valid which the interpreter doesn't implement.
+    Unsupported,
+
+    /// Methods called each other too deeply.
+    TooDeep,
+
+    /// A While loop ran for too long.
+    LoopTimeout,
+
+    /// The firmware executed a Fatal operator.
+    Fatal { kind: u8, code: u32, argument: u64 },
+
+    /// The table has the wrong signature or an invalid checksum.
+    InvalidTable,
+
+    /// There's no table with the signature and OEM IDs a DataTableRegion gave.
+    TableNotFound
+}
+
+/// The namespace, once the tables have been loaded.
+static NAMESPACE: Mutex<Namespace> = Mutex::new(Namespace::new());
+
+/// Loads the DSDT (which the FADT points to) and every SSDT into the namespace.
+/// UNSAFE: Dereferences the table pointers in the FADT and the root table.
+/// UNSAFE: The tables must stay mapped for as long as the namespace is used.
+pub unsafe fn init(acpi: &ACPI) -> Result<(), AmlError> {
+    let fadt = acpi.find_table::<FADT>().ok_or(AmlError::InvalidTable)?;
+    let dsdt = acpi.table_at(fadt.dsdt_address()).ok_or(AmlError::InvalidTable)?;
+
+    let mut namespace = NAMESPACE.lock();
+    namespace.add_predefined()?;
+
+    // Revision 1 tables only have 32-bit integers.
+    namespace.narrow_integers = dsdt.revision < 2;
+
+    // The AML can find these by signature itself (with DataTableRegion and LoadTable).
+    for address in acpi.raw_tables() {
+        if let Some(table) = acpi.table_at(address) {
+            namespace.add_table(address, &*(table as *const SDTHeader));
+        }
+    }
+
+    load_table(&mut namespace, definition_block(dsdt, b"DSDT")?)?;
+
+    for address in acpi.raw_tables() {
+        let table = match acpi.table_at(address) {
+            Some(table) if &table.signature == b"SSDT" => table,
+            _ => continue
+        };
+
+        // One broken SSDT shouldn't cost us the rest of the namespace.
+        if let Err(error) = definition_block(table, b"SSDT").and_then(|aml| load_table(&mut namespace, aml)) {
+            color_println!(::vga::Color::Red, "- AML: SSDT @ 0x{:x} failed to load ({:?})", address, error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates a definition block (a DSDT or SSDT), and obtains the AML after it's header.
+/// UNSAFE: The table must stay where it is for as long as the AML is used.
+pub unsafe fn definition_block(table: &SDTHeader, signature: &[u8]) -> Result<&'static [u8], AmlError> {
+    let length = table.length as usize;
+
+    if &table.signature != signature || length < mem::size_of::<SDTHeader>() || !table.verify_checksum() {
+        return Err(AmlError::InvalidTable);
+    }
+
+    let start = (table as *const SDTHeader as *const u8).offset(mem::size_of::<SDTHeader>() as isize);
+
+    Ok(slice::from_raw_parts(start, length - mem::size_of::<SDTHeader>()))
+}
+
+/// Loads a definition block's AML into the namespace.
+fn load_table(namespace: &mut Namespace, aml: &'static [u8]) -> Result<(), AmlError> {
+    Interpreter::new(namespace).load_table(aml)
+}
+
+/// Evaluates the object at the given absolute path (e.g. "\\_SB.PCI0._PRT"): methods are invoked with the
+/// given arguments, and other objects are just read.
+pub fn evaluate(path: &str, args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+    evaluate_relative(ROOT, path, args)
+}
+
+/// Evaluates the object at the given path relative to a node (e.g. "_CRS" relative to a device).
+pub fn evaluate_relative(node: NodeId, path: &str, args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+    let mut namespace = NAMESPACE.lock();
+    let target = namespace.lookup(node, path).ok_or(AmlError::NameNotFound)?;
+
+    // Nothing from the last evaluation can be referred to any more.
+    namespace.reset_temporary();
+
+    Interpreter::new(&mut namespace).invoke(target, args)
+}
+
+/// Like evaluate(), but gives up (returning None) rather than waiting if the namespace is in use.
+pub fn try_evaluate(path: &str, args: &[AmlValue]) -> Option<Result<AmlValue, AmlError>> {
+    let mut namespace = NAMESPACE.try_lock()?;
+    let target = match namespace.lookup(ROOT, path) {
+        Some(target) => target,
+        None => return Some(Err(AmlError::NameNotFound))
+    };
+
+    namespace.reset_temporary();
+    Some(Interpreter::new(&mut namespace).invoke(target, args))
+}
+
+/// Evaluates the object at the given path, converting the result to an integer.
+pub fn evaluate_integer(path: &str) -> Result<u64, AmlError> {
+    let value = evaluate(path, &[])?;
+
+    Interpreter::new(&mut NAMESPACE.lock()).to_integer(value)
+}
+
+/// Finds the node at the given absolute path.
+pub fn find(path: &str) -> Option<NodeId> {
+    NAMESPACE.lock().lookup(ROOT, path)
+}
+
+/// True if there's an object at the given absolute path.
+pub fn exists(path: &str) -> bool {
+    find(path).is_some()
+}
+
+/// The number of objects in the namespace.
+pub fn object_count() -> usize {
+    NAMESPACE.lock().len()
+}
+
+/// Takes the oldest notification (the object and the notification value) raised by a Notify in the methods
+/// evaluated so far.
+pub fn take_notification() -> Option<(NodeId, u64)> {
+    NAMESPACE.lock().take_notification()
+}
+
+/// Obtains an element of a package returned by evaluate(). Must be called before the next evaluation, as
+/// elements may refer to temporary buffers.
+pub fn package_element(package: AmlValue, index: usize) -> Result<AmlValue, AmlError> {
+    Interpreter::new(&mut NAMESPACE.lock()).package_element(package, index)
+}
+
+/// Obtains an element of a package as an integer.
+pub fn package_integer(package: AmlValue, index: usize) -> Result<u64, AmlError> {
+    let mut namespace = NAMESPACE.lock();
+    let mut interpreter = Interpreter::new(&mut namespace);
+
+    let element = interpreter.package_element(package, index)?;
+    interpreter.to_integer(element)
+}
+
+/// Copies the bytes of a buffer (or string) value into the given slice, returning the length of the buffer
+/// (which may be longer than the slice).
+pub fn buffer_bytes(value: AmlValue, bytes: &mut [u8]) -> Result<usize, AmlError> {
+    let namespace = NAMESPACE.lock();
+
+    let (data, length) = match value {
+        AmlValue::Buffer { data, length } | AmlValue::String { data, length } => (data, length),
+        _ => return Err(AmlError::TypeMismatch)
+    };
+
+    for (index, byte) in bytes.iter_mut().take(length).enumerate() {
+        *byte = namespace.buffer_byte(data, index);
+    }
+
+    Ok(length)
+}
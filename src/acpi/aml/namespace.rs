@@ -0,0 +1,441 @@
+//! Provides the ACPI namespace: the tree of named objects the DSDT and SSDTs define. Nodes live in a fixed
+//! size array rather than on the heap, like the values' arenas, and refer to their parents by index.
+
+use acpi::SDTHeader;
+use super::AmlError;
+use super::parser::NameString;
+use super::value::{AmlValue, BufferData};
+
+/// The index of a node in the namespace.
+pub type NodeId = usize;
+
+/// The root of the namespace (\).
+pub const ROOT: NodeId = 0;
+
+/// The maximum number of nodes the namespace can hold; QEMU's tables define a few hundred.
+pub const MAX_NODES: usize = 2048;
+
+/// The number of bytes available for writable copies of buffers.
+pub const ARENA_SIZE: usize = 16 * 1024;
+
+/// The number of package elements which can exist at once; QEMU's routing tables have over a thousand.
+pub const MAX_ELEMENTS: usize = 4096;
+
+/// The number of tables DataTableRegion and LoadTable can find.
+pub const MAX_TABLES: usize = 64;
+
+/// The number of Notify operations which can be waiting to be taken.
+pub const MAX_NOTIFICATIONS: usize = 16;
+
+/// Methods which the interpreter implements itself, rather than the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    /// \_OSI, which the firmware uses to ask which operating system interfaces we support.
+    Osi
+}
+
+/// The kind of object a node is, along with any data specific to that kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A plain scope, like the root or \_SB.
+    Scope,
+    Device,
+    Processor { id: u8, block_address: u32, block_length: u8 },
+    PowerResource,
+    ThermalZone,
+
+    /// A control method, whose bytecode is `code`.
+    Method { code: &'static [u8], arg_count: u8, serialized: bool },
+
+    /// A method implemented by the interpreter.
+    Builtin(Builtin),
+
+    /// A named data object.
+    Name(AmlValue),
+
+    /// An operation region in the given address space.
+    OperationRegion { space: u8, offset: u64, length: u64 },
+
+    /// A field within an operation region.
+    Field { region: NodeId, bit_offset: u64, bit_length: u64, flags: u8 },
+
+    /// A field accessed by writing an index to one field and then accessing another.
+    IndexField { index: NodeId, data: NodeId, bit_offset: u64, bit_length: u64, flags: u8 },
+
+    /// A field within a buffer; the buffer is always a writable copy in the arena, shared with whatever
+    /// object held the buffer when the field was created.
+    BufferField { buffer: AmlValue, bit_offset: u64, bit_length: u64 },
+
+    /// Another name for the given node.
+    Alias(NodeId),
+    Mutex,
+    Event,
+
+    /// A declaration that a name exists in some other table.
+    External
+}
+
+/// A single named object in the namespace.
+#[derive(Debug, Clone, Copy)]
+pub struct Node {
+    /// The NameSeg of this node.
+    pub name: [u8; 4],
+
+    /// The scope this node lives in; the root is it's own parent.
+    pub parent: NodeId,
+
+    /// What this node actually is.
+    pub kind: NodeKind
+}
+
+/// An unused node slot.
+const EMPTY_NODE: Node = Node { name: [0; 4], parent: ROOT, kind: NodeKind::Scope };
+
+/// The whole ACPI namespace, plus the byte arena used for modified buffers, and the arena package elements
+/// live in.
+pub struct Namespace {
+    /// The nodes; only the first `count` are in use.
+    nodes: [Node; MAX_NODES],
+
+    /// The number of nodes in use.
+    count: usize,
+
+    /// The number of nodes which were created by loading tables; see is_persistent().
+    persistent_count: usize,
+
+    /// Storage for writable buffers. Persistent buffers (belonging to objects created while loading tables)
+    /// grow up from the bottom; temporary ones (created while evaluating a method) grow down from the top
+    /// and are thrown away before the next evaluation.
+    arena: [u8; ARENA_SIZE],
+
+    /// The end of the persistent part of the arena.
+    persistent_top: usize,
+
+    /// The start of the temporary part of the arena.
+    temporary_bottom: usize,
+
+    /// The elements of packages, split between persistent and temporary packages in the same way as the arena.
+    elements: [AmlValue; MAX_ELEMENTS],
+    persistent_elements: usize,
+    temporary_elements: usize,
+
+    /// The tables in the root table, by their physical address, for DataTableRegion and LoadTable.
+    tables: [Option<(u64, &'static SDTHeader)>; MAX_TABLES],
+
+    /// True while tables are being loaded, as opposed to methods being evaluated.
+    pub loading: bool,
+
+    /// True if integers are only 32 bits wide (for DSDTs with revision < 2).
+    pub narrow_integers: bool,
+
+    /// The notifications (the object and the notification value) raised by Notify which haven't been taken yet,
+    /// oldest first.
+    notifications: [(NodeId, u64); MAX_NOTIFICATIONS],
+    notification_count: usize
+}
+
+impl Namespace {
+    /// Creates an empty namespace, which contains only the root.
+    pub const fn new() -> Namespace {
+        Namespace {
+            nodes: [EMPTY_NODE; MAX_NODES],
+            count: 1,
+            persistent_count: 1,
+            arena: [0; ARENA_SIZE],
+            persistent_top: 0,
+            temporary_bottom: ARENA_SIZE,
+            elements: [AmlValue::Uninitialized; MAX_ELEMENTS],
+            persistent_elements: 0,
+            temporary_elements: MAX_ELEMENTS,
+            tables: [None; MAX_TABLES],
+            loading: false,
+            narrow_integers: false,
+            notifications: [(ROOT, 0); MAX_NOTIFICATIONS],
+            notification_count: 0
+        }
+    }
+
+    /// Adds the objects the specification says exist before any table is loaded.
+    pub fn add_predefined(&mut self) -> Result<(), AmlError> {
+        for name in &[b"_GPE", b"_PR_", b"_SB_", b"_SI_", b"_TZ_"] {
+            self.add(ROOT, **name, NodeKind::Scope)?;
+        }
+
+        self.add(ROOT, *b"_OS_", NodeKind::Name(AmlValue::string(b"Microsoft Windows NT")))?;
+        self.add(ROOT, *b"_REV", NodeKind::Name(AmlValue::Integer(2)))?;
+        self.add(ROOT, *b"_OSI", NodeKind::Builtin(Builtin::Osi))?;
+        self.add(ROOT, *b"_GL_", NodeKind::Mutex)?;
+
+        Ok(())
+    }
+
+    /// Records a table DataTableRegion and LoadTable can find; past `MAX_TABLES`, tables are ignored.
+    pub fn add_table(&mut self, address: u64, header: &'static SDTHeader) {
+        if let Some(slot) = self.tables.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((address, header));
+        }
+    }
+
+    /// Finds a table by it's signature, OEM ID and OEM table ID, padded with zeros to the size of the header's
+    /// fields; empty IDs match any table. Gives the table's physical address and it's header.
+    pub fn find_table(&self, signature: &[u8; 4], oem_id: &[u8; 6], oem_table_id: &[u8; 8])
+        -> Option<(u64, &'static SDTHeader)> {
+
+        self.tables.iter().filter_map(|&table| table).find(|&(_, header)| {
+            header.signature == *signature && (oem_id[0] == 0 || header.oem_id == *oem_id)
+                && (oem_table_id[0] == 0 || header.oem_table_id == *oem_table_id)
+        })
+    }
+
+    /// The number of nodes in the namespace.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Obtains the node with the given id.
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id]
+    }
+
+    /// Obtains a mutable reference to the node with the given id.
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id]
+    }
+
+    /// Finds the child of the given scope with the given name.
+    pub fn child(&self, parent: NodeId, name: [u8; 4]) -> Option<NodeId> {
+        (1 .. self.count).find(|&id| self.nodes[id].parent == parent && self.nodes[id].name == name)
+    }
+
+    /// Adds a node to the given scope, replacing the kind of any node already there with the same name.
+    pub fn add(&mut self, parent: NodeId, name: [u8; 4], kind: NodeKind) -> Result<NodeId, AmlError> {
+        if let Some(existing) = self.child(parent, name) {
+            // Scopes and externals get replaced by the real object, but we never downgrade an object.
+            if kind != NodeKind::Scope && kind != NodeKind::External {
+                self.nodes[existing].kind = kind;
+            }
+
+            return Ok(existing);
+        }
+
+        if self.count >= MAX_NODES { return Err(AmlError::NamespaceFull); }
+
+        let id = self.count;
+        self.nodes[id] = Node { name: name, parent: parent, kind: kind };
+        self.count += 1;
+
+        Ok(id)
+    }
+
+    /// Walks the given number of parent prefixes up from the scope.
+    fn ascend(&self, mut scope: NodeId, prefixes: u8) -> Result<NodeId, AmlError> {
+        for _ in 0 .. prefixes {
+            if scope == ROOT { return Err(AmlError::InvalidNameString); }
+            scope = self.nodes[scope].parent;
+        }
+
+        Ok(scope)
+    }
+
+    /// Follows a chain of aliases to the actual object.
+    fn follow_aliases(&self, mut id: NodeId) -> NodeId {
+        // Bound the chain, so a malicious alias loop can't hang us.
+        for _ in 0 .. MAX_NODES {
+            match self.nodes[id].kind {
+                NodeKind::Alias(target) => id = target,
+                _ => break
+            }
+        }
+
+        id
+    }
+
+    /// Resolves a name relative to the given scope, following the namespace search rules for single
+    /// segment names (searching each enclosing scope up to the root).
+    pub fn resolve(&self, scope: NodeId, name: &NameString) -> Option<NodeId> {
+        if name.segment_count() == 0 {
+            return if name.root { Some(ROOT) } else { self.ascend(scope, name.parent_prefixes).ok() };
+        }
+
+        if name.is_simple() {
+            let seg = name.segment(0);
+            let mut current = scope;
+
+            loop {
+                if let Some(found) = self.child(current, seg) { return Some(self.follow_aliases(found)); }
+                if current == ROOT { return None; }
+
+                current = self.nodes[current].parent;
+            }
+        }
+
+        let mut current = if name.root { ROOT } else { self.ascend(scope, name.parent_prefixes).ok()? };
+        for index in 0 .. name.segment_count() {
+            current = self.follow_aliases(self.child(current, name.segment(index))?);
+        }
+
+        Some(current)
+    }
+
+    /// Resolves everything but the last segment of the name, so a new object can be created; returns
+    /// the scope to create it in and the name to give it.
+    pub fn resolve_for_create(&self, scope: NodeId, name: &NameString) -> Result<(NodeId, [u8; 4]), AmlError> {
+        let count = name.segment_count();
+        if count == 0 { return Err(AmlError::InvalidNameString); }
+
+        let mut current = if name.root { ROOT } else { self.ascend(scope, name.parent_prefixes)? };
+        for index in 0 .. count - 1 {
+            let child = self.child(current, name.segment(index)).ok_or(AmlError::NameNotFound)?;
+            current = self.follow_aliases(child);
+        }
+
+        Ok((current, name.segment(count - 1)))
+    }
+
+    /// Finds a node from a textual path like "\_SB.PCI0._PRT". Paths without a leading backslash are relative
+    /// to the given scope (and are not subject to the search rules). Segments shorter than 4 characters are
+    /// padded with underscores.
+    pub fn lookup(&self, scope: NodeId, path: &str) -> Option<NodeId> {
+        let (mut current, path) = if path.starts_with('\\') { (ROOT, &path[1 ..]) } else { (scope, path) };
+        if path.is_empty() { return Some(current); }
+
+        for segment in path.split('.') {
+            let bytes = segment.as_bytes();
+            if bytes.is_empty() || bytes.len() > 4 { return None; }
+
+            let mut name = *b"____";
+            name[.. bytes.len()].copy_from_slice(bytes);
+
+            current = self.follow_aliases(self.child(current, name)?);
+        }
+
+        Some(current)
+    }
+
+    /// Finds the closest enclosing node (including the node itself) which is a device, if any.
+    pub fn enclosing_device(&self, mut id: NodeId) -> Option<NodeId> {
+        loop {
+            if self.nodes[id].kind == NodeKind::Device { return Some(id); }
+            if id == ROOT { return None; }
+
+            id = self.nodes[id].parent;
+        }
+    }
+
+    /// Masks an integer to the width integers have in the loaded tables.
+    pub fn truncate(&self, value: u64) -> u64 {
+        if self.narrow_integers { value & 0xFFFFFFFF } else { value }
+    }
+
+    /// Allocates `length` zeroed bytes in the arena, returning their offset. Persistent storage lives as long
+    /// as the namespace; temporary storage only until the next evaluation starts.
+    pub fn allocate(&mut self, length: usize, persistent: bool) -> Result<usize, AmlError> {
+        if self.temporary_bottom - self.persistent_top < length { return Err(AmlError::OutOfMemory); }
+
+        let offset = if persistent {
+            self.persistent_top += length;
+            self.persistent_top - length
+        } else {
+            self.temporary_bottom -= length;
+            self.temporary_bottom
+        };
+
+        for byte in &mut self.arena[offset .. offset + length] {
+            *byte = 0;
+        }
+
+        Ok(offset)
+    }
+
+    /// True if the node was created while loading a table (rather than by a method), and so anything it
+    /// refers to must stay around for good.
+    pub fn is_persistent(&self, id: NodeId) -> bool {
+        id < self.persistent_count
+    }
+
+    /// Marks every node created so far as persistent; called once a table has been loaded.
+    pub fn mark_persistent(&mut self) {
+        self.persistent_count = self.count;
+    }
+
+    /// Records a notification for an object; if too many are already waiting, the notification is dropped.
+    pub fn notify(&mut self, id: NodeId, value: u64) {
+        if self.notification_count == MAX_NOTIFICATIONS { return; }
+
+        self.notifications[self.notification_count] = (id, value);
+        self.notification_count += 1;
+    }
+
+    /// Takes the oldest notification which is waiting.
+    pub fn take_notification(&mut self) -> Option<(NodeId, u64)> {
+        if self.notification_count == 0 { return None; }
+
+        let notification = self.notifications[0];
+        for index in 1 .. self.notification_count {
+            self.notifications[index - 1] = self.notifications[index];
+        }
+
+        self.notification_count -= 1;
+        Some(notification)
+    }
+
+    /// Allocates `count` uninitialized package elements, returning the offset of the first. As with allocate(),
+    /// temporary elements only last until the next evaluation starts.
+    pub fn allocate_elements(&mut self, count: usize, persistent: bool) -> Result<usize, AmlError> {
+        if self.temporary_elements - self.persistent_elements < count { return Err(AmlError::OutOfMemory); }
+
+        let offset = if persistent {
+            self.persistent_elements += count;
+            self.persistent_elements - count
+        } else {
+            self.temporary_elements -= count;
+            self.temporary_elements
+        };
+
+        for element in &mut self.elements[offset .. offset + count] {
+            *element = AmlValue::Uninitialized;
+        }
+
+        Ok(offset)
+    }
+
+    /// Reads an element of the package whose elements start at the given offset.
+    pub fn element(&self, offset: usize, index: usize) -> AmlValue {
+        self.elements[offset + index]
+    }
+
+    /// Replaces an element of the package whose elements start at the given offset.
+    pub fn set_element(&mut self, offset: usize, index: usize, value: AmlValue) {
+        self.elements[offset + index] = value;
+    }
+
+    /// Throws away all temporary buffers and packages.
+    pub fn reset_temporary(&mut self) {
+        self.temporary_bottom = ARENA_SIZE;
+        self.temporary_elements = MAX_ELEMENTS;
+    }
+
+    /// Reads a byte of a buffer; bytes past the end of an AML initializer are zero.
+    pub fn buffer_byte(&self, data: BufferData, index: usize) -> u8 {
+        match data {
+            BufferData::Aml(bytes) => bytes.get(index).cloned().unwrap_or(0),
+            BufferData::Arena(offset) => self.arena[offset + index]
+        }
+    }
+
+    /// Writes a byte of a buffer which lives in the arena.
+    pub fn set_buffer_byte(&mut self, offset: usize, index: usize, value: u8) {
+        self.arena[offset + index] = value;
+    }
+
+    /// Makes a writable copy of the given buffer in the arena, returning the offset of the copy.
+    pub fn copy_buffer(&mut self, data: BufferData, length: usize, persistent: bool) -> Result<usize, AmlError> {
+        let offset = self.allocate(length, persistent)?;
+
+        for index in 0 .. length {
+            self.arena[offset + index] = self.buffer_byte(data, index);
+        }
+
+        Ok(offset)
+    }
+}
@@ -0,0 +1,201 @@
+//! Provides the low-level decoding of the AML byte stream: fixed-width integers, package lengths, and
+//! name strings. Everything here works on slices of the (static) ACPI tables, so nothing is copied.
+
+use super::AmlError;
+use super::opcodes::*;
+
+/// A cursor over a slice of AML bytecode.
+#[derive(Debug, Clone, Copy)]
+pub struct Stream {
+    /// The bytecode of the whole term list/method/table being parsed.
+    data: &'static [u8],
+
+    /// The position of the next byte to read.
+    position: usize
+}
+
+impl Stream {
+    /// Creates a new stream over the given bytecode.
+    pub fn new(data: &'static [u8]) -> Stream {
+        Stream { data: data, position: 0 }
+    }
+
+    /// The position of the next byte to read.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Moves the stream to the given position.
+    pub fn seek(&mut self, position: usize) {
+        self.position = position;
+    }
+
+    /// True if there's nothing left to read before the given end position.
+    pub fn at_end(&self, end: usize) -> bool {
+        self.position >= end
+    }
+
+    /// Obtains the bytes between the two positions.
+    pub fn slice(&self, start: usize, end: usize) -> Result<&'static [u8], AmlError> {
+        if start > end || end > self.data.len() { return Err(AmlError::UnexpectedEnd); }
+
+        Ok(&self.data[start .. end])
+    }
+
+    /// Looks at the next byte without consuming it.
+    pub fn peek(&self) -> Result<u8, AmlError> {
+        self.data.get(self.position).cloned().ok_or(AmlError::UnexpectedEnd)
+    }
+
+    /// Looks at the byte after the next byte without consuming anything.
+    pub fn peek_second(&self) -> Result<u8, AmlError> {
+        self.data.get(self.position + 1).cloned().ok_or(AmlError::UnexpectedEnd)
+    }
+
+    /// Reads a single byte.
+    pub fn byte(&mut self) -> Result<u8, AmlError> {
+        let byte = self.peek()?;
+        self.position += 1;
+
+        Ok(byte)
+    }
+
+    /// Reads a little-endian integer of the given width (in bytes).
+    fn integer(&mut self, width: usize) -> Result<u64, AmlError> {
+        let mut value = 0u64;
+
+        for index in 0 .. width {
+            value |= (self.byte()? as u64) << (index * 8);
+        }
+
+        Ok(value)
+    }
+
+    /// Reads a little-endian word.
+    pub fn word(&mut self) -> Result<u16, AmlError> {
+        self.integer(2).map(|value| value as u16)
+    }
+
+    /// Reads a little-endian double word.
+    pub fn dword(&mut self) -> Result<u32, AmlError> {
+        self.integer(4).map(|value| value as u32)
+    }
+
+    /// Reads a little-endian quad word.
+    pub fn qword(&mut self) -> Result<u64, AmlError> {
+        self.integer(8)
+    }
+
+    /// Reads the bytes of a null-terminated string (without the terminator).
+    pub fn string(&mut self) -> Result<&'static [u8], AmlError> {
+        let start = self.position;
+        let length = self.data[start ..].iter().position(|&byte| byte == 0).ok_or(AmlError::UnexpectedEnd)?;
+
+        self.position += length + 1;
+
+        Ok(&self.data[start .. start + length])
+    }
+
+    /// Reads a PkgLength, returning the raw encoded value. The lead byte's top two bits give the number of
+    /// bytes which follow it; with none, the low 6 bits are the value, otherwise the low 4 bits are.
+    pub fn raw_pkg_length(&mut self) -> Result<usize, AmlError> {
+        let lead = self.byte()?;
+        let follow = (lead >> 6) as usize;
+
+        if follow == 0 { return Ok((lead & 0x3F) as usize); }
+
+        let mut length = (lead & 0x0F) as usize;
+        for index in 0 .. follow {
+            length |= (self.byte()? as usize) << (4 + index * 8);
+        }
+
+        Ok(length)
+    }
+
+    /// Reads a PkgLength, returning the position at which the package ends (the length counts the
+    /// PkgLength bytes themselves).
+    pub fn pkg_end(&mut self) -> Result<usize, AmlError> {
+        let start = self.position;
+        let end = start + self.raw_pkg_length()?;
+
+        if end > self.data.len() { return Err(AmlError::UnexpectedEnd); }
+
+        Ok(end)
+    }
+
+    /// Reads a single 4-character NameSeg.
+    pub fn name_seg(&mut self) -> Result<[u8; 4], AmlError> {
+        let seg = self.slice(self.position, self.position + 4)?;
+
+        if !is_lead_name_char(seg[0]) || !seg[1 ..].iter().all(|&byte| is_name_char(byte)) {
+            return Err(AmlError::InvalidNameString);
+        }
+
+        self.position += 4;
+
+        Ok([seg[0], seg[1], seg[2], seg[3]])
+    }
+
+    /// Reads a NameString.
+    pub fn name_string(&mut self) -> Result<NameString, AmlError> {
+        let mut name = NameString { root: false, parent_prefixes: 0, segments: &[] };
+
+        match self.peek()? {
+            ROOT_CHAR => { self.position += 1; name.root = true; },
+            PARENT_PREFIX_CHAR => while self.peek()? == PARENT_PREFIX_CHAR {
+                self.position += 1;
+                name.parent_prefixes += 1;
+            },
+            _ => {}
+        }
+
+        let count = match self.peek()? {
+            ZERO_OP => { self.position += 1; 0 },
+            DUAL_NAME_PREFIX => { self.position += 1; 2 },
+            MULTI_NAME_PREFIX => { self.position += 1; self.byte()? as usize },
+            _ => 1
+        };
+
+        // Validate the segments, so nobody else has to.
+        let start = self.position;
+        for _ in 0 .. count {
+            self.name_seg()?;
+        }
+
+        name.segments = &self.data[start .. self.position];
+
+        Ok(name)
+    }
+}
+
+/// A decoded (but unresolved) AML NameString.
+#[derive(Debug, Clone, Copy)]
+pub struct NameString {
+    /// True if the name is absolute (starts with the root character).
+    pub root: bool,
+
+    /// The number of parent prefixes (^) before the name segments.
+    pub parent_prefixes: u8,
+
+    /// The raw name segments, which are each 4 bytes long.
+    pub segments: &'static [u8]
+}
+
+impl NameString {
+    /// The number of name segments in this name.
+    pub fn segment_count(&self) -> usize {
+        self.segments.len() / 4
+    }
+
+    /// Obtains the name segment at the given index.
+    pub fn segment(&self, index: usize) -> [u8; 4] {
+        let seg = &self.segments[index * 4 .. index * 4 + 4];
+
+        [seg[0], seg[1], seg[2], seg[3]]
+    }
+
+    /// True if the name is a single name segment with no prefixes, and so follows the namespace search rules.
+    pub fn is_simple(&self) -> bool {
+        !self.root && self.parent_prefixes == 0 && self.segment_count() == 1
+    }
+}
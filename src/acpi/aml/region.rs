@@ -0,0 +1,255 @@
+//! Provides access to operation regions: the hardware (I/O ports, memory, PCI configuration space) that
+//! AML fields are backed by.
+
+use core::{ptr, slice};
+
+use arch::x86_64::port;
+use memory::layout;
+use pci::{self, PciAddress};
+use super::AmlError;
+
+/// The operation region address spaces we can access; the rest (the embedded controller, SMBus and so on)
+/// give UnsupportedRegionSpace.
+pub const SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const SPACE_SYSTEM_IO: u8 = 1;
+pub const SPACE_PCI_CONFIG: u8 = 2;
+pub const SPACE_CMOS: u8 = 5;
+
+/// The access types in the low bits of the field flags which are wider than a byte; the rest (any, byte and
+/// buffer access) go a byte at a time.
+pub const ACCESS_WORD: u8 = 2;
+pub const ACCESS_DWORD: u8 = 3;
+pub const ACCESS_QWORD: u8 = 4;
+
+/// The update rules in bits 5 and 6 of the field flags, which say what to do with the bits of an access
+/// unit that aren't part of the field being written; anything else preserves them.
+pub const UPDATE_WRITE_AS_ONES: u8 = 1;
+pub const UPDATE_WRITE_AS_ZEROS: u8 = 2;
+
+/// The CMOS index/data ports.
+const CMOS_INDEX_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+/// The location of an operation region, once the interpreter has worked out where it is.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    /// The address space (SPACE_*).
+    pub space: u8,
+
+    /// The address of the region within the address space.
+    pub offset: u64,
+
+    /// The function whose configuration space this is, for PCI configuration regions.
+    pub pci: Option<PciAddress>
+}
+
+/// The width in bytes of each access, given the field flags.
+pub fn access_width(flags: u8) -> usize {
+    match flags & 0xF {
+        ACCESS_WORD => 2,
+        ACCESS_DWORD => 4,
+        ACCESS_QWORD => 8,
+        _ => 1
+    }
+}
+
+/// The update rule, given the field flags.
+pub fn update_rule(flags: u8) -> u8 {
+    (flags >> 5) & 0b11
+}
+
+/// A mask with the lowest `bits` bits set.
+pub fn mask(bits: u64) -> u64 {
+    if bits >= 64 { !0 } else { (1 << bits) - 1 }
+}
+
+impl Region {
+    /// Reads a single access unit of the given width at the given byte offset into the region.
+    unsafe fn read_unit(&self, offset: u64, width: usize) -> Result<u64, AmlError> {
+        let address = self.offset + offset;
+
+        match self.space {
+            SPACE_SYSTEM_MEMORY => {
+                let memory = layout::phys_to_virt(address).ok_or(AmlError::UnmappedRegion(address))?;
+
+                Ok(match width {
+                    1 => ptr::read_volatile(memory as *const u8) as u64,
+                    2 => ptr::read_volatile(memory as *const u16) as u64,
+                    4 => ptr::read_volatile(memory as *const u32) as u64,
+                    _ => ptr::read_volatile(memory as *const u64)
+                })
+            },
+            SPACE_SYSTEM_IO => Ok(match width {
+                1 => port::inb(address as u16) as u64,
+                2 => port::inw(address as u16) as u64,
+                4 => port::inl(address as u16) as u64,
+                _ => port::inl(address as u16) as u64 | (port::inl(address as u16 + 4) as u64) << 32
+            }),
+            SPACE_PCI_CONFIG => {
+                let pci = self.pci.ok_or(AmlError::UnsupportedRegionSpace(self.space))?;
+                let mut value = 0;
+
+                for index in 0 .. width as u64 {
+                    let byte = pci::read_u8(pci, (address + index) as u16).ok_or(AmlError::IndexOutOfBounds)?;
+                    value |= (byte as u64) << (index * 8);
+                }
+
+                Ok(value)
+            },
+            SPACE_CMOS => {
+                let mut value = 0;
+
+                for index in 0 .. width as u64 {
+                    port::outb(CMOS_INDEX_PORT, (address + index) as u8);
+                    value |= (port::inb(CMOS_DATA_PORT) as u64) << (index * 8);
+                }
+
+                Ok(value)
+            },
+            space => Err(AmlError::UnsupportedRegionSpace(space))
+        }
+    }
+
+    /// Writes a single access unit of the given width at the given byte offset into the region.
+    unsafe fn write_unit(&self, offset: u64, width: usize, value: u64) -> Result<(), AmlError> {
+        let address = self.offset + offset;
+
+        match self.space {
+            SPACE_SYSTEM_MEMORY => {
+                let memory = layout::phys_to_virt(address).ok_or(AmlError::UnmappedRegion(address))?;
+
+                match width {
+                    1 => ptr::write_volatile(memory as *mut u8, value as u8),
+                    2 => ptr::write_volatile(memory as *mut u16, value as u16),
+                    4 => ptr::write_volatile(memory as *mut u32, value as u32),
+                    _ => ptr::write_volatile(memory as *mut u64, value)
+                }
+            },
+            SPACE_SYSTEM_IO => match width {
+                1 => port::outb(address as u16, value as u8),
+                2 => port::outw(address as u16, value as u16),
+                4 => port::outl(address as u16, value as u32),
+                _ => {
+                    port::outl(address as u16, value as u32);
+                    port::outl(address as u16 + 4, (value >> 32) as u32);
+                }
+            },
+            SPACE_PCI_CONFIG => {
+                let pci = self.pci.ok_or(AmlError::UnsupportedRegionSpace(self.space))?;
+
+                for index in 0 .. width as u64 {
+                    if !pci::write_u8(pci, (address + index) as u16, (value >> (index * 8)) as u8) {
+                        return Err(AmlError::IndexOutOfBounds);
+                    }
+                }
+            },
+            SPACE_CMOS => for index in 0 .. width as u64 {
+                port::outb(CMOS_INDEX_PORT, (address + index) as u8);
+                port::outb(CMOS_DATA_PORT, (value >> (index * 8)) as u8);
+            },
+            space => return Err(AmlError::UnsupportedRegionSpace(space))
+        }
+
+        Ok(())
+    }
+
+    /// Reads a field of up to 64 bits from this region.
+    pub unsafe fn read_field(&self, bit_offset: u64, bit_length: u64, flags: u8) -> Result<u64, AmlError> {
+        let width = access_width(flags);
+
+        read_units(bit_offset, bit_length, flags, |offset| self.read_unit(offset, width))
+    }
+
+    /// Writes a field of up to 64 bits to this region.
+    pub unsafe fn write_field(&self, bit_offset: u64, bit_length: u64, flags: u8, value: u64) -> Result<(), AmlError> {
+        let width = access_width(flags);
+
+        write_units(bit_offset, bit_length, flags, value, |access| match access {
+            UnitAccess::Read(offset) => self.read_unit(offset, width),
+            UnitAccess::Write(offset, unit) => self.write_unit(offset, width, unit).map(|_| 0)
+        })
+    }
+}
+
+/// The memory of part of the system memory address space, for Load to read a table out of.
+/// UNSAFE: The memory mustn't change for as long as the slice is used.
+pub unsafe fn system_memory(address: u64, length: u64) -> Result<&'static [u8], AmlError> {
+    let end = address.checked_add(length).ok_or(AmlError::UnmappedRegion(address))?;
+    let start = layout::phys_to_virt(address).ok_or(AmlError::UnmappedRegion(address))?;
+
+    // Physical memory is mapped in one piece, so if the end is mapped, so is everything before it.
+    if length > 0 { layout::phys_to_virt(end - 1).ok_or(AmlError::UnmappedRegion(end - 1))?; }
+
+    Ok(slice::from_raw_parts(start as *const u8, length as usize))
+}
+
+/// A single access made while reading or writing a field; offsets are in bytes.
+#[derive(Debug, Clone, Copy)]
+pub enum UnitAccess {
+    Read(u64),
+    Write(u64, u64)
+}
+
+/// Reads a field of up to 64 bits, made up of however many access units it spans; `read` is given the
+/// byte offset of each access unit and returns it's contents.
+pub fn read_units<F>(bit_offset: u64, bit_length: u64, flags: u8, mut read: F) -> Result<u64, AmlError>
+    where F: FnMut(u64) -> Result<u64, AmlError> {
+
+    if bit_length > 64 { return Err(AmlError::Unsupported); }
+
+    let unit_bits = access_width(flags) as u64 * 8;
+    let field_end = bit_offset + bit_length;
+
+    let mut value = 0;
+    let mut unit_start = bit_offset - bit_offset % unit_bits;
+
+    while unit_start < field_end {
+        let unit = read(unit_start / 8)?;
+
+        // The part of this unit which overlaps the field.
+        let start = if bit_offset > unit_start { bit_offset } else { unit_start };
+        let end = if field_end < unit_start + unit_bits { field_end } else { unit_start + unit_bits };
+
+        value |= ((unit >> (start - unit_start)) & mask(end - start)) << (start - bit_offset);
+        unit_start += unit_bits;
+    }
+
+    Ok(value)
+}
+
+/// Writes a field of up to 64 bits, handling the bits of partially-covered access units according to the
+/// update rule in the flags. Reads return the unit's contents; the result of writes is ignored.
+pub fn write_units<F>(bit_offset: u64, bit_length: u64, flags: u8, value: u64, mut access: F) -> Result<(), AmlError>
+    where F: FnMut(UnitAccess) -> Result<u64, AmlError> {
+
+    if bit_length > 64 { return Err(AmlError::Unsupported); }
+
+    let unit_bits = access_width(flags) as u64 * 8;
+    let field_end = bit_offset + bit_length;
+
+    let mut unit_start = bit_offset - bit_offset % unit_bits;
+
+    while unit_start < field_end {
+        let start = if bit_offset > unit_start { bit_offset } else { unit_start };
+        let end = if field_end < unit_start + unit_bits { field_end } else { unit_start + unit_bits };
+        let unit_mask = mask(end - start) << (start - unit_start);
+
+        // Only read the old contents if we actually need to preserve some of them.
+        let base = if end - start == unit_bits {
+            0
+        } else {
+            match update_rule(flags) {
+                UPDATE_WRITE_AS_ONES => !0,
+                UPDATE_WRITE_AS_ZEROS => 0,
+                _ => access(UnitAccess::Read(unit_start / 8))?
+            }
+        };
+
+        let bits = ((value >> (start - bit_offset)) << (start - unit_start)) & unit_mask;
+        access(UnitAccess::Write(unit_start / 8, (base & !unit_mask) | bits))?;
+
+        unit_start += unit_bits;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Provides a decoder for resource templates, the buffers returned by _CRS and friends which describe the
+//! interrupts, I/O ports and memory a device uses.
+
+/// The small resource descriptor types (bits 3-6 of the tag).
+const SMALL_IRQ: u8 = 0x04;
+const SMALL_IO: u8 = 0x08;
+const SMALL_FIXED_IO: u8 = 0x09;
+const SMALL_END_TAG: u8 = 0x0F;
+
+/// The large resource descriptor types (bits 0-6 of the tag).
+const LARGE_MEMORY32_FIXED: u8 = 0x06;
+const LARGE_DWORD_ADDRESS_SPACE: u8 = 0x07;
+const LARGE_WORD_ADDRESS_SPACE: u8 = 0x08;
+const LARGE_EXTENDED_INTERRUPT: u8 = 0x09;
+const LARGE_QWORD_ADDRESS_SPACE: u8 = 0x0A;
+
+/// The interrupt flag bits, as used in both IRQ and Extended Interrupt descriptors (after normalization).
+pub const INTERRUPT_EDGE_TRIGGERED: u8 = 1 << 0;
+pub const INTERRUPT_ACTIVE_LOW: u8 = 1 << 1;
+pub const INTERRUPT_SHARED: u8 = 1 << 2;
+
+/// A single decoded resource descriptor.
+#[derive(Debug, Clone, Copy)]
+pub enum Resource<'a> {
+    /// Legacy (ISA) interrupts; each set bit of the mask is an IRQ which may be used. The flags are
+    /// INTERRUPT_*.
+    Irq { mask: u16, flags: u8 },
+
+    /// A range of I/O ports which may be placed anywhere between the minimum and maximum base.
+    Io { minimum: u16, maximum: u16, alignment: u8, length: u8 },
+
+    /// A fixed range of I/O ports.
+    FixedIo { base: u16, length: u8 },
+
+    /// A fixed range of 32-bit memory.
+    FixedMemory32 { writable: bool, base: u32, length: u32 },
+
+    /// A word, dword, or qword address space (memory, I/O, or bus numbers) descriptor.
+    AddressSpace { resource_type: u8, minimum: u64, maximum: u64, translation: u64, length: u64 },
+
+    /// Global system interrupts; the flags are INTERRUPT_*, and `interrupts` the raw list of dwords.
+    ExtendedInterrupt { flags: u8, interrupts: &'a [u8] },
+
+    /// Any other descriptor, with it's type and raw contents.
+    Other { large: bool, kind: u8, data: &'a [u8] }
+}
+
+impl<'a> Resource<'a> {
+    /// The number of interrupts in an Extended Interrupt descriptor.
+    pub fn interrupt_count(&self) -> usize {
+        match *self {
+            Resource::ExtendedInterrupt { interrupts, .. } => interrupts.len() / 4,
+            _ => 0
+        }
+    }
+
+    /// The interrupt at the given index in an Extended Interrupt descriptor.
+    pub fn interrupt(&self, index: usize) -> Option<u32> {
+        match *self {
+            Resource::ExtendedInterrupt { interrupts, .. } if index < interrupts.len() / 4 =>
+                Some(read_u32(interrupts, index * 4)),
+            _ => None
+        }
+    }
+}
+
+/// An iterator over the descriptors in a resource template, which stops at the end tag (or at anything
+/// malformed).
+#[derive(Debug, Clone)]
+pub struct ResourceIter<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+/// Iterates over the descriptors in a resource template.
+pub fn resources(bytes: &[u8]) -> ResourceIter {
+    ResourceIter { bytes: bytes, position: 0 }
+}
+
+impl<'a> Iterator for ResourceIter<'a> {
+    type Item = Resource<'a>;
+
+    fn next(&mut self) -> Option<Resource<'a>> {
+        let tag = *self.bytes.get(self.position)?;
+
+        if tag & 0x80 == 0 {
+            // Small descriptor: the length is in the tag.
+            let kind = (tag >> 3) & 0x0F;
+            let length = (tag & 0x07) as usize;
+            let data = self.bytes.get(self.position + 1 .. self.position + 1 + length)?;
+
+            if kind == SMALL_END_TAG { return None; }
+            self.position += 1 + length;
+
+            Some(match kind {
+                SMALL_IRQ if length >= 2 => {
+                    // Without the flags byte, the interrupts are edge triggered and active high.
+                    let flags = if length >= 3 { data[2] } else { 0x01 };
+
+                    Resource::Irq {
+                        mask: read_u16(data, 0),
+                        // The IRQ flags have edge in bit 0, active low in bit 3, and shared in bit 4.
+                        flags: (flags & 0x01) | ((flags >> 2) & 0x02) | ((flags >> 2) & 0x04)
+                    }
+                },
+                SMALL_IO if length >= 7 => Resource::Io {
+                    minimum: read_u16(data, 1),
+                    maximum: read_u16(data, 3),
+                    alignment: data[5],
+                    length: data[6]
+                },
+                SMALL_FIXED_IO if length >= 3 => Resource::FixedIo { base: read_u16(data, 0), length: data[2] },
+                kind => Resource::Other { large: false, kind: kind, data: data }
+            })
+        } else {
+            // Large descriptor: a 16-bit length follows the tag.
+            let kind = tag & 0x7F;
+            let header = self.bytes.get(self.position + 1 .. self.position + 3)?;
+            let length = read_u16(header, 0) as usize;
+            let data = self.bytes.get(self.position + 3 .. self.position + 3 + length)?;
+
+            self.position += 3 + length;
+
+            Some(match kind {
+                LARGE_MEMORY32_FIXED if length >= 9 => Resource::FixedMemory32 {
+                    writable: data[0] & 1 != 0,
+                    base: read_u32(data, 1),
+                    length: read_u32(data, 5)
+                },
+                LARGE_WORD_ADDRESS_SPACE if length >= 13 => address_space(data, 2),
+                LARGE_DWORD_ADDRESS_SPACE if length >= 23 => address_space(data, 4),
+                LARGE_QWORD_ADDRESS_SPACE if length >= 43 => address_space(data, 8),
+                LARGE_EXTENDED_INTERRUPT if length >= 2 => {
+                    let count = data[1] as usize;
+                    let interrupts = data.get(2 .. 2 + count * 4)?;
+
+                    // The flags have edge in bit 1, active low in bit 2, and shared in bit 3.
+                    Resource::ExtendedInterrupt { flags: (data[0] >> 1) & 0x07, interrupts: interrupts }
+                },
+                kind => Resource::Other { large: true, kind: kind, data: data }
+            })
+        }
+    }
+}
+
+/// Decodes an address space descriptor whose fields are `width` bytes wide.
+fn address_space(data: &[u8], width: usize) -> Resource {
+    // After the type and flags, the fields are granularity, minimum, maximum, translation and length.
+    let field = |index: usize| read_integer(data, 3 + index * width, width);
+
+    Resource::AddressSpace {
+        resource_type: data[0],
+        minimum: field(1),
+        maximum: field(2),
+        translation: field(3),
+        length: field(4)
+    }
+}
+
+/// Reads a little-endian integer of the given width.
+fn read_integer(bytes: &[u8], offset: usize, width: usize) -> u64 {
+    (0 .. width).fold(0, |value, index| value | (bytes[offset + index] as u64) << (index * 8))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    read_integer(bytes, offset, 2) as u16
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_integer(bytes, offset, 4) as u32
+}
@@ -0,0 +1,91 @@
+//! Provides PCI interrupt routing: working out which global system interrupt a PCI device's interrupt pin
+//! is wired to, from the _PRT of it's host bridge and the _CRS of any interrupt link devices.
+
+use super::{AmlError, AmlValue};
+use super::resource::{self, Resource, INTERRUPT_EDGE_TRIGGERED, INTERRUPT_ACTIVE_LOW};
+
+/// The largest resource template we'll look at for a link device.
+const MAX_LINK_RESOURCES: usize = 64;
+
+/// Where a PCI interrupt ends up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciInterrupt {
+    /// The global system interrupt (or ISA IRQ, in PIC mode) the pin is wired to.
+    pub gsi: u32,
+
+    pub level_triggered: bool,
+    pub active_low: bool
+}
+
+/// Tells the firmware which interrupt model we're using (through \_PIC), which changes what _PRT returns.
+pub fn select_interrupt_model(apic: bool) -> Result<(), AmlError> {
+    // _PIC is optional; without it the firmware only has one set of routing tables.
+    if !super::exists("\\_PIC") { return Ok(()); }
+
+    super::evaluate("\\_PIC", &[AmlValue::Integer(apic as u64)]).map(|_| ())
+}
+
+/// Finds the interrupt for the given pin (0 = INTA, ... 3 = INTD) of a device on the bus below the given
+/// host bridge (e.g. "\\_SB.PCI0").
+pub fn route(bridge: &str, device: u8, pin: u8) -> Result<PciInterrupt, AmlError> {
+    let bridge = super::find(bridge).ok_or(AmlError::NameNotFound)?;
+    let prt = super::evaluate_relative(bridge, "_PRT", &[])?;
+
+    let count = match prt {
+        AmlValue::Package { count, .. } => count,
+        _ => return Err(AmlError::TypeMismatch)
+    };
+
+    for index in 0 .. count {
+        let entry = super::package_element(prt, index)?;
+
+        // Each entry is { Address, Pin, Source, Source Index }, where the function in the address is 0xFFFF.
+        let address = super::package_integer(entry, 0)?;
+        let entry_pin = super::package_integer(entry, 1)?;
+        if (address >> 16) as u8 != device || entry_pin != pin as u64 { continue; }
+
+        return match super::package_element(entry, 2)? {
+            // No source means the index is the GSI itself, wired the way PCI interrupts normally are.
+            AmlValue::Integer(0) => Ok(PciInterrupt {
+                gsi: super::package_integer(entry, 3)? as u32,
+                level_triggered: true,
+                active_low: true
+            }),
+            AmlValue::Reference(link) => {
+                let source_index = super::package_integer(entry, 3)? as usize;
+                link_interrupt(link, source_index)
+            },
+            _ => Err(AmlError::TypeMismatch)
+        };
+    }
+
+    Err(AmlError::NameNotFound)
+}
+
+/// Finds the interrupt an interrupt link device is currently set to, from it's _CRS.
+fn link_interrupt(link: super::NodeId, index: usize) -> Result<PciInterrupt, AmlError> {
+    let crs = super::evaluate_relative(link, "_CRS", &[])?;
+
+    let mut bytes = [0; MAX_LINK_RESOURCES];
+    let length = super::buffer_bytes(crs, &mut bytes)?;
+    let bytes = &bytes[.. if length < MAX_LINK_RESOURCES { length } else { MAX_LINK_RESOURCES }];
+
+    for descriptor in resource::resources(bytes) {
+        let (gsi, flags) = match descriptor {
+            Resource::Irq { mask, flags } if mask != 0 => (mask.trailing_zeros(), flags),
+            Resource::ExtendedInterrupt { flags, .. } => match descriptor.interrupt(index) {
+                Some(gsi) => (gsi, flags),
+                None => continue
+            },
+            _ => continue
+        };
+
+        return Ok(PciInterrupt {
+            gsi: gsi,
+            level_triggered: flags & INTERRUPT_EDGE_TRIGGERED == 0,
+            active_low: flags & INTERRUPT_ACTIVE_LOW != 0
+        });
+    }
+
+    Err(AmlError::NameNotFound)
+}
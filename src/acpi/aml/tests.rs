@@ -0,0 +1,362 @@
+//! Tests for the interpreter, run on the host against a captured DSDT and some hand-assembled AML.
+
+use std::boxed::Box;
+use std::vec::Vec;
+
+use super::*;
+use super::namespace::Namespace;
+use super::interpreter::Interpreter;
+use super::resource::{self, Resource};
+
+/// The DSDT from a Firecracker microVM; see the tests in the acpi module.
+const FIRECRACKER_DSDT: &'static [u8] = include_bytes!("../tests/fixtures/firecracker/dsdt.dat");
+
+/// The AML in the captured DSDT, after it's header.
+fn firecracker_aml() -> &'static [u8] {
+    &FIRECRACKER_DSDT[mem::size_of::<SDTHeader>() ..]
+}
+
+/// Creates a namespace containing just the predefined objects. The namespace is too big to keep on the
+/// stack of a test thread comfortably, hence the box.
+fn namespace() -> Box<Namespace> {
+    let mut namespace = Box::new(Namespace::new());
+    namespace.add_predefined().unwrap();
+
+    namespace
+}
+
+/// Creates a namespace with the given AML loaded into it.
+fn load(aml: &'static [u8]) -> Box<Namespace> {
+    let mut namespace = namespace();
+    load_table(&mut namespace, aml).unwrap();
+
+    namespace
+}
+
+/// Evaluates the object at the given absolute path.
+fn evaluate_in(namespace: &mut Namespace, path: &str, args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+    let node = namespace.lookup(ROOT, path).ok_or(AmlError::NameNotFound)?;
+    namespace.reset_temporary();
+
+    Interpreter::new(namespace).invoke(node, args)
+}
+
+/// Copies the bytes of a buffer or string value out of the namespace.
+fn bytes_of(namespace: &Namespace, value: AmlValue) -> Vec<u8> {
+    match value {
+        AmlValue::Buffer { data, length } | AmlValue::String { data, length } =>
+            (0 .. length).map(|index| namespace.buffer_byte(data, index)).collect(),
+        other => panic!("expected a buffer or string, got {:?}", other)
+    }
+}
+
+#[test]
+fn loads_the_captured_dsdt() {
+    let mut namespace = load(firecracker_aml());
+
+    for path in &["\\_SB.PC00", "\\_SB.PC00._CRS", "\\_SB.PC00._PRT", "\\_SB.VCLK", "\\_SB.GED"] {
+        assert!(namespace.lookup(ROOT, path).is_some(), "{} is missing", path);
+    }
+
+    assert_eq!(evaluate_in(&mut namespace, "\\_SB.VCLK._STA", &[]), Ok(AmlValue::Integer(0x0F)));
+}
+
+#[test]
+fn evaluates_the_host_bridge_resources() {
+    let mut namespace = load(firecracker_aml());
+
+    let crs = evaluate_in(&mut namespace, "\\_SB.PC00._CRS", &[]).unwrap();
+    let bytes = bytes_of(&namespace, crs);
+    let descriptors: Vec<Resource> = resource::resources(&bytes).collect();
+
+    // Bus 0, the configuration ports, the ECAM window (which matches the MCFG), then 32-bit and 64-bit
+    // memory, and the I/O ports either side of the configuration ports.
+    assert_eq!(descriptors.len(), 7, "{:?}", descriptors);
+
+    let ranges: Vec<(u8, u64, u64)> = descriptors.iter().filter_map(|descriptor| match *descriptor {
+        Resource::AddressSpace { resource_type, minimum, length, .. } => Some((resource_type, minimum, length)),
+        _ => None
+    }).collect();
+    assert_eq!(ranges, vec![
+        (2, 0, 1),
+        (0, 0xC0001000, 0x2EBFF000),
+        (0, 0x4000000000, 0x4000000000),
+        (1, 0, 0xCF8),
+        (1, 0xD00, 0xF300)
+    ]);
+
+    match descriptors[1] {
+        Resource::Io { minimum: 0xCF8, maximum: 0xCF8, length: 8, .. } => {},
+        other => panic!("expected the configuration ports, got {:?}", other)
+    }
+
+    match descriptors[2] {
+        Resource::FixedMemory32 { base: 0xEEC00000, length: 0x100000, .. } => {},
+        other => panic!("expected the ECAM window, got {:?}", other)
+    }
+}
+
+#[test]
+fn reads_the_captured_routing_table() {
+    let mut namespace = load(firecracker_aml());
+
+    let prt = evaluate_in(&mut namespace, "\\_SB.PC00._PRT", &[]).unwrap();
+    let mut interpreter = Interpreter::new(&mut namespace);
+
+    let count = match prt {
+        AmlValue::Package { count, .. } => count,
+        other => panic!("expected a package, got {:?}", other)
+    };
+    assert_eq!(count, 32);
+
+    // Every slot gets an entry for INTA, which is wired straight to a GSI rather than through a link device.
+    for index in 0 .. count {
+        let entry = interpreter.package_element(prt, index).unwrap();
+        let address = interpreter.package_element(entry, 0).and_then(|value| interpreter.to_integer(value));
+
+        assert_eq!(address, Ok(((index as u64) << 16) | 0xFFFF));
+        assert_eq!(interpreter.package_element(entry, 1), Ok(AmlValue::Integer(0)));
+        assert_eq!(interpreter.package_element(entry, 2), Ok(AmlValue::Integer(0)));
+    }
+}
+
+/// Name(_S5, Package(4) { 5, 5, 0, 0 })
+static SLEEP_PACKAGE: [u8; 14] = [0x08, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x08, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00];
+
+#[test]
+fn reads_packages() {
+    let mut namespace = load(&SLEEP_PACKAGE);
+
+    let package = evaluate_in(&mut namespace, "\\_S5", &[]).unwrap();
+    let mut interpreter = Interpreter::new(&mut namespace);
+
+    assert_eq!(interpreter.package_element(package, 0), Ok(AmlValue::Integer(5)));
+    assert_eq!(interpreter.package_element(package, 1), Ok(AmlValue::Integer(5)));
+    assert_eq!(interpreter.package_element(package, 3), Ok(AmlValue::Integer(0)));
+    assert_eq!(interpreter.package_element(package, 4), Err(AmlError::IndexOutOfBounds));
+}
+
+/// Method(PKGM, 1) { Local0 = 7; Local1 = Package(2) { Local0, Arg0 }; Local0 = 9; Return (Local1) }
+/// Name(PKGN, Package(1) { DEV0 })
+/// Device(DEV0) {}
+static PACKAGE_ELEMENTS: [u8; 43] = [
+    0x14, 0x17, 0x50, 0x4B, 0x47, 0x4D, 0x01,
+    0x70, 0x0A, 0x07, 0x60,
+    0x70, 0x12, 0x04, 0x02, 0x60, 0x68, 0x61,
+    0x70, 0x0A, 0x09, 0x60,
+    0xA4, 0x61,
+    0x08, 0x50, 0x4B, 0x47, 0x4E, 0x12, 0x06, 0x01, 0x44, 0x45, 0x56, 0x30,
+    0x5B, 0x82, 0x05, 0x44, 0x45, 0x56, 0x30
+];
+
+#[test]
+fn evaluates_package_elements_where_the_package_is_built() {
+    let mut namespace = load(&PACKAGE_ELEMENTS);
+    let device = namespace.lookup(ROOT, "\\DEV0").unwrap();
+
+    // The locals and arguments are the ones the method had when it built the package.
+    let package = evaluate_in(&mut namespace, "\\PKGM", &[AmlValue::Integer(3)]).unwrap();
+    let mut interpreter = Interpreter::new(&mut namespace);
+
+    assert_eq!(interpreter.package_element(package, 0), Ok(AmlValue::Integer(7)));
+    assert_eq!(interpreter.package_element(package, 1), Ok(AmlValue::Integer(3)));
+
+    // The device didn't exist yet when the package was built.
+    let package = evaluate_in(&mut namespace, "\\PKGN", &[]).unwrap();
+    let mut interpreter = Interpreter::new(&mut namespace);
+
+    assert_eq!(interpreter.package_element(package, 0), Ok(AmlValue::Reference(device)));
+}
+
+/// Method(TIDX) { Local0 = Package(2) {}; Local1 = Package(2) { 1, 2 }; Local1[0] = 5; Local0[1] = Local1;
+///     Return (Local0) }
+/// Method(TBUF) { Local2 = Buffer(2) { 0x11, 0x22 }; Local2[1] = 0x33; Local2[0]++; Return (Local2) }
+static INDEX_TARGETS: [u8; 64] = [
+    0x14, 0x22, 0x54, 0x49, 0x44, 0x58, 0x00,
+    0x70, 0x12, 0x02, 0x02, 0x60,
+    0x70, 0x12, 0x05, 0x02, 0x01, 0x0A, 0x02, 0x61,
+    0x70, 0x0A, 0x05, 0x88, 0x61, 0x00, 0x00,
+    0x70, 0x61, 0x88, 0x60, 0x01, 0x00,
+    0xA4, 0x60,
+    0x14, 0x1C, 0x54, 0x42, 0x55, 0x46, 0x00,
+    0x70, 0x11, 0x05, 0x0A, 0x02, 0x11, 0x22, 0x62,
+    0x70, 0x0A, 0x33, 0x88, 0x62, 0x01, 0x00,
+    0x75, 0x88, 0x62, 0x00, 0x00,
+    0xA4, 0x62
+];
+
+#[test]
+fn stores_to_indexed_elements() {
+    let mut namespace = load(&INDEX_TARGETS);
+
+    let outer = evaluate_in(&mut namespace, "\\TIDX", &[]).unwrap();
+    let mut interpreter = Interpreter::new(&mut namespace);
+
+    assert_eq!(interpreter.package_element(outer, 0), Ok(AmlValue::Uninitialized));
+    let inner = interpreter.package_element(outer, 1).unwrap();
+    assert_eq!(interpreter.package_element(inner, 0), Ok(AmlValue::Integer(5)));
+    assert_eq!(interpreter.package_element(inner, 1), Ok(AmlValue::Integer(2)));
+
+    let buffer = evaluate_in(&mut namespace, "\\TBUF", &[]).unwrap();
+    assert_eq!(bytes_of(&namespace, buffer), vec![0x12, 0x33]);
+}
+
+/// Method(THEX) { Return (ToHexString(0x1A2B)) }
+/// Method(THXB) { Return (ToHexString(Buffer() { 0x01, 0xAB })) }
+/// Method(TDEC) { Return (ToDecimalString(Buffer() { 0x01, 0x20 })) }
+/// Method(TSTR) { Return (ToString(Buffer() { 0x41, 0x42, 0x00, 0x43 }, Ones)) }
+/// Method(TMID) { Return (Mid("ABCDEF", 2, 10)) }
+/// Method(TCAT) { Return (Concatenate("AB", 0x1F)) }
+static CONVERSIONS: [u8; 102] = [
+    0x14, 0x0C, 0x54, 0x48, 0x45, 0x58, 0x00, 0xA4, 0x98, 0x0B, 0x2B, 0x1A, 0x00,
+    0x14, 0x0F, 0x54, 0x48, 0x58, 0x42, 0x00, 0xA4, 0x98, 0x11, 0x05, 0x0A, 0x02, 0x01, 0xAB, 0x00,
+    0x14, 0x0F, 0x54, 0x44, 0x45, 0x43, 0x00, 0xA4, 0x97, 0x11, 0x05, 0x0A, 0x02, 0x01, 0x20, 0x00,
+    0x14, 0x12, 0x54, 0x53, 0x54, 0x52, 0x00, 0xA4, 0x9C, 0x11, 0x07, 0x0A, 0x04, 0x41, 0x42, 0x00, 0x43, 0xFF, 0x00,
+    0x14, 0x15, 0x54, 0x4D, 0x49, 0x44, 0x00,
+    0xA4, 0x9E, 0x0D, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x00, 0x0A, 0x02, 0x0A, 0x0A, 0x00,
+    0x14, 0x0F, 0x54, 0x43, 0x41, 0x54, 0x00, 0xA4, 0x73, 0x0D, 0x41, 0x42, 0x00, 0x0A, 0x1F, 0x00
+];
+
+#[test]
+fn converts_to_and_concatenates_strings() {
+    let mut namespace = load(&CONVERSIONS);
+
+    let expected: [(&str, &[u8]); 6] = [
+        ("\\THEX", b"0000000000001A2B"),
+        ("\\THXB", b"0x01,0xAB"),
+        ("\\TDEC", b"1,32"),
+        ("\\TSTR", b"AB"),
+        ("\\TMID", b"CDEF"),
+        ("\\TCAT", b"AB000000000000001F")
+    ];
+
+    for &(path, string) in expected.iter() {
+        let value = evaluate_in(&mut namespace, path, &[]).unwrap();
+
+        match value {
+            AmlValue::String { .. } => assert_eq!(bytes_of(&namespace, value), string, "{}", path),
+            other => panic!("expected a string from {}, got {:?}", path, other)
+        }
+    }
+}
+
+/// DefinitionBlock("", "SSDT", 2, "TESTOE", "TESTTBL_", 1) { Name(LDED, 0x2A) }
+static LOADABLE_SSDT: [u8; 43] = [
+    0x53, 0x53, 0x44, 0x54, 0x2B, 0x00, 0x00, 0x00, 0x02, 0xA9, 0x54, 0x45, 0x53, 0x54, 0x4F, 0x45, 0x54, 0x45,
+    0x53, 0x54, 0x54, 0x42, 0x4C, 0x5F, 0x01, 0x00, 0x00, 0x00, 0x54, 0x45, 0x53, 0x54, 0x01, 0x00, 0x00, 0x00,
+    0x08, 0x4C, 0x44, 0x45, 0x44, 0x0A, 0x2A
+];
+
+/// Device(SUB) {}
+/// Name(PRM, 0)
+/// DataTableRegion(DTR, "SSDT", "", "")
+/// Method(LTBL) { Return (LoadTable("SSDT", "", "TESTTBL_", "\\SUB", "\\PRM", 0x55)) }
+static LOAD_TABLE: [u8; 73] = [
+    0x5B, 0x82, 0x05, 0x53, 0x55, 0x42, 0x5F, 0x08, 0x50, 0x52, 0x4D, 0x5F, 0x00,
+    0x5B, 0x88, 0x44, 0x54, 0x52, 0x5F, 0x0D, 0x53, 0x53, 0x44, 0x54, 0x00, 0x0D, 0x00, 0x0D, 0x00,
+    0x14, 0x2B, 0x4C, 0x54, 0x42, 0x4C, 0x00,
+    0xA4, 0x5B, 0x1F, 0x0D, 0x53, 0x53, 0x44, 0x54, 0x00, 0x0D, 0x00, 0x0D, 0x54, 0x45, 0x53, 0x54, 0x54, 0x42, 0x4C,
+    0x5F, 0x00, 0x0D, 0x5C, 0x53, 0x55, 0x42, 0x5F, 0x00, 0x0D, 0x5C, 0x50, 0x52, 0x4D, 0x5F, 0x00, 0x0A, 0x55
+];
+
+#[test]
+fn loads_tables_from_aml() {
+    let mut namespace = namespace();
+    let address = LOADABLE_SSDT.as_ptr() as u64;
+
+    // UNSAFE: Safe, as the header is packed, and the table is static.
+    namespace.add_table(address, unsafe { &*(LOADABLE_SSDT.as_ptr() as *const SDTHeader) });
+    load_table(&mut namespace, &LOAD_TABLE).unwrap();
+
+    let region = namespace.lookup(ROOT, "\\DTR").unwrap();
+    assert_eq!(namespace.node(region).kind, NodeKind::OperationRegion {
+        space: region::SPACE_SYSTEM_MEMORY,
+        offset: address,
+        length: LOADABLE_SSDT.len() as u64
+    });
+
+    assert_eq!(evaluate_in(&mut namespace, "\\SUB.LDED", &[]), Err(AmlError::NameNotFound));
+    assert_eq!(evaluate_in(&mut namespace, "\\LTBL", &[]), Ok(AmlValue::Integer(!0)));
+    assert_eq!(evaluate_in(&mut namespace, "\\SUB.LDED", &[]), Ok(AmlValue::Integer(0x2A)));
+    assert_eq!(evaluate_in(&mut namespace, "\\PRM", &[]), Ok(AmlValue::Integer(0x55)));
+}
+
+/// Method(SUM, 1) { Local0 = 0; Local1 = 0; While (Local1 < Arg0) { Local1++; Local0 += Local1 } Return (Local0) }
+static SUM_METHOD: [u8; 26] = [
+    0x14, 0x19, 0x53, 0x55, 0x4D, 0x5F, 0x01,
+    0x70, 0x00, 0x60,
+    0x70, 0x00, 0x61,
+    0xA2, 0x0A, 0x95, 0x61, 0x68, 0x75, 0x61, 0x72, 0x60, 0x61, 0x60,
+    0xA4, 0x60
+];
+
+#[test]
+fn runs_loops_in_methods() {
+    let mut namespace = load(&SUM_METHOD);
+
+    assert_eq!(evaluate_in(&mut namespace, "\\SUM", &[AmlValue::Integer(10)]), Ok(AmlValue::Integer(55)));
+    assert_eq!(evaluate_in(&mut namespace, "\\SUM", &[AmlValue::Integer(0)]), Ok(AmlValue::Integer(0)));
+}
+
+/// Method(NEST, 1) { Local0 = 0; If (Arg0) { If (Zero) { Local0 = 1 } } Else { Local0 = 2 }; Return (Local0) }
+static NESTED_IF: [u8; 27] = [
+    0x14, 0x1A, 0x4E, 0x45, 0x53, 0x54, 0x01,
+    0x70, 0x00, 0x60,
+    0xA0, 0x08, 0x68, 0xA0, 0x05, 0x00, 0x70, 0x01, 0x60,
+    0xA1, 0x05, 0x70, 0x0A, 0x02, 0x60,
+    0xA4, 0x60
+];
+
+#[test]
+fn matches_else_to_the_enclosing_if() {
+    let mut namespace = load(&NESTED_IF);
+
+    // The inner If is last in the outer one's body, so the Else after that body isn't it's.
+    assert_eq!(evaluate_in(&mut namespace, "\\NEST", &[AmlValue::Integer(1)]), Ok(AmlValue::Integer(0)));
+    assert_eq!(evaluate_in(&mut namespace, "\\NEST", &[AmlValue::Integer(0)]), Ok(AmlValue::Integer(2)));
+}
+
+/// Name(BUF, Buffer(4) { 0x11, 0x22, 0x33, 0x44 })
+/// Method(FLD) { CreateWordField(BUF, 1, WRD); WRD = 0xBEEF; Return (BUF) }
+static BUFFER_FIELD: [u8; 43] = [
+    0x08, 0x42, 0x55, 0x46, 0x5F, 0x11, 0x07, 0x0A, 0x04, 0x11, 0x22, 0x33, 0x44,
+    0x14, 0x1D, 0x46, 0x4C, 0x44, 0x5F, 0x00,
+    0x8B, 0x42, 0x55, 0x46, 0x5F, 0x01, 0x57, 0x52, 0x44, 0x5F,
+    0x70, 0x0B, 0xEF, 0xBE, 0x57, 0x52, 0x44, 0x5F,
+    0xA4, 0x42, 0x55, 0x46, 0x5F
+];
+
+#[test]
+fn writes_buffer_fields() {
+    let mut namespace = load(&BUFFER_FIELD);
+
+    let buffer = evaluate_in(&mut namespace, "\\FLD", &[]).unwrap();
+    assert_eq!(bytes_of(&namespace, buffer), vec![0x11, 0xEF, 0xBE, 0x44]);
+
+    // The write went to the named buffer itself, not a copy.
+    let buffer = evaluate_in(&mut namespace, "\\BUF", &[]).unwrap();
+    assert_eq!(bytes_of(&namespace, buffer), vec![0x11, 0xEF, 0xBE, 0x44]);
+}
+
+/// Device(PWRB) { Name(_HID, EisaId("PNP0C0C")) }
+/// Method(PRES) { Notify(PWRB, 0x80) }
+static NOTIFY_METHOD: [u8; 31] = [
+    0x5B, 0x82, 0x0F, 0x50, 0x57, 0x52, 0x42, 0x08, 0x5F, 0x48, 0x49, 0x44, 0x0C, 0x41, 0xD0, 0x0C, 0x0C,
+    0x14, 0x0D, 0x50, 0x52, 0x45, 0x53, 0x00,
+    0x86, 0x50, 0x57, 0x52, 0x42, 0x0A, 0x80
+];
+
+#[test]
+fn queues_notifications() {
+    let mut namespace = load(&NOTIFY_METHOD);
+    let button = namespace.lookup(ROOT, "\\PWRB").unwrap();
+
+    assert_eq!(namespace.take_notification(), None);
+
+    evaluate_in(&mut namespace, "\\PRES", &[]).unwrap();
+    evaluate_in(&mut namespace, "\\PRES", &[]).unwrap();
+
+    assert_eq!(namespace.take_notification(), Some((button, 0x80)));
+    assert_eq!(namespace.take_notification(), Some((button, 0x80)));
+    assert_eq!(namespace.take_notification(), None);
+}
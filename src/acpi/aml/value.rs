@@ -0,0 +1,108 @@
+//! Provides the values AML code operates on. Strings and buffers refer back into the bytecode they were defined
+//! in, and ones which get modified (or are made by the interpreter) live in the namespace's byte arena instead; the
+//! elements of packages live in the namespace's element arena. The arenas are fixed size rather than on the heap,
+//! so evaluating AML never allocates: it can't fail part way through a method for want of memory, and everything
+//! temporary is thrown away at once before the next evaluation.
+
+use super::namespace::NodeId;
+
+/// Where the bytes of a buffer (or string) live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferData {
+    /// The buffer's initializer in the bytecode; any bytes past the end of the initializer are zero.
+    Aml(&'static [u8]),
+
+    /// A writable copy at the given offset in the namespace's byte arena.
+    Arena(usize)
+}
+
+/// A value produced or consumed by AML code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmlValue {
+    /// The value of a local or argument which has not been assigned.
+    Uninitialized,
+
+    /// An integer; 32 or 64 bits wide depending on the revision of the DSDT.
+    Integer(u64),
+
+    /// An ASCII string of the given length (not counting the null terminator, which arena strings keep after
+    /// the end).
+    String { data: BufferData, length: usize },
+
+    /// A buffer of the given length.
+    Buffer { data: BufferData, length: usize },
+
+    /// A package of `count` elements, at the given offset in the namespace's element arena.
+    Package { elements: usize, count: usize },
+
+    /// A reference to a namespace node (a device, for instance, or a name in a package).
+    Reference(NodeId),
+
+    /// A name in a package which didn't exist yet when the package was built (a package in a table can name a
+    /// device defined further on), as the AML of the name and the scope it's relative to. It's resolved when the
+    /// element is read, so it's never handed out.
+    Name { name: &'static [u8], scope: NodeId }
+}
+
+/// The object type codes returned by the ObjectType operator.
+pub const TYPE_UNINITIALIZED: u64 = 0;
+pub const TYPE_INTEGER: u64 = 1;
+pub const TYPE_STRING: u64 = 2;
+pub const TYPE_BUFFER: u64 = 3;
+pub const TYPE_PACKAGE: u64 = 4;
+pub const TYPE_FIELD_UNIT: u64 = 5;
+pub const TYPE_DEVICE: u64 = 6;
+pub const TYPE_EVENT: u64 = 7;
+pub const TYPE_METHOD: u64 = 8;
+pub const TYPE_MUTEX: u64 = 9;
+pub const TYPE_OPERATION_REGION: u64 = 10;
+pub const TYPE_POWER_RESOURCE: u64 = 11;
+pub const TYPE_PROCESSOR: u64 = 12;
+pub const TYPE_THERMAL_ZONE: u64 = 13;
+pub const TYPE_BUFFER_FIELD: u64 = 14;
+
+impl AmlValue {
+    /// The AML "true" value, which is all ones.
+    pub fn ones() -> AmlValue {
+        AmlValue::Integer(!0)
+    }
+
+    /// Converts a boolean to the AML logical values (Ones/Zero).
+    pub fn from_bool(value: bool) -> AmlValue {
+        if value { AmlValue::ones() } else { AmlValue::Integer(0) }
+    }
+
+    /// A string in the bytecode (or some other static).
+    pub fn string(bytes: &'static [u8]) -> AmlValue {
+        AmlValue::String { data: BufferData::Aml(bytes), length: bytes.len() }
+    }
+
+    /// Obtains the integer in this value, if it is one.
+    pub fn as_integer(&self) -> Option<u64> {
+        match *self {
+            AmlValue::Integer(value) => Some(value),
+            _ => None
+        }
+    }
+
+    /// Obtains the referenced node, if this is a reference.
+    pub fn as_reference(&self) -> Option<NodeId> {
+        match *self {
+            AmlValue::Reference(node) => Some(node),
+            _ => None
+        }
+    }
+
+    /// The ObjectType code for this value.
+    pub fn type_code(&self) -> u64 {
+        match *self {
+            AmlValue::Uninitialized => TYPE_UNINITIALIZED,
+            AmlValue::Integer(_) => TYPE_INTEGER,
+            AmlValue::String { .. } => TYPE_STRING,
+            AmlValue::Buffer { .. } => TYPE_BUFFER,
+            AmlValue::Package { .. } => TYPE_PACKAGE,
+            // References are typed by what they refer to; the interpreter handles those itself.
+            AmlValue::Reference(_) | AmlValue::Name { .. } => TYPE_UNINITIALIZED
+        }
+    }
+}
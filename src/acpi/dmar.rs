@@ -0,0 +1,418 @@
+//! Provides definitions for the DMA Remapping Reporting table (DMAR), which describes the Intel VT-d IOMMUs
+//! (DMA remapping hardware units), which devices sit behind each of them, and the memory some devices need to
+//! keep reaching through them (like the buffers the firmware's USB keyboard emulation uses).
+
+use core::{mem, slice};
+use core::marker::PhantomData;
+
+use super::tables::{SDTHeader, SystemTable};
+
+/// Set in the DMAR flags if the IOMMUs support interrupt remapping.
+pub const DMAR_INTR_REMAP: u8 = 1;
+
+/// Set in the DMAR flags if the firmware asks for x2APIC mode not to be used with interrupt remapping.
+pub const DMAR_X2APIC_OPT_OUT: u8 = 1 << 1;
+
+/// Set in the DMAR flags if the firmware asks for DMA protection to stay on through the hand over to the OS.
+pub const DMAR_DMA_CTRL_PLATFORM_OPT_IN: u8 = 1 << 2;
+
+/// Set in a DRHD's flags if the unit handles every device in it's segment group which no other unit lists.
+pub const DRHD_INCLUDE_PCI_ALL: u8 = 1;
+
+/// Set in an ATSR's flags if every root port in the segment group supports address translation services.
+pub const ATSR_ALL_PORTS: u8 = 1;
+
+/// The remapping structure types defined by the VT-d specification which we understand.
+pub const DMAR_ENTRY_DRHD: u16 = 0;
+pub const DMAR_ENTRY_RMRR: u16 = 1;
+pub const DMAR_ENTRY_ATSR: u16 = 2;
+pub const DMAR_ENTRY_RHSA: u16 = 3;
+
+/// The types of device scope.
+pub const SCOPE_PCI_ENDPOINT: u8 = 1;
+pub const SCOPE_PCI_SUB_HIERARCHY: u8 = 2;
+pub const SCOPE_IOAPIC: u8 = 3;
+pub const SCOPE_HPET: u8 = 4;
+pub const SCOPE_ACPI_NAMESPACE_DEVICE: u8 = 5;
+
+/// The DMA Remapping Reporting table, which is followed by a variable number of remapping structures.
+#[repr(packed)]
+#[derive(Debug)]
+pub struct DMAR {
+    /// The header of the DMAR.
+    pub header: SDTHeader,
+
+    /// The number of bits of physical address DMA can reach, minus one.
+    pub host_address_width: u8,
+
+    /// The DMAR_* flags.
+    pub flags: u8,
+
+    _reserved: [u8; 10]
+}
+
+impl SystemTable for DMAR {
+    fn raw_header(&self) -> *const SDTHeader {
+        (&self.header) as *const SDTHeader
+    }
+
+    fn signature() -> &'static [u8] { b"DMAR" }
+}
+
+impl DMAR {
+    /// The number of bits of physical address DMA can reach.
+    pub fn address_width(&self) -> u8 {
+        self.host_address_width + 1
+    }
+
+    /// True if the IOMMUs can remap interrupts as well as DMA.
+    pub fn supports_interrupt_remapping(&self) -> bool {
+        self.flags & DMAR_INTR_REMAP != 0
+    }
+
+    /// Returns an iterator over all of the remapping structures in this table.
+    pub fn entries(&self) -> DmarEntriesIter {
+        let table_start = self as *const DMAR as *const u8;
+
+        // UNSAFE: Safe, as the length is given by the table itself.
+        let entries_start = unsafe { table_start.offset(mem::size_of::<Self>() as isize) };
+        let entries_end = unsafe { table_start.offset(self.header.length as isize) };
+
+        DmarEntriesIter { location: entries_start, end: entries_end, _table: PhantomData }
+    }
+
+    /// Returns an iterator over the DMA remapping hardware units.
+    pub fn hardware_units(&self) -> DrhdIter {
+        DrhdIter { entries: self.entries() }
+    }
+
+    /// Returns an iterator over the reserved memory regions.
+    pub fn reserved_regions(&self) -> RmrrIter {
+        RmrrIter { entries: self.entries() }
+    }
+}
+
+/// The header common to all of the remapping structures; unlike most ACPI tables, the fields are 16 bits.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct RemappingHeader {
+    /// The type of the structure, which determines it's layout.
+    pub structure_type: u16,
+
+    /// The total length of the structure, including this header.
+    pub length: u16
+}
+
+/// A DMA remapping hardware unit (an IOMMU), and the devices behind it.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct DrhdEntry {
+    /// The common structure header.
+    pub header: RemappingHeader,
+
+    /// The DRHD_* flags.
+    pub flags: u8,
+
+    /// The size of the register block, as a power of two number of 4KiB pages.
+    pub size: u8,
+
+    /// The PCI segment group the unit handles devices in.
+    pub segment: u16,
+
+    /// The physical address of the unit's registers.
+    pub register_base: u64
+}
+
+impl DrhdEntry {
+    /// True if the unit handles every device in it's segment group that no other unit lists.
+    pub fn includes_all(&self) -> bool {
+        self.flags & DRHD_INCLUDE_PCI_ALL != 0
+    }
+
+    /// The size of the unit's register block, in bytes.
+    pub fn register_size(&self) -> u64 {
+        0x1000 << (self.size & 0xF)
+    }
+
+    /// Returns an iterator over the devices the unit lists.
+    pub fn scopes(&self) -> DeviceScopeIter {
+        DeviceScopeIter::following(self, self.header.length)
+    }
+}
+
+/// A reserved memory region, which the listed devices may DMA to at any time, so has to stay identity mapped for
+/// them.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct RmrrEntry {
+    /// The common structure header.
+    pub header: RemappingHeader,
+
+    _reserved: u16,
+
+    /// The PCI segment group the devices are in.
+    pub segment: u16,
+
+    /// The first byte of the region, which is 4KiB aligned.
+    pub base_address: u64,
+
+    /// The last byte of the region, making the region a whole number of 4KiB pages.
+    pub limit_address: u64
+}
+
+impl RmrrEntry {
+    /// The length of the region, in bytes.
+    pub fn length(&self) -> u64 {
+        self.limit_address.saturating_add(1).saturating_sub(self.base_address)
+    }
+
+    /// Returns an iterator over the devices which use the region.
+    pub fn scopes(&self) -> DeviceScopeIter {
+        DeviceScopeIter::following(self, self.header.length)
+    }
+}
+
+/// Describes which root ports support address translation services (ATS), which lets devices cache translations.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct AtsrEntry {
+    /// The common structure header.
+    pub header: RemappingHeader,
+
+    /// The ATSR_* flags.
+    pub flags: u8,
+
+    _reserved: u8,
+
+    /// The PCI segment group the root ports are in.
+    pub segment: u16
+}
+
+impl AtsrEntry {
+    /// True if every root port in the segment group supports ATS, rather than just the listed ones.
+    pub fn all_ports(&self) -> bool {
+        self.flags & ATSR_ALL_PORTS != 0
+    }
+
+    /// Returns an iterator over the root ports which support ATS.
+    pub fn scopes(&self) -> DeviceScopeIter {
+        DeviceScopeIter::following(self, self.header.length)
+    }
+}
+
+/// Assigns a DMA remapping hardware unit to a proximity domain (NUMA node).
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct RhsaEntry {
+    /// The common structure header.
+    pub header: RemappingHeader,
+
+    _reserved: u32,
+
+    /// The register base of the unit, matching one of the DRHDs.
+    pub register_base: u64,
+
+    /// The proximity domain the unit belongs to.
+    pub proximity_domain: u32
+}
+
+/// A single typed remapping structure of the DMAR.
+#[derive(Debug)]
+pub enum DmarEntry<'a> {
+    Drhd(&'a DrhdEntry),
+    Rmrr(&'a RmrrEntry),
+    Atsr(&'a AtsrEntry),
+    Rhsa(&'a RhsaEntry),
+
+    /// A structure type we don't (yet) understand; only the header is provided.
+    Unknown(&'a RemappingHeader)
+}
+
+/// Provides iteration over the variable-length remapping structures in the DMAR.
+#[derive(Debug)]
+pub struct DmarEntriesIter<'a> {
+    /// The memory location of the next structure to return.
+    location: *const u8,
+
+    /// The end of the table; no structure may extend past this point.
+    end: *const u8,
+
+    /// Ties the lifetime of the returned structures to the table.
+    _table: PhantomData<&'a DMAR>
+}
+
+impl<'a> DmarEntriesIter<'a> {
+    /// Reinterprets the structure at the current location as the given type, if it is long enough to hold it.
+    unsafe fn entry_as<T>(&self, header: &RemappingHeader) -> Option<&'a T> {
+        if (header.length as usize) < mem::size_of::<T>() { return None; }
+
+        Some(&*(self.location as *const T))
+    }
+}
+
+impl<'a> Iterator for DmarEntriesIter<'a> {
+    type Item = DmarEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Make sure there's at least a header left to read.
+        if (self.end as usize) < (self.location as usize) + mem::size_of::<RemappingHeader>() { return None; }
+
+        // UNSAFE: Safe, as we checked the header lies within the table.
+        let header: &'a RemappingHeader = unsafe { &*(self.location as *const RemappingHeader) };
+
+        // A zero-length (or overlong) structure means the table is corrupted; stop instead of looping forever.
+        let length = header.length as usize;
+        if length < mem::size_of::<RemappingHeader>() || (self.location as usize) + length > (self.end as usize) {
+            return None;
+        }
+
+        let entry = unsafe {
+            match header.structure_type {
+                DMAR_ENTRY_DRHD => self.entry_as(header).map(DmarEntry::Drhd),
+                DMAR_ENTRY_RMRR => self.entry_as(header).map(DmarEntry::Rmrr),
+                DMAR_ENTRY_ATSR => self.entry_as(header).map(DmarEntry::Atsr),
+                DMAR_ENTRY_RHSA => self.entry_as(header).map(DmarEntry::Rhsa),
+                _ => None
+            }
+        }.unwrap_or(DmarEntry::Unknown(header));
+
+        self.location = unsafe { self.location.offset(length as isize) };
+
+        Some(entry)
+    }
+}
+
+/// Provides iteration over the DMA remapping hardware units described by the DMAR.
+#[derive(Debug)]
+pub struct DrhdIter<'a> {
+    entries: DmarEntriesIter<'a>
+}
+
+impl<'a> Iterator for DrhdIter<'a> {
+    type Item = &'a DrhdEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for entry in &mut self.entries {
+            if let DmarEntry::Drhd(drhd) = entry {
+                return Some(drhd);
+            }
+        }
+
+        None
+    }
+}
+
+/// Provides iteration over the reserved memory regions described by the DMAR.
+#[derive(Debug)]
+pub struct RmrrIter<'a> {
+    entries: DmarEntriesIter<'a>
+}
+
+impl<'a> Iterator for RmrrIter<'a> {
+    type Item = &'a RmrrEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for entry in &mut self.entries {
+            if let DmarEntry::Rmrr(rmrr) = entry {
+                return Some(rmrr);
+            }
+        }
+
+        None
+    }
+}
+
+/// One step of the path from a scope's start bus to a device: the device and function of a bridge (whose
+/// secondary bus the next step is on), or of the device itself for the last step.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciPathEntry {
+    pub device: u8,
+    pub function: u8
+}
+
+/// Identifies a device (or a whole hierarchy below a bridge) by the path to it from a bus number the firmware
+/// assigned; the path is what stays the same if the OS renumbers the buses.
+#[repr(packed)]
+#[derive(Debug)]
+pub struct DeviceScope {
+    /// The SCOPE_* type of the device.
+    pub scope_type: u8,
+
+    /// The length of the scope, including the path.
+    pub length: u8,
+
+    /// Flags (since VT-d 3.0); reserved before.
+    pub flags: u8,
+
+    _reserved: u8,
+
+    /// The I/O APIC id, HPET number or ACPI device number, for the scope types which are enumerated that way.
+    pub enumeration_id: u8,
+
+    /// The bus the path starts on.
+    pub start_bus: u8
+}
+
+impl DeviceScope {
+    /// The path from the start bus to the device.
+    pub fn path(&self) -> &[PciPathEntry] {
+        let count = (self.length as usize).saturating_sub(mem::size_of::<Self>()) / mem::size_of::<PciPathEntry>();
+
+        // UNSAFE: Safe, as the iterator checked the whole scope lies within the structure.
+        unsafe {
+            let start = (self as *const DeviceScope as *const u8).offset(mem::size_of::<Self>() as isize);
+            slice::from_raw_parts(start as *const PciPathEntry, count)
+        }
+    }
+}
+
+/// Provides iteration over the device scopes which follow a remapping structure's fixed fields.
+#[derive(Debug)]
+pub struct DeviceScopeIter<'a> {
+    /// The memory location of the next scope to return.
+    location: *const u8,
+
+    /// The end of the structure; no scope may extend past this point.
+    end: *const u8,
+
+    /// Ties the lifetime of the returned scopes to the structure.
+    _entry: PhantomData<&'a DeviceScope>
+}
+
+impl<'a> DeviceScopeIter<'a> {
+    /// Iterates over the scopes after the fixed fields of the given structure, up to it's length.
+    fn following<T>(entry: &'a T, length: u16) -> DeviceScopeIter<'a> {
+        let start = entry as *const T as *const u8;
+
+        // UNSAFE: Safe, as the entries iterator checked the structure is at least as long as T, and it's length
+        // lies within the table.
+        unsafe {
+            DeviceScopeIter {
+                location: start.offset(mem::size_of::<T>() as isize),
+                end: start.offset(length as isize),
+                _entry: PhantomData
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for DeviceScopeIter<'a> {
+    type Item = &'a DeviceScope;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if (self.end as usize) < (self.location as usize) + mem::size_of::<DeviceScope>() { return None; }
+
+        // UNSAFE: Safe, as we checked the fixed part of the scope lies within the structure.
+        let scope: &'a DeviceScope = unsafe { &*(self.location as *const DeviceScope) };
+
+        let length = scope.length as usize;
+        if length < mem::size_of::<DeviceScope>() || (self.location as usize) + length > (self.end as usize) {
+            return None;
+        }
+
+        self.location = unsafe { self.location.offset(length as isize) };
+
+        Some(scope)
+    }
+}
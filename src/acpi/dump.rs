@@ -0,0 +1,222 @@
+//! Dumps the ACPI tables in a readable form, so what the firmware hands us can be compared across machines
+//! without reaching for a separate tool. Every table gets it's header printed; the tables this module
+//! understands get their interesting contents printed below it.
+
+use core::fmt::{self, Write};
+use core::mem;
+
+use super::{ACPI, PhysicalMemory, SDTHeader, MADT, MADTEntry, FADT, FACS, HPET, MCFG, SRAT, SratEntry, SLIT, DMAR,
+    DmarEntry, DeviceScopeIter};
+
+/// Writes out every table the root table points to, followed by the DSDT (which only the FADT points to).
+pub fn dump<M: PhysicalMemory, W: Write>(acpi: &ACPI<M>, out: &mut W) -> fmt::Result {
+    writeln!(out, "{} @ 0x{:x}", acpi.root_header(), acpi.root_address)?;
+
+    for address in acpi.raw_tables() {
+        dump_table_at(acpi, address, out)?;
+    }
+
+    if let Some(fadt) = acpi.find_table::<FADT>() {
+        dump_table_at(acpi, fadt.dsdt_address(), out)?;
+
+        if let Some(facs) = acpi.facs() {
+            dump_facs(facs, fadt.facs_address(), out)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes out the header of the table at the given physical address, and it's contents if they're known.
+pub fn dump_table_at<M: PhysicalMemory, W: Write>(acpi: &ACPI<M>, address: u64, out: &mut W) -> fmt::Result {
+    let header = match acpi.table_at(address) {
+        Some(header) => header,
+        None => return writeln!(out, "Inaccessible table @ 0x{:x}", address)
+    };
+
+    writeln!(out, "{} @ 0x{:x}", header, address)?;
+
+    match &header.signature {
+        b"APIC" => acpi.typed_table_at(address).map(|madt| dump_madt(madt, out)),
+        b"FACP" => acpi.typed_table_at(address).map(|fadt| dump_fadt(fadt, out)),
+        b"HPET" => acpi.typed_table_at(address).map(|hpet| dump_hpet(hpet, out)),
+        b"MCFG" => acpi.typed_table_at(address).map(|mcfg| dump_mcfg(mcfg, out)),
+        b"SRAT" => acpi.typed_table_at(address).map(|srat| dump_srat(srat, out)),
+        b"SLIT" => acpi.typed_table_at(address).map(|slit| dump_slit(slit, out)),
+        b"DMAR" => acpi.typed_table_at(address).map(|dmar| dump_dmar(dmar, out)),
+        b"DSDT" | b"SSDT" => Some(writeln!(out, "    AML: {} bytes",
+            (header.length as usize).saturating_sub(mem::size_of::<SDTHeader>()))),
+        _ => None
+    }.unwrap_or(Ok(()))
+}
+
+/// Writes out the local APIC address and every interrupt controller entry.
+fn dump_madt<W: Write>(madt: &MADT, out: &mut W) -> fmt::Result {
+    writeln!(out, "    Local APIC @ 0x{:x}, legacy PICs: {}", madt.local_apic_address(), madt.has_legacy_pics())?;
+
+    for entry in madt.entries() {
+        match entry {
+            MADTEntry::LocalApic(entry) => writeln!(out, "    Local APIC: processor {}, APIC {}, flags 0x{:x}",
+                entry.processor_id, entry.apic_id, { entry.flags }),
+            MADTEntry::IoApic(entry) => writeln!(out, "    I/O APIC {} @ 0x{:x}, GSI base {}", entry.io_apic_id,
+                { entry.address }, { entry.global_system_interrupt_base }),
+            MADTEntry::InterruptSourceOverride(entry) => writeln!(out,
+                "    Override: bus {} IRQ {} -> GSI {}, {:?}, {:?}", entry.bus, entry.source,
+                { entry.global_system_interrupt }, entry.polarity(), entry.trigger_mode()),
+            MADTEntry::NmiSource(entry) => writeln!(out, "    NMI source: GSI {}, {:?}, {:?}",
+                { entry.global_system_interrupt }, entry.polarity(), entry.trigger_mode()),
+            MADTEntry::LocalApicNmi(entry) => writeln!(out, "    Local APIC NMI: processor 0x{:x}, LINT{}",
+                entry.processor_id, entry.lint),
+            MADTEntry::LocalApicAddressOverride(entry) => writeln!(out, "    Local APIC override @ 0x{:x}",
+                { entry.address }),
+            MADTEntry::LocalX2Apic(entry) => writeln!(out, "    Local x2APIC: UID {}, x2APIC {}, flags 0x{:x}",
+                { entry.processor_uid }, { entry.x2apic_id }, { entry.flags }),
+            MADTEntry::LocalX2ApicNmi(entry) => writeln!(out, "    Local x2APIC NMI: UID 0x{:x}, LINT{}",
+                { entry.processor_uid }, entry.lint),
+            MADTEntry::Unknown(header) => writeln!(out, "    Unknown entry: type {}, {} bytes", header.entry_type,
+                header.length)
+        }?;
+    }
+
+    Ok(())
+}
+
+/// Writes out the fixed hardware registers and flags which the kernel makes use of.
+fn dump_fadt<W: Write>(fadt: &FADT, out: &mut W) -> fmt::Result {
+    writeln!(out, "    DSDT @ 0x{:x}, FACS @ 0x{:x}, SCI IRQ {}, version {}.{}", fadt.dsdt_address(),
+        fadt.facs_address(), { fadt.sci_interrupt }, fadt.header.revision, fadt.minor_version())?;
+    writeln!(out, "    Flags 0x{:x}, boot architecture 0x{:x}, hardware reduced: {}", { fadt.flags },
+        fadt.boot_architecture_flags(), fadt.is_hardware_reduced())?;
+
+    let registers = [
+        ("PM1a event", fadt.pm1a_event_block()),
+        ("PM1b event", fadt.pm1b_event_block()),
+        ("PM1a control", fadt.pm1a_control_block()),
+        ("PM1b control", fadt.pm1b_control_block()),
+        ("PM2 control", fadt.pm2_control_block()),
+        ("PM timer", fadt.pm_timer_block()),
+        ("GPE0", fadt.gpe0_block()),
+        ("GPE1", fadt.gpe1_block()),
+        ("Sleep control", fadt.sleep_control_register()),
+        ("Sleep status", fadt.sleep_status_register())
+    ];
+
+    for &(name, register) in &registers {
+        if let Some(register) = register {
+            writeln!(out, "    {}: {}", name, register)?;
+        }
+    }
+
+    if let Some((register, value)) = fadt.reset_register() {
+        writeln!(out, "    Reset: write 0x{:x} to {}", value, register)?;
+    }
+
+    Ok(())
+}
+
+/// Writes out the FACS, which has no standard header to print.
+fn dump_facs<W: Write>(facs: &FACS, address: u64, out: &mut W) -> fmt::Result {
+    writeln!(out, "FACS version {}, {} bytes @ 0x{:x}", facs.version, { facs.length }, address)?;
+    writeln!(out, "    Hardware signature 0x{:x}, flags 0x{:x}, waking vector 0x{:x}", { facs.hardware_signature },
+        { facs.flags }, { facs.firmware_waking_vector })
+}
+
+/// Writes out where the HPET lives and what it can do.
+fn dump_hpet<W: Write>(hpet: &HPET, out: &mut W) -> fmt::Result {
+    writeln!(out, "    HPET {} @ {}, vendor 0x{:04x}", hpet.hpet_number, { hpet.base_address }, hpet.vendor_id())?;
+    writeln!(out, "    {} comparators, {}-bit counter, legacy replacement: {}, minimum tick {}",
+        hpet.comparator_count(), if hpet.has_64_bit_counter() { 64 } else { 32 },
+        hpet.supports_legacy_replacement(), { hpet.minimum_tick })
+}
+
+/// Writes out every ECAM region.
+fn dump_mcfg<W: Write>(mcfg: &MCFG, out: &mut W) -> fmt::Result {
+    for entry in mcfg.entries() {
+        writeln!(out, "    Segment {} buses {}-{} @ 0x{:x}", { entry.segment_group }, entry.start_bus,
+            entry.end_bus, { entry.base_address })?;
+    }
+
+    Ok(())
+}
+
+/// Writes out every affinity entry, enabled or not.
+fn dump_srat<W: Write>(srat: &SRAT, out: &mut W) -> fmt::Result {
+    for entry in srat.entries() {
+        match entry {
+            SratEntry::ProcessorAffinity(entry) => writeln!(out, "    APIC {} -> domain {}, flags 0x{:x}",
+                entry.apic_id, entry.proximity_domain(), { entry.flags }),
+            SratEntry::X2ApicAffinity(entry) => writeln!(out, "    x2APIC {} -> domain {}, flags 0x{:x}",
+                { entry.x2apic_id }, { entry.proximity_domain }, { entry.flags }),
+            SratEntry::MemoryAffinity(entry) => writeln!(out, "    Memory 0x{:x}-0x{:x} -> domain {}, flags 0x{:x}",
+                entry.base_address(), entry.base_address().saturating_add(entry.length()),
+                { entry.proximity_domain }, { entry.flags }),
+            SratEntry::Unknown(header) => writeln!(out, "    Unknown entry: type {}, {} bytes", header.entry_type,
+                header.length)
+        }?;
+    }
+
+    Ok(())
+}
+
+/// Writes out the distance matrix, one row per locality.
+fn dump_slit<W: Write>(slit: &SLIT, out: &mut W) -> fmt::Result {
+    let localities = slit.localities();
+
+    for from in 0 .. localities {
+        out.write_str("   ")?;
+
+        for to in 0 .. localities {
+            write!(out, " {:3}", slit.distance(from, to).unwrap_or(0))?;
+        }
+
+        out.write_str("\n")?;
+    }
+
+    Ok(())
+}
+
+/// Writes out the address width, and every remapping structure with the devices it covers.
+fn dump_dmar<W: Write>(dmar: &DMAR, out: &mut W) -> fmt::Result {
+    writeln!(out, "    Host address width {} bits, flags 0x{:x}", dmar.address_width(), dmar.flags)?;
+
+    for entry in dmar.entries() {
+        match entry {
+            DmarEntry::Drhd(entry) => {
+                writeln!(out, "    Remapping unit @ 0x{:x}, segment {}, flags 0x{:x}", { entry.register_base },
+                    { entry.segment }, entry.flags)?;
+                dump_device_scopes(entry.scopes(), out)
+            },
+            DmarEntry::Rmrr(entry) => {
+                writeln!(out, "    Reserved memory 0x{:x}-0x{:x}, segment {}", { entry.base_address },
+                    { entry.limit_address }, { entry.segment })?;
+                dump_device_scopes(entry.scopes(), out)
+            },
+            DmarEntry::Atsr(entry) => {
+                writeln!(out, "    ATS reporting, segment {}, flags 0x{:x}", { entry.segment }, entry.flags)?;
+                dump_device_scopes(entry.scopes(), out)
+            },
+            DmarEntry::Rhsa(entry) => writeln!(out, "    Unit @ 0x{:x} -> domain {}", { entry.register_base },
+                { entry.proximity_domain }),
+            DmarEntry::Unknown(header) => writeln!(out, "    Unknown entry: type {}, {} bytes",
+                { header.structure_type }, { header.length })
+        }?;
+    }
+
+    Ok(())
+}
+
+/// Writes out a remapping structure's device scopes, with their paths as device.function steps.
+fn dump_device_scopes<W: Write>(scopes: DeviceScopeIter, out: &mut W) -> fmt::Result {
+    for scope in scopes {
+        write!(out, "        Scope type {}, id {}, bus {:02x}", scope.scope_type, scope.enumeration_id,
+            scope.start_bus)?;
+
+        for step in scope.path() {
+            write!(out, " {:02x}.{}", { step.device }, { step.function })?;
+        }
+
+        out.write_str("\n")?;
+    }
+
+    Ok(())
+}
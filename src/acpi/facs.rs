@@ -0,0 +1,77 @@
+//! Provides the Firmware ACPI Control Structure, which the FADT points to. It isn't a system table (it has no
+//! checksum or standard header, and lives in memory the firmware reserves for itself), and it holds the
+//! waking vector the firmware jumps to when the machine wakes up from a sleep state.
+
+use core::ptr;
+
+/// Set in the flags if the firmware can jump to the 64-bit waking vector.
+pub const FACS_64BIT_WAKE_SUPPORTED: u32 = 1 << 1;
+
+/// The smallest FACS the specification allows.
+const FACS_MIN_LENGTH: u32 = 64;
+
+/// The offsets of the waking vectors, which are written to rather than read.
+const FIRMWARE_WAKING_VECTOR_OFFSET: usize = 12;
+const X_FIRMWARE_WAKING_VECTOR_OFFSET: usize = 24;
+
+/// The Firmware ACPI Control Structure.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct FACS {
+    /// The signature, "FACS".
+    pub signature: [u8; 4],
+
+    /// The length of the structure; at least 64 bytes.
+    pub length: u32,
+
+    /// Changes whenever the hardware configuration does, so a resume into a different machine can be noticed.
+    pub hardware_signature: u32,
+
+    /// The real mode address the firmware jumps to on wake up, as CS:IP = (vector >> 4):(vector & 0xF).
+    pub firmware_waking_vector: u32,
+
+    /// The global lock, shared between the firmware and the OS.
+    pub global_lock: u32,
+
+    /// The FACS_* flags.
+    pub flags: u32,
+
+    /// The 64-bit waking vector, used instead of the real mode one if it's non-zero and the firmware supports it.
+    pub x_firmware_waking_vector: u64,
+
+    /// The version of this structure.
+    pub version: u8,
+
+    _reserved0: [u8; 3],
+
+    /// Flags the OS sets to tell the firmware what it supports.
+    pub ospm_flags: u32,
+
+    _reserved1: [u8; 24]
+}
+
+impl FACS {
+    /// The signature the structure should have.
+    pub fn signature() -> &'static [u8] { b"FACS" }
+
+    /// True if the signature and length look right.
+    pub fn is_valid(&self) -> bool {
+        &self.signature == FACS::signature() && self.length >= FACS_MIN_LENGTH
+    }
+
+    /// True if the firmware can jump to a 64-bit waking vector.
+    pub fn supports_64bit_wake(&self) -> bool {
+        self.version >= 1 && self.flags & FACS_64BIT_WAKE_SUPPORTED != 0
+    }
+
+    /// Points the firmware at the real mode code to run on wake up (or at nothing, with zero). The 64-bit vector
+    /// is cleared, so the firmware always uses the real mode one.
+    /// UNSAFE: The FACS must be the real one, in writable memory, rather than a copy in a table image; and the
+    /// vector has to point at code which can cope with being entered in real mode.
+    pub unsafe fn set_waking_vector(&self, vector: u32) {
+        let base = self as *const FACS as *mut u8;
+
+        ptr::write_volatile(base.offset(X_FIRMWARE_WAKING_VECTOR_OFFSET as isize) as *mut u64, 0);
+        ptr::write_volatile(base.offset(FIRMWARE_WAKING_VECTOR_OFFSET as isize) as *mut u32, vector);
+    }
+}
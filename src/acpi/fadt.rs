@@ -0,0 +1,371 @@
+//! Provides definitions for the Fixed ACPI Description Table (FADT), which describes the fixed hardware
+//! registers used for power management, and points to the DSDT.
+
+use core::mem;
+
+use super::tables::{SDTHeader, SystemTable};
+use super::address::GenericAddress;
+
+/// IA-PC boot architecture flag: the motherboard has legacy devices (like the PIT or the 8259 PICs).
+pub const BOOT_ARCH_LEGACY_DEVICES: u16 = 1;
+
+/// IA-PC boot architecture flag: the motherboard has an 8042 keyboard controller.
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+
+/// IA-PC boot architecture flag: there is no VGA hardware; don't probe for it.
+pub const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+
+/// IA-PC boot architecture flag: message signaled interrupts must not be enabled.
+pub const BOOT_ARCH_MSI_NOT_SUPPORTED: u16 = 1 << 3;
+
+/// IA-PC boot architecture flag: the OS must not enable PCIe ASPM.
+pub const BOOT_ARCH_PCIE_ASPM_CONTROLS: u16 = 1 << 4;
+
+/// IA-PC boot architecture flag: there is no CMOS real time clock.
+pub const BOOT_ARCH_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+/// Fixed feature flag: the power button is a control method device rather than a fixed feature.
+pub const FLAG_PWR_BUTTON: u32 = 1 << 4;
+
+/// Fixed feature flag: the sleep button is a control method device (or absent) rather than a fixed feature.
+pub const FLAG_SLP_BUTTON: u32 = 1 << 5;
+
+/// Fixed feature flag: the reset register is supported.
+pub const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+/// Fixed feature flag: the platform has no fixed ACPI hardware (PM1/GPE blocks and so on).
+pub const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// The Fixed ACPI Description Table. Older firmware provides shorter versions of this table, so the
+/// fields after `flags` should only be accessed through the accessor methods, which check the length.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct FADT {
+    /// The header of the FADT.
+    pub header: SDTHeader,
+
+    /// The 32-bit physical address of the FACS.
+    pub firmware_ctrl: u32,
+
+    /// The 32-bit physical address of the DSDT.
+    pub dsdt: u32,
+
+    /// Used in ACPI 1.0 for the interrupt model; reserved since.
+    _reserved0: u8,
+
+    /// The preferred power management profile (desktop, mobile, server, ...).
+    pub preferred_pm_profile: u8,
+
+    /// The legacy interrupt the SCI is wired to.
+    pub sci_interrupt: u16,
+
+    /// The I/O port of the SMI command port, used to switch into and out of ACPI mode.
+    pub smi_command: u32,
+
+    /// The value to write to the SMI command port to enable ACPI mode.
+    pub acpi_enable: u8,
+
+    /// The value to write to the SMI command port to disable ACPI mode.
+    pub acpi_disable: u8,
+
+    /// The value to write to the SMI command port to enter the S4BIOS state.
+    pub s4bios_request: u8,
+
+    /// The value to write to the SMI command port to take over processor performance control.
+    pub pstate_control: u8,
+
+    /// The I/O port of the PM1a event register block.
+    pub pm1a_event_block: u32,
+
+    /// The I/O port of the PM1b event register block, or 0.
+    pub pm1b_event_block: u32,
+
+    /// The I/O port of the PM1a control register block.
+    pub pm1a_control_block: u32,
+
+    /// The I/O port of the PM1b control register block, or 0.
+    pub pm1b_control_block: u32,
+
+    /// The I/O port of the PM2 control register block, or 0.
+    pub pm2_control_block: u32,
+
+    /// The I/O port of the power management timer.
+    pub pm_timer_block: u32,
+
+    /// The I/O port of the general purpose event 0 register block, or 0.
+    pub gpe0_block: u32,
+
+    /// The I/O port of the general purpose event 1 register block, or 0.
+    pub gpe1_block: u32,
+
+    /// The length, in bytes, of the PM1 event blocks.
+    pub pm1_event_length: u8,
+
+    /// The length, in bytes, of the PM1 control blocks.
+    pub pm1_control_length: u8,
+
+    /// The length, in bytes, of the PM2 control block.
+    pub pm2_control_length: u8,
+
+    /// The length, in bytes, of the power management timer block.
+    pub pm_timer_length: u8,
+
+    /// The length, in bytes, of the GPE0 block.
+    pub gpe0_block_length: u8,
+
+    /// The length, in bytes, of the GPE1 block.
+    pub gpe1_block_length: u8,
+
+    /// The GPE number the GPE1 block starts at.
+    pub gpe1_base: u8,
+
+    /// The value to write to the SMI command port to indicate OS support for _CST.
+    pub cstate_control: u8,
+
+    /// The worst-case latency to enter/exit C2, in microseconds.
+    pub worst_c2_latency: u16,
+
+    /// The worst-case latency to enter/exit C3, in microseconds.
+    pub worst_c3_latency: u16,
+
+    /// Legacy cache flushing parameters.
+    pub flush_size: u16,
+    pub flush_stride: u16,
+
+    /// The location and width of the processor duty cycle setting.
+    pub duty_offset: u8,
+    pub duty_width: u8,
+
+    /// The RTC CMOS RAM indices of the day-of-month/month alarm values, or 0.
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+
+    /// The RTC CMOS RAM index of the century, or 0 if the RTC doesn't have a century field.
+    pub century: u8,
+
+    /// The IA-PC boot architecture flags (BOOT_ARCH_*); only valid since ACPI 2.0.
+    pub boot_architecture_flags: u16,
+
+    /// Reserved, should be zero.
+    _reserved1: u8,
+
+    /// The fixed feature flags (FLAG_*).
+    pub flags: u32,
+
+    /// The register to write to in order to reset the system.
+    reset_register: GenericAddress,
+
+    /// The value to write to the reset register.
+    reset_value: u8,
+
+    /// The ARM boot architecture flags.
+    arm_boot_architecture_flags: u16,
+
+    /// The minor version of this table.
+    minor_version: u8,
+
+    /// The 64-bit physical address of the FACS.
+    x_firmware_ctrl: u64,
+
+    /// The 64-bit physical address of the DSDT.
+    x_dsdt: u64,
+
+    /// Extended versions of the register blocks above.
+    x_pm1a_event_block: GenericAddress,
+    x_pm1b_event_block: GenericAddress,
+    x_pm1a_control_block: GenericAddress,
+    x_pm1b_control_block: GenericAddress,
+    x_pm2_control_block: GenericAddress,
+    x_pm_timer_block: GenericAddress,
+    x_gpe0_block: GenericAddress,
+    x_gpe1_block: GenericAddress,
+
+    /// The sleep registers used on hardware-reduced platforms.
+    sleep_control_register: GenericAddress,
+    sleep_status_register: GenericAddress,
+
+    /// The identity of the hypervisor, if any.
+    hypervisor_vendor_id: u64
+}
+
+/// The offsets of the fields which later revisions of the FADT appended; a table is only as long as the
+/// revision of the specification the firmware was written against.
+const RESET_VALUE_OFFSET: usize = 128;
+const ARM_BOOT_ARCH_OFFSET: usize = 129;
+const MINOR_VERSION_OFFSET: usize = 131;
+const X_FIRMWARE_CTRL_OFFSET: usize = 132;
+const X_DSDT_OFFSET: usize = 140;
+const X_PM1A_EVENT_BLOCK_OFFSET: usize = 148;
+const X_PM1B_EVENT_BLOCK_OFFSET: usize = 160;
+const X_PM1A_CONTROL_BLOCK_OFFSET: usize = 172;
+const X_PM1B_CONTROL_BLOCK_OFFSET: usize = 184;
+const X_PM2_CONTROL_BLOCK_OFFSET: usize = 196;
+const X_PM_TIMER_BLOCK_OFFSET: usize = 208;
+const X_GPE0_BLOCK_OFFSET: usize = 220;
+const X_GPE1_BLOCK_OFFSET: usize = 232;
+const SLEEP_CONTROL_REGISTER_OFFSET: usize = 244;
+const SLEEP_STATUS_REGISTER_OFFSET: usize = 256;
+const HYPERVISOR_VENDOR_ID_OFFSET: usize = 268;
+
+impl SystemTable for FADT {
+    fn raw_header(&self) -> *const SDTHeader {
+        (&self.header) as *const SDTHeader
+    }
+
+    fn signature() -> &'static [u8] { b"FACP" }
+}
+
+impl FADT {
+    /// True if the table is long enough to contain the field which ends at the given offset.
+    fn covers(&self, end_offset: usize) -> bool {
+        self.header.length as usize >= end_offset
+    }
+
+    /// Picks the 64-bit extended version of a register block if the table is long enough to have it and
+    /// it's filled in, and otherwise builds one from the legacy 32-bit I/O port and length.
+    fn register_block(&self, extended: &GenericAddress, extended_offset: usize, port: u32, length: u8)
+        -> Option<GenericAddress> {
+
+        if self.covers(extended_offset + mem::size_of::<GenericAddress>()) && extended.is_present() {
+            Some(*extended)
+        } else if port != 0 {
+            Some(GenericAddress::system_io(port, length))
+        } else {
+            None
+        }
+    }
+
+    /// The physical address of the DSDT, preferring the 64-bit pointer when it is present.
+    pub fn dsdt_address(&self) -> u64 {
+        if self.covers(X_DSDT_OFFSET + 8) && self.x_dsdt != 0 {
+            self.x_dsdt
+        } else {
+            self.dsdt as u64
+        }
+    }
+
+    /// The physical address of the FACS, preferring the 64-bit pointer when it is present.
+    pub fn facs_address(&self) -> u64 {
+        if self.covers(X_FIRMWARE_CTRL_OFFSET + 8) && self.x_firmware_ctrl != 0 {
+            self.x_firmware_ctrl
+        } else {
+            self.firmware_ctrl as u64
+        }
+    }
+
+    /// The PM1a event register block (status and enable registers).
+    pub fn pm1a_event_block(&self) -> Option<GenericAddress> {
+        self.register_block(&self.x_pm1a_event_block, X_PM1A_EVENT_BLOCK_OFFSET,
+            self.pm1a_event_block, self.pm1_event_length)
+    }
+
+    /// The PM1b event register block, if the platform splits it's PM1 registers.
+    pub fn pm1b_event_block(&self) -> Option<GenericAddress> {
+        self.register_block(&self.x_pm1b_event_block, X_PM1B_EVENT_BLOCK_OFFSET,
+            self.pm1b_event_block, self.pm1_event_length)
+    }
+
+    /// The PM1a control register block, which contains SCI_EN, SLP_TYP and SLP_EN.
+    pub fn pm1a_control_block(&self) -> Option<GenericAddress> {
+        self.register_block(&self.x_pm1a_control_block, X_PM1A_CONTROL_BLOCK_OFFSET,
+            self.pm1a_control_block, self.pm1_control_length)
+    }
+
+    /// The PM1b control register block, if the platform splits it's PM1 registers.
+    pub fn pm1b_control_block(&self) -> Option<GenericAddress> {
+        self.register_block(&self.x_pm1b_control_block, X_PM1B_CONTROL_BLOCK_OFFSET,
+            self.pm1b_control_block, self.pm1_control_length)
+    }
+
+    /// The PM2 control register block, if there is one.
+    pub fn pm2_control_block(&self) -> Option<GenericAddress> {
+        self.register_block(&self.x_pm2_control_block, X_PM2_CONTROL_BLOCK_OFFSET,
+            self.pm2_control_block, self.pm2_control_length)
+    }
+
+    /// The power management timer block.
+    pub fn pm_timer_block(&self) -> Option<GenericAddress> {
+        self.register_block(&self.x_pm_timer_block, X_PM_TIMER_BLOCK_OFFSET,
+            self.pm_timer_block, self.pm_timer_length)
+    }
+
+    /// The general purpose event 0 register block.
+    pub fn gpe0_block(&self) -> Option<GenericAddress> {
+        self.register_block(&self.x_gpe0_block, X_GPE0_BLOCK_OFFSET,
+            self.gpe0_block, self.gpe0_block_length)
+    }
+
+    /// The general purpose event 1 register block.
+    pub fn gpe1_block(&self) -> Option<GenericAddress> {
+        self.register_block(&self.x_gpe1_block, X_GPE1_BLOCK_OFFSET,
+            self.gpe1_block, self.gpe1_block_length)
+    }
+
+    /// The register (and value to write to it) used to reset the system, if it is supported.
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        let supported = self.flags & FLAG_RESET_REG_SUP != 0;
+
+        if supported && self.covers(RESET_VALUE_OFFSET + 1) && self.reset_register.is_present() {
+            Some((self.reset_register, self.reset_value))
+        } else {
+            None
+        }
+    }
+
+    /// The ARM boot architecture flags, which are 0 on older tables.
+    pub fn arm_boot_architecture_flags(&self) -> u16 {
+        if self.covers(ARM_BOOT_ARCH_OFFSET + 2) { self.arm_boot_architecture_flags } else { 0 }
+    }
+
+    /// The minor version of this table (the major version being the header revision).
+    pub fn minor_version(&self) -> u8 {
+        if self.covers(MINOR_VERSION_OFFSET + 1) { self.minor_version } else { 0 }
+    }
+
+    /// The sleep control register, used instead of PM1 control on hardware-reduced platforms.
+    pub fn sleep_control_register(&self) -> Option<GenericAddress> {
+        let end = SLEEP_CONTROL_REGISTER_OFFSET + mem::size_of::<GenericAddress>();
+
+        if !self.covers(end) || !self.sleep_control_register.is_present() { return None; }
+
+        Some(self.sleep_control_register)
+    }
+
+    /// The sleep status register, used instead of PM1 status on hardware-reduced platforms.
+    pub fn sleep_status_register(&self) -> Option<GenericAddress> {
+        let end = SLEEP_STATUS_REGISTER_OFFSET + mem::size_of::<GenericAddress>();
+
+        if !self.covers(end) || !self.sleep_status_register.is_present() { return None; }
+
+        Some(self.sleep_status_register)
+    }
+
+    /// The identity of the hypervisor we're running under, if the firmware tells us.
+    pub fn hypervisor_vendor_id(&self) -> Option<u64> {
+        if self.covers(HYPERVISOR_VENDOR_ID_OFFSET + 8) && self.hypervisor_vendor_id != 0 {
+            Some(self.hypervisor_vendor_id)
+        } else {
+            None
+        }
+    }
+
+    /// The IA-PC boot architecture flags; ACPI 1.0 tables don't have them, so we return 0 for those.
+    pub fn boot_architecture_flags(&self) -> u16 {
+        if self.header.revision >= 2 { self.boot_architecture_flags } else { 0 }
+    }
+
+    /// The RTC CMOS RAM index of the century, if the RTC has one.
+    pub fn century_register(&self) -> Option<u8> {
+        if self.century != 0 { Some(self.century) } else { None }
+    }
+
+    /// True if the platform has a legacy 8042 keyboard controller; ACPI 1.0 machines always did.
+    pub fn has_8042(&self) -> bool {
+        self.header.revision < 2 || self.boot_architecture_flags & BOOT_ARCH_8042 != 0
+    }
+
+    /// True if this is a hardware-reduced ACPI platform, with no fixed hardware registers.
+    pub fn is_hardware_reduced(&self) -> bool {
+        self.flags & FLAG_HW_REDUCED_ACPI != 0
+    }
+}
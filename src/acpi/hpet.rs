@@ -0,0 +1,73 @@
+//! Provides the HPET table, which describes where the High Precision Event Timer's registers live.
+
+use super::{SDTHeader, SystemTable, GenericAddress};
+
+/// The page protection value for an HPET with no guarantees about what else shares it's 4KiB page.
+pub const PAGE_PROTECTION_NONE: u8 = 0;
+
+/// The HPET has it's own 4KiB page, which can be mapped without exposing anything else.
+pub const PAGE_PROTECTION_4K: u8 = 1;
+
+/// The HPET has it's own 64KiB region, which can be mapped without exposing anything else.
+pub const PAGE_PROTECTION_64K: u8 = 2;
+
+/// The High Precision Event Timer Table. Each HPET block in the machine gets one of these; the first (and
+/// usually only) one is block 0.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct HPET {
+    /// The header of the HPET table.
+    pub header: SDTHeader,
+
+    /// A copy of the upper half of the general capabilities register: the hardware revision (bits 0-7), the
+    /// number of comparators minus one (bits 8-12), whether the counter is 64 bits wide (bit 13), whether
+    /// legacy replacement routing is supported (bit 15), and the PCI vendor id (bits 16-31).
+    pub event_timer_block_id: u32,
+
+    /// Where the HPET's registers live; always in system memory.
+    pub base_address: GenericAddress,
+
+    /// The sequence number of this HPET block.
+    pub hpet_number: u8,
+
+    /// The minimum number of ticks a periodic comparator can be set to without losing interrupts.
+    pub minimum_tick: u16,
+
+    /// The page protection (PAGE_PROTECTION_*) in the lower 4 bits, and OEM attributes in the upper 4.
+    pub page_protection: u8
+}
+
+impl SystemTable for HPET {
+    fn raw_header(&self) -> *const SDTHeader {
+        (&self.header) as *const SDTHeader
+    }
+
+    fn signature() -> &'static [u8] { b"HPET" }
+}
+
+impl HPET {
+    /// The number of comparators in this HPET block.
+    pub fn comparator_count(&self) -> u8 {
+        ((self.event_timer_block_id >> 8) & 0x1F) as u8 + 1
+    }
+
+    /// True if the main counter is 64 bits wide; otherwise it's only 32 bits wide, and wraps rather quickly.
+    pub fn has_64_bit_counter(&self) -> bool {
+        self.event_timer_block_id & (1 << 13) != 0
+    }
+
+    /// True if the HPET can take over the PIT and RTC interrupts (IRQ 0 and 8).
+    pub fn supports_legacy_replacement(&self) -> bool {
+        self.event_timer_block_id & (1 << 15) != 0
+    }
+
+    /// The PCI vendor id of the HPET's manufacturer.
+    pub fn vendor_id(&self) -> u16 {
+        (self.event_timer_block_id >> 16) as u16
+    }
+
+    /// The page protection guarantee the firmware makes about the HPET's registers (PAGE_PROTECTION_*).
+    pub fn page_protection(&self) -> u8 {
+        self.page_protection & 0x0F
+    }
+}
@@ -0,0 +1,501 @@
+//! Provides definitions for the Multiple APIC Description Table (MADT), which describes every interrupt
+//! controller in the system - and, since every processor has a local APIC, every processor as well.
+
+use core::mem;
+use core::marker::PhantomData;
+
+use super::tables::{SDTHeader, SystemTable};
+
+/// Set in the MADT flags if the system also has a PC-AT compatible dual 8259 setup, which needs to
+/// be disabled (masked) before the APICs are used.
+pub const MADT_PCAT_COMPAT: u32 = 1;
+
+/// Set in a local APIC/x2APIC entry's flags if the processor is enabled and ready for use.
+pub const LOCAL_APIC_ENABLED: u32 = 1;
+
+/// Set in a local APIC/x2APIC entry's flags if the processor is disabled, but can be brought online later.
+pub const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// The processor id used in the local APIC NMI entries to mean "all processors".
+pub const ALL_PROCESSORS: u8 = 0xFF;
+
+/// The processor UID used in the local x2APIC NMI entries to mean "all processors".
+pub const ALL_PROCESSORS_X2APIC: u32 = 0xFFFFFFFF;
+
+/// The Multiple APIC Description Table, which lists the local APIC address and then a variable number of
+/// entries describing the processors, I/O APICs, and any interrupt routing quirks.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct MADT {
+    /// The header of the MADT.
+    pub header: SDTHeader,
+
+    /// The 32-bit physical address at which each processor can access it's own local APIC; this may be
+    /// overridden by a local APIC address override entry.
+    pub local_apic_address: u32,
+
+    /// Multiple APIC flags; currently only MADT_PCAT_COMPAT is defined.
+    pub flags: u32
+}
+
+impl SystemTable for MADT {
+    fn raw_header(&self) -> *const SDTHeader {
+        (&self.header) as *const SDTHeader
+    }
+
+    fn signature() -> &'static [u8] { b"APIC" }
+}
+
+impl MADT {
+    /// Returns an iterator over all of the interrupt controller entries in this table.
+    pub fn entries(&self) -> MADTEntriesIter {
+        let table_start = self as *const MADT as *const u8;
+
+        // Entries start right after the fixed part of the table and go on until the end of the "length" field.
+        // UNSAFE: Safe, as the length is given by the table itself.
+        let entries_start = unsafe { table_start.offset(mem::size_of::<Self>() as isize) };
+        let entries_end = unsafe { table_start.offset(self.header.length as isize) };
+
+        MADTEntriesIter { location: entries_start, end: entries_end, _table: PhantomData }
+    }
+
+    /// Returns true if the system also has legacy 8259 PICs which should be masked.
+    pub fn has_legacy_pics(&self) -> bool {
+        self.flags & MADT_PCAT_COMPAT != 0
+    }
+
+    /// Obtains the physical address of the local APIC, taking any 64-bit address override entry into account.
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .filter_map(|entry| match entry {
+                MADTEntry::LocalApicAddressOverride(over) => Some(over.address),
+                _ => None
+            })
+            .next()
+            .unwrap_or(self.local_apic_address as u64)
+    }
+
+    /// Returns an iterator over every processor described by this table, whether it uses an xAPIC or x2APIC.
+    pub fn processors(&self) -> ProcessorIter {
+        ProcessorIter { entries: self.entries() }
+    }
+
+    /// Returns an iterator over every I/O APIC described by this table.
+    pub fn io_apics(&self) -> IoApicIter {
+        IoApicIter { entries: self.entries() }
+    }
+
+    /// Finds the override entry for the given legacy ISA IRQ, if it has one.
+    pub fn interrupt_source_override(&self, irq: u8) -> Option<&InterruptSourceOverrideEntry> {
+        self.entries()
+            .filter_map(|entry| match entry {
+                MADTEntry::InterruptSourceOverride(over) if over.bus == 0 && over.source == irq => Some(over),
+                _ => None
+            })
+            .next()
+    }
+
+    /// Finds the global system interrupt which the given legacy ISA IRQ is connected to, along with the
+    /// polarity and trigger mode of the line. If no override exists, the IRQ is identity-mapped and uses the
+    /// ISA defaults (active high, edge triggered).
+    pub fn isa_irq_route(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        self.interrupt_source_override(irq)
+            .map(|over| {
+                let polarity = match over.polarity() {
+                    Polarity::ConformsToBus => Polarity::ActiveHigh,
+                    other => other
+                };
+
+                let trigger = match over.trigger_mode() {
+                    TriggerMode::ConformsToBus => TriggerMode::Edge,
+                    other => other
+                };
+
+                (over.global_system_interrupt, polarity, trigger)
+            })
+            .unwrap_or((irq as u32, Polarity::ActiveHigh, TriggerMode::Edge))
+    }
+}
+
+/// The header shared by every entry in the MADT.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct EntryHeader {
+    /// The type of the entry, which determines it's layout.
+    pub entry_type: u8,
+
+    /// The total length of the entry, including this header.
+    pub length: u8
+}
+
+/// The entry types defined by the ACPI specification which we understand.
+pub const ENTRY_LOCAL_APIC: u8 = 0;
+pub const ENTRY_IO_APIC: u8 = 1;
+pub const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+pub const ENTRY_NMI_SOURCE: u8 = 3;
+pub const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+pub const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+pub const ENTRY_LOCAL_X2APIC: u8 = 9;
+pub const ENTRY_LOCAL_X2APIC_NMI: u8 = 10;
+
+/// Describes a single processor and it's local APIC.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// The ACPI processor id, which the processor objects in the namespace refer to.
+    pub processor_id: u8,
+
+    /// The processor's local APIC id.
+    pub apic_id: u8,
+
+    /// The local APIC flags (LOCAL_APIC_ENABLED and LOCAL_APIC_ONLINE_CAPABLE).
+    pub flags: u32
+}
+
+/// Describes an I/O APIC, which routes a contiguous range of global system interrupts to processors.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// The I/O APIC's id.
+    pub io_apic_id: u8,
+
+    /// Reserved, should be zero.
+    _reserved: u8,
+
+    /// The 32-bit physical address of the I/O APIC's registers.
+    pub address: u32,
+
+    /// The first global system interrupt this I/O APIC handles.
+    pub global_system_interrupt_base: u32
+}
+
+/// Describes how a legacy (ISA) interrupt is actually wired up to the I/O APICs, when it isn't identity mapped.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverrideEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// The bus the interrupt source is on; 0 is ISA, which is the only bus defined.
+    pub bus: u8,
+
+    /// The bus-relative interrupt source (the IRQ).
+    pub source: u8,
+
+    /// The global system interrupt that this source will signal.
+    pub global_system_interrupt: u32,
+
+    /// The MPS INTI flags, which describe polarity and trigger mode.
+    pub flags: u16
+}
+
+impl InterruptSourceOverrideEntry {
+    /// Decodes the polarity of this interrupt line.
+    pub fn polarity(&self) -> Polarity { Polarity::from_flags(self.flags) }
+
+    /// Decodes the trigger mode of this interrupt line.
+    pub fn trigger_mode(&self) -> TriggerMode { TriggerMode::from_flags(self.flags) }
+}
+
+/// Describes a global system interrupt which should be configured as a non-maskable interrupt.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct NmiSourceEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// The MPS INTI flags, which describe polarity and trigger mode.
+    pub flags: u16,
+
+    /// The global system interrupt which is the NMI source.
+    pub global_system_interrupt: u32
+}
+
+impl NmiSourceEntry {
+    /// Decodes the polarity of this interrupt line.
+    pub fn polarity(&self) -> Polarity { Polarity::from_flags(self.flags) }
+
+    /// Decodes the trigger mode of this interrupt line.
+    pub fn trigger_mode(&self) -> TriggerMode { TriggerMode::from_flags(self.flags) }
+}
+
+/// Describes which local APIC interrupt input (LINT0/LINT1) the NMI is connected to on a processor.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmiEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// The ACPI processor id this applies to, or ALL_PROCESSORS.
+    pub processor_id: u8,
+
+    /// The MPS INTI flags, which describe polarity and trigger mode.
+    pub flags: u16,
+
+    /// The local APIC interrupt input the NMI is connected to (0 or 1).
+    pub lint: u8
+}
+
+impl LocalApicNmiEntry {
+    /// Decodes the polarity of this interrupt line.
+    pub fn polarity(&self) -> Polarity { Polarity::from_flags(self.flags) }
+
+    /// Decodes the trigger mode of this interrupt line.
+    pub fn trigger_mode(&self) -> TriggerMode { TriggerMode::from_flags(self.flags) }
+}
+
+/// Provides a 64-bit address for the local APICs, which supersedes the 32-bit address in the MADT itself.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicAddressOverrideEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// Reserved, should be zero.
+    _reserved: u16,
+
+    /// The 64-bit physical address of the local APICs.
+    pub address: u64
+}
+
+/// Describes a single processor with an x2APIC id too large to fit in a LocalApicEntry.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct LocalX2ApicEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// Reserved, should be zero.
+    _reserved: u16,
+
+    /// The processor's local x2APIC id.
+    pub x2apic_id: u32,
+
+    /// The local APIC flags (LOCAL_APIC_ENABLED and LOCAL_APIC_ONLINE_CAPABLE).
+    pub flags: u32,
+
+    /// The ACPI processor UID, which the processor devices in the namespace refer to.
+    pub processor_uid: u32
+}
+
+/// Describes which local x2APIC interrupt input the NMI is connected to on a processor.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct LocalX2ApicNmiEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// The MPS INTI flags, which describe polarity and trigger mode.
+    pub flags: u16,
+
+    /// The ACPI processor UID this applies to, or ALL_PROCESSORS_X2APIC.
+    pub processor_uid: u32,
+
+    /// The local x2APIC interrupt input the NMI is connected to (0 or 1).
+    pub lint: u8,
+
+    /// Reserved, should be zero.
+    _reserved: [u8; 3]
+}
+
+impl LocalX2ApicNmiEntry {
+    /// Decodes the polarity of this interrupt line.
+    pub fn polarity(&self) -> Polarity { Polarity::from_flags(self.flags) }
+
+    /// Decodes the trigger mode of this interrupt line.
+    pub fn trigger_mode(&self) -> TriggerMode { TriggerMode::from_flags(self.flags) }
+}
+
+/// The polarity of an interrupt line, decoded from the MPS INTI flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// Uses whatever the bus specification says (active high for ISA).
+    ConformsToBus,
+    ActiveHigh,
+    ActiveLow,
+    /// A reserved value; firmware shouldn't give us this.
+    Reserved
+}
+
+impl Polarity {
+    /// Decodes the polarity from the lowest two bits of the MPS INTI flags.
+    pub fn from_flags(flags: u16) -> Polarity {
+        match flags & 0b11 {
+            0b00 => Polarity::ConformsToBus,
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::Reserved
+        }
+    }
+}
+
+/// The trigger mode of an interrupt line, decoded from the MPS INTI flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Uses whatever the bus specification says (edge triggered for ISA).
+    ConformsToBus,
+    Edge,
+    Level,
+    /// A reserved value; firmware shouldn't give us this.
+    Reserved
+}
+
+impl TriggerMode {
+    /// Decodes the trigger mode from bits 2 and 3 of the MPS INTI flags.
+    pub fn from_flags(flags: u16) -> TriggerMode {
+        match (flags >> 2) & 0b11 {
+            0b00 => TriggerMode::ConformsToBus,
+            0b01 => TriggerMode::Edge,
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::Reserved
+        }
+    }
+}
+
+/// A single typed entry of the MADT.
+#[derive(Debug)]
+pub enum MADTEntry<'a> {
+    LocalApic(&'a LocalApicEntry),
+    IoApic(&'a IoApicEntry),
+    InterruptSourceOverride(&'a InterruptSourceOverrideEntry),
+    NmiSource(&'a NmiSourceEntry),
+    LocalApicNmi(&'a LocalApicNmiEntry),
+    LocalApicAddressOverride(&'a LocalApicAddressOverrideEntry),
+    LocalX2Apic(&'a LocalX2ApicEntry),
+    LocalX2ApicNmi(&'a LocalX2ApicNmiEntry),
+
+    /// An entry type we don't (yet) understand; only the header is provided.
+    Unknown(&'a EntryHeader)
+}
+
+/// Provides iteration over the variable-length entries in the MADT.
+#[derive(Debug)]
+pub struct MADTEntriesIter<'a> {
+    /// The memory location of the next entry to return.
+    location: *const u8,
+
+    /// The end of the table; no entry may extend past this point.
+    end: *const u8,
+
+    /// Ties the lifetime of the returned entries to the table.
+    _table: PhantomData<&'a MADT>
+}
+
+impl<'a> MADTEntriesIter<'a> {
+    /// Reinterprets the entry at the current location as the given type, if it is long enough to hold it.
+    unsafe fn entry_as<T>(&self, header: &EntryHeader) -> Option<&'a T> {
+        if (header.length as usize) < mem::size_of::<T>() { return None; }
+
+        Some(&*(self.location as *const T))
+    }
+}
+
+impl<'a> Iterator for MADTEntriesIter<'a> {
+    type Item = MADTEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Make sure there's at least a header left to read.
+        if (self.end as usize) < (self.location as usize) + mem::size_of::<EntryHeader>() { return None; }
+
+        // UNSAFE: Safe, as we checked the header lies within the table.
+        let header: &'a EntryHeader = unsafe { &*(self.location as *const EntryHeader) };
+
+        // A zero-length (or overlong) entry means the table is corrupted; stop instead of looping forever.
+        let length = header.length as usize;
+        if length < mem::size_of::<EntryHeader>() || (self.location as usize) + length > (self.end as usize) {
+            return None;
+        }
+
+        let entry = unsafe {
+            match header.entry_type {
+                ENTRY_LOCAL_APIC => self.entry_as(header).map(MADTEntry::LocalApic),
+                ENTRY_IO_APIC => self.entry_as(header).map(MADTEntry::IoApic),
+                ENTRY_INTERRUPT_SOURCE_OVERRIDE => self.entry_as(header).map(MADTEntry::InterruptSourceOverride),
+                ENTRY_NMI_SOURCE => self.entry_as(header).map(MADTEntry::NmiSource),
+                ENTRY_LOCAL_APIC_NMI => self.entry_as(header).map(MADTEntry::LocalApicNmi),
+                ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => self.entry_as(header).map(MADTEntry::LocalApicAddressOverride),
+                ENTRY_LOCAL_X2APIC => self.entry_as(header).map(MADTEntry::LocalX2Apic),
+                ENTRY_LOCAL_X2APIC_NMI => self.entry_as(header).map(MADTEntry::LocalX2ApicNmi),
+                _ => None
+            }
+        }.unwrap_or(MADTEntry::Unknown(header));
+
+        self.location = unsafe { self.location.offset(length as isize) };
+
+        Some(entry)
+    }
+}
+
+/// A processor described by the MADT, either through a local APIC or local x2APIC entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    /// The ACPI processor id/UID.
+    pub processor_id: u32,
+
+    /// The (x2)APIC id of the processor, used to address it with IPIs.
+    pub apic_id: u32,
+
+    /// True if the processor is enabled and can be started.
+    pub enabled: bool,
+
+    /// True if the processor is currently disabled, but can be brought online later.
+    pub online_capable: bool
+}
+
+/// Provides iteration over the processors described by the MADT.
+#[derive(Debug)]
+pub struct ProcessorIter<'a> {
+    entries: MADTEntriesIter<'a>
+}
+
+impl<'a> Iterator for ProcessorIter<'a> {
+    type Item = Processor;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for entry in &mut self.entries {
+            match entry {
+                MADTEntry::LocalApic(apic) => return Some(Processor {
+                    processor_id: apic.processor_id as u32,
+                    apic_id: apic.apic_id as u32,
+                    enabled: apic.flags & LOCAL_APIC_ENABLED != 0,
+                    online_capable: apic.flags & LOCAL_APIC_ONLINE_CAPABLE != 0
+                }),
+                MADTEntry::LocalX2Apic(apic) => return Some(Processor {
+                    processor_id: apic.processor_uid,
+                    apic_id: apic.x2apic_id,
+                    enabled: apic.flags & LOCAL_APIC_ENABLED != 0,
+                    online_capable: apic.flags & LOCAL_APIC_ONLINE_CAPABLE != 0
+                }),
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+/// Provides iteration over the I/O APICs described by the MADT.
+#[derive(Debug)]
+pub struct IoApicIter<'a> {
+    entries: MADTEntriesIter<'a>
+}
+
+impl<'a> Iterator for IoApicIter<'a> {
+    type Item = &'a IoApicEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for entry in &mut self.entries {
+            if let MADTEntry::IoApic(io_apic) = entry {
+                return Some(io_apic);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,106 @@
+//! Provides the MCFG table, which lists the memory-mapped (ECAM) PCI Express configuration space regions.
+
+use core::marker::PhantomData;
+use core::{mem, ptr};
+
+use super::{SDTHeader, SystemTable};
+
+/// The PCI Express Memory-mapped Configuration table. After the fixed part of the table comes a list of
+/// McfgEntry structures, which goes on until the end of the table.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct MCFG {
+    /// The header of the MCFG.
+    pub header: SDTHeader,
+
+    _reserved: u64
+}
+
+impl SystemTable for MCFG {
+    fn raw_header(&self) -> *const SDTHeader {
+        (&self.header) as *const SDTHeader
+    }
+
+    fn signature() -> &'static [u8] { b"MCFG" }
+}
+
+impl MCFG {
+    /// Returns an iterator over the configuration space regions in this table.
+    pub fn entries(&self) -> McfgEntriesIter {
+        let table_start = self as *const MCFG as *const u8;
+        let length = self.header.length as usize;
+        let count = length.saturating_sub(mem::size_of::<Self>()) / mem::size_of::<McfgEntry>();
+
+        // UNSAFE: Safe, as the length is given by the table itself.
+        let entries_start = unsafe { table_start.offset(mem::size_of::<Self>() as isize) };
+
+        McfgEntriesIter { location: entries_start, remaining: count, _table: PhantomData }
+    }
+}
+
+/// A configuration space region, covering a range of buses in one PCI segment group.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// The physical address the configuration space of bus 0 would be at, whichever bus the region starts at; the
+    /// start bus's configuration space is `start_bus << 20` bytes into it.
+    pub base_address: u64,
+
+    /// The PCI segment group the buses are in.
+    pub segment_group: u16,
+
+    /// The first bus number the region decodes.
+    pub start_bus: u8,
+
+    /// The last bus number the region decodes.
+    pub end_bus: u8,
+
+    _reserved: u32
+}
+
+impl McfgEntry {
+    /// The number of bytes of physical memory the region covers: 4KiB for each function of each device of
+    /// each bus.
+    pub fn size(&self) -> u64 {
+        (self.end_bus as u64 + 1).saturating_sub(self.start_bus as u64) << 20
+    }
+
+    /// The first physical address past the configuration space of the end bus.
+    pub fn end(&self) -> u64 {
+        self.base_address + ((self.end_bus as u64 + 1) << 20)
+    }
+
+    /// The physical address of the configuration space of the given function, if it's in this region.
+    pub fn function_address(&self, segment_group: u16, bus: u8, device: u8, function: u8) -> Option<u64> {
+        if segment_group != self.segment_group || bus < self.start_bus || bus > self.end_bus
+            || device >= 32 || function >= 8 {
+            return None;
+        }
+
+        Some(self.base_address + ((bus as u64) << 20) + ((device as u64) << 15) + ((function as u64) << 12))
+    }
+}
+
+/// Provides iteration over the configuration space regions in the MCFG.
+#[derive(Debug)]
+pub struct McfgEntriesIter<'a> {
+    location: *const u8,
+    remaining: usize,
+    _table: PhantomData<&'a MCFG>
+}
+
+impl<'a> Iterator for McfgEntriesIter<'a> {
+    type Item = McfgEntry;
+
+    fn next(&mut self) -> Option<McfgEntry> {
+        if self.remaining == 0 { return None; }
+
+        // UNSAFE: Safe, as the entries are within the table.
+        let entry = unsafe { ptr::read_unaligned(self.location as *const McfgEntry) };
+
+        self.location = unsafe { self.location.offset(mem::size_of::<McfgEntry>() as isize) };
+        self.remaining -= 1;
+
+        Some(entry)
+    }
+}
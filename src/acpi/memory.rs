@@ -0,0 +1,97 @@
+//! Provides access to the physical memory the ACPI tables live in. The table parsers go through the
+//! PhysicalMemory trait rather than dereferencing physical addresses themselves, so they work the same on
+//! the kernel's mappings of physical memory and on plain byte buffers (which is how the tests feed them
+//! table dumps).
+
+use core::mem;
+use core::slice;
+
+use memory::layout;
+
+use super::tables::SDTHeader;
+
+/// Something which can hand out the contents of physical memory.
+pub trait PhysicalMemory {
+    /// Obtains the `length` bytes of physical memory starting at `address`, or None if any of them can't be
+    /// accessed.
+    fn bytes(&self, address: u64, length: usize) -> Option<&[u8]>;
+
+    /// Reads a little-endian integer of the given width (in bytes, at most 8).
+    fn read_integer(&self, address: u64, width: usize) -> Option<u64> {
+        let bytes = self.bytes(address, width)?;
+
+        Some(bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    /// Reinterprets the memory at the given address as a structure of the given type.
+    /// UNSAFE: The type must be a packed structure which any bit pattern is valid for.
+    unsafe fn structure<T>(&self, address: u64) -> Option<&T> {
+        self.bytes(address, mem::size_of::<T>()).map(|bytes| &*(bytes.as_ptr() as *const T))
+    }
+
+    /// Obtains the system table at the given address, as long as the whole table (according to the length
+    /// in it's header) can be accessed.
+    fn table(&self, address: u64) -> Option<&SDTHeader> {
+        // UNSAFE: Safe, as the header is a packed structure of integers.
+        let header: &SDTHeader = unsafe { self.structure(address)? };
+        let length = header.length as usize;
+
+        if length < mem::size_of::<SDTHeader>() { return None; }
+        self.bytes(address, length)?;
+
+        Some(header)
+    }
+}
+
+/// Physical memory as the kernel reaches it: through the physical map once paging has set it up, and through
+/// the bootstrap's identity map of the first four gigabytes before then. Anything beyond that can't be accessed.
+#[derive(Debug)]
+pub struct KernelMapped {
+    _private: ()
+}
+
+impl KernelMapped {
+    /// Creates an accessor for the kernel's mappings of physical memory.
+    /// UNSAFE: Every address read through the accessor must actually be memory (rather than device registers,
+    /// which mustn't be read through cached mappings), and stay mapped for as long as it's in use.
+    pub unsafe fn new() -> KernelMapped {
+        KernelMapped { _private: () }
+    }
+}
+
+impl PhysicalMemory for KernelMapped {
+    fn bytes(&self, address: u64, length: usize) -> Option<&[u8]> {
+        let end = address.checked_add(length as u64)?;
+
+        // Both mappings cover physical memory from 0 up, so the range is mapped if it's last byte is.
+        if address == 0 || (end > address && layout::phys_to_virt(end - 1).is_none()) { return None; }
+        let start = layout::phys_to_virt(address)?;
+
+        // UNSAFE: Safe, as whoever created this promised the memory is memory.
+        Some(unsafe { slice::from_raw_parts(start as *const u8, length) })
+    }
+}
+
+/// Physical memory backed by a byte buffer, which holds the memory starting at `base`; anything outside
+/// the buffer can't be accessed.
+#[derive(Debug, Clone, Copy)]
+pub struct BufferMemory<'a> {
+    base: u64,
+    buffer: &'a [u8]
+}
+
+impl<'a> BufferMemory<'a> {
+    /// Creates an accessor for a buffer which holds the physical memory starting at `base`.
+    pub fn new(base: u64, buffer: &'a [u8]) -> BufferMemory<'a> {
+        BufferMemory { base: base, buffer: buffer }
+    }
+}
+
+impl<'a> PhysicalMemory for BufferMemory<'a> {
+    fn bytes(&self, address: u64, length: usize) -> Option<&[u8]> {
+        let start = address.checked_sub(self.base)? as usize;
+        let end = start.checked_add(length)?;
+
+        self.buffer.get(start .. end)
+    }
+}
@@ -5,65 +5,241 @@
 //! is managed by (and can be found on the website of) the UEFI committee.
 
 mod tables;
+mod memory;
+mod address;
+mod madt;
+mod fadt;
+mod facs;
+mod hpet;
+mod mcfg;
+mod srat;
+mod slit;
+mod dmar;
+mod dump;
+pub mod aml;
+
+#[cfg(test)]
+pub mod test_support;
+#[cfg(test)]
+mod tests;
 
 // We do use all of the structs here and other people probably will too, so may as well import.
 pub use self::tables::*;
+pub use self::memory::*;
+pub use self::address::*;
+pub use self::madt::*;
+pub use self::fadt::*;
+pub use self::facs::*;
+pub use self::hpet::*;
+pub use self::mcfg::*;
+pub use self::srat::*;
+pub use self::slit::*;
+pub use self::dmar::*;
+pub use self::dump::*;
+
+use core::{cmp, mem};
 
-/// Represents a handle into all of the ACPI data structures, and eases
-/// information retrieval.
+use boot_info::BootInfo;
+
+/// Represents a handle into all of the ACPI data structures, and eases information retrieval. All of the
+/// tables are read through the given physical memory accessor.
 #[derive(Debug)]
-pub enum ACPI {
-    /// A version 1 ACPI table, which has 32-bit pointers.
-    Version1(&'static RSDT),
+pub struct ACPI<M: PhysicalMemory = KernelMapped> {
+    /// The physical memory the tables live in.
+    memory: M,
+
+    /// The physical address of the root table.
+    root_address: u64,
+
+    /// True if the root table is a version 2 (or above) XSDT, which has 64-bit pointers; otherwise it's a
+    /// version 1 RSDT, which has 32-bit pointers.
+    extended: bool
+}
+
+/// The reasons ACPI discovery can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No RSDP signature could be found anywhere it should be.
+    NotFound,
+
+    /// An RSDP signature was found, but the checksum over the ACPI 1.0 portion was invalid.
+    InvalidRsdpChecksum,
 
-    /// A version 2 (or above) ACPI table, which has 64-bit pointers.
-    Version2(&'static XSDT)
+    /// An XSDP signature was found, but the extended checksum over the entire structure was invalid.
+    InvalidExtendedChecksum,
+
+    /// The RSDP has a revision we don't know how to interpret.
+    UnknownRevision(u8),
+
+    /// The root table pointed to by the RSDP does not have the RSDT/XSDT signature.
+    InvalidRootSignature,
+
+    /// The root table pointed to by the RSDP has an invalid checksum (or a nonsensical length).
+    InvalidRootChecksum
 }
 
-impl ACPI {
-    
+impl ACPI<KernelMapped> {
+
+    /// Locates the ACPI tables, preferring the copies of the RSDP/XSDP which a multiboot2 loader hands us in
+    /// the boot information (the only option on UEFI machines, which have no BIOS areas to scan), and
+    /// falling back to scanning low memory if there are none (or they are invalid).
+    /// UNSAFE: The tables must be in memory the kernel maps.
+    pub unsafe fn find(boot_info: &BootInfo) -> Result<ACPI, AcpiError> {
+        let mut copy_error = None;
+
+        // Prefer the XSDP, as it gives us 64-bit table pointers.
+        for rsdp in boot_info.new_rsdp().into_iter().chain(boot_info.old_rsdp()) {
+            if rsdp.len() < mem::size_of::<RSDP>() { continue; }
+
+            match ACPI::from_rsdp_copy(KernelMapped::new(), rsdp) {
+                Ok(acpi) => return Ok(acpi),
+                Err(error) => if copy_error.is_none() { copy_error = Some(error) }
+            }
+        }
+
+        // A bad copy is a more useful error than not finding anything in low memory.
+        match (ACPI::find_in_memory(KernelMapped::new()), copy_error) {
+            (Err(AcpiError::NotFound), Some(error)) => Err(error),
+            (result, _) => result
+        }
+    }
+}
+
+/// Validates the RSDP (or XSDP) at the given address, and reads the address of the root table out of it, along with
+/// whether it's an XSDT.
+fn root_pointer<M: PhysicalMemory>(memory: &M, address: u64) -> Result<(u64, bool), AcpiError> {
+    validate_rsdp(memory, address)?;
+
+    // UNSAFE: Safe, as validation checked the whole structure is there.
+    let rsdp: &RSDP = unsafe { memory.structure(address) }.ok_or(AcpiError::NotFound)?;
+
+    match rsdp.revision {
+        RSDP_VERSION_1 => Ok((rsdp.address as u64, false)),
+        RSDP_VERSION_2 => {
+            // Version 2 means we're actually dealing with an XSDP.
+            let xsdp: &XSDP = unsafe { memory.structure(address) }.ok_or(AcpiError::NotFound)?;
+
+            Ok((xsdp.address, true))
+        },
+        revision => Err(AcpiError::UnknownRevision(revision))
+    }
+}
+
+impl<M: PhysicalMemory> ACPI<M> {
+
     /// Attempts to locate the root ACPI table in the designated memory area and return
     /// a handle to it.
-    /// UNSAFE: Unsafe, as it has to scan low physical memory to find the tables.
-    pub unsafe fn find_in_memory() -> Option<ACPI> {
-        // TODO: Change this to return a result, as there are multiple failure conditions.
-        find_rsdp().and_then(|ptr| {
-            match (*ptr).revision {
-                RSDP_VERSION_1 => Some(ACPI::Version1(&*((*ptr).address as *const RSDT))),
-                RSDP_VERSION_2 => {
-                    // Version 2 means we're actually dealing with an XSDP.
-                    let xptr = ptr as *mut XSDP;
-
-                    Some(ACPI::Version2(&*((*xptr).address as *const XSDT)))
-                },
-                _ => None
-            }
-        })
+    pub fn find_in_memory(memory: M) -> Result<ACPI<M>, AcpiError> {
+        let address = find_rsdp(&memory)?;
+
+        ACPI::from_rsdp(memory, address)
     }
 
-    /// Provides an iterator over all of the tables pointed to by the root system descriptor table.
+    /// Creates a handle from the RSDP (or XSDP) at the given physical address, validating both the RSDP
+    /// and the root table it points to.
+    pub fn from_rsdp(memory: M, address: u64) -> Result<ACPI<M>, AcpiError> {
+        let (root_address, extended) = root_pointer(&memory, address)?;
+
+        ACPI::from_root(memory, root_address, extended)
+    }
+
+    /// Creates a handle from a copy of the RSDP (or XSDP) which isn't in the physical memory the tables are, like
+    /// the one the boot loader hands us, validating both the copy and the root table it points to.
+    pub fn from_rsdp_copy(memory: M, rsdp: &[u8]) -> Result<ACPI<M>, AcpiError> {
+        let (root_address, extended) = root_pointer(&BufferMemory::new(0, rsdp), 0)?;
+
+        ACPI::from_root(memory, root_address, extended)
+    }
+
+    /// Creates a handle from the address of the root table, once it's been read out of the RSDP.
+    fn from_root(memory: M, root_address: u64, extended: bool) -> Result<ACPI<M>, AcpiError> {
+        let acpi = ACPI { memory: memory, root_address: root_address, extended: extended };
+        acpi.validate_root().map(|_| acpi)
+    }
+
+    /// Checks that the root table has the signature we expect and a valid checksum.
+    fn validate_root(&self) -> Result<(), AcpiError> {
+        // A missing (or too short) root table is as good as a corrupted one.
+        let header = self.memory.table(self.root_address).ok_or(AcpiError::InvalidRootChecksum)?;
+        let expected_signature = if self.extended { XSDT::signature() } else { RSDT::signature() };
+
+        if &header.signature != expected_signature { return Err(AcpiError::InvalidRootSignature); }
+        if !header.verify_checksum() { return Err(AcpiError::InvalidRootChecksum); }
+
+        Ok(())
+    }
+
+    /// The physical memory the tables are read from.
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    /// The header of the root table (the RSDT or XSDT).
+    pub fn root_header(&self) -> &SDTHeader {
+        // The root table was validated when this handle was created.
+        self.memory.table(self.root_address).expect("root table vanished")
+    }
+
+    /// True if the root table is an XSDT, rather than an RSDT.
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    /// Provides an iterator over the physical addresses of all of the tables pointed to by the root system
+    /// descriptor table.
     pub fn raw_tables(&self) -> RawTablesIter {
-        match *self {
-            ACPI::Version1(rsdt) => rsdt.raw_tables(),
-            ACPI::Version2(xsdt) => xsdt.raw_tables()
+        let header = self.root_header();
+
+        // UNSAFE: Safe, as the RSDT and XSDT are just their headers followed by the pointers.
+        if self.extended {
+            unsafe { &*(header as *const SDTHeader as *const XSDT) }.raw_tables()
+        } else {
+            unsafe { &*(header as *const SDTHeader as *const RSDT) }.raw_tables()
         }
     }
 
-    /// Attempt to find a table header in the root system descriptor table which has a signature
-    /// matching the given signature; return a raw pointer to it.
-    /// UNSAFE: Has to deference raw pointers in the root system description table and
-    /// re-interpret them as pointers.
-    pub unsafe fn find_raw_table(&self, signature: &[u8]) -> Option<*const SDTHeader> {
-        // TODO: Use a trait for automatically borrowing as a slice.
-        self.raw_tables().find(|&table_ptr| {
-            &(*table_ptr).signature == signature
+    /// Obtains the header of the table at the given physical address, if the whole table is accessible.
+    pub fn table_at(&self, address: u64) -> Option<&SDTHeader> {
+        self.memory.table(address)
+    }
+
+    /// Attempt to find a table in the root system descriptor table which has a signature
+    /// matching the given signature; return it's physical address.
+    pub fn find_raw_table(&self, signature: &[u8]) -> Option<u64> {
+        self.raw_tables().find(|&address| {
+            self.table_at(address).map(|header| &header.signature == signature).unwrap_or(false)
         })
     }
 
     /// Attempt to find the given system table and return a typed reference to it if it exists.
-    /// UNSAFE: Has to deference raw pointers in the root system description table and
-    /// re-interpet them as tables.
-    pub unsafe fn find_table<T: SystemTable>(&self) -> Option<&T> {
-        self.find_raw_table(T::signature()).map(|ptr| &*(ptr as *const T))
+    pub fn find_table<T: SystemTable>(&self) -> Option<&T> {
+        self.typed_table_at(self.find_raw_table(T::signature())?)
     }
-}
\ No newline at end of file
+
+    /// Obtains a typed reference to the table at the given physical address, if it has the signature of
+    /// the given type.
+    pub fn typed_table_at<T: SystemTable>(&self, address: u64) -> Option<&T> {
+        let header = self.table_at(address)?;
+        if &header.signature != T::signature() { return None; }
+
+        let length = header.length as usize;
+
+        // Older versions of some tables are shorter than the structs describing them; the accessors check
+        // the length, but the reference must still point at accessible memory.
+        self.memory.bytes(address, cmp::max(length, mem::size_of::<T>()))?;
+
+        // UNSAFE: Safe, as system tables are packed structures of integers, and all of the memory is there.
+        unsafe { self.memory.structure(address) }
+    }
+
+    /// Obtains the FACS the FADT points to, if there is one and it looks valid.
+    pub fn facs(&self) -> Option<&FACS> {
+        let address = self.find_table::<FADT>()?.facs_address();
+
+        // UNSAFE: Safe, as the FACS is a packed structure of integers.
+        let facs: &FACS = unsafe { self.memory.structure(address)? };
+        if !facs.is_valid() { return None; }
+
+        Some(facs)
+    }
+}
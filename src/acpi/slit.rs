@@ -0,0 +1,65 @@
+//! Provides the System Locality Information Table (SLIT), which gives the relative distances between
+//! proximity domains (NUMA nodes).
+
+use core::{mem, slice};
+
+use super::tables::{SDTHeader, SystemTable};
+
+/// The distance from a locality to itself; every other distance is relative to this.
+pub const LOCAL_DISTANCE: u8 = 10;
+
+/// The distance between two localities which can't reach each other at all.
+pub const UNREACHABLE_DISTANCE: u8 = 0xFF;
+
+/// The System Locality Information Table. The fixed part is followed by a matrix of `locality_count`
+/// squared distances, one row per locality.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SLIT {
+    /// The header of the SLIT.
+    pub header: SDTHeader,
+
+    /// The number of localities (proximity domains) in the system.
+    pub locality_count: u64
+}
+
+impl SystemTable for SLIT {
+    fn raw_header(&self) -> *const SDTHeader {
+        (&self.header) as *const SDTHeader
+    }
+
+    fn signature() -> &'static [u8] { b"SLIT" }
+}
+
+impl SLIT {
+    /// The distance matrix, truncated to the length of the table if it claims to have more localities than
+    /// actually fit.
+    fn matrix(&self) -> &[u8] {
+        let available = (self.header.length as usize).saturating_sub(mem::size_of::<Self>());
+        let count = self.locality_count as usize;
+        let length = match count.checked_mul(count) {
+            Some(length) if length <= available => length,
+            _ => 0
+        };
+
+        // UNSAFE: Safe, as the matrix lies within the table.
+        unsafe {
+            let start = (self as *const SLIT as *const u8).offset(mem::size_of::<Self>() as isize);
+            slice::from_raw_parts(start, length)
+        }
+    }
+
+    /// The number of localities the table actually has distances for.
+    pub fn localities(&self) -> usize {
+        if self.matrix().is_empty() { 0 } else { self.locality_count as usize }
+    }
+
+    /// The relative distance from one locality to another, where LOCAL_DISTANCE is the distance from a
+    /// locality to itself, or None if either locality is out of range.
+    pub fn distance(&self, from: usize, to: usize) -> Option<u8> {
+        let count = self.localities();
+        if from >= count || to >= count { return None; }
+
+        Some(self.matrix()[from * count + to])
+    }
+}
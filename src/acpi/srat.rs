@@ -0,0 +1,218 @@
+//! Provides definitions for the System Resource Affinity Table (SRAT), which assigns processors and ranges
+//! of physical memory to proximity domains (NUMA nodes).
+
+use core::mem;
+use core::marker::PhantomData;
+
+use super::tables::{SDTHeader, SystemTable};
+use super::madt::EntryHeader;
+
+/// Set in an affinity entry's flags if the entry should be used; disabled entries are to be ignored.
+pub const AFFINITY_ENABLED: u32 = 1;
+
+/// Set in a memory affinity entry's flags if the memory can be hot-plugged.
+pub const MEMORY_HOT_PLUGGABLE: u32 = 1 << 1;
+
+/// Set in a memory affinity entry's flags if the memory is non-volatile.
+pub const MEMORY_NON_VOLATILE: u32 = 1 << 2;
+
+/// The entry types defined by the ACPI specification which we understand.
+pub const SRAT_ENTRY_PROCESSOR_AFFINITY: u8 = 0;
+pub const SRAT_ENTRY_MEMORY_AFFINITY: u8 = 1;
+pub const SRAT_ENTRY_X2APIC_AFFINITY: u8 = 2;
+
+/// The System Resource Affinity Table, which is followed by a variable number of affinity entries.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SRAT {
+    /// The header of the SRAT.
+    pub header: SDTHeader,
+
+    /// Must be 1, for backwards compatibility.
+    _reserved0: u32,
+
+    _reserved1: u64
+}
+
+impl SystemTable for SRAT {
+    fn raw_header(&self) -> *const SDTHeader {
+        (&self.header) as *const SDTHeader
+    }
+
+    fn signature() -> &'static [u8] { b"SRAT" }
+}
+
+impl SRAT {
+    /// Returns an iterator over all of the affinity entries in this table.
+    pub fn entries(&self) -> SratEntriesIter {
+        let table_start = self as *const SRAT as *const u8;
+
+        // UNSAFE: Safe, as the length is given by the table itself.
+        let entries_start = unsafe { table_start.offset(mem::size_of::<Self>() as isize) };
+        let entries_end = unsafe { table_start.offset(self.header.length as isize) };
+
+        SratEntriesIter { location: entries_start, end: entries_end, _table: PhantomData }
+    }
+}
+
+/// Assigns a processor, identified by it's local APIC id, to a proximity domain.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessorAffinityEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// Bits 0-7 of the proximity domain.
+    pub proximity_domain_low: u8,
+
+    /// The processor's local APIC id.
+    pub apic_id: u8,
+
+    /// The affinity flags (AFFINITY_ENABLED).
+    pub flags: u32,
+
+    /// The processor's local SAPIC EID (only used on Itanium).
+    pub local_sapic_eid: u8,
+
+    /// Bits 8-31 of the proximity domain.
+    pub proximity_domain_high: [u8; 3],
+
+    /// The clock domain the processor belongs to.
+    pub clock_domain: u32
+}
+
+impl ProcessorAffinityEntry {
+    /// The full 32-bit proximity domain, put back together.
+    pub fn proximity_domain(&self) -> u32 {
+        self.proximity_domain_low as u32 | (self.proximity_domain_high[0] as u32) << 8
+            | (self.proximity_domain_high[1] as u32) << 16 | (self.proximity_domain_high[2] as u32) << 24
+    }
+}
+
+/// Assigns a range of physical memory to a proximity domain.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAffinityEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    /// The proximity domain the memory belongs to.
+    pub proximity_domain: u32,
+
+    _reserved0: u16,
+
+    /// The physical address the range starts at, split in two.
+    pub base_address_low: u32,
+    pub base_address_high: u32,
+
+    /// The length of the range, in bytes, split in two.
+    pub length_low: u32,
+    pub length_high: u32,
+
+    _reserved1: u32,
+
+    /// The memory affinity flags (AFFINITY_ENABLED, MEMORY_HOT_PLUGGABLE and MEMORY_NON_VOLATILE).
+    pub flags: u32,
+
+    _reserved2: u64
+}
+
+impl MemoryAffinityEntry {
+    /// The physical address the range starts at.
+    pub fn base_address(&self) -> u64 {
+        self.base_address_low as u64 | (self.base_address_high as u64) << 32
+    }
+
+    /// The length of the range, in bytes.
+    pub fn length(&self) -> u64 {
+        self.length_low as u64 | (self.length_high as u64) << 32
+    }
+}
+
+/// Assigns a processor, identified by it's x2APIC id, to a proximity domain.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct X2ApicAffinityEntry {
+    /// The common entry header.
+    pub header: EntryHeader,
+
+    _reserved0: u16,
+
+    /// The proximity domain the processor belongs to.
+    pub proximity_domain: u32,
+
+    /// The processor's x2APIC id.
+    pub x2apic_id: u32,
+
+    /// The affinity flags (AFFINITY_ENABLED).
+    pub flags: u32,
+
+    /// The clock domain the processor belongs to.
+    pub clock_domain: u32,
+
+    _reserved1: u32
+}
+
+/// A single typed entry of the SRAT.
+#[derive(Debug)]
+pub enum SratEntry<'a> {
+    ProcessorAffinity(&'a ProcessorAffinityEntry),
+    MemoryAffinity(&'a MemoryAffinityEntry),
+    X2ApicAffinity(&'a X2ApicAffinityEntry),
+
+    /// An entry type we don't (yet) understand; only the header is provided.
+    Unknown(&'a EntryHeader)
+}
+
+/// Provides iteration over the variable-length entries in the SRAT.
+#[derive(Debug)]
+pub struct SratEntriesIter<'a> {
+    /// The memory location of the next entry to return.
+    location: *const u8,
+
+    /// The end of the table; no entry may extend past this point.
+    end: *const u8,
+
+    /// Ties the lifetime of the returned entries to the table.
+    _table: PhantomData<&'a SRAT>
+}
+
+impl<'a> SratEntriesIter<'a> {
+    /// Reinterprets the entry at the current location as the given type, if it is long enough to hold it.
+    unsafe fn entry_as<T>(&self, header: &EntryHeader) -> Option<&'a T> {
+        if (header.length as usize) < mem::size_of::<T>() { return None; }
+
+        Some(&*(self.location as *const T))
+    }
+}
+
+impl<'a> Iterator for SratEntriesIter<'a> {
+    type Item = SratEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Make sure there's at least a header left to read.
+        if (self.end as usize) < (self.location as usize) + mem::size_of::<EntryHeader>() { return None; }
+
+        // UNSAFE: Safe, as we checked the header lies within the table.
+        let header: &'a EntryHeader = unsafe { &*(self.location as *const EntryHeader) };
+
+        // A zero-length (or overlong) entry means the table is corrupted; stop instead of looping forever.
+        let length = header.length as usize;
+        if length < mem::size_of::<EntryHeader>() || (self.location as usize) + length > (self.end as usize) {
+            return None;
+        }
+
+        let entry = unsafe {
+            match header.entry_type {
+                SRAT_ENTRY_PROCESSOR_AFFINITY => self.entry_as(header).map(SratEntry::ProcessorAffinity),
+                SRAT_ENTRY_MEMORY_AFFINITY => self.entry_as(header).map(SratEntry::MemoryAffinity),
+                SRAT_ENTRY_X2APIC_AFFINITY => self.entry_as(header).map(SratEntry::X2ApicAffinity),
+                _ => None
+            }
+        }.unwrap_or(SratEntry::Unknown(header));
+
+        self.location = unsafe { self.location.offset(length as isize) };
+
+        Some(entry)
+    }
+}
@@ -1,14 +1,19 @@
 //! Provides definitions for common ACPI tables, pointers, and other such structures.
 
+use core::{fmt, ptr};
 use core::slice;
 use core::mem;
 use core::num::Wrapping;
+use core::marker::PhantomData;
+
+use super::AcpiError;
+use super::memory::PhysicalMemory;
 
 /// The unique signature which identifies the RSDP.
 pub const RSDP_SIGNATURE: &'static [u8] = b"RSD PTR ";
 
 /// The location where the segment pointer to the extended bios location can be found.
-pub const EXTENDED_BIOS_AREA_POINTER_LOC: *const u16 = 0x40E as *const u16;
+pub const EXTENDED_BIOS_AREA_POINTER_LOC: u64 = 0x40E;
 
 /// The maximum size to look at in the extended bios data area.
 pub const EXTENDED_BIOS_AREA_MAX_SIZE: usize = 1 << 10;
@@ -30,7 +35,7 @@ pub const RSDP_VERSION_2: u8 = 2;
 /// peripherals/power control.
 ///
 /// The RSDP should be 16-byte aligned.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(packed)]
 pub struct RSDP {
     /// The unique signature for finding the RSDP, is equal to RSDP_SIGNATURE.
@@ -56,7 +61,7 @@ pub struct RSDP {
 /// The eXtended Root System Description Pointer for ACPI v2.0 and above; it
 /// contains all of the same fields as the RSDP, except it adds a length field
 /// and provides a 64-bit pointer to the XSDT.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(packed)]
 pub struct XSDP {
     /// The unique signature for finding the RSDP, is equal to RSDP_SIGNATURE.
@@ -93,7 +98,7 @@ pub struct XSDP {
 
 /// The header for any System Description Table, containing identifying
 /// information and other metadata.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(packed)]
 pub struct SDTHeader {
     /// The signature for this table which uniquely identifies it.
@@ -128,20 +133,73 @@ pub struct SDTHeader {
 impl SDTHeader {
     /// Verify the checksum of the table this header belongs to, by summing up all the bytes in the header.
     /// The sum should equal 0 for the checksum to be valid.
+    /// Headers are only handed out (by PhysicalMemory::table) when the whole table can be accessed.
     pub fn verify_checksum(&self) -> bool {
-        let self_start = self as *const SDTHeader as *const u8;
+        // UNSAFE: Safe, as the length covers exactly this table.
+        unsafe { checksum(slice::from_raw_parts(self as *const SDTHeader as *const u8, self.length as usize)) == 0 }
+    }
+}
 
-        let mut sum = Wrapping(0u8);
-        for offset in 0 .. self.length {
-            sum += Wrapping(unsafe { *(self_start.offset(offset as isize)) });
-        }
+impl fmt::Display for SDTHeader {
+    /// Summarizes the header on one line, with the identifying strings as ASCII.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let creator_id = self.creator_id;
+        let creator_id = [creator_id as u8, (creator_id >> 8) as u8, (creator_id >> 16) as u8,
+            (creator_id >> 24) as u8];
+
+        write_ascii(f, &self.signature)?;
+        write!(f, " rev {}, {} bytes, checksum {}, OEM ", self.revision, { self.length },
+            if self.verify_checksum() { "ok" } else { "BAD" })?;
+        write_ascii(f, &self.oem_id)?;
+        f.write_str(" ")?;
+        write_ascii(f, &self.oem_table_id)?;
+        write!(f, " rev 0x{:x}, creator ", { self.oem_revision })?;
+        write_ascii(f, &creator_id)?;
+        write!(f, " rev 0x{:x}", { self.creator_revision })
+    }
+}
 
-        sum == Wrapping(0u8)
+/// Writes out an identifying string from a table, which is meant to be ASCII but is often padded with
+/// nulls (which are dropped) and occasionally contains garbage (which is replaced with '?').
+fn write_ascii(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    let length = bytes.iter().rposition(|&byte| byte != 0 && byte != b' ').map(|last| last + 1).unwrap_or(0);
+    if length == 0 { return f.write_str("-"); }
+
+    for &byte in &bytes[.. length] {
+        let character = if byte >= 0x20 && byte < 0x7F { byte as char } else { '?' };
+        write!(f, "{}", character)?;
+    }
+
+    Ok(())
+}
+
+impl RSDP {
+    /// Verify the checksum of the ACPI 1.0 portion of the RSDP, which every revision has.
+    pub fn verify_checksum(&self) -> bool {
+        // UNSAFE: Safe, as we only sum the bytes of this structure.
+        unsafe { checksum(slice::from_raw_parts(self as *const RSDP as *const u8, mem::size_of::<RSDP>())) == 0 }
     }
 }
 
+impl XSDP {
+    /// Verify the extended checksum, which covers the entire XSDP (including the ACPI 1.0 portion); the
+    /// bytes are those of the whole structure, which may be longer than this struct.
+    pub fn verify_extended_checksum(&self, bytes: &[u8]) -> bool {
+        // The length should never be shorter than the structure itself; if it is, this is garbage.
+        if (self.length as usize) < mem::size_of::<XSDP>() || bytes.len() < self.length as usize { return false; }
+
+        checksum(&bytes[.. self.length as usize]) == 0
+    }
+}
+
+/// Sums up the bytes with wrapping addition; ACPI structures are valid when this sum comes out as 0.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(Wrapping(0u8), |sum, &byte| sum + Wrapping(byte)).0
+}
+
 /// An abstract trait representing a system table; provides methods for verifying the table,
-/// getting it's expected signature, and finding it's header.
+/// getting it's expected signature, and finding it's header. Tables are made by reinterpreting memory, so
+/// implementors must be packed structures which any bit pattern is valid for.
 pub trait SystemTable {
     /// Obtains a raw pointer to the header of the table.
     fn raw_header(&self) -> *const SDTHeader;
@@ -187,11 +245,11 @@ impl RSDT {
         let table_start = self as *const RSDT as *const u8;
 
         // Pointers start at the end of the table and go for the rest of the "length" field.
-        // UNSAFE: Safe, as these pointers will be under 1 MB.
+        // UNSAFE: Safe, as the pointers are within the table.
         let pointer_start = unsafe { table_start.offset(mem::size_of::<Self>() as isize) };
         let pointer_count = (self.header.length as usize - mem::size_of::<Self>()) / mem::size_of::<u32>();
 
-        RawTablesIter { location: pointer_start, remaining: pointer_count, is_64_bit: false }
+        RawTablesIter { location: pointer_start, remaining: pointer_count, is_64_bit: false, _table: PhantomData }
     }
 }
 
@@ -218,17 +276,17 @@ impl XSDT {
         let table_start = self as *const XSDT as *const u8;
 
         // Pointers start at the end of the table and go for the rest of the "length" field.
-        // UNSAFE: Safe, as these pointers will be under 1 MB.
+        // UNSAFE: Safe, as the pointers are within the table.
         let pointer_start = unsafe { table_start.offset(mem::size_of::<Self>() as isize) };
         let pointer_count = (self.header.length as usize - mem::size_of::<Self>()) / mem::size_of::<u64>();
 
-        RawTablesIter { location: pointer_start, remaining: pointer_count, is_64_bit: true }
+        RawTablesIter { location: pointer_start, remaining: pointer_count, is_64_bit: true, _table: PhantomData }
     }
 }
 
-/// Provides iteration over the pointers to other tables in the RSDT/XSDT.
+/// Provides iteration over the pointers to other tables in the RSDT/XSDT, which are physical addresses.
 #[derive(Debug)]
-pub struct RawTablesIter {
+pub struct RawTablesIter<'a> {
     /// The memory location of the next pointer to return.
     location: *const u8,
 
@@ -236,65 +294,94 @@ pub struct RawTablesIter {
     remaining: usize,
 
     /// If true, then we're interpreting 64-bit pointers; otherwise, 32-bit pointers.
-    is_64_bit: bool
+    is_64_bit: bool,
+
+    /// Ties the lifetime of the iterator to the root table.
+    _table: PhantomData<&'a SDTHeader>
 }
 
-impl Iterator for RawTablesIter {
-    type Item = *const SDTHeader;
+impl<'a> Iterator for RawTablesIter<'a> {
+    type Item = u64;
 
     fn next(&mut self) -> Option<Self::Item> {
         // If there are none remaining we return immediately.
         if self.remaining == 0 { return None; }
 
-        // Otherwise, interpret the value properly and advance.
-        if self.is_64_bit { 
-            let ptr64 = self.location as *const u64;
-
-            // UNSAFE: Safe, as the pointers are in physical memory under 1 MB.
-            let value = unsafe { (*ptr64) as *const SDTHeader };
-
-            self.location = unsafe { self.location.offset(mem::size_of::<u64>() as isize) };
-            self.remaining = self.remaining - 1;
-
-            Some(value)
-        } else {
-            let ptr32 = self.location as *const u32;
+        // Otherwise, interpret the value properly and advance; the pointers are only 4-byte aligned.
+        let size = if self.is_64_bit { mem::size_of::<u64>() } else { mem::size_of::<u32>() };
 
-            // UNSAFE: Safe, as the pointers are in physical memory under 1 MB.
-            let value = unsafe { (*ptr32) as *const SDTHeader };
+        // UNSAFE: Safe, as the pointers are within the root table.
+        let value = unsafe {
+            if self.is_64_bit {
+                ptr::read_unaligned(self.location as *const u64)
+            } else {
+                ptr::read_unaligned(self.location as *const u32) as u64
+            }
+        };
 
-            self.location = unsafe { self.location.offset(mem::size_of::<u32>() as isize) };
-            self.remaining = self.remaining - 1;
+        self.location = unsafe { self.location.offset(size as isize) };
+        self.remaining = self.remaining - 1;
 
-            Some(value)
-        }
+        Some(value)
     }
 }
 
 /// Obtains the starting memory location of the extended bios data area.
-pub unsafe fn extended_bios_data_area_start() -> *mut u8 {
-    let actual_ptr = ((*EXTENDED_BIOS_AREA_POINTER_LOC) as usize) << 4;
+pub fn extended_bios_data_area_start<M: PhysicalMemory>(memory: &M) -> Option<u64> {
+    memory.read_integer(EXTENDED_BIOS_AREA_POINTER_LOC, 2).map(|segment| segment << 4)
+}
 
-    actual_ptr as *mut u8
+/// Checks that the RSDP at the given location is actually valid, and not just something which happens to
+/// have the right signature: the checksum must be valid, the revision must be known, and for revision 2
+/// and above the extended checksum must also be valid.
+pub fn validate_rsdp<M: PhysicalMemory>(memory: &M, address: u64) -> Result<(), AcpiError> {
+    // UNSAFE: Safe, as the RSDP is a packed structure of integers.
+    let rsdp: &RSDP = unsafe { memory.structure(address) }.ok_or(AcpiError::NotFound)?;
+
+    if !rsdp.verify_checksum() { return Err(AcpiError::InvalidRsdpChecksum); }
+
+    match rsdp.revision {
+        RSDP_VERSION_1 => Ok(()),
+        RSDP_VERSION_2 => {
+            // UNSAFE: Safe, as the XSDP is a packed structure of integers.
+            let xsdp: &XSDP = unsafe { memory.structure(address) }.ok_or(AcpiError::InvalidExtendedChecksum)?;
+            let bytes = memory.bytes(address, xsdp.length as usize).ok_or(AcpiError::InvalidExtendedChecksum)?;
+
+            if xsdp.verify_extended_checksum(bytes) {
+                Ok(())
+            } else {
+                Err(AcpiError::InvalidExtendedChecksum)
+            }
+        },
+        revision => Err(AcpiError::UnknownRevision(revision))
+    }
 }
 
 /// Attempts to find the RSDP by looking at the defined regions
 /// in memory where it should be located (see RSDP_LOCATION_START, and extended_bios_data_area_start).
-pub unsafe fn find_rsdp() -> Option<*mut RSDP> {
-    let ebda_start = extended_bios_data_area_start() as usize;
-
-    // Yay for iterators; this steps in 16-byte intervals looking for the 8-byte signature
-    // of the RSDP, first checking the RSDP location and then checking the extended bios area.
-    // This is much better than anything I would get to write in C++ without some horrifying
-    // template metaprogramming hacks...
-    (RSDP_LOCATION_START .. RSDP_LOCATION_END).step_by(16)
-        .chain((ebda_start .. (ebda_start + EXTENDED_BIOS_AREA_MAX_SIZE)).step_by(16))
-        .find(|&mem_location| {
-            // Make up a slice out of nothing at the given memory location, comparing it against the
-            // RSDP signature.
-            let raw_slice = slice::from_raw_parts(mem_location as *const u8, RSDP_SIGNATURE.len());
-
-            raw_slice == RSDP_SIGNATURE
-        })
-        .map(|loc| loc as *mut RSDP)
-}
\ No newline at end of file
+///
+/// Matching signatures which fail validation are skipped; if nothing valid is found, the error for the
+/// first matching signature (or AcpiError::NotFound if there were none) is returned.
+pub fn find_rsdp<M: PhysicalMemory>(memory: &M) -> Result<u64, AcpiError> {
+    let mut first_error = None;
+
+    // Without an EBDA pointer, scanning from 0 would just repeat part of the BIOS area check.
+    let ebda_start = extended_bios_data_area_start(memory).unwrap_or(0) as usize;
+    let ebda_size = if ebda_start == 0 { 0 } else { EXTENDED_BIOS_AREA_MAX_SIZE };
+
+    // This steps in 16-byte intervals looking for the 8-byte signature of the RSDP, first checking the
+    // RSDP location and then checking the extended bios area.
+    let locations = (RSDP_LOCATION_START .. RSDP_LOCATION_END).step_by(16)
+        .chain((ebda_start .. (ebda_start + ebda_size)).step_by(16));
+
+    for mem_location in locations {
+        if memory.bytes(mem_location as u64, RSDP_SIGNATURE.len()) != Some(RSDP_SIGNATURE) { continue; }
+
+        match validate_rsdp(memory, mem_location as u64) {
+            Ok(()) => return Ok(mem_location as u64),
+            Err(error) => if first_error.is_none() { first_error = Some(error) }
+        }
+    }
+
+    Err(first_error.unwrap_or(AcpiError::NotFound))
+}
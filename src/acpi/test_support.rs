@@ -0,0 +1,209 @@
+//! Builds the images of physical memory and the tables the ACPI tests run over. The table builders are also used
+//! by the tests of modules built on top of the tables, like numa.
+
+use std::vec::Vec;
+
+use super::*;
+
+pub const FIRECRACKER_FADT: &'static [u8] = include_bytes!("tests/fixtures/firecracker/facp.dat");
+pub const FIRECRACKER_DSDT: &'static [u8] = include_bytes!("tests/fixtures/firecracker/dsdt.dat");
+pub const FIRECRACKER_MADT: &'static [u8] = include_bytes!("tests/fixtures/firecracker/apic.dat");
+pub const FIRECRACKER_MCFG: &'static [u8] = include_bytes!("tests/fixtures/firecracker/mcfg.dat");
+
+/// Where Firecracker put each of it's tables.
+pub const FIRECRACKER_RSDP_ADDRESS: u64 = 0xE0000;
+pub const FIRECRACKER_XSDT_ADDRESS: u64 = 0xA0E13;
+pub const FIRECRACKER_FADT_ADDRESS: u64 = 0xA0C83;
+pub const FIRECRACKER_DSDT_ADDRESS: u64 = 0x9FD30;
+pub const FIRECRACKER_MADT_ADDRESS: u64 = 0xA0D97;
+pub const FIRECRACKER_MCFG_ADDRESS: u64 = 0xA0DD7;
+
+/// Where firecracker_image_with() puts the first of the tables it's given.
+const ADDED_TABLES_ADDRESS: u64 = 0x7000;
+
+/// The size of the memory images; everything lives in the first megabyte.
+pub const IMAGE_SIZE: usize = 0x100000;
+
+/// An image of physical memory, starting at address 0.
+pub struct MemoryImage {
+    pub bytes: Vec<u8>
+}
+
+impl MemoryImage {
+    pub fn new() -> MemoryImage {
+        MemoryImage { bytes: vec![0; IMAGE_SIZE] }
+    }
+
+    /// Copies the bytes into the image at the given physical address.
+    pub fn place(&mut self, address: u64, bytes: &[u8]) {
+        self.bytes[address as usize .. address as usize + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn memory(&self) -> BufferMemory {
+        BufferMemory::new(0, &self.bytes)
+    }
+}
+
+/// Encodes an integer as `width` little-endian bytes.
+pub fn little_endian(value: u64, width: usize) -> Vec<u8> {
+    (0 .. width).map(|index| (value >> (index * 8)) as u8).collect()
+}
+
+/// Sets the checksum byte at the given offset so that the first `length` bytes sum to zero.
+pub fn fix_checksum(bytes: &mut [u8], offset: usize, length: usize) {
+    bytes[offset] = 0;
+
+    let sum = bytes[.. length].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    bytes[offset] = 0u8.wrapping_sub(sum);
+}
+
+/// Builds an RSDP (revision 0) or XSDP (revision 2) pointing at the given root table.
+pub fn rsdp(revision: u8, root_address: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    bytes.extend_from_slice(RSDP_SIGNATURE);
+    bytes.push(0);
+    bytes.extend_from_slice(b"FIRECK");
+    bytes.push(revision);
+
+    if revision == RSDP_VERSION_1 {
+        bytes.extend_from_slice(&little_endian(root_address, 4));
+        fix_checksum(&mut bytes, 8, 20);
+    } else {
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&little_endian(36, 4));
+        bytes.extend_from_slice(&little_endian(root_address, 8));
+        bytes.extend_from_slice(&[0; 4]);
+
+        fix_checksum(&mut bytes, 8, 20);
+        fix_checksum(&mut bytes, 32, 36);
+    }
+
+    bytes
+}
+
+/// Builds a table with the given signature and body.
+pub fn table(signature: &[u8], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let length = 36 + body.len() as u32;
+
+    bytes.extend_from_slice(signature);
+    bytes.extend_from_slice(&little_endian(length as u64, 4));
+    bytes.push(revision);
+    bytes.push(0);
+    bytes.extend_from_slice(b"FIRECK");
+    bytes.extend_from_slice(b"FCMVXSDT");
+    bytes.extend_from_slice(&[0; 4]);
+    bytes.extend_from_slice(b"FCAT");
+    bytes.extend_from_slice(&little_endian(0x20240119, 4));
+    bytes.extend_from_slice(body);
+
+    let length = bytes.len();
+    fix_checksum(&mut bytes, 9, length);
+
+    bytes
+}
+
+/// Builds an RSDT (with 32-bit pointers) or XSDT (with 64-bit pointers) pointing at the given tables.
+pub fn root_table(extended: bool, tables: &[u64]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    for &address in tables {
+        if extended {
+            body.extend_from_slice(&little_endian(address, 8));
+        } else {
+            body.extend_from_slice(&little_endian(address, 4));
+        }
+    }
+
+    table(if extended { b"XSDT" } else { b"RSDT" }, 1, &body)
+}
+
+/// Lays out the Firecracker tables as they were in the guest, with an XSDT.
+pub fn firecracker_image() -> MemoryImage {
+    let mut image = MemoryImage::new();
+
+    image.place(FIRECRACKER_RSDP_ADDRESS, &rsdp(RSDP_VERSION_2, FIRECRACKER_XSDT_ADDRESS));
+    image.place(FIRECRACKER_XSDT_ADDRESS, &root_table(true, &[
+        FIRECRACKER_FADT_ADDRESS, FIRECRACKER_MADT_ADDRESS, FIRECRACKER_MCFG_ADDRESS
+    ]));
+    image.place(FIRECRACKER_FADT_ADDRESS, FIRECRACKER_FADT);
+    image.place(FIRECRACKER_DSDT_ADDRESS, FIRECRACKER_DSDT);
+    image.place(FIRECRACKER_MADT_ADDRESS, FIRECRACKER_MADT);
+    image.place(FIRECRACKER_MCFG_ADDRESS, FIRECRACKER_MCFG);
+
+    image
+}
+
+/// Lays out the Firecracker tables with the given ones added a page apart from ADDED_TABLES_ADDRESS, and the XSDT
+/// listing the FADT and them in place of the captured MADT and MCFG.
+pub fn firecracker_image_with(tables: &[&[u8]]) -> MemoryImage {
+    let mut image = firecracker_image();
+    let mut addresses = vec![FIRECRACKER_FADT_ADDRESS];
+
+    for (index, table) in tables.iter().enumerate() {
+        let address = ADDED_TABLES_ADDRESS + index as u64 * 0x1000;
+
+        image.place(address, table);
+        addresses.push(address);
+    }
+
+    image.place(FIRECRACKER_XSDT_ADDRESS, &root_table(true, &addresses));
+    image
+}
+
+/// Builds an SRAT processor affinity entry.
+pub fn processor_affinity(apic_id: u8, domain: u32, flags: u32) -> Vec<u8> {
+    let mut entry = vec![SRAT_ENTRY_PROCESSOR_AFFINITY, 16, domain as u8, apic_id];
+    entry.extend_from_slice(&little_endian(flags as u64, 4));
+    entry.push(0);
+    entry.extend_from_slice(&little_endian(domain as u64 >> 8, 3));
+    entry.extend_from_slice(&[0; 4]);
+    entry
+}
+
+/// Builds an SRAT x2APIC affinity entry.
+pub fn x2apic_affinity(x2apic_id: u32, domain: u32, flags: u32) -> Vec<u8> {
+    let mut entry = vec![SRAT_ENTRY_X2APIC_AFFINITY, 24, 0, 0];
+    entry.extend_from_slice(&little_endian(domain as u64, 4));
+    entry.extend_from_slice(&little_endian(x2apic_id as u64, 4));
+    entry.extend_from_slice(&little_endian(flags as u64, 4));
+    entry.extend_from_slice(&[0; 8]);
+    entry
+}
+
+/// Builds an SRAT memory affinity entry.
+pub fn memory_affinity(base: u64, length: u64, domain: u32, flags: u32) -> Vec<u8> {
+    let mut entry = vec![SRAT_ENTRY_MEMORY_AFFINITY, 40];
+    entry.extend_from_slice(&little_endian(domain as u64, 4));
+    entry.extend_from_slice(&[0; 2]);
+    entry.extend_from_slice(&little_endian(base, 8));
+    entry.extend_from_slice(&little_endian(length, 8));
+    entry.extend_from_slice(&[0; 4]);
+    entry.extend_from_slice(&little_endian(flags as u64, 4));
+    entry.extend_from_slice(&[0; 8]);
+    entry
+}
+
+/// Builds the SRAT and SLIT QEMU generates for two nodes of 512MiB and two processors each, with a distance
+/// of 21 between them (`-numa node,cpus=0-1,mem=512M -numa node,cpus=2-3,mem=512M -numa dist,src=0,dst=1,val=21`).
+/// There's also a disabled processor entry and a hot-pluggable range, which QEMU adds for CPU and memory
+/// hotplug.
+pub fn qemu_numa_tables() -> (Vec<u8>, Vec<u8>) {
+    let mut srat = vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    for &(apic_id, domain) in &[(0, 0), (1, 0), (2, 1), (3, 1)] {
+        srat.extend_from_slice(&processor_affinity(apic_id, domain, AFFINITY_ENABLED));
+    }
+    srat.extend_from_slice(&processor_affinity(4, 1, 0));
+
+    srat.extend_from_slice(&memory_affinity(0, 0xA0000, 0, AFFINITY_ENABLED));
+    srat.extend_from_slice(&memory_affinity(0x100000, 0x1FF00000, 0, AFFINITY_ENABLED));
+    srat.extend_from_slice(&memory_affinity(0x20000000, 0x20000000, 1, AFFINITY_ENABLED));
+    srat.extend_from_slice(&memory_affinity(0x100000000, 0x40000000, 1, AFFINITY_ENABLED | MEMORY_HOT_PLUGGABLE));
+
+    let mut slit = little_endian(2, 8);
+    slit.extend_from_slice(&[10, 21, 21, 10]);
+
+    (table(b"SRAT", 1, &srat), table(b"SLIT", 1, &slit))
+}
@@ -0,0 +1,567 @@
+//! Tests for ACPI discovery and the table parsers, run on the host over images of physical memory.
+//!
+//! The FADT, DSDT, MADT and MCFG in fixtures/firecracker were captured from a Firecracker microVM (through
+//! /sys/firmware/acpi/tables); the RSDP and XSDT aren't exposed there, so they're rebuilt here to point at
+//! the other tables, at the addresses the guest kernel reported them at.
+
+use std::string::String;
+use std::vec::Vec;
+
+use super::*;
+use super::test_support::*;
+
+/// Lays out the same tables behind an ACPI 1.0 RSDP and RSDT instead.
+fn rsdt_image() -> MemoryImage {
+    let mut image = firecracker_image();
+
+    image.place(FIRECRACKER_RSDP_ADDRESS, &[0; 36]);
+    image.place(0xF5A40, &rsdp(RSDP_VERSION_1, 0x7000));
+    image.place(0x7000, &root_table(false, &[FIRECRACKER_FADT_ADDRESS, FIRECRACKER_MADT_ADDRESS]));
+
+    image
+}
+
+#[test]
+fn finds_the_xsdp_in_the_bios_area() {
+    let image = firecracker_image();
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+
+    assert!(acpi.is_extended());
+    assert_eq!(&acpi.root_header().signature, b"XSDT");
+    assert_eq!(find_rsdp(&image.memory()), Ok(FIRECRACKER_RSDP_ADDRESS));
+}
+
+#[test]
+fn iterates_the_xsdt() {
+    let image = firecracker_image();
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+
+    let addresses: Vec<u64> = acpi.raw_tables().collect();
+    assert_eq!(addresses, vec![FIRECRACKER_FADT_ADDRESS, FIRECRACKER_MADT_ADDRESS, FIRECRACKER_MCFG_ADDRESS]);
+
+    let signatures: Vec<[u8; 4]> = acpi.raw_tables().map(|address| acpi.table_at(address).unwrap().signature).collect();
+    assert_eq!(signatures, vec![*b"FACP", *b"APIC", *b"MCFG"]);
+}
+
+#[test]
+fn iterates_the_rsdt() {
+    let image = rsdt_image();
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+
+    assert!(!acpi.is_extended());
+    assert_eq!(&acpi.root_header().signature, b"RSDT");
+    assert_eq!(acpi.raw_tables().collect::<Vec<u64>>(), vec![FIRECRACKER_FADT_ADDRESS, FIRECRACKER_MADT_ADDRESS]);
+    assert!(acpi.find_table::<MADT>().is_some());
+}
+
+#[test]
+fn finds_the_rsdp_in_the_ebda() {
+    let mut image = MemoryImage::new();
+
+    // The EBDA segment pointer, and an RSDP 0x20 bytes into the EBDA.
+    image.place(EXTENDED_BIOS_AREA_POINTER_LOC, &little_endian(0x9FC0, 2));
+    image.place(0x9FC20, &rsdp(RSDP_VERSION_1, 0x7000));
+    image.place(0x7000, &root_table(false, &[]));
+
+    assert_eq!(find_rsdp(&image.memory()), Ok(0x9FC20));
+    assert_eq!(ACPI::find_in_memory(image.memory()).unwrap().raw_tables().count(), 0);
+}
+
+#[test]
+fn captured_tables_have_valid_checksums() {
+    let image = firecracker_image();
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+
+    for address in acpi.raw_tables() {
+        assert!(acpi.table_at(address).unwrap().verify_checksum());
+    }
+
+    assert!(acpi.table_at(FIRECRACKER_DSDT_ADDRESS).unwrap().verify_checksum());
+}
+
+#[test]
+fn reports_missing_tables() {
+    let image = MemoryImage::new();
+
+    assert_eq!(ACPI::find_in_memory(image.memory()).err(), Some(AcpiError::NotFound));
+}
+
+#[test]
+fn rejects_a_corrupted_rsdp() {
+    let mut image = firecracker_image();
+    image.bytes[FIRECRACKER_RSDP_ADDRESS as usize + 9] ^= 0xFF;
+
+    assert_eq!(ACPI::find_in_memory(image.memory()).err(), Some(AcpiError::InvalidRsdpChecksum));
+}
+
+#[test]
+fn rejects_a_corrupted_xsdp() {
+    let mut image = firecracker_image();
+
+    // Only the extended part is covered by the extended checksum alone.
+    image.bytes[FIRECRACKER_RSDP_ADDRESS as usize + 33] ^= 0xFF;
+
+    assert_eq!(ACPI::find_in_memory(image.memory()).err(), Some(AcpiError::InvalidExtendedChecksum));
+}
+
+#[test]
+fn rejects_an_unknown_revision() {
+    let mut image = MemoryImage::new();
+    let mut pointer = rsdp(RSDP_VERSION_1, 0x7000);
+
+    pointer[15] = 1;
+    fix_checksum(&mut pointer, 8, 20);
+    image.place(FIRECRACKER_RSDP_ADDRESS, &pointer);
+
+    assert_eq!(ACPI::find_in_memory(image.memory()).err(), Some(AcpiError::UnknownRevision(1)));
+}
+
+#[test]
+fn skips_invalid_rsdps_while_scanning() {
+    let mut image = firecracker_image();
+    let mut broken = rsdp(RSDP_VERSION_1, 0x7000);
+
+    broken[8] ^= 0xFF;
+    image.place(FIRECRACKER_RSDP_ADDRESS, &broken);
+    image.place(0xF0000, &rsdp(RSDP_VERSION_2, FIRECRACKER_XSDT_ADDRESS));
+
+    assert_eq!(find_rsdp(&image.memory()), Ok(0xF0000));
+}
+
+#[test]
+fn finds_the_tables_through_a_copy_of_the_rsdp() {
+    let mut image = firecracker_image();
+    image.place(FIRECRACKER_RSDP_ADDRESS, &[0; 36]);
+
+    // The copy a boot loader hands us, which isn't anywhere in the image.
+    let acpi = ACPI::from_rsdp_copy(image.memory(), &rsdp(RSDP_VERSION_2, FIRECRACKER_XSDT_ADDRESS)).unwrap();
+    assert!(acpi.is_extended());
+    assert!(acpi.find_table::<MADT>().is_some());
+
+    let mut copy = rsdp(RSDP_VERSION_1, FIRECRACKER_XSDT_ADDRESS);
+    copy[8] ^= 0xFF;
+    assert_eq!(ACPI::from_rsdp_copy(image.memory(), &copy).err(), Some(AcpiError::InvalidRsdpChecksum));
+}
+
+#[test]
+fn rejects_a_bad_root_table() {
+    let mut image = firecracker_image();
+    image.bytes[FIRECRACKER_XSDT_ADDRESS as usize + 40] ^= 0xFF;
+
+    assert_eq!(ACPI::from_rsdp(image.memory(), FIRECRACKER_RSDP_ADDRESS).err(), Some(AcpiError::InvalidRootChecksum));
+
+    let mut image = firecracker_image();
+    image.place(FIRECRACKER_RSDP_ADDRESS, &rsdp(RSDP_VERSION_2, FIRECRACKER_MADT_ADDRESS));
+
+    assert_eq!(ACPI::from_rsdp(image.memory(), FIRECRACKER_RSDP_ADDRESS).err(), Some(AcpiError::InvalidRootSignature));
+}
+
+#[test]
+fn rejects_tables_outside_of_memory() {
+    let image = firecracker_image();
+    let memory = BufferMemory::new(0, &image.bytes[.. FIRECRACKER_MCFG_ADDRESS as usize + 40]);
+
+    // The MCFG is cut off, so it can't be handed out, but everything else is still there.
+    let acpi = ACPI::find_in_memory(BufferMemory::new(0, &image.bytes)).unwrap();
+    assert!(acpi.table_at(FIRECRACKER_MCFG_ADDRESS).is_some());
+    assert!(memory.table(FIRECRACKER_MCFG_ADDRESS).is_none());
+    assert!(memory.table(FIRECRACKER_MADT_ADDRESS).is_some());
+    assert!(memory.table(IMAGE_SIZE as u64).is_none());
+}
+
+#[test]
+fn parses_the_captured_madt() {
+    let image = firecracker_image();
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let madt = acpi.find_table::<MADT>().unwrap();
+
+    assert_eq!(madt.local_apic_address(), 0xFEE00000);
+    assert!(!madt.has_legacy_pics());
+
+    let processors: Vec<Processor> = madt.processors().collect();
+    assert_eq!(processors, vec![Processor { processor_id: 0, apic_id: 0, enabled: true, online_capable: false }]);
+
+    let io_apics: Vec<(u8, u32, u32)> = madt.io_apics()
+        .map(|io_apic| (io_apic.io_apic_id, io_apic.address, io_apic.global_system_interrupt_base))
+        .collect();
+    assert_eq!(io_apics, vec![(0, 0xFEC00000, 0)]);
+
+    // There are no overrides, so ISA IRQs are identity mapped with the ISA defaults.
+    assert_eq!(madt.isa_irq_route(4), (4, Polarity::ActiveHigh, TriggerMode::Edge));
+}
+
+#[test]
+fn parses_the_captured_fadt() {
+    let image = firecracker_image();
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let fadt = acpi.find_table::<FADT>().unwrap();
+
+    assert_eq!(fadt.header.revision, 6);
+    assert_eq!(fadt.minor_version(), 5);
+    assert_eq!(fadt.dsdt_address(), FIRECRACKER_DSDT_ADDRESS);
+    assert!(fadt.is_hardware_reduced());
+    assert!(!fadt.has_8042());
+    assert_eq!(fadt.boot_architecture_flags(), BOOT_ARCH_VGA_NOT_PRESENT);
+    assert!(fadt.pm1a_control_block().is_none());
+    assert!(fadt.reset_register().is_none());
+    // "FIRECKVM", read as a little-endian integer.
+    assert_eq!(fadt.hypervisor_vendor_id(), Some(0x4D564B4345524946));
+}
+
+#[test]
+fn reads_short_fadts_within_their_length() {
+    let mut image = firecracker_image();
+
+    // Cut the FADT down to the ACPI 1.0 length; the extended fields then mustn't be used.
+    let mut fadt = FIRECRACKER_FADT[.. 116].to_vec();
+    fadt[4 .. 8].copy_from_slice(&little_endian(116, 4));
+    fadt[8] = 1;
+    fadt[40 .. 44].copy_from_slice(&little_endian(0x8000, 4));
+
+    // A legacy GPE0 block of 32 bytes, like an Intel PCH's, which is too long for a generic address' bit width.
+    fadt[80 .. 84].copy_from_slice(&little_endian(0x1860, 4));
+    fadt[92] = 0x20;
+    fix_checksum(&mut fadt, 9, 116);
+
+    image.place(FIRECRACKER_FADT_ADDRESS, &vec![0; FIRECRACKER_FADT.len()]);
+    image.place(FIRECRACKER_FADT_ADDRESS, &fadt);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let fadt = acpi.find_table::<FADT>().unwrap();
+
+    assert_eq!(fadt.dsdt_address(), 0x8000);
+    assert_eq!(fadt.minor_version(), 0);
+    assert_eq!(fadt.hypervisor_vendor_id(), None);
+
+    let gpe0 = fadt.gpe0_block().unwrap();
+    assert_eq!(({ gpe0.address }, gpe0.bit_width, fadt.gpe0_block_length), (0x1860, 0, 0x20));
+}
+
+/// Builds a version 2 FACS with the given hardware signature and flags.
+fn facs(hardware_signature: u32, flags: u32) -> Vec<u8> {
+    let mut bytes = b"FACS".to_vec();
+
+    bytes.extend_from_slice(&little_endian(64, 4));
+    bytes.extend_from_slice(&little_endian(hardware_signature as u64, 4));
+    bytes.extend_from_slice(&little_endian(0x9A000, 4));
+    bytes.extend_from_slice(&[0; 4]);
+    bytes.extend_from_slice(&little_endian(flags as u64, 4));
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.push(2);
+    bytes.extend_from_slice(&[0; 31]);
+
+    bytes
+}
+
+/// Points the captured FADT's 32-bit FACS pointer at the given address.
+fn point_fadt_at_facs(image: &mut MemoryImage, address: u64) {
+    let mut fadt = FIRECRACKER_FADT.to_vec();
+    let length = fadt.len();
+
+    fadt[36 .. 40].copy_from_slice(&little_endian(address, 4));
+    fix_checksum(&mut fadt, 9, length);
+    image.place(FIRECRACKER_FADT_ADDRESS, &fadt);
+}
+
+#[test]
+fn finds_the_facs_through_the_fadt() {
+    let mut image = firecracker_image();
+    image.place(0x7000, &facs(0x1234, FACS_64BIT_WAKE_SUPPORTED));
+    point_fadt_at_facs(&mut image, 0x7000);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let facs = acpi.facs().unwrap();
+
+    assert_eq!({ facs.hardware_signature }, 0x1234);
+    assert_eq!({ facs.firmware_waking_vector }, 0x9A000);
+    assert!(facs.supports_64bit_wake());
+
+    let mut output = String::new();
+    dump(&acpi, &mut output).unwrap();
+    assert!(output.ends_with("FACS version 2, 64 bytes @ 0x7000\n    \
+        Hardware signature 0x1234, flags 0x2, waking vector 0x9a000\n"));
+
+    // Firecracker has no FACS at all, and anything without the signature doesn't count as one.
+    assert!(ACPI::find_in_memory(firecracker_image().memory()).unwrap().facs().is_none());
+
+    point_fadt_at_facs(&mut image, 0x8000);
+    assert!(ACPI::find_in_memory(image.memory()).unwrap().facs().is_none());
+}
+
+#[test]
+fn parses_an_hpet_table() {
+    // The HPET table QEMU generates: revision 1 with three comparators, a 64-bit counter and legacy
+    // replacement, from Intel, at the usual address.
+    let mut body = little_endian(0x8086A201, 4);
+    body.extend_from_slice(&[ADDRESS_SPACE_SYSTEM_MEMORY, 64, 0, 0]);
+    body.extend_from_slice(&little_endian(0xFED00000, 8));
+    body.extend_from_slice(&[0, 0x80, 0, PAGE_PROTECTION_4K]);
+
+    let image = firecracker_image_with(&[&table(b"HPET", 1, &body)]);
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let hpet = acpi.find_table::<HPET>().unwrap();
+
+    assert_eq!(hpet.comparator_count(), 3);
+    assert!(hpet.has_64_bit_counter());
+    assert!(hpet.supports_legacy_replacement());
+    assert_eq!(hpet.vendor_id(), 0x8086);
+    assert_eq!({ hpet.base_address.address }, 0xFED00000);
+    assert_eq!({ hpet.minimum_tick }, 0x80);
+    assert_eq!(hpet.page_protection(), PAGE_PROTECTION_4K);
+
+    // Firecracker doesn't have one at all.
+    assert!(ACPI::find_in_memory(firecracker_image().memory()).unwrap().find_table::<HPET>().is_none());
+}
+
+#[test]
+fn parses_the_captured_mcfg() {
+    let image = firecracker_image();
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let mcfg = acpi.find_table::<MCFG>().unwrap();
+
+    // Firecracker has a single bus, with a 1MiB window.
+    let entries: Vec<McfgEntry> = mcfg.entries().collect();
+    assert_eq!(entries.len(), 1);
+
+    let entry = entries[0];
+    assert_eq!({ entry.base_address }, 0xEEC00000);
+    assert_eq!((entry.segment_group, entry.start_bus, entry.end_bus), (0, 0, 0));
+    assert_eq!(entry.size(), 0x100000);
+
+    assert_eq!(entry.function_address(0, 0, 0, 0), Some(0xEEC00000));
+    assert_eq!(entry.function_address(0, 0, 3, 1), Some(0xEEC19000));
+    assert_eq!(entry.function_address(0, 1, 0, 0), None);
+    assert_eq!(entry.function_address(1, 0, 0, 0), None);
+}
+
+#[test]
+fn maps_functions_on_later_buses() {
+    let mut body = vec![0; 8];
+
+    // Segment group 1, buses 0x10-0x1F, as a machine with more than one host bridge might have.
+    body.extend_from_slice(&little_endian(0xB0000000, 8));
+    body.extend_from_slice(&[1, 0, 0x10, 0x1F, 0, 0, 0, 0]);
+
+    let image = firecracker_image_with(&[&table(b"MCFG", 1, &body)]);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let entry = acpi.find_table::<MCFG>().unwrap().entries().next().unwrap();
+
+    // The base address is where bus 0 would be, so the region's own buses start 16MiB in.
+    assert_eq!(entry.size(), 16 << 20);
+    assert_eq!(entry.end(), 0xB0000000 + (0x20 << 20));
+    assert_eq!(entry.function_address(1, 0x12, 31, 7), Some(0xB0000000 + (0x12 << 20) + (31 << 15) + (7 << 12)));
+    assert_eq!(entry.function_address(1, 0x0F, 0, 0), None);
+    assert_eq!(entry.function_address(1, 0x20, 0, 0), None);
+}
+
+#[test]
+fn parses_srat_and_slit() {
+    let (srat, slit) = qemu_numa_tables();
+    let image = firecracker_image_with(&[&srat, &slit]);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let srat = acpi.find_table::<SRAT>().unwrap();
+
+    let processors: Vec<(u8, u32, bool)> = srat.entries().filter_map(|entry| match entry {
+        SratEntry::ProcessorAffinity(affinity) =>
+            Some((affinity.apic_id, affinity.proximity_domain(), affinity.flags & AFFINITY_ENABLED != 0)),
+        _ => None
+    }).collect();
+    assert_eq!(processors, vec![(0, 0, true), (1, 0, true), (2, 1, true), (3, 1, true), (4, 1, false)]);
+
+    let memory: Vec<(u64, u64, u32)> = srat.entries().filter_map(|entry| match entry {
+        SratEntry::MemoryAffinity(affinity) => {
+            Some((affinity.base_address(), affinity.length(), affinity.proximity_domain))
+        },
+        _ => None
+    }).collect();
+    assert_eq!(memory, vec![
+        (0, 0xA0000, 0), (0x100000, 0x1FF00000, 0), (0x20000000, 0x20000000, 1), (0x100000000, 0x40000000, 1)
+    ]);
+
+    let slit = acpi.find_table::<SLIT>().unwrap();
+    assert_eq!(slit.localities(), 2);
+    assert_eq!(slit.distance(0, 0), Some(LOCAL_DISTANCE));
+    assert_eq!(slit.distance(0, 1), Some(21));
+    assert_eq!(slit.distance(1, 0), Some(21));
+    assert_eq!(slit.distance(2, 0), None);
+}
+
+#[test]
+fn decodes_wide_proximity_domains() {
+    let mut srat = vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    srat.extend_from_slice(&processor_affinity(7, 0x12345678, AFFINITY_ENABLED));
+    srat.extend_from_slice(&x2apic_affinity(0x1000, 0xABCDEF, AFFINITY_ENABLED));
+
+    let image = firecracker_image_with(&[&table(b"SRAT", 3, &srat)]);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let mut entries = acpi.find_table::<SRAT>().unwrap().entries();
+
+    match entries.next() {
+        Some(SratEntry::ProcessorAffinity(affinity)) => assert_eq!(affinity.proximity_domain(), 0x12345678),
+        other => panic!("expected a processor affinity entry, got {:?}", other)
+    }
+
+    match entries.next() {
+        Some(SratEntry::X2ApicAffinity(affinity)) =>
+            assert_eq!(({ affinity.x2apic_id }, { affinity.proximity_domain }), (0x1000, 0xABCDEF)),
+        other => panic!("expected an x2APIC affinity entry, got {:?}", other)
+    }
+
+    assert!(entries.next().is_none());
+}
+
+#[test]
+fn ignores_slit_matrices_longer_than_the_table() {
+    let mut slit = little_endian(4, 8);
+    slit.extend_from_slice(&[10, 20, 20, 10]);
+
+    let image = firecracker_image_with(&[&table(b"SLIT", 1, &slit)]);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let slit = acpi.find_table::<SLIT>().unwrap();
+
+    assert_eq!(slit.localities(), 0);
+    assert_eq!(slit.distance(0, 0), None);
+}
+
+#[test]
+fn dumps_the_captured_tables() {
+    let image = firecracker_image();
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+
+    let mut output = String::new();
+    dump(&acpi, &mut output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+
+    // Every table gets a header line, with the DSDT at the end, and the tables we understand get details.
+    assert_eq!(lines[0],
+        "XSDT rev 1, 60 bytes, checksum ok, OEM FIRECK FCMVXSDT rev 0x0, creator FCAT rev 0x20240119 @ 0xa0e13");
+    assert!(lines[1].starts_with("FACP rev 6, 276 bytes, checksum ok, OEM FIRECK FCVMFADT"));
+    assert!(lines.contains(&"    DSDT @ 0x9fd30, FACS @ 0x0, SCI IRQ 0, version 6.5"));
+    assert!(lines.contains(&"    I/O APIC 0 @ 0xfec00000, GSI base 0"));
+    assert!(lines.contains(&"    Local APIC: processor 0, APIC 0, flags 0x1"));
+    assert!(lines.contains(&"    Segment 0 buses 0-0 @ 0xeec00000"));
+    assert!(lines[lines.len() - 2].starts_with("DSDT rev 2, 3923 bytes, checksum ok"));
+    assert_eq!(lines[lines.len() - 1], "    AML: 3887 bytes");
+}
+
+#[test]
+fn dumps_numa_tables_and_bad_checksums() {
+    let (srat, mut slit) = qemu_numa_tables();
+
+    // Break the SLIT's checksum; the dump should say so rather than refusing to show it.
+    slit[9] = slit[9].wrapping_add(1);
+
+    let image = firecracker_image_with(&[&srat, &slit]);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let mut output = String::new();
+    dump(&acpi, &mut output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert!(lines.contains(&"    APIC 2 -> domain 1, flags 0x1"));
+    assert!(lines.contains(&"    Memory 0x20000000-0x40000000 -> domain 1, flags 0x1"));
+    assert!(lines.iter().any(|line| line.starts_with("SLIT rev 1, 48 bytes, checksum BAD")));
+    assert!(lines.contains(&"     10  21"));
+    assert!(lines.contains(&"     21  10"));
+}
+
+/// Builds a device scope with the given type, enumeration id, start bus and path.
+fn device_scope(scope_type: u8, id: u8, bus: u8, path: &[(u8, u8)]) -> Vec<u8> {
+    let mut bytes = vec![scope_type, 6 + 2 * path.len() as u8, 0, 0, id, bus];
+
+    for &(device, function) in path {
+        bytes.extend_from_slice(&[device, function]);
+    }
+
+    bytes
+}
+
+/// Builds a DMAR like QEMU's intel-iommu device: one unit covering everything, plus a reserved region for a device
+/// behind a bridge, and an ATS structure.
+fn qemu_dmar() -> Vec<u8> {
+    let mut body = vec![38, DMAR_INTR_REMAP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    let ioapic = device_scope(SCOPE_IOAPIC, 0, 0xFF, &[(0, 0)]);
+    body.extend_from_slice(&little_endian(DMAR_ENTRY_DRHD as u64, 2));
+    body.extend_from_slice(&little_endian(16 + ioapic.len() as u64, 2));
+    body.extend_from_slice(&[DRHD_INCLUDE_PCI_ALL, 0]);
+    body.extend_from_slice(&little_endian(0, 2));
+    body.extend_from_slice(&little_endian(0xFED90000, 8));
+    body.extend_from_slice(&ioapic);
+
+    let endpoint = device_scope(SCOPE_PCI_ENDPOINT, 0, 0, &[(0x1C, 0), (0, 0)]);
+    body.extend_from_slice(&little_endian(DMAR_ENTRY_RMRR as u64, 2));
+    body.extend_from_slice(&little_endian(24 + endpoint.len() as u64, 2));
+    body.extend_from_slice(&[0, 0]);
+    body.extend_from_slice(&little_endian(0, 2));
+    body.extend_from_slice(&little_endian(0x7D000, 8));
+    body.extend_from_slice(&little_endian(0x7FFFF, 8));
+    body.extend_from_slice(&endpoint);
+
+    body.extend_from_slice(&little_endian(DMAR_ENTRY_ATSR as u64, 2));
+    body.extend_from_slice(&little_endian(8, 2));
+    body.extend_from_slice(&[ATSR_ALL_PORTS, 0]);
+    body.extend_from_slice(&little_endian(0, 2));
+
+    // A structure type from a later revision, which should be skipped over.
+    body.extend_from_slice(&little_endian(7, 2));
+    body.extend_from_slice(&little_endian(8, 2));
+    body.extend_from_slice(&[0; 4]);
+
+    table(b"DMAR", 1, &body)
+}
+
+#[test]
+fn parses_a_dmar() {
+    let image = firecracker_image_with(&[&qemu_dmar()]);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let dmar = acpi.find_table::<DMAR>().unwrap();
+
+    assert_eq!(dmar.address_width(), 39);
+    assert!(dmar.supports_interrupt_remapping());
+    assert_eq!(dmar.entries().count(), 4);
+
+    let units: Vec<&DrhdEntry> = dmar.hardware_units().collect();
+    assert_eq!(units.len(), 1);
+    assert!(units[0].includes_all());
+    assert_eq!(({ units[0].register_base }, units[0].register_size()), (0xFED90000, 0x1000));
+
+    let scopes: Vec<(u8, u8, u8)> = units[0].scopes().map(|scope| (scope.scope_type, scope.enumeration_id,
+        scope.start_bus)).collect();
+    assert_eq!(scopes, vec![(SCOPE_IOAPIC, 0, 0xFF)]);
+
+    let regions: Vec<&RmrrEntry> = dmar.reserved_regions().collect();
+    assert_eq!(regions.len(), 1);
+    assert_eq!(({ regions[0].base_address }, regions[0].length()), (0x7D000, 0x3000));
+
+    let paths: Vec<Vec<PciPathEntry>> = regions[0].scopes().map(|scope| scope.path().to_vec()).collect();
+    assert_eq!(paths, vec![vec![PciPathEntry { device: 0x1C, function: 0 }, PciPathEntry { device: 0, function: 0 }]]);
+
+    match dmar.entries().nth(3) {
+        Some(DmarEntry::Unknown(header)) => assert_eq!({ header.structure_type }, 7),
+        other => panic!("expected an unknown entry, got {:?}", other)
+    }
+}
+
+#[test]
+fn dumps_a_dmar() {
+    let image = firecracker_image_with(&[&qemu_dmar()]);
+
+    let acpi = ACPI::find_in_memory(image.memory()).unwrap();
+    let mut output = String::new();
+    dump(&acpi, &mut output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert!(lines.contains(&"    Host address width 39 bits, flags 0x1"));
+    assert!(lines.contains(&"    Remapping unit @ 0xfed90000, segment 0, flags 0x1"));
+    assert!(lines.contains(&"        Scope type 3, id 0, bus ff 00.0"));
+    assert!(lines.contains(&"    Reserved memory 0x7d000-0x7ffff, segment 0"));
+    assert!(lines.contains(&"        Scope type 1, id 0, bus 00 1c.0 00.0"));
+    assert!(lines.contains(&"    ATS reporting, segment 0, flags 0x1"));
+    assert!(lines.contains(&"    Unknown entry: type 7, 8 bytes"));
+}
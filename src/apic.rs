@@ -0,0 +1,290 @@
+//! Provides the interrupt controllers: this processor's local APIC, which interrupts are acknowledged through,
+//! and the I/O APICs, which route device interrupts (global system interrupts) to vectors. The legacy 8259
+//! PICs are moved out of the way of the exceptions and masked, as everything goes through the APICs instead.
+
+use spin::{Mutex, Once};
+
+use acpi::{ACPI, MADT, Polarity, TriggerMode};
+use arch::x86_64::port;
+use interrupts::{PIC_MASTER_VECTOR, PIC_SLAVE_VECTOR, SPURIOUS_VECTOR};
+use memory::mmio::{self, Mmio};
+use memory::paging::MemoryType;
+
+/// The local APIC's registers, as offsets from it's base address.
+const LOCAL_APIC_ID: u64 = 0x20;
+const LOCAL_APIC_TASK_PRIORITY: u64 = 0x80;
+const LOCAL_APIC_EOI: u64 = 0xB0;
+const LOCAL_APIC_SPURIOUS_VECTOR: u64 = 0xF0;
+
+/// The sizes of the local APIC's and an I/O APIC's registers.
+const LOCAL_APIC_SIZE: u64 = 0x400;
+const IO_APIC_SIZE: u64 = 0x20;
+
+/// Set in the spurious vector register to enable the local APIC.
+const LOCAL_APIC_ENABLE: u32 = 1 << 8;
+
+/// The I/O APIC's index and data registers, and the registers reached through them.
+const IO_APIC_INDEX: u64 = 0x00;
+const IO_APIC_DATA: u64 = 0x10;
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Fields of an I/O APIC redirection entry.
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION_SHIFT: u64 = 56;
+
+/// The legacy PICs' command and data ports.
+const PIC_MASTER_COMMAND: u16 = 0x20;
+const PIC_MASTER_DATA: u16 = 0x21;
+const PIC_SLAVE_COMMAND: u16 = 0xA0;
+const PIC_SLAVE_DATA: u16 = 0xA1;
+
+/// The most I/O APICs we keep track of.
+const MAX_IO_APICS: usize = 8;
+
+/// The most inputs an I/O APIC can have, as the version register only has 8 bits for them.
+const MAX_IO_APIC_INPUTS: usize = 256;
+
+/// The reasons the interrupt controllers can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// There's no MADT, so we don't know where the APICs are.
+    NoMadt,
+
+    /// The local APIC's registers (at the given physical address) couldn't be mapped.
+    NotMapped(u64)
+}
+
+/// An I/O APIC, and the global system interrupts it handles.
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    /// It's registers, mapped uncached.
+    registers: Mmio,
+
+    /// The first global system interrupt it handles, and how many it handles.
+    interrupt_base: u32,
+    interrupt_count: u32
+}
+
+/// Where the interrupt controllers are, filled in by init().
+#[derive(Debug)]
+struct Controllers {
+    local_apic: Mmio,
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+
+    /// True if there are legacy PICs, which have to be masked again after a sleep state.
+    legacy_pics: bool
+}
+
+static CONTROLLERS: Once<Controllers> = Once::new();
+
+/// Every I/O APIC's redirection entries, saved by suspend() as they're lost in sleep states.
+static SAVED_REDIRECTIONS: Mutex<[[u64; MAX_IO_APIC_INPUTS]; MAX_IO_APICS]> =
+    Mutex::new([[0; MAX_IO_APIC_INPUTS]; MAX_IO_APICS]);
+
+/// Masks the legacy PICs, enables this processor's local APIC and masks every I/O APIC input. Returns the number of
+/// I/O APICs found.
+/// UNSAFE: The IDT should be loaded first, as the PICs can still raise spurious interrupts.
+pub unsafe fn init(acpi: &ACPI) -> Result<usize, ApicError> {
+    let madt = acpi.find_table::<MADT>().ok_or(ApicError::NoMadt)?;
+
+    if madt.has_legacy_pics() {
+        disable_pics();
+    }
+
+    let local_apic = madt.local_apic_address();
+    let local_apic = mmio::ioremap(local_apic, LOCAL_APIC_SIZE, MemoryType::Uncached)
+        .map_err(|_| ApicError::NotMapped(local_apic))?;
+
+    let controllers = CONTROLLERS.call_once(|| {
+        let mut io_apics = [None; MAX_IO_APICS];
+
+        for (slot, entry) in io_apics.iter_mut().zip(madt.io_apics()) {
+            let registers = match mmio::ioremap(entry.address as u64, IO_APIC_SIZE, MemoryType::Uncached) {
+                Ok(registers) => registers,
+                Err(_) => continue
+            };
+
+            // The version register holds the index of the last redirection entry.
+            let version = read_io_apic(&registers, IO_APIC_VERSION);
+
+            *slot = Some(IoApic {
+                registers: registers,
+                interrupt_base: entry.global_system_interrupt_base,
+                interrupt_count: ((version >> 16) & 0xFF) + 1
+            });
+        }
+
+        Controllers { local_apic: local_apic, io_apics: io_apics, legacy_pics: madt.has_legacy_pics() }
+    });
+
+    enable_local_apic(controllers);
+
+    // Nothing is listening for any device interrupts yet.
+    for io_apic in controllers.io_apics.iter().filter_map(|io_apic| *io_apic) {
+        for input in 0 .. io_apic.interrupt_count {
+            write_redirection(&io_apic, input, REDIRECTION_MASKED);
+        }
+    }
+
+    Ok(controllers.io_apics.iter().filter(|io_apic| io_apic.is_some()).count())
+}
+
+/// Saves the I/O APICs' redirection entries before the machine enters a sleep state, which resets them.
+pub fn suspend() {
+    let controllers = match CONTROLLERS.try() {
+        Some(controllers) => controllers,
+        None => return
+    };
+
+    let mut saved = SAVED_REDIRECTIONS.lock();
+
+    for (io_apic, entries) in controllers.io_apics.iter().zip(saved.iter_mut()) {
+        if let Some(io_apic) = *io_apic {
+            for (input, entry) in entries.iter_mut().enumerate().take(io_apic.interrupt_count as usize) {
+                // UNSAFE: Safe, as reading a redirection entry has no side effects.
+                *entry = unsafe { read_redirection(&io_apic, input as u32) };
+            }
+        }
+    }
+}
+
+/// Puts the interrupt controllers back the way they were before the machine entered a sleep state: the PICs
+/// masked, the local APIC enabled, and the I/O APICs' redirection entries as suspend() saved them.
+/// UNSAFE: Has to be called with interrupts disabled, after waking up, with the same handlers registered.
+pub unsafe fn resume() {
+    let controllers = match CONTROLLERS.try() {
+        Some(controllers) => controllers,
+        None => return
+    };
+
+    if controllers.legacy_pics {
+        disable_pics();
+    }
+
+    enable_local_apic(controllers);
+
+    let saved = SAVED_REDIRECTIONS.lock();
+
+    for (io_apic, entries) in controllers.io_apics.iter().zip(saved.iter()) {
+        if let Some(io_apic) = *io_apic {
+            for (input, &entry) in entries.iter().enumerate().take(io_apic.interrupt_count as usize) {
+                write_redirection(&io_apic, input as u32, entry);
+            }
+        }
+    }
+}
+
+/// Enables this processor's local APIC, with spurious interrupts on SPURIOUS_VECTOR, accepting every priority.
+unsafe fn enable_local_apic(controllers: &Controllers) {
+    controllers.local_apic.write(LOCAL_APIC_TASK_PRIORITY, 0u32);
+    controllers.local_apic.write(LOCAL_APIC_SPURIOUS_VECTOR, LOCAL_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// Moves the PICs' interrupts to vectors 32-47, out of the way of the exceptions, and masks all of them.
+unsafe fn disable_pics() {
+    // ICW1: initialize, expect ICW4.
+    port::outb(PIC_MASTER_COMMAND, 0x11);
+    port::outb(PIC_SLAVE_COMMAND, 0x11);
+
+    // ICW2: the vector offsets.
+    port::outb(PIC_MASTER_DATA, PIC_MASTER_VECTOR);
+    port::outb(PIC_SLAVE_DATA, PIC_SLAVE_VECTOR);
+
+    // ICW3: the slave is on the master's IRQ 2.
+    port::outb(PIC_MASTER_DATA, 1 << 2);
+    port::outb(PIC_SLAVE_DATA, 2);
+
+    // ICW4: 8086 mode.
+    port::outb(PIC_MASTER_DATA, 0x01);
+    port::outb(PIC_SLAVE_DATA, 0x01);
+
+    port::outb(PIC_MASTER_DATA, 0xFF);
+    port::outb(PIC_SLAVE_DATA, 0xFF);
+}
+
+/// Acknowledges the interrupt being handled, so the local APIC delivers the next one.
+pub fn end_of_interrupt() {
+    if let Some(controllers) = CONTROLLERS.try() {
+        // UNSAFE: Safe, as writing the EOI register only acknowledges the interrupt.
+        unsafe { controllers.local_apic.write(LOCAL_APIC_EOI, 0u32); }
+    }
+}
+
+/// The local APIC id of this processor.
+pub fn local_apic_id() -> Option<u32> {
+    // UNSAFE: Safe, as reading the id register has no side effects.
+    CONTROLLERS.try().map(|controllers| unsafe { controllers.local_apic.read::<u32>(LOCAL_APIC_ID) } >> 24)
+}
+
+/// Finds the I/O APIC which handles the given global system interrupt, and the input it arrives on.
+fn io_apic_input(interrupt: u32) -> Option<(IoApic, u32)> {
+    CONTROLLERS.try()?.io_apics.iter()
+        .filter_map(|io_apic| *io_apic)
+        .find(|io_apic| interrupt >= io_apic.interrupt_base
+            && interrupt - io_apic.interrupt_base < io_apic.interrupt_count)
+        .map(|io_apic| (io_apic, interrupt - io_apic.interrupt_base))
+}
+
+/// Routes a global system interrupt to the given vector on this processor, and unmasks it. Returns false if no
+/// I/O APIC handles the interrupt.
+/// UNSAFE: A handler should be registered for the vector first.
+pub unsafe fn route(interrupt: u32, vector: u8, polarity: Polarity, trigger: TriggerMode) -> bool {
+    let (io_apic, input) = match io_apic_input(interrupt) {
+        Some(found) => found,
+        None => return false
+    };
+
+    let mut entry = vector as u64 | (local_apic_id().unwrap_or(0) as u64) << REDIRECTION_DESTINATION_SHIFT;
+    if polarity == Polarity::ActiveLow { entry |= REDIRECTION_ACTIVE_LOW; }
+    if trigger == TriggerMode::Level { entry |= REDIRECTION_LEVEL_TRIGGERED; }
+
+    write_redirection(&io_apic, input, entry);
+    true
+}
+
+/// Masks a global system interrupt, so it's no longer delivered. Returns false if no I/O APIC handles it.
+pub fn mask(interrupt: u32) -> bool {
+    match io_apic_input(interrupt) {
+        Some((io_apic, input)) => {
+            // UNSAFE: Safe, as masking an input only stops interrupts.
+            unsafe {
+                let entry = read_redirection(&io_apic, input);
+                write_redirection(&io_apic, input, entry | REDIRECTION_MASKED);
+            }
+
+            true
+        },
+        None => false
+    }
+}
+
+/// Reads an I/O APIC register, by selecting it through the index register.
+unsafe fn read_io_apic(registers: &Mmio, register: u32) -> u32 {
+    registers.write(IO_APIC_INDEX, register);
+    registers.read(IO_APIC_DATA)
+}
+
+/// Writes an I/O APIC register, by selecting it through the index register.
+unsafe fn write_io_apic(registers: &Mmio, register: u32, value: u32) {
+    registers.write(IO_APIC_INDEX, register);
+    registers.write(IO_APIC_DATA, value)
+}
+
+/// Reads the redirection entry for one of an I/O APIC's inputs.
+unsafe fn read_redirection(io_apic: &IoApic, input: u32) -> u64 {
+    let register = IO_APIC_REDIRECTION_TABLE + input * 2;
+
+    read_io_apic(&io_apic.registers, register) as u64 | (read_io_apic(&io_apic.registers, register + 1) as u64) << 32
+}
+
+/// Writes the redirection entry for one of an I/O APIC's inputs. The low half (with the mask bit) goes last, so
+/// the input isn't unmasked before it's destination is set.
+unsafe fn write_redirection(io_apic: &IoApic, input: u32, entry: u64) {
+    let register = IO_APIC_REDIRECTION_TABLE + input * 2;
+
+    write_io_apic(&io_apic.registers, register + 1, (entry >> 32) as u32);
+    write_io_apic(&io_apic.registers, register, entry as u32);
+}
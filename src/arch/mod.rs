@@ -0,0 +1,4 @@
+//! Architecture-specific code which needs to be callable from Rust; the bootstrapping assembly for each
+//! architecture lives alongside it.
+
+pub mod x86_64;
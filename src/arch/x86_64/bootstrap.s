@@ -1,8 +1,13 @@
 ; TODO: I very much would like to change most of this to a 32-bit rust loader.
-; This bootstrapper identity maps the first gigabyte and furthermore maps the 1st gigabyte after the KERNEL_VIRTUAL
-; address to the first gigabyte as well.
+; This bootstrapper identity maps the first four gigabytes and furthermore maps the 4 gigabytes after the
+; KERNEL_VIRTUAL address to the first four gigabytes as well. Four gigabytes, rather than one, so that the memory-mapped
+; devices below 4GiB (the I/O APICs, the HPET, ...) are reachable without a proper paging setup.
 
-%define INIT_STACK_SIZE 1024
+; The number of gigabytes to map, each of which takes one p2 table; this is IDENTITY_MAP_SIZE in arch/x86_64/mod.rs.
+%define MAPPED_GIGABYTES 4
+
+; The AML interpreter recurses for every nested term and method call, so this can't be tiny.
+%define INIT_STACK_SIZE 0x10000
 
 ; TODO: This is redefined in linker.ld (well, it almost is - this is the start of where to map, not where to
 ; load the kernel; here, this needs to be 512-gb aligned)
@@ -14,7 +19,7 @@ bits 32
 ; The 32-bit entry point for the initial processor; multiboot passes off control to this.
 global asm_init32
 asm_init32:
-    ; Set up our initialization stack which is relatively small.
+    ; Set up our initialization stack.
     mov esp, init_stack_top
 
     ; Upon entry, eax has the magic value and ebx has the multiboot structure ptr.
@@ -131,10 +136,18 @@ setup_page_tables:
     ; Set up the p2 table.
     call setup_page_tables_p2
 
-    ; Set up the p3 table, which should just map the 1st entry to the p2 table.
-    mov eax, page_tables.p2
+    ; Set up the p3 table, which should map the first few entries to the p2 tables.
+    mov ecx, 0
+.p3_loop:
+    mov eax, ecx
+    shl eax, 12
+    add eax, page_tables.p2
     or eax, 0b11 ; Add writable, present.
-    mov [page_tables.p3], eax
+    mov [page_tables.p3 + 8 * ecx], eax
+
+    inc ecx
+    cmp ecx, MAPPED_GIGABYTES
+    jne .p3_loop
 
     ; Set up identity mappings in p4, sets 1st index to point to p3.
     mov eax, page_tables.p3
@@ -148,11 +161,13 @@ setup_page_tables:
 
     ret
 
-; A utility method for filling the p2 with mappings to the 1st gigabyte of memory.
+; A utility method for filling the p2 tables with mappings to the first few gigabytes of memory.
+; The p2 tables are contiguous, so this just treats them as one big table.
 setup_page_tables_p2:
     mov ecx, 0 ; Our counter for counting the tables.
 .loop:
-    ; We map the nth entry to n * 2MB, eg, identity map.
+    ; We map the nth entry to n * 2MB, eg, identity map. This clobbers edx with the upper half, but the
+    ; product always fits in 32 bits.
     mov eax, 0x200000
     mul ecx
 
@@ -165,7 +180,7 @@ setup_page_tables_p2:
     inc ecx
 
     ; If we haven't gone through every entry, keep trucking...
-    cmp ecx, 512
+    cmp ecx, 512 * MAPPED_GIGABYTES
     jne .loop
 
     ret
@@ -222,11 +237,11 @@ page_tables:
 .p3:
     resb 0x1000
 .p2:
-    resb 0x1000
+    resb 0x1000 * MAPPED_GIGABYTES
 
-; The stack used during initialization and the kernel init phase;
-; this stack will be dropped in favor of thread-managed stack once
-; initialization is complete.
+; The stack used during initialization, until rust_init has paging set
+; up and switches to a boot stack with a guard page below it (see
+; memory/stack.rs).
 init_stack_bottom:
     resb INIT_STACK_SIZE
 init_stack_top:
@@ -0,0 +1,140 @@
+//! Provides the kernel's global descriptor table, which replaces the bootstrap's: the same 64-bit code and data
+//! segments (at the same selectors, so the segment registers don't have to be loaded again), and a task state
+//! segment. In long mode, the TSS is only there for it's interrupt stack table: stacks the processor switches to
+//! for the exceptions which can't trust the one in use, like double faults from running off the end of it.
+
+use core::cell::UnsafeCell;
+use core::mem;
+
+use super::instructions;
+
+/// The selectors of the segments in the GDT.
+pub const CODE_SELECTOR: u16 = 0x08;
+pub const DATA_SELECTOR: u16 = 0x10;
+pub const TSS_SELECTOR: u16 = 0x18;
+
+/// The interrupt stack table entry (counting from 1, as IDT gates do) double faults run on.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+
+/// The size of the double fault stack; the handler only has to print what happened.
+const DOUBLE_FAULT_STACK_SIZE: usize = 0x4000;
+
+/// The code and data segment descriptors, as in bootstrap.s: present, 64-bit code which can be read, and data
+/// which can be written.
+const CODE_SEGMENT: u64 = (1 << 53) | (1 << 47) | (1 << 44) | (1 << 43) | (1 << 41);
+const DATA_SEGMENT: u64 = (1 << 47) | (1 << 44) | (1 << 41);
+
+/// The type of a present, available 64-bit TSS descriptor, and the bit which marks it busy once it's loaded.
+const TSS_AVAILABLE: u64 = 0x89 << 40;
+const TSS_BUSY: u64 = 1 << 41;
+
+/// The entries in the GDT: the null descriptor, code, data, and the TSS descriptor (which takes two).
+const GDT_ENTRIES: usize = 5;
+
+/// The 64-bit task state segment.
+#[repr(C, packed)]
+struct TaskStateSegment {
+    _reserved: u32,
+
+    /// The stacks to switch to on entering rings 0-2 from a less privileged ring.
+    privilege_stacks: [u64; 3],
+    _reserved2: u64,
+
+    /// The interrupt stack table, which IDT gates pick a stack from.
+    interrupt_stacks: [u64; 7],
+    _reserved3: u64,
+    _reserved4: u16,
+
+    /// The offset of the I/O permission bitmap; anything past the end of the TSS means there isn't one.
+    io_map_base: u16
+}
+
+/// The GDT, TSS and double fault stack, which the processor reads (and in the GDT's case, writes) behind our back.
+struct Tables {
+    gdt: [u64; GDT_ENTRIES],
+    tss: TaskStateSegment,
+    double_fault_stack: [u8; DOUBLE_FAULT_STACK_SIZE]
+}
+
+struct TablesCell(UnsafeCell<Tables>);
+
+// UNSAFE: Safe, as the tables are only changed by init() and resume(), which run before anything else uses them.
+unsafe impl Sync for TablesCell {}
+
+static TABLES: TablesCell = TablesCell(UnsafeCell::new(Tables {
+    gdt: [0, CODE_SEGMENT, DATA_SEGMENT, 0, 0],
+    tss: TaskStateSegment {
+        _reserved: 0,
+        privilege_stacks: [0; 3],
+        _reserved2: 0,
+        interrupt_stacks: [0; 7],
+        _reserved3: 0,
+        _reserved4: 0,
+        io_map_base: mem::size_of::<TaskStateSegment>() as u16
+    },
+    double_fault_stack: [0; DOUBLE_FAULT_STACK_SIZE]
+}));
+
+/// The two GDT entries describing a TSS at the given address.
+fn tss_descriptor(address: u64) -> (u64, u64) {
+    let limit = mem::size_of::<TaskStateSegment>() as u64 - 1;
+    let low = (limit & 0xFFFF) | (address & 0xFF_FFFF) << 16 | TSS_AVAILABLE | (limit >> 16 & 0xF) << 48
+        | (address >> 24 & 0xFF) << 56;
+
+    (low, address >> 32)
+}
+
+/// Loads the GDT and the TSS, with the double fault stack in the interrupt stack table.
+pub fn init() {
+    // UNSAFE: Safe, as this is the only thing changing the tables, and it does so before they're loaded.
+    unsafe {
+        let tables = &mut *TABLES.0.get();
+
+        // Stacks grow down, from a 16 byte aligned top.
+        let stack = &tables.double_fault_stack as *const _ as u64;
+        tables.tss.interrupt_stacks[DOUBLE_FAULT_IST as usize - 1] = (stack + DOUBLE_FAULT_STACK_SIZE as u64) & !0xF;
+
+        let (low, high) = tss_descriptor(&tables.tss as *const _ as u64);
+        tables.gdt[TSS_SELECTOR as usize / 8] = low;
+        tables.gdt[TSS_SELECTOR as usize / 8 + 1] = high;
+
+        load(tables);
+    }
+}
+
+/// Loads the task register again after the machine wakes up from a sleep state, which loses it (the wakeup
+/// trampoline puts the GDT back).
+/// UNSAFE: init() must have been called before the machine went to sleep.
+pub unsafe fn resume() {
+    let tables = &mut *TABLES.0.get();
+
+    // Loading the TSS marks it busy in the GDT, and a busy TSS can't be loaded, so the old mark has to go first.
+    tables.gdt[TSS_SELECTOR as usize / 8] &= !TSS_BUSY;
+    instructions::load_task_register(TSS_SELECTOR);
+}
+
+/// Loads the GDT and then the task register.
+unsafe fn load(tables: &Tables) {
+    let base = tables.gdt.as_ptr() as u64;
+    let limit = (GDT_ENTRIES * 8 - 1) as u16;
+
+    instructions::load_gdt(&[limit, base as u16, (base >> 16) as u16, (base >> 32) as u16, (base >> 48) as u16]);
+    instructions::load_task_register(TSS_SELECTOR);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_tss() {
+        assert_eq!(mem::size_of::<TaskStateSegment>(), 104);
+
+        let (low, high) = tss_descriptor(0xFFFF_E000_1234_5678);
+        assert_eq!(low & 0xFFFF, 103);
+        assert_eq!(low >> 16 & 0xFF_FFFF, 0x34_5678);
+        assert_eq!(low >> 40 & 0xFF, 0x89);
+        assert_eq!(low >> 56, 0x12);
+        assert_eq!(high, 0xFFFF_E000);
+    }
+}
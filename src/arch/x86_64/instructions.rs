@@ -0,0 +1,173 @@
+//! Provides wrappers around miscellaneous privileged instructions.
+
+/// Halts the processor until the next interrupt arrives.
+pub fn halt() {
+    unsafe { asm!("hlt" :::: "volatile"); }
+}
+
+/// Disables maskable interrupts on this processor.
+pub unsafe fn disable_interrupts() {
+    asm!("cli" :::: "volatile");
+}
+
+/// Enables maskable interrupts on this processor.
+pub unsafe fn enable_interrupts() {
+    asm!("sti" :::: "volatile");
+}
+
+/// Enables maskable interrupts and halts until the next one arrives. An interrupt can't sneak in between the two
+/// (sti only takes effect after the following instruction), so checking for work with interrupts disabled and then
+/// calling this can't miss a wakeup.
+pub unsafe fn enable_interrupts_and_halt() {
+    asm!("sti; hlt" :::: "volatile");
+}
+
+/// True if maskable interrupts are enabled on this processor.
+pub fn interrupts_enabled() -> bool {
+    let flags: u64;
+    unsafe { asm!("pushfq; popq $0" : "=r"(flags) ::: "volatile"); }
+
+    // The interrupt flag is bit 9 of RFLAGS.
+    flags & (1 << 9) != 0
+}
+
+/// Runs the given closure with maskable interrupts disabled, restoring the previous state afterwards; used around
+/// locks which interrupt handlers also take.
+pub fn without_interrupts<T, F: FnOnce() -> T>(f: F) -> T {
+    let enabled = interrupts_enabled();
+    unsafe { disable_interrupts(); }
+
+    let result = f();
+
+    if enabled { unsafe { enable_interrupts(); } }
+    result
+}
+
+/// Obtains the code segment selector we're running with.
+pub fn code_segment() -> u16 {
+    let selector: u16;
+    unsafe { asm!("movw %cs, $0" : "=r"(selector) ::: "volatile"); }
+    selector
+}
+
+/// Loads the interrupt descriptor table described by the given pointer (the 2-byte limit followed by the
+/// 8-byte base address).
+/// UNSAFE: The table must stay where it is, and contain valid gates, for as long as it's loaded.
+pub unsafe fn load_idt(pointer: &[u16; 5]) {
+    asm!("lidt ($0)" :: "r"(pointer) : "memory" : "volatile");
+}
+
+/// Loads the global descriptor table described by the given pointer (laid out like load_idt()'s). The segment
+/// registers keep what they loaded from the old table until they're loaded again.
+/// UNSAFE: The table must stay where it is, and hold the segments in use, for as long as it's loaded.
+pub unsafe fn load_gdt(pointer: &[u16; 5]) {
+    asm!("lgdt ($0)" :: "r"(pointer) : "memory" : "volatile");
+}
+
+/// Loads the task register with the TSS descriptor the selector picks out of the GDT, which marks it busy.
+/// UNSAFE: The descriptor must be an available (not busy) 64-bit TSS, which stays where it is while it's loaded.
+pub unsafe fn load_task_register(selector: u16) {
+    asm!("ltr $0" :: "r"(selector) : "memory" : "volatile");
+}
+
+/// Switches to the stack with the given top, and calls the function on it.
+/// UNSAFE: The stack must be mapped and unused, and aligned to 16 bytes; nothing on the old stack can be used
+/// afterwards.
+pub unsafe fn switch_stack(top: u64, function: extern "C" fn() -> !) -> ! {
+    asm!("movq $0, %rsp; callq *$1" :: "r"(top), "r"(function) : "memory" : "volatile");
+
+    // The function never returns.
+    loop { halt(); }
+}
+
+/// Runs CPUID for the given leaf (and subleaf 0), returning EAX, EBX, ECX and EDX.
+pub fn cpuid(leaf: u32) -> [u32; 4] {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid" : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx) : "{eax}"(leaf), "{ecx}"(0)
+            :: "volatile");
+    }
+
+    [eax, ebx, ecx, edx]
+}
+
+/// Reads a model specific register.
+/// UNSAFE: The register must exist, or this raises a general protection fault.
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "volatile");
+
+    (high as u64) << 32 | low as u64
+}
+
+/// Writes a model specific register.
+/// UNSAFE: The register must exist, and the value must be one which doesn't break anything.
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32) : "memory" : "volatile");
+}
+
+/// Reads CR0, which holds the processor's operating mode flags.
+pub fn read_cr0() -> u64 {
+    let cr0: u64;
+    unsafe { asm!("movq %cr0, $0" : "=r"(cr0) ::: "volatile"); }
+    cr0
+}
+
+/// Writes CR0.
+/// UNSAFE: The flags must be ones the kernel can keep running with.
+pub unsafe fn write_cr0(cr0: u64) {
+    asm!("movq $0, %cr0" :: "r"(cr0) : "memory" : "volatile");
+}
+
+/// The physical address of the top level page table being used, from CR3.
+pub fn page_table_root() -> u64 {
+    let cr3: u64;
+    unsafe { asm!("movq %cr3, $0" : "=r"(cr3) ::: "volatile"); }
+
+    // The low 12 bits are cache control flags.
+    cr3 & !0xFFF
+}
+
+/// Switches to the page tables with the given top level table, which also flushes the TLB (apart from global
+/// pages).
+/// UNSAFE: The tables must map the code, stack and data being used, and the table must be below 4GiB if the
+/// machine is to wake from suspend-to-RAM with it.
+pub unsafe fn set_page_table_root(root: u64) {
+    asm!("movq $0, %cr3" :: "r"(root) : "memory" : "volatile");
+}
+
+/// The address the last page fault was raised for, from CR2.
+pub fn page_fault_address() -> u64 {
+    let cr2: u64;
+    unsafe { asm!("movq %cr2, $0" : "=r"(cr2) ::: "volatile"); }
+
+    cr2
+}
+
+/// Flushes the TLB entries for the page holding the given address, on this processor.
+pub fn flush_tlb_page(address: u64) {
+    unsafe { asm!("invlpg ($0)" :: "r"(address) : "memory" : "volatile"); }
+}
+
+/// Writes back every modified cache line to memory and invalidates the caches, as has to be done before the caches
+/// lose power in a sleep state.
+pub unsafe fn write_back_and_invalidate_caches() {
+    asm!("wbinvd" ::: "memory" : "volatile");
+}
+
+/// Writes back the cache line holding the given address to memory (and invalidates it), for hardware which reads
+/// memory without snooping the caches.
+pub unsafe fn flush_cache_line(address: u64) {
+    asm!("clflush ($0)" :: "r"(address) : "memory" : "volatile");
+}
+
+/// Forces a triple fault by loading an empty interrupt descriptor table and then raising an interrupt,
+/// which resets the machine. This is the reset method of last resort.
+pub unsafe fn triple_fault() -> ! {
+    // A zero-limit IDT means every interrupt fails to be delivered.
+    let null_idt: [u16; 5] = [0; 5];
+
+    asm!("lidt ($0); int3" :: "r"(&null_idt) : "memory" : "volatile");
+
+    loop { halt(); }
+}
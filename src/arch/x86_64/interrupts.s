@@ -0,0 +1,79 @@
+; The entry points for all 256 interrupt vectors. Each one pushes the vector number (and a zero in place of the error
+; code, for the vectors where the processor doesn't push one) so every interrupt arrives with the same stack layout,
+; then saves the general purpose registers and calls into rust; see InterruptFrame in interrupts.rs, which has to
+; match the layout built here.
+
+section .text
+bits 64
+
+extern interrupt_dispatch
+
+; Generate the stubs; the processor pushes an error code for #DF, #TS, #NP, #SS, #GP, #PF, #AC, #CP, #VC and #SX
+; (which has_error_code() in interrupts.rs has to agree with).
+%assign vector 0
+%rep 256
+interrupt_stub_%+vector:
+%if vector == 8 || (vector >= 10 && vector <= 14) || vector == 17 || vector == 21 || vector == 29 || vector == 30
+%else
+    push qword 0
+%endif
+    push qword vector
+    jmp interrupt_common
+%assign vector vector + 1
+%endrep
+
+; Saves the rest of the interrupted state, hands a pointer to it to rust, and then restores it (including any
+; changes the handler made) and returns to wherever we were interrupted.
+interrupt_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; The processor aligned the stack to 16 bytes before pushing it's frame, and we've pushed 22 quadwords since,
+    ; so the stack is still aligned as the System V ABI expects.
+    mov rdi, rsp
+    cld
+    call interrupt_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    ; Drop the vector number and error code.
+    add rsp, 16
+    iretq
+
+section .rodata
+
+; The address of each stub, indexed by vector, for building the IDT.
+global interrupt_stubs
+interrupt_stubs:
+%assign vector 0
+%rep 256
+    dq interrupt_stub_%+vector
+%assign vector vector + 1
+%endrep
@@ -0,0 +1,57 @@
+//! Provides thin wrappers around the x86_64 instructions which the rest of the kernel needs, but which
+//! have no Rust equivalent.
+
+pub mod port;
+pub mod instructions;
+pub mod gdt;
+pub mod wakeup;
+
+/// The amount of physical memory (from address 0) which the bootstrap identity maps; see MAPPED_GIGABYTES in
+/// bootstrap.s.
+pub const IDENTITY_MAP_SIZE: u64 = 4 << 30;
+
+/// Where the kernel image is loaded in physical memory, and where it's mapped in virtual memory; see linker.ld.
+pub const KERNEL_PHYSICAL: u64 = 0x100000;
+pub const KERNEL_VIRTUAL: u64 = 0xFFFFE00000100000;
+
+extern "C" {
+    /// The end of the low region holding the multiboot header, wakeup trampoline and bootstrap code (along with
+    /// the bootstrap page tables and stack), from linker.ld.
+    static bootstrap_end: u8;
+
+    /// The start and end of the pages holding the wakeup trampoline's code, from linker.ld.
+    static wakeup_start: u8;
+    static wakeup_end: u8;
+
+    /// The (virtual) end of the kernel image, from linker.ld.
+    static kernel_end: u8;
+}
+
+/// The physical addresses of the start of the kernel image and of the first byte past it's end.
+pub fn kernel_image() -> (u64, u64) {
+    // UNSAFE: Safe, as only the address of the symbol is taken.
+    (KERNEL_PHYSICAL, kernel_physical_address(unsafe { &kernel_end as *const u8 as u64 }))
+}
+
+/// The physical address of the first byte past the low bootstrap region.
+pub fn bootstrap_end_address() -> u64 {
+    // UNSAFE: Safe, as only the address of the symbol is taken.
+    unsafe { &bootstrap_end as *const u8 as u64 }
+}
+
+/// The physical addresses of the start of the pages holding the wakeup trampoline's code, and of the first byte past
+/// their end.
+pub fn wakeup_code() -> (u64, u64) {
+    // UNSAFE: Safe, as only the addresses of the symbols are taken.
+    unsafe { (&wakeup_start as *const u8 as u64, &wakeup_end as *const u8 as u64) }
+}
+
+/// The physical address of something in the kernel image, like a static.
+pub fn kernel_physical_address(virtual_address: u64) -> u64 {
+    virtual_address.wrapping_sub(KERNEL_VIRTUAL).wrapping_add(KERNEL_PHYSICAL)
+}
+
+/// The virtual address of something in the kernel image, given it's physical address.
+pub fn kernel_virtual_address(physical_address: u64) -> u64 {
+    physical_address.wrapping_sub(KERNEL_PHYSICAL).wrapping_add(KERNEL_VIRTUAL)
+}
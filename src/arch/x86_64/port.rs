@@ -0,0 +1,37 @@
+//! Provides access to the x86 I/O port address space through the in/out instructions.
+
+/// Reads a byte from the given I/O port.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("inb %dx, %al" : "={al}"(value) : "{dx}"(port) :: "volatile");
+    value
+}
+
+/// Reads a word from the given I/O port.
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    asm!("inw %dx, %ax" : "={ax}"(value) : "{dx}"(port) :: "volatile");
+    value
+}
+
+/// Reads a double word from the given I/O port.
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("inl %dx, %eax" : "={eax}"(value) : "{dx}"(port) :: "volatile");
+    value
+}
+
+/// Writes a byte to the given I/O port.
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("outb %al, %dx" :: "{dx}"(port), "{al}"(value) :: "volatile");
+}
+
+/// Writes a word to the given I/O port.
+pub unsafe fn outw(port: u16, value: u16) {
+    asm!("outw %ax, %dx" :: "{dx}"(port), "{ax}"(value) :: "volatile");
+}
+
+/// Writes a double word to the given I/O port.
+pub unsafe fn outl(port: u16, value: u32) {
+    asm!("outl %eax, %dx" :: "{dx}"(port), "{eax}"(value) :: "volatile");
+}
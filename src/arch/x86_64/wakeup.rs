@@ -0,0 +1,26 @@
+//! Provides the processor side of suspend-to-RAM: saving the state the processor loses while the machine is
+//! asleep, and the trampoline (in wakeup.s) the firmware jumps to when it wakes up, which restores it.
+
+extern "C" {
+    /// Saves the processor's state, returning 0; returns 1 when the machine wakes up.
+    fn wakeup_save_state() -> u64;
+
+    /// The physical address of the trampoline, from wakeup.s.
+    static wakeup_trampoline_address: u64;
+}
+
+/// The physical address of the trampoline to put in the FACS waking vector; it's in the low bootstrap region, so
+/// always fits in 32 bits.
+pub fn trampoline_address() -> u32 {
+    // UNSAFE: Safe, as the address is never modified.
+    unsafe { wakeup_trampoline_address as u32 }
+}
+
+/// Saves the processor's state and returns false. When the machine wakes up from a sleep state with the waking
+/// vector pointing at the trampoline, the trampoline restores the state and this returns again, with true.
+/// UNSAFE: Like setjmp, so this has to be inlined into the caller, and the caller mustn't return (or touch it's
+/// locals) between the two returns; everything other than the processor's state has to be restored separately.
+#[inline(always)]
+pub unsafe fn save_state() -> bool {
+    wakeup_save_state() != 0
+}
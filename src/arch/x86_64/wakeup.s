@@ -0,0 +1,201 @@
+; The processor side of suspend-to-RAM. Before the machine goes to sleep, wakeup_save_state saves everything the
+; processor loses (the control registers, EFER and the PAT, descriptor tables, stack and callee-saved registers);
+; when it wakes up, the firmware jumps to the waking vector in the FACS in real mode, which points at
+; wakeup_trampoline here. That climbs back up to long mode with the saved state, and returns from wakeup_save_state a
+; second time.
+;
+; The trampoline (and the state it restores) has to be below 1MiB for the firmware to reach it in real mode, so it's
+; placed in the low bootstrap region by linker.ld; it relies on that region still being identity mapped by the
+; saved page tables, and on those tables being below 4GiB, as it loads CR3 before long mode is back on. It's code
+; is the only low memory the kernel's tables leave executable, and it's read only there, so the GDT (whose
+; accessed bits the processor sets) and the saved state live in a section of their own, which isn't executable.
+
+%define EFER 0xC0000080
+%define PAT 0x277
+
+section .wakeup progbits alloc exec nowrite align=16
+bits 16
+
+; Entered by the firmware with CS:IP = (address >> 4):(address & 0xF), which is CS:0, as this is 16 byte aligned. The
+; whole low region is in the first 64KiB (see linker.ld), so with DS at zero, the GDT pointer in .wakeup_data can be
+; addressed by it's linked address.
+global wakeup_trampoline
+wakeup_trampoline:
+    cli
+    cld
+
+    xor ax, ax
+    mov ds, ax
+    o32 lgdt [wakeup_gdt.pointer]
+
+    ; Switch on protected mode, and jump to the 32-bit code segment, which has a base of zero, so from here on
+    ; everything is addressed by it's physical (linked) address.
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    jmp dword wakeup_gdt.code32:wakeup_protected
+
+bits 32
+wakeup_protected:
+    mov ax, wakeup_gdt.data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; Put back PAE (and the rest of CR4), the kernel's page tables, long mode (and the rest of EFER) and the memory
+    ; types the page tables pick from; turning paging on through CR0 then brings us back to long mode, in the 32-bit
+    ; compatibility segment for now.
+    mov eax, [wakeup_state.cr4]
+    mov cr4, eax
+
+    mov eax, [wakeup_state.cr3]
+    mov cr3, eax
+
+    mov ecx, EFER
+    mov eax, [wakeup_state.efer]
+    mov edx, [wakeup_state.efer + 4]
+    wrmsr
+
+    mov ecx, PAT
+    mov eax, [wakeup_state.pat]
+    mov edx, [wakeup_state.pat + 4]
+    wrmsr
+
+    mov eax, [wakeup_state.cr0]
+    mov cr0, eax
+
+    jmp wakeup_gdt.code64:wakeup_long
+
+bits 64
+wakeup_long:
+    ; Back to the kernel's own descriptor tables.
+    lgdt [wakeup_state.gdtr]
+    lidt [wakeup_state.idtr]
+
+    mov ax, [wakeup_state.ss]
+    mov ss, ax
+    mov ds, ax
+    mov es, ax
+
+    mov rsp, [wakeup_state.rsp]
+    mov rbx, [wakeup_state.rbx]
+    mov rbp, [wakeup_state.rbp]
+    mov r12, [wakeup_state.r12]
+    mov r13, [wakeup_state.r13]
+    mov r14, [wakeup_state.r14]
+    mov r15, [wakeup_state.r15]
+
+    push qword [wakeup_state.rflags]
+    popfq
+
+    ; Return to wherever wakeup_save_state was called from, reloading the kernel's code segment on the way, with 1
+    ; to say we're waking up.
+    movzx rax, word [wakeup_state.cs]
+    push rax
+    push qword [wakeup_state.rip]
+
+    mov eax, 1
+    o64 retf
+
+; Everything the trampoline reads and writes, rather than runs.
+section .wakeup_data progbits alloc noexec write align=16
+
+; A GDT with just enough in it to get from real mode to long mode: flat 32-bit code and data, and 64-bit code.
+align 8
+wakeup_gdt:
+.null: equ $ - wakeup_gdt
+    dq 0
+.code32: equ $ - wakeup_gdt
+    dq 0x00CF9A000000FFFF ; present, exec/read, 4GiB limit, 32-bit
+.data: equ $ - wakeup_gdt
+    dq 0x00CF92000000FFFF ; present, read/write, 4GiB limit, 32-bit
+.code64: equ $ - wakeup_gdt
+    dq 0x00AF9A000000FFFF ; present, exec/read, 64-bit
+
+; The 6 byte pointer lgdt takes in real mode (the limit, and a 32-bit base).
+.pointer:
+    dw $ - wakeup_gdt - 1
+    dd wakeup_gdt
+
+; The state saved by wakeup_save_state.
+align 8
+wakeup_state:
+.rip: dq 0
+.rsp: dq 0
+.rbx: dq 0
+.rbp: dq 0
+.r12: dq 0
+.r13: dq 0
+.r14: dq 0
+.r15: dq 0
+.rflags: dq 0
+.cr0: dq 0
+.cr3: dq 0
+.cr4: dq 0
+.efer: dq 0
+.pat: dq 0
+.cs: dw 0
+.ss: dw 0
+.gdtr: times 10 db 0
+.idtr: times 10 db 0
+
+; ===============================
+; EVERYTHING BELOW IS IN THE KERNEL PROPER.
+; ===============================
+
+section .text
+bits 64
+
+; Saves the processor's state for the trampoline and returns 0. When the machine wakes up, the trampoline returns
+; from here again (to the same caller, on the same stack) with 1. Like setjmp, the caller's frame has to still be
+; there when that happens.
+global wakeup_save_state
+wakeup_save_state:
+    mov rax, [rsp]
+    mov [wakeup_state.rip], rax
+    lea rax, [rsp + 8]
+    mov [wakeup_state.rsp], rax
+
+    mov [wakeup_state.rbx], rbx
+    mov [wakeup_state.rbp], rbp
+    mov [wakeup_state.r12], r12
+    mov [wakeup_state.r13], r13
+    mov [wakeup_state.r14], r14
+    mov [wakeup_state.r15], r15
+
+    pushfq
+    pop qword [wakeup_state.rflags]
+
+    mov rax, cr0
+    mov [wakeup_state.cr0], rax
+    mov rax, cr3
+    mov [wakeup_state.cr3], rax
+    mov rax, cr4
+    mov [wakeup_state.cr4], rax
+
+    mov ecx, EFER
+    rdmsr
+    mov [wakeup_state.efer], eax
+    mov [wakeup_state.efer + 4], edx
+
+    mov ecx, PAT
+    rdmsr
+    mov [wakeup_state.pat], eax
+    mov [wakeup_state.pat + 4], edx
+
+    mov [wakeup_state.cs], cs
+    mov [wakeup_state.ss], ss
+    sgdt [wakeup_state.gdtr]
+    sidt [wakeup_state.idtr]
+
+    xor eax, eax
+    ret
+
+section .rodata
+
+; The physical address of the trampoline, for the waking vector; the kernel proper can't refer to the trampoline
+; directly, as it's too far away for a 32-bit relative address.
+global wakeup_trampoline_address
+wakeup_trampoline_address:
+    dq wakeup_trampoline
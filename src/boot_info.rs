@@ -0,0 +1,705 @@
+//! Provides what the boot loader told us about the machine and the kernel: the memory map, the command line, the
+//! loaded modules, the kernel's ELF sections, the framebuffer and the ACPI RSDP. It's all copied out of the multiboot2
+//! boot information into the kernel's own types when init() runs, so it stays available after low memory (where
+//! the loader leaves the boot information) is reused or unmapped.
+//!
+//! The multiboot2 crate is used for the tags it handles fully (the boot loader name and the modules); the rest are
+//! read through the raw tags in the multiboot module, as the crate only hands back available memory areas, skips
+//! the last ELF section, and doesn't know about the framebuffer or RSDP tags.
+
+use core::{cmp, fmt, mem, ptr, slice, str};
+
+use spin::Once;
+
+use multiboot::{self, TagHeader};
+use multiboot2;
+
+/// The types of the tags read here, on top of the ones in the multiboot module.
+pub const TAG_MODULE: u32 = 3;
+pub const TAG_MEMORY_MAP: u32 = 6;
+pub const TAG_FRAMEBUFFER: u32 = 8;
+pub const TAG_ELF_SECTIONS: u32 = 9;
+
+/// The most memory areas, modules and ELF sections we keep track of.
+pub const MAX_MEMORY_AREAS: usize = 64;
+pub const MAX_MODULES: usize = 8;
+pub const MAX_ELF_SECTIONS: usize = 32;
+
+/// The longest command line, boot loader name, module name and section name we keep; longer ones are cut short.
+pub const MAX_COMMAND_LINE: usize = 256;
+pub const MAX_LOADER_NAME: usize = 64;
+pub const MAX_MODULE_NAME: usize = 64;
+pub const MAX_SECTION_NAME: usize = 16;
+
+/// The size of the longest RSDP (an ACPI 2.0+ XSDP).
+const MAX_RSDP: usize = 36;
+
+/// The ELF section flags.
+pub const ELF_SECTION_WRITABLE: u64 = 0x1;
+pub const ELF_SECTION_ALLOCATED: u64 = 0x2;
+pub const ELF_SECTION_EXECUTABLE: u64 = 0x4;
+
+/// The ELF section type of the unused first section header.
+const ELF_SECTION_NULL: u32 = 0;
+
+/// What a range of physical memory can be used for, as the memory map says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free for the kernel to use.
+    Available,
+
+    /// Holds ACPI tables, which can be reused once they've been read.
+    AcpiReclaimable,
+
+    /// Has to be kept as it is across sleep states, for the firmware.
+    AcpiNvs,
+
+    /// Memory which has been found to be faulty.
+    Defective,
+
+    /// Reserved for the firmware or devices (or of a type we don't know), and never to be touched.
+    Reserved(u32)
+}
+
+impl MemoryKind {
+    fn from_type(area_type: u32) -> MemoryKind {
+        match area_type {
+            1 => MemoryKind::Available,
+            3 => MemoryKind::AcpiReclaimable,
+            4 => MemoryKind::AcpiNvs,
+            5 => MemoryKind::Defective,
+            other => MemoryKind::Reserved(other)
+        }
+    }
+}
+
+/// A range of physical memory from the memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryArea {
+    pub base: u64,
+    pub length: u64,
+    pub kind: MemoryKind
+}
+
+impl MemoryArea {
+    /// The first address past the end of the area.
+    pub fn end(&self) -> u64 {
+        self.base.saturating_add(self.length)
+    }
+}
+
+/// A module the boot loader loaded alongside the kernel.
+#[derive(Clone, Copy)]
+pub struct Module {
+    /// The physical addresses of the start of the module, and of the first byte past it's end.
+    pub start: u64,
+    pub end: u64,
+
+    name: [u8; MAX_MODULE_NAME],
+    name_length: usize
+}
+
+impl Module {
+    /// The name (really the command line) the module was loaded with.
+    pub fn name(&self) -> &str {
+        from_utf8_prefix(&self.name[.. self.name_length])
+    }
+}
+
+impl fmt::Debug for Module {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.debug_struct("Module").field("start", &self.start).field("end", &self.end)
+            .field("name", &self.name()).finish()
+    }
+}
+
+/// One of the kernel's ELF sections, as it was loaded.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfSection {
+    /// The (virtual) addresses of the start of the section, and of the first byte past it's end.
+    pub start: u64,
+    pub end: u64,
+
+    /// The ELF_SECTION_* flags.
+    pub flags: u64,
+
+    name: [u8; MAX_SECTION_NAME],
+    name_length: usize
+}
+
+impl ElfSection {
+    /// The name of the section, if the loader gave us the section name table; empty otherwise.
+    pub fn name(&self) -> &str {
+        from_utf8_prefix(&self.name[.. self.name_length])
+    }
+
+    /// True if the section takes up memory while the kernel runs (rather than being debug information and such).
+    pub fn is_allocated(&self) -> bool {
+        self.flags & ELF_SECTION_ALLOCATED != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & ELF_SECTION_WRITABLE != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & ELF_SECTION_EXECUTABLE != 0
+    }
+}
+
+/// How the pixels (or characters) of a framebuffer are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferFormat {
+    /// Each pixel is an index into a palette of the given number of colours.
+    Indexed(u32),
+
+    /// Each pixel holds red, green and blue fields, each given as (position, size) in bits.
+    Rgb { red: (u8, u8), green: (u8, u8), blue: (u8, u8) },
+
+    /// An EGA text mode screen, of characters and their attributes.
+    Text,
+
+    /// A type we don't know.
+    Unknown(u8)
+}
+
+/// The framebuffer the boot loader set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    /// The physical address of the framebuffer.
+    pub address: u64,
+
+    /// The number of bytes in a row.
+    pub pitch: u32,
+
+    /// The size of the screen, in pixels (or characters, for text mode).
+    pub width: u32,
+    pub height: u32,
+
+    pub bits_per_pixel: u8,
+    pub format: FramebufferFormat
+}
+
+/// Everything the boot loader told us, in memory the kernel owns.
+pub struct BootInfo {
+    /// The physical addresses of the start of the boot information, and of the first byte past it's end.
+    info_start: u64,
+    info_end: u64,
+
+    memory_areas: [MemoryArea; MAX_MEMORY_AREAS],
+    memory_area_count: usize,
+
+    command_line: [u8; MAX_COMMAND_LINE],
+    command_line_length: usize,
+
+    loader_name: [u8; MAX_LOADER_NAME],
+    loader_name_length: usize,
+
+    modules: [Module; MAX_MODULES],
+    module_count: usize,
+
+    /// The number of module tags, including any past the ones kept in modules.
+    module_tag_count: usize,
+
+    elf_sections: [ElfSection; MAX_ELF_SECTIONS],
+    elf_section_count: usize,
+
+    framebuffer: Option<Framebuffer>,
+
+    /// The copies of the ACPI 1.0 RSDP and the ACPI 2.0+ XSDP, and their lengths.
+    old_rsdp: [u8; MAX_RSDP],
+    old_rsdp_length: usize,
+    new_rsdp: [u8; MAX_RSDP],
+    new_rsdp_length: usize
+}
+
+/// The boot information, copied by init().
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+/// Copies everything out of the multiboot2 boot information at the given address, which can be reused once this
+/// returns.
+/// UNSAFE: The address must point to a valid (mapped) multiboot2 boot information structure, as well as the section
+/// name table it's ELF sections tag points to.
+pub unsafe fn init(info: *const u8) -> &'static BootInfo {
+    BOOT_INFO.call_once(|| BootInfo::new(info))
+}
+
+/// The boot information copied by init(), if it's been called.
+pub fn get() -> Option<&'static BootInfo> {
+    BOOT_INFO.try()
+}
+
+/// The fixed part of the memory map tag, after the tag header.
+#[repr(packed)]
+struct MemoryMapHeader {
+    entry_size: u32,
+    _entry_version: u32
+}
+
+/// An entry of the memory map tag.
+#[repr(packed)]
+struct MemoryMapEntry {
+    base: u64,
+    length: u64,
+    area_type: u32,
+    _reserved: u32
+}
+
+/// The fixed part of the ELF sections tag, after the tag header.
+#[repr(packed)]
+struct ElfSectionsHeader {
+    count: u32,
+    entry_size: u32,
+    name_table_index: u32
+}
+
+/// An ELF64 section header, as copied into the ELF sections tag.
+#[repr(packed)]
+struct SectionHeader {
+    name_offset: u32,
+    section_type: u32,
+    flags: u64,
+    address: u64,
+    _offset: u64,
+    size: u64,
+    _link: u32,
+    _info: u32,
+    _alignment: u64,
+    _entry_size: u64
+}
+
+/// The fixed part of the module tag, after the tag header; the name follows.
+#[repr(packed)]
+struct ModuleHeader {
+    start: u32,
+    end: u32
+}
+
+/// The fixed part of the framebuffer tag, after the tag header; the colour information follows.
+#[repr(packed)]
+struct FramebufferHeader {
+    address: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bits_per_pixel: u8,
+    framebuffer_type: u8,
+    _reserved: u16
+}
+
+impl BootInfo {
+    /// Copies everything out of the multiboot2 boot information at the given address.
+    /// UNSAFE: As for init().
+    pub unsafe fn new(info: *const u8) -> BootInfo {
+        let header = &*(info as *const multiboot::InfoHeader);
+        let empty_module = Module { start: 0, end: 0, name: [0; MAX_MODULE_NAME], name_length: 0 };
+        let empty_section = ElfSection { start: 0, end: 0, flags: 0, name: [0; MAX_SECTION_NAME], name_length: 0 };
+
+        let mut boot_info = BootInfo {
+            info_start: info as u64,
+            info_end: info as u64 + header.total_size as u64,
+            memory_areas: [MemoryArea { base: 0, length: 0, kind: MemoryKind::Reserved(0) }; MAX_MEMORY_AREAS],
+            memory_area_count: 0,
+            command_line: [0; MAX_COMMAND_LINE],
+            command_line_length: 0,
+            loader_name: [0; MAX_LOADER_NAME],
+            loader_name_length: 0,
+            modules: [empty_module; MAX_MODULES],
+            module_count: 0,
+            module_tag_count: 0,
+            elf_sections: [empty_section; MAX_ELF_SECTIONS],
+            elf_section_count: 0,
+            framebuffer: None,
+            old_rsdp: [0; MAX_RSDP],
+            old_rsdp_length: 0,
+            new_rsdp: [0; MAX_RSDP],
+            new_rsdp_length: 0
+        };
+
+        if let Some(line) = multiboot::command_line(info) {
+            boot_info.command_line_length = copy_prefix(line.as_bytes(), &mut boot_info.command_line);
+        }
+
+        let multiboot = multiboot2::load(info as usize);
+
+        if let Some(tag) = multiboot.boot_loader_name_tag() {
+            let name = tag.name().trim_end_matches('\0');
+            boot_info.loader_name_length = copy_prefix(name.as_bytes(), &mut boot_info.loader_name);
+        }
+
+        for (slot, tag) in boot_info.modules.iter_mut().zip(multiboot.module_tags()) {
+            slot.start = tag.start_address() as u64;
+            slot.end = tag.end_address() as u64;
+            slot.name_length = copy_prefix(tag.name().trim_end_matches('\0').as_bytes(), &mut slot.name);
+            boot_info.module_count += 1;
+        }
+
+        for tag in multiboot::tags(info) {
+            match tag.tag_type {
+                TAG_MODULE => boot_info.module_tag_count += 1,
+                TAG_MEMORY_MAP => boot_info.read_memory_map(tag),
+                TAG_ELF_SECTIONS => boot_info.read_elf_sections(tag),
+                TAG_FRAMEBUFFER => boot_info.framebuffer = read_framebuffer(tag),
+                multiboot::TAG_ACPI_OLD_RSDP => {
+                    boot_info.old_rsdp_length = copy_prefix(tag_bytes(tag), &mut boot_info.old_rsdp);
+                },
+                multiboot::TAG_ACPI_NEW_RSDP => {
+                    boot_info.new_rsdp_length = copy_prefix(tag_bytes(tag), &mut boot_info.new_rsdp);
+                },
+                _ => {}
+            }
+        }
+
+        boot_info
+    }
+
+    /// Copies the entries of the memory map tag.
+    unsafe fn read_memory_map(&mut self, tag: &TagHeader) {
+        if tag.data_size() < mem::size_of::<MemoryMapHeader>() { return; }
+
+        let header = &*(tag.data() as *const MemoryMapHeader);
+        let entry_size = header.entry_size as usize;
+
+        // Later versions may make the entries longer, but never shorter.
+        if entry_size < mem::size_of::<MemoryMapEntry>() { return; }
+
+        let count = (tag.data_size() - mem::size_of::<MemoryMapHeader>()) / entry_size;
+        let entries = tag.data().offset(mem::size_of::<MemoryMapHeader>() as isize);
+
+        for index in 0 .. count {
+            if self.memory_area_count == MAX_MEMORY_AREAS { break; }
+
+            let entry = &*(entries.offset((index * entry_size) as isize) as *const MemoryMapEntry);
+
+            self.memory_areas[self.memory_area_count] = MemoryArea {
+                base: entry.base,
+                length: entry.length,
+                kind: MemoryKind::from_type(entry.area_type)
+            };
+            self.memory_area_count += 1;
+        }
+    }
+
+    /// Copies the section headers of the ELF sections tag, along with their names (if the name table was loaded).
+    unsafe fn read_elf_sections(&mut self, tag: &TagHeader) {
+        if tag.data_size() < mem::size_of::<ElfSectionsHeader>() { return; }
+
+        let header = &*(tag.data() as *const ElfSectionsHeader);
+        let entry_size = header.entry_size as usize;
+        if entry_size < mem::size_of::<SectionHeader>() { return; }
+
+        let count = cmp::min(header.count as usize,
+            (tag.data_size() - mem::size_of::<ElfSectionsHeader>()) / entry_size);
+        let headers = tag.data().offset(mem::size_of::<ElfSectionsHeader>() as isize);
+        let section = |index: usize| &*(headers.offset((index * entry_size) as isize) as *const SectionHeader);
+
+        // The loader puts the name table somewhere in memory, like any other section, and tells us where.
+        let names = if (header.name_table_index as usize) < count {
+            let table = section(header.name_table_index as usize);
+            if table.address != 0 { Some(slice::from_raw_parts(table.address as *const u8, table.size as usize)) }
+            else { None }
+        } else {
+            None
+        };
+
+        for index in 0 .. count {
+            if self.elf_section_count == MAX_ELF_SECTIONS { break; }
+
+            let header = section(index);
+            if header.section_type == ELF_SECTION_NULL { continue; }
+
+            let slot = &mut self.elf_sections[self.elf_section_count];
+            slot.start = header.address;
+            slot.end = header.address.saturating_add(header.size);
+            slot.flags = header.flags;
+
+            if let Some(names) = names {
+                let name = names.get(header.name_offset as usize ..).unwrap_or(&[]);
+                let length = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+
+                slot.name_length = copy_prefix(&name[.. length], &mut slot.name);
+            }
+
+            self.elf_section_count += 1;
+        }
+    }
+
+    /// The physical addresses of the start of the boot information, and of the first byte past it's end; it can be
+    /// reused, as everything has been copied out of it.
+    pub fn info_region(&self) -> (u64, u64) {
+        (self.info_start, self.info_end)
+    }
+
+    /// Every area of the memory map, of every kind.
+    pub fn memory_areas(&self) -> &[MemoryArea] {
+        &self.memory_areas[.. self.memory_area_count]
+    }
+
+    /// The total size of the available memory.
+    pub fn available_memory(&self) -> u64 {
+        self.memory_areas().iter().filter(|area| area.kind == MemoryKind::Available).map(|area| area.length).sum()
+    }
+
+    /// The kernel command line; empty if the loader didn't give us one.
+    pub fn command_line(&self) -> &str {
+        from_utf8_prefix(&self.command_line[.. self.command_line_length])
+    }
+
+    /// The name of the boot loader, if it gave us one.
+    pub fn loader_name(&self) -> Option<&str> {
+        if self.loader_name_length == 0 { return None; }
+
+        Some(from_utf8_prefix(&self.loader_name[.. self.loader_name_length]))
+    }
+
+    /// The modules loaded alongside the kernel; only the first MAX_MODULES are kept.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules[.. self.module_count]
+    }
+
+    /// The number of modules past the first MAX_MODULES, which modules() leaves out.
+    pub fn dropped_modules(&self) -> usize {
+        self.module_tag_count.saturating_sub(self.module_count)
+    }
+
+    /// The physical ranges of every module, including the ones modules() leaves out, read from the module tags.
+    /// UNSAFE: The boot information must still be where the loader left it, so not yet reused or unmapped.
+    pub unsafe fn module_ranges(&self) -> impl Iterator<Item = (u64, u64)> {
+        multiboot::tags(self.info_start as *const u8)
+            .filter(|tag| tag.tag_type == TAG_MODULE && tag.data_size() >= mem::size_of::<ModuleHeader>())
+            .map(|tag| {
+                let header = &*(tag.data() as *const ModuleHeader);
+                (header.start as u64, header.end as u64)
+            })
+    }
+
+    /// The kernel's ELF sections, without the null section.
+    pub fn elf_sections(&self) -> &[ElfSection] {
+        &self.elf_sections[.. self.elf_section_count]
+    }
+
+    /// The framebuffer, if the loader set one up.
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        self.framebuffer
+    }
+
+    /// The copy of the ACPI 2.0+ XSDP the loader gave us, if any.
+    pub fn new_rsdp(&self) -> Option<&[u8]> {
+        if self.new_rsdp_length == 0 { None } else { Some(&self.new_rsdp[.. self.new_rsdp_length]) }
+    }
+
+    /// The copy of the ACPI 1.0 RSDP the loader gave us, if any.
+    pub fn old_rsdp(&self) -> Option<&[u8]> {
+        if self.old_rsdp_length == 0 { None } else { Some(&self.old_rsdp[.. self.old_rsdp_length]) }
+    }
+}
+
+/// Reads the framebuffer tag, if it's long enough to hold what it's type says it does.
+unsafe fn read_framebuffer(tag: &TagHeader) -> Option<Framebuffer> {
+    if tag.data_size() < mem::size_of::<FramebufferHeader>() { return None; }
+
+    let header = &*(tag.data() as *const FramebufferHeader);
+    let colour_info = tag_bytes(tag).get(mem::size_of::<FramebufferHeader>() ..).unwrap_or(&[]);
+
+    let format = match header.framebuffer_type {
+        0 if colour_info.len() >= 4 => {
+            FramebufferFormat::Indexed(ptr::read_unaligned(colour_info.as_ptr() as *const u32))
+        },
+        1 if colour_info.len() >= 6 => FramebufferFormat::Rgb {
+            red: (colour_info[0], colour_info[1]),
+            green: (colour_info[2], colour_info[3]),
+            blue: (colour_info[4], colour_info[5])
+        },
+        2 => FramebufferFormat::Text,
+        0 | 1 => return None,
+        other => FramebufferFormat::Unknown(other)
+    };
+
+    Some(Framebuffer {
+        address: header.address,
+        pitch: header.pitch,
+        width: header.width,
+        height: header.height,
+        bits_per_pixel: header.bits_per_pixel,
+        format: format
+    })
+}
+
+/// The data of a tag, as bytes.
+unsafe fn tag_bytes(tag: &TagHeader) -> &[u8] {
+    slice::from_raw_parts(tag.data(), tag.data_size())
+}
+
+/// Copies as much of the source as fits into the destination, returning the number of bytes copied.
+fn copy_prefix(source: &[u8], destination: &mut [u8]) -> usize {
+    let length = cmp::min(source.len(), destination.len());
+    destination[.. length].copy_from_slice(&source[.. length]);
+
+    length
+}
+
+/// The longest prefix of the bytes which is valid UTF-8; a copy cut short can end part way through a character.
+fn from_utf8_prefix(bytes: &[u8]) -> &str {
+    match str::from_utf8(bytes) {
+        Ok(string) => string,
+
+        // UNSAFE: Safe, as the error says everything up to there is valid.
+        Err(error) => unsafe { str::from_utf8_unchecked(&bytes[.. error.valid_up_to()]) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use acpi::test_support::little_endian;
+    use super::*;
+
+    /// Appends a tag with the given type and data, padded out to 8 bytes.
+    fn tag(bytes: &mut Vec<u8>, tag_type: u32, data: &[u8]) {
+        bytes.extend_from_slice(&little_endian(tag_type as u64, 4));
+        bytes.extend_from_slice(&little_endian(8 + data.len() as u64, 4));
+        bytes.extend_from_slice(data);
+
+        while bytes.len() % 8 != 0 { bytes.push(0); }
+    }
+
+    /// Builds boot information like GRUB's for a QEMU machine with 512MiB and a module, with the section name table
+    /// at the given address.
+    fn grub_boot_info(name_table: u64) -> Vec<u8> {
+        let mut bytes = vec![0; 8];
+
+        tag(&mut bytes, multiboot::TAG_COMMAND_LINE, b"acpi.dump log=serial\0");
+        tag(&mut bytes, 2, b"GRUB 2.06\0");
+
+        let mut module = little_endian(0x112000, 4);
+        module.extend_from_slice(&little_endian(0x113400, 4));
+        module.extend_from_slice(b"initrd.img\0");
+        tag(&mut bytes, TAG_MODULE, &module);
+
+        let mut map = little_endian(24, 4);
+        map.extend_from_slice(&little_endian(0, 4));
+        for &(base, length, area_type) in &[(0, 0x9FC00, 1), (0x9FC00, 0x400, 2), (0x100000, 0x1FEE0000, 1),
+            (0x1FFE0000, 0x20000, 2), (0xFFFC0000, 0x40000, 2)] {
+
+            map.extend_from_slice(&little_endian(base, 8));
+            map.extend_from_slice(&little_endian(length, 8));
+            map.extend_from_slice(&little_endian(area_type, 4));
+            map.extend_from_slice(&[0; 4]);
+        }
+        tag(&mut bytes, TAG_MEMORY_MAP, &map);
+
+        // The null section, .text, .data and the section name table.
+        let mut sections = little_endian(4, 4);
+        sections.extend_from_slice(&little_endian(64, 4));
+        sections.extend_from_slice(&little_endian(3, 4));
+        for &(name, section_type, flags, address, size) in &[(0, 0, 0, 0, 0),
+            (1, 1, ELF_SECTION_ALLOCATED | ELF_SECTION_EXECUTABLE, 0xFFFFE00000100000, 0x5000),
+            (7, 1, ELF_SECTION_ALLOCATED | ELF_SECTION_WRITABLE, 0xFFFFE00000105000, 0x1000),
+            (13, 3, 0, name_table, 23)] {
+
+            sections.extend_from_slice(&little_endian(name, 4));
+            sections.extend_from_slice(&little_endian(section_type, 4));
+            sections.extend_from_slice(&little_endian(flags, 8));
+            sections.extend_from_slice(&little_endian(address, 8));
+            sections.extend_from_slice(&[0; 8]);
+            sections.extend_from_slice(&little_endian(size, 8));
+            sections.extend_from_slice(&[0; 24]);
+        }
+        tag(&mut bytes, TAG_ELF_SECTIONS, &sections);
+
+        let mut framebuffer = little_endian(0xB8000, 8);
+        framebuffer.extend_from_slice(&little_endian(160, 4));
+        framebuffer.extend_from_slice(&little_endian(80, 4));
+        framebuffer.extend_from_slice(&little_endian(25, 4));
+        framebuffer.extend_from_slice(&[16, 2, 0, 0]);
+        tag(&mut bytes, TAG_FRAMEBUFFER, &framebuffer);
+
+        let mut rsdp = b"RSD PTR ".to_vec();
+        rsdp.extend_from_slice(&[0; 12]);
+        tag(&mut bytes, multiboot::TAG_ACPI_OLD_RSDP, &rsdp);
+
+        tag(&mut bytes, multiboot::TAG_END, &[]);
+
+        let length = bytes.len() as u64;
+        bytes[.. 4].copy_from_slice(&little_endian(length, 4));
+        bytes
+    }
+
+    /// Copies the bytes into 8-byte aligned memory, as the boot information always is.
+    fn aligned(bytes: &[u8]) -> Vec<u64> {
+        let mut words = vec![0u64; (bytes.len() + 7) / 8];
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), words.as_mut_ptr() as *mut u8, bytes.len()); }
+
+        words
+    }
+
+    #[test]
+    fn copies_the_boot_information() {
+        let names = b"\0.text\0.data\0.shstrtab\0";
+        let info = aligned(&grub_boot_info(names.as_ptr() as u64));
+        let boot_info = unsafe { BootInfo::new(info.as_ptr() as *const u8) };
+
+        assert_eq!(boot_info.command_line(), "acpi.dump log=serial");
+        assert_eq!(boot_info.loader_name(), Some("GRUB 2.06"));
+
+        assert_eq!(boot_info.modules().len(), 1);
+        assert_eq!((boot_info.modules()[0].start, boot_info.modules()[0].end), (0x112000, 0x113400));
+        assert_eq!(boot_info.modules()[0].name(), "initrd.img");
+
+        assert_eq!(boot_info.memory_areas().len(), 5);
+        assert_eq!(boot_info.memory_areas()[1],
+            MemoryArea { base: 0x9FC00, length: 0x400, kind: MemoryKind::Reserved(2) });
+        assert_eq!(boot_info.available_memory(), 0x9FC00 + 0x1FEE0000);
+
+        let sections: Vec<(&str, u64, bool, bool)> = boot_info.elf_sections().iter()
+            .map(|section| (section.name(), section.start, section.is_writable(), section.is_executable())).collect();
+        assert_eq!(sections, vec![(".text", 0xFFFFE00000100000, false, true),
+            (".data", 0xFFFFE00000105000, true, false), (".shstrtab", names.as_ptr() as u64, false, false)]);
+        assert!(!boot_info.elf_sections()[2].is_allocated());
+
+        assert_eq!(boot_info.framebuffer(), Some(Framebuffer {
+            address: 0xB8000, pitch: 160, width: 80, height: 25, bits_per_pixel: 16, format: FramebufferFormat::Text
+        }));
+
+        assert_eq!(&boot_info.old_rsdp().unwrap()[.. 8], b"RSD PTR ");
+        assert_eq!(boot_info.new_rsdp(), None);
+    }
+
+    #[test]
+    fn keeps_track_of_every_module() {
+        let mut bytes = vec![0; 8];
+
+        for index in 0 .. MAX_MODULES as u64 + 2 {
+            let mut module = little_endian(0x200000 + index * 0x1000, 4);
+            module.extend_from_slice(&little_endian(0x200800 + index * 0x1000, 4));
+            module.extend_from_slice(b"module\0");
+            tag(&mut bytes, TAG_MODULE, &module);
+        }
+
+        tag(&mut bytes, multiboot::TAG_END, &[]);
+
+        let length = bytes.len() as u64;
+        bytes[.. 4].copy_from_slice(&little_endian(length, 4));
+
+        let info = aligned(&bytes);
+        let boot_info = unsafe { BootInfo::new(info.as_ptr() as *const u8) };
+
+        assert_eq!(boot_info.modules().len(), MAX_MODULES);
+        assert_eq!(boot_info.dropped_modules(), 2);
+
+        let ranges: Vec<(u64, u64)> = unsafe { boot_info.module_ranges() }.collect();
+        assert_eq!(ranges.len(), MAX_MODULES + 2);
+        assert_eq!(ranges[MAX_MODULES + 1], (0x209000, 0x209800));
+    }
+
+    #[test]
+    fn cuts_long_strings_short() {
+        assert_eq!(from_utf8_prefix("añb".as_bytes()), "añb");
+        assert_eq!(from_utf8_prefix(&"añb".as_bytes()[.. 2]), "a");
+
+        let mut destination = [0; 4];
+        assert_eq!(copy_prefix(b"console", &mut destination), 4);
+        assert_eq!(&destination, b"cons");
+    }
+}
@@ -0,0 +1,258 @@
+//! Provides the options given on the kernel command line (the rest of the `multiboot2` line in grub.cfg, or
+//! whatever was typed in at the GRUB menu). Options are whitespace separated, and are either flags (`acpi.dump`)
+//! or `key=value` pairs (`console=serial`); the ones the kernel knows are parsed into typed settings, and every
+//! option can be looked up by name, so subsystems can have options of their own without changes here.
+//!
+//! The options the kernel knows:
+//!
+//! - `console=vga|serial`: where output goes; can be given more than once to use both (the default is `vga`).
+//! - `log=error|warn|info|debug`: how much to print while starting up (the default is `info`; `debug` adds the
+//!   memory map and the kernel's sections).
+//! - `acpi=off`: ignore the ACPI tables, and everything found through them.
+//! - `maxcpus=N`: the most processors to use, once the others are started.
+//! - `test`: power the machine off once initialization is done, like building with the test-mode feature.
+//! - `acpi.dump`: print everything in the ACPI tables, rather than just where they are.
+//! - `acpi.suspend`: suspend to RAM once initialization is done.
+
+use spin::Once;
+
+/// The most options we keep, and the most bad ones we keep to report.
+pub const MAX_OPTIONS: usize = 32;
+pub const MAX_REJECTED: usize = 8;
+
+/// How much gets printed while starting up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Only failures.
+    Error,
+
+    /// Failures, and things which look wrong but can be carried on past.
+    Warning,
+
+    /// What was found and set up, without the details.
+    Info,
+
+    /// Everything, like every processor and I/O APIC.
+    Debug
+}
+
+/// Where output goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Console {
+    pub vga: bool,
+    pub serial: bool
+}
+
+/// The options from a command line.
+#[derive(Debug)]
+pub struct Options<'a> {
+    /// Every option, as it's name and (for key=value options) value, in the order given.
+    options: [(&'a str, Option<&'a str>); MAX_OPTIONS],
+    option_count: usize,
+
+    /// The options the kernel knows, but which had a value it doesn't understand.
+    rejected: [&'a str; MAX_REJECTED],
+    rejected_count: usize,
+
+    pub log_level: LogLevel,
+    pub console: Console,
+    pub acpi: bool,
+    pub max_cpus: Option<usize>,
+    pub test_mode: bool
+}
+
+impl<'a> Options<'a> {
+    /// Parses a command line. Options the kernel knows but which have a bad value keep their defaults, and are
+    /// listed by rejected().
+    pub fn parse(line: &'a str) -> Options<'a> {
+        let mut options = Options {
+            options: [("", None); MAX_OPTIONS],
+            option_count: 0,
+            rejected: [""; MAX_REJECTED],
+            rejected_count: 0,
+            log_level: LogLevel::Info,
+            console: Console { vga: true, serial: false },
+            acpi: true,
+            max_cpus: None,
+            test_mode: cfg!(feature = "test-mode")
+        };
+
+        // The first console option replaces the default, and later ones add to it.
+        let mut console = None;
+
+        for word in line.split_whitespace() {
+            let (name, value) = match word.find('=') {
+                Some(index) => (&word[.. index], Some(&word[index + 1 ..])),
+                None => (word, None)
+            };
+
+            if options.option_count < MAX_OPTIONS {
+                options.options[options.option_count] = (name, value);
+                options.option_count += 1;
+            }
+
+            let known = match (name, value) {
+                ("console", Some(name @ "vga")) | ("console", Some(name @ "serial")) => {
+                    let mut selected = console.unwrap_or(Console { vga: false, serial: false });
+
+                    if name == "vga" { selected.vga = true; } else { selected.serial = true; }
+                    console = Some(selected);
+                    true
+                },
+                ("log", Some(level)) => match parse_log_level(level) {
+                    Some(level) => { options.log_level = level; true },
+                    None => false
+                },
+                ("acpi", Some("off")) => { options.acpi = false; true },
+                ("acpi", Some("on")) => { options.acpi = true; true },
+                ("maxcpus", Some(count)) => match count.parse() {
+                    Ok(count) if count > 0 => { options.max_cpus = Some(count); true },
+                    _ => false
+                },
+                ("test", None) => { options.test_mode = true; true },
+                ("console", _) | ("log", _) | ("acpi", _) | ("maxcpus", _) | ("test", _) => false,
+
+                // Anything else belongs to someone else, who can look it up.
+                _ => true
+            };
+
+            if !known && options.rejected_count < MAX_REJECTED {
+                options.rejected[options.rejected_count] = word;
+                options.rejected_count += 1;
+            }
+        }
+
+        if let Some(console) = console {
+            options.console = console;
+        }
+
+        options
+    }
+
+    /// True if the given flag (or a key=value option with the given key) was given.
+    pub fn flag(&self, name: &str) -> bool {
+        self.options().iter().any(|&(option, _)| option == name)
+    }
+
+    /// The value of the last key=value option with the given key.
+    pub fn value(&self, name: &str) -> Option<&'a str> {
+        self.options().iter().rev().filter(|&&(option, _)| option == name).filter_map(|&(_, value)| value).next()
+    }
+
+    /// Every option, as it's name and (for key=value options) value, in the order given.
+    pub fn options(&self) -> &[(&'a str, Option<&'a str>)] {
+        &self.options[.. self.option_count]
+    }
+
+    /// The options the kernel knows, but which had a value it doesn't understand.
+    pub fn rejected(&self) -> &[&'a str] {
+        &self.rejected[.. self.rejected_count]
+    }
+}
+
+/// Parses a log level, by name.
+fn parse_log_level(level: &str) -> Option<LogLevel> {
+    match level {
+        "error" => Some(LogLevel::Error),
+        "warn" | "warning" => Some(LogLevel::Warning),
+        "info" => Some(LogLevel::Info),
+        "debug" => Some(LogLevel::Debug),
+        _ => None
+    }
+}
+
+/// The options, parsed by init().
+static OPTIONS: Once<Options<'static>> = Once::new();
+
+/// Parses the kernel command line, so the options can be looked up from then on.
+pub fn init(line: &'static str) -> &'static Options<'static> {
+    OPTIONS.call_once(|| Options::parse(line))
+}
+
+/// The options parsed by init(); before then, there are none, and everything has it's default.
+fn options() -> Option<&'static Options<'static>> {
+    OPTIONS.try()
+}
+
+/// True if the given flag (or a key=value option with the given key) was given.
+pub fn flag(name: &str) -> bool {
+    options().map(|options| options.flag(name)).unwrap_or(false)
+}
+
+/// The value of the last key=value option with the given key.
+pub fn value(name: &str) -> Option<&'static str> {
+    options().and_then(|options| options.value(name))
+}
+
+/// How much gets printed while starting up.
+pub fn log_level() -> LogLevel {
+    options().map(|options| options.log_level).unwrap_or(LogLevel::Info)
+}
+
+/// True if messages at the given level should be printed.
+pub fn logs(level: LogLevel) -> bool {
+    level <= log_level()
+}
+
+/// Where output goes.
+pub fn console() -> Console {
+    options().map(|options| options.console).unwrap_or(Console { vga: true, serial: false })
+}
+
+/// False if the ACPI tables should be ignored.
+pub fn acpi_enabled() -> bool {
+    options().map(|options| options.acpi).unwrap_or(true)
+}
+
+/// The most processors to use, if it's limited.
+pub fn max_cpus() -> Option<usize> {
+    options().and_then(|options| options.max_cpus)
+}
+
+/// True if the machine should be powered off once initialization is done.
+pub fn test_mode() -> bool {
+    options().map(|options| options.test_mode).unwrap_or(cfg!(feature = "test-mode"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_options() {
+        let options = Options::parse("  console=serial log=debug acpi=off maxcpus=2 test acpi.dump ");
+
+        assert_eq!(options.console, Console { vga: false, serial: true });
+        assert_eq!(options.log_level, LogLevel::Debug);
+        assert!(!options.acpi);
+        assert_eq!(options.max_cpus, Some(2));
+        assert!(options.test_mode);
+        assert!(options.flag("acpi.dump"));
+        assert!(!options.flag("acpi.suspend"));
+        assert_eq!(options.options().len(), 6);
+        assert!(options.rejected().is_empty());
+    }
+
+    #[test]
+    fn keeps_defaults_for_bad_values() {
+        let options = Options::parse("log=loud maxcpus=0 console=serial console=vga acpi test=yes");
+
+        assert_eq!(options.log_level, LogLevel::Info);
+        assert_eq!(options.max_cpus, None);
+        assert_eq!(options.console, Console { vga: true, serial: true });
+        assert!(options.acpi);
+        assert_eq!(options.rejected(), &["log=loud", "maxcpus=0", "acpi", "test=yes"]);
+    }
+
+    #[test]
+    fn looks_up_other_options() {
+        let options = Options::parse("root=/dev/sda1 quiet=1 root=/dev/sdb1 debug");
+
+        assert_eq!(options.value("root"), Some("/dev/sdb1"));
+        assert_eq!(options.value("debug"), None);
+        assert!(options.flag("debug"));
+        assert!(options.flag("quiet"));
+        assert_eq!(options.value("missing"), None);
+        assert!(options.rejected().is_empty());
+    }
+}
@@ -0,0 +1,457 @@
+//! Provides a driver for the High Precision Event Timer: a monotonic clock with nanosecond resolution,
+//! driven by the HPET's main counter, and one-shot timers driven by it's comparators.
+//!
+//! The comparators are routed to I/O APIC inputs, which are left masked; to take the interrupt, allocate a
+//! vector with interrupts::allocate() and route the input to it with apic::route(). Otherwise, timers can be
+//! polled with OneShotTimer::has_expired().
+
+use spin::{Mutex, Once};
+
+use acpi::{ACPI, HPET, ADDRESS_SPACE_SYSTEM_MEMORY};
+use memory::mmio::{self, Mmio};
+use memory::paging::MemoryType;
+
+/// The offsets of the general registers.
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+/// The offsets of the first comparator's registers; each comparator has 0x20 bytes of registers.
+const COMPARATOR_CONFIGURATION: u64 = 0x100;
+const COMPARATOR_VALUE: u64 = 0x108;
+const COMPARATOR_STRIDE: u64 = 0x20;
+
+/// The size of the register block.
+const REGISTERS_SIZE: u64 = 0x400;
+
+/// The general capabilities bits.
+const CAPABILITY_COUNTER_64_BIT: u64 = 1 << 13;
+
+/// The general configuration bits: whether the main counter runs, and whether comparators 0 and 1 have taken
+/// over the PIT and RTC interrupts.
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+/// The comparator configuration bits.
+const COMPARATOR_LEVEL_TRIGGERED: u64 = 1 << 1;
+const COMPARATOR_INTERRUPT_ENABLE: u64 = 1 << 2;
+const COMPARATOR_PERIODIC: u64 = 1 << 3;
+const COMPARATOR_32_BIT_MODE: u64 = 1 << 8;
+const COMPARATOR_ROUTE_SHIFT: u64 = 9;
+const COMPARATOR_ROUTE_MASK: u64 = 0x1F << COMPARATOR_ROUTE_SHIFT;
+const COMPARATOR_FSB_ENABLE: u64 = 1 << 14;
+
+/// The largest tick period the specification allows (100ns), in femtoseconds.
+const MAX_PERIOD: u64 = 100_000_000;
+
+/// The number of femtoseconds in a nanosecond.
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+/// The number of femtoseconds in a second.
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// How many times to read the main counter while waiting for it to move, after enabling it.
+const START_ATTEMPTS: usize = 1_000_000;
+
+/// The most comparators an HPET can have.
+const MAX_COMPARATORS: usize = 32;
+
+/// The lowest I/O APIC input we route comparators to when we have the choice, as the ones below are the
+/// ISA IRQs.
+const FIRST_NON_ISA_INTERRUPT: u32 = 16;
+
+/// The reasons the HPET can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// There's no HPET table, or init() hasn't been called.
+    NotPresent,
+
+    /// The registers are in an address space other than system memory.
+    UnsupportedAddressSpace(u8),
+
+    /// The registers (at the given physical address) couldn't be mapped.
+    NotMapped(u64),
+
+    /// The period in the capabilities register is outside of what the specification allows.
+    InvalidPeriod(u64),
+
+    /// The main counter didn't move after it was enabled.
+    NotCounting,
+
+    /// Every comparator which can interrupt through the I/O APIC is already in use.
+    NoFreeComparator
+}
+
+/// The HPET, as found by init().
+#[derive(Debug)]
+struct Hpet {
+    /// The registers, mapped uncached.
+    registers: Mmio,
+
+    /// The length of a tick, in femtoseconds.
+    period: u64,
+
+    /// The number of comparators.
+    comparators: u8,
+
+    /// True if the main counter is 64 bits wide.
+    wide_counter: bool
+}
+
+/// The HPET, filled in by init().
+static HPET_DEVICE: Once<Hpet> = Once::new();
+
+/// For HPETs with 32-bit counters: the last value read from the counter, and the number of ticks counted
+/// in the wraps before that.
+static COUNTER_EXTENSION: Mutex<(u32, u64)> = Mutex::new((0, 0));
+
+/// A bitmask of the comparators handed out as OneShotTimers.
+static COMPARATORS_IN_USE: Mutex<u32> = Mutex::new(0);
+
+/// The main counter, and each comparator's configuration and value, saved by suspend() as they're lost in sleep
+/// states.
+static SAVED_STATE: Mutex<(u64, [(u64, u64); MAX_COMPARATORS])> = Mutex::new((0, [(0, 0); MAX_COMPARATORS]));
+
+/// Finds the HPET, reads and checks it's period, and starts the main counter.
+/// UNSAFE: Programs the HPET; nothing else may be using it.
+pub unsafe fn init(acpi: &ACPI) -> Result<(), HpetError> {
+    let table = acpi.find_table::<HPET>().ok_or(HpetError::NotPresent)?;
+    let address = table.base_address;
+
+    if address.address_space != ADDRESS_SPACE_SYSTEM_MEMORY {
+        return Err(HpetError::UnsupportedAddressSpace(address.address_space));
+    }
+
+    if address.address == 0 { return Err(HpetError::NotMapped(0)); }
+
+    let registers = mmio::ioremap(address.address, REGISTERS_SIZE, MemoryType::Uncached)
+        .map_err(|_| HpetError::NotMapped(address.address))?;
+    let capabilities = read_register(&registers, GENERAL_CAPABILITIES);
+    let period = capabilities >> 32;
+
+    if period == 0 || period > MAX_PERIOD {
+        return Err(HpetError::InvalidPeriod(period));
+    }
+
+    let hpet = Hpet {
+        registers: registers,
+        period: period,
+        comparators: ((capabilities >> 8) & 0x1F) as u8 + 1,
+        wide_counter: capabilities & CAPABILITY_COUNTER_64_BIT != 0
+    };
+
+    // Nobody is listening for the firmware's interrupts, so turn all of the comparators off, and leave the
+    // PIT and RTC with their own interrupts.
+    for comparator in 0 .. hpet.comparators {
+        let offset = COMPARATOR_CONFIGURATION + comparator as u64 * COMPARATOR_STRIDE;
+        let configuration = read_register(&registers, offset);
+
+        write_register(&registers, offset, configuration & !(COMPARATOR_INTERRUPT_ENABLE | COMPARATOR_FSB_ENABLE));
+    }
+
+    let configuration = read_register(&registers, GENERAL_CONFIGURATION) & !CONFIGURATION_LEGACY_REPLACEMENT;
+    write_register(&registers, GENERAL_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+
+    // Make sure the counter is really running before anything relies on it.
+    let start = read_register(&registers, MAIN_COUNTER);
+    if !(0 .. START_ATTEMPTS).any(|_| read_register(&registers, MAIN_COUNTER) != start) {
+        return Err(HpetError::NotCounting);
+    }
+
+    HPET_DEVICE.call_once(|| hpet);
+    Ok(())
+}
+
+/// Saves the main counter and the comparators before the machine enters a sleep state. The counter is stopped, so
+/// the clock doesn't count the time the machine spends asleep.
+/// UNSAFE: Nothing may use the HPET until resume() is called.
+pub unsafe fn suspend() {
+    let hpet = match HPET_DEVICE.try() {
+        Some(hpet) => hpet,
+        None => return
+    };
+
+    let configuration = read_register(&hpet.registers, GENERAL_CONFIGURATION);
+    write_register(&hpet.registers, GENERAL_CONFIGURATION, configuration & !CONFIGURATION_ENABLE);
+
+    let mut saved = SAVED_STATE.lock();
+    saved.0 = read_register(&hpet.registers, MAIN_COUNTER);
+
+    for (comparator, state) in saved.1.iter_mut().enumerate().take(hpet.comparators as usize) {
+        let offset = comparator as u64 * COMPARATOR_STRIDE;
+
+        *state = (read_register(&hpet.registers, COMPARATOR_CONFIGURATION + offset),
+            read_register(&hpet.registers, COMPARATOR_VALUE + offset));
+    }
+}
+
+/// Puts back the main counter and comparators saved by suspend(), and starts the counter again, after the machine
+/// wakes up.
+/// UNSAFE: Reprograms the HPET; suspend() must have been called first.
+pub unsafe fn resume() {
+    let hpet = match HPET_DEVICE.try() {
+        Some(hpet) => hpet,
+        None => return
+    };
+
+    let saved = SAVED_STATE.lock();
+
+    // The counter can only be written while it's stopped.
+    let configuration = read_register(&hpet.registers, GENERAL_CONFIGURATION)
+        & !(CONFIGURATION_ENABLE | CONFIGURATION_LEGACY_REPLACEMENT);
+    write_register(&hpet.registers, GENERAL_CONFIGURATION, configuration);
+    write_register(&hpet.registers, MAIN_COUNTER, saved.0);
+
+    for (comparator, &(comparator_configuration, value)) in saved.1.iter().enumerate()
+        .take(hpet.comparators as usize) {
+
+        let offset = comparator as u64 * COMPARATOR_STRIDE;
+
+        write_register(&hpet.registers, COMPARATOR_VALUE + offset, value);
+        write_register(&hpet.registers, COMPARATOR_CONFIGURATION + offset, comparator_configuration);
+    }
+
+    write_register(&hpet.registers, GENERAL_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+}
+
+/// True if init() found a working HPET.
+pub fn is_present() -> bool {
+    HPET_DEVICE.try().is_some()
+}
+
+/// The length of one tick of the main counter, in femtoseconds.
+pub fn period() -> Option<u64> {
+    HPET_DEVICE.try().map(|hpet| hpet.period)
+}
+
+/// The frequency of the main counter, in hertz.
+pub fn frequency() -> Option<u64> {
+    period().map(|period| FEMTOSECONDS_PER_SECOND / period)
+}
+
+/// The number of comparators the HPET has.
+pub fn comparator_count() -> Option<u8> {
+    HPET_DEVICE.try().map(|hpet| hpet.comparators)
+}
+
+/// The number of ticks since the main counter was started (or reset by the firmware).
+pub fn ticks() -> Option<u64> {
+    HPET_DEVICE.try().map(|hpet| hpet.counter())
+}
+
+/// The number of nanoseconds since the main counter was started; this never goes backwards.
+///
+/// If the HPET only has a 32-bit counter, this has to be called at least once per wrap of the counter (which
+/// is every 7 minutes or so at the usual 10MHz) to stay accurate.
+pub fn nanoseconds() -> Option<u64> {
+    HPET_DEVICE.try().map(|hpet| ticks_to_nanoseconds(hpet.counter(), hpet.period))
+}
+
+/// Converts a number of ticks, with the given period (in femtoseconds), into nanoseconds.
+pub fn ticks_to_nanoseconds(ticks: u64, period: u64) -> u64 {
+    scale(ticks, period, FEMTOSECONDS_PER_NANOSECOND)
+}
+
+/// Converts a number of nanoseconds into ticks with the given period (in femtoseconds), rounding down.
+pub fn nanoseconds_to_ticks(nanoseconds: u64, period: u64) -> u64 {
+    scale(nanoseconds, FEMTOSECONDS_PER_NANOSECOND, period)
+}
+
+/// Calculates value * numerator / denominator without overflowing in the middle, as long as the remainder
+/// times the numerator fits (which it does for anything up to 32 bits).
+fn scale(value: u64, numerator: u64, denominator: u64) -> u64 {
+    let quotient = value / denominator;
+    let remainder = value % denominator;
+
+    quotient.wrapping_mul(numerator).wrapping_add(remainder * numerator / denominator)
+}
+
+impl Hpet {
+    /// Reads the main counter, extending it to 64 bits in software if it's only 32 bits wide.
+    fn counter(&self) -> u64 {
+        // UNSAFE: Safe, as reading the counter has no side effects.
+        let value = unsafe { read_register(&self.registers, MAIN_COUNTER) };
+        if self.wide_counter { return value; }
+
+        let mut extension = COUNTER_EXTENSION.lock();
+        let (last, mut wraps) = *extension;
+        let value = value as u32;
+
+        if value < last {
+            wraps += 1 << 32;
+        }
+
+        *extension = (value, wraps);
+        wraps + value as u64
+    }
+}
+
+/// A comparator, set up to fire once when the main counter reaches a deadline. The comparator is returned
+/// (and turned off) when this is dropped.
+#[derive(Debug)]
+pub struct OneShotTimer {
+    /// The index of the comparator.
+    comparator: u8,
+
+    /// The I/O APIC input the comparator's interrupt is routed to.
+    interrupt: u32,
+
+    /// The tick the timer is currently armed for.
+    deadline: Option<u64>
+}
+
+impl OneShotTimer {
+    /// Claims a free comparator which can deliver it's interrupt through the I/O APIC, and routes it to
+    /// one of the inputs it supports (preferring the ones above the ISA IRQs). The timer starts disarmed.
+    pub fn new() -> Result<OneShotTimer, HpetError> {
+        let hpet = HPET_DEVICE.try().ok_or(HpetError::NotPresent)?;
+        let mut in_use = COMPARATORS_IN_USE.lock();
+
+        for comparator in 0 .. hpet.comparators {
+            if *in_use & (1 << comparator) != 0 { continue; }
+
+            let offset = COMPARATOR_CONFIGURATION + comparator as u64 * COMPARATOR_STRIDE;
+
+            // UNSAFE: Safe, as reading the configuration has no side effects.
+            let configuration = unsafe { read_register(&hpet.registers, offset) };
+
+            // The upper half is a bitmask of the I/O APIC inputs the comparator can be routed to.
+            let routes = (configuration >> 32) as u32;
+            let preferred = routes & !((1 << FIRST_NON_ISA_INTERRUPT) - 1);
+            let interrupt = match (preferred, routes) {
+                (0, 0) => continue,
+                (0, routes) => routes.trailing_zeros(),
+                (preferred, _) => preferred.trailing_zeros()
+            };
+
+            // One-shot, edge triggered, not yet enabled, with the full width of the comparator.
+            let configuration = (configuration & !(COMPARATOR_LEVEL_TRIGGERED | COMPARATOR_INTERRUPT_ENABLE
+                | COMPARATOR_PERIODIC | COMPARATOR_32_BIT_MODE | COMPARATOR_ROUTE_MASK | COMPARATOR_FSB_ENABLE))
+                | ((interrupt as u64) << COMPARATOR_ROUTE_SHIFT);
+
+            // UNSAFE: Safe, as we own the comparator now.
+            unsafe { write_register(&hpet.registers, offset, configuration); }
+
+            *in_use |= 1 << comparator;
+            return Ok(OneShotTimer { comparator: comparator, interrupt: interrupt, deadline: None });
+        }
+
+        Err(HpetError::NoFreeComparator)
+    }
+
+    /// The I/O APIC input (the global system interrupt, relative to the I/O APIC's base) the timer's
+    /// interrupt arrives on.
+    pub fn interrupt(&self) -> u32 {
+        self.interrupt
+    }
+
+    /// Arms the timer to fire when the clock reaches the given time (in nanoseconds, as from nanoseconds()).
+    /// Returns false if the deadline had already passed by the time the timer was armed, in which case the
+    /// interrupt may never arrive, and the caller should treat the timer as already having fired.
+    pub fn arm_at(&mut self, deadline: u64) -> bool {
+        let hpet = HPET_DEVICE.try().expect("OneShotTimers can't exist without an HPET");
+        let ticks = nanoseconds_to_ticks(deadline, hpet.period);
+
+        // UNSAFE: Safe, as we own the comparator.
+        unsafe {
+            write_register(&hpet.registers, self.value_offset(), ticks);
+            self.update_configuration(|configuration| configuration | COMPARATOR_INTERRUPT_ENABLE);
+        }
+
+        self.deadline = Some(ticks);
+
+        // The comparator only fires when the counter matches it exactly, so if the counter was already past
+        // the deadline, it won't fire until the counter wraps.
+        hpet.counter() < ticks
+    }
+
+    /// Arms the timer to fire after the given number of nanoseconds. See arm_at().
+    pub fn arm_after(&mut self, delay: u64) -> bool {
+        let now = nanoseconds().expect("OneShotTimers can't exist without an HPET");
+
+        self.arm_at(now.saturating_add(delay))
+    }
+
+    /// Disarms the timer, if it was armed.
+    pub fn disarm(&mut self) {
+        // UNSAFE: Safe, as we own the comparator.
+        unsafe { self.update_configuration(|configuration| configuration & !COMPARATOR_INTERRUPT_ENABLE); }
+
+        self.deadline = None;
+    }
+
+    /// True if the timer is armed and it's deadline has passed.
+    pub fn has_expired(&self) -> bool {
+        match (self.deadline, HPET_DEVICE.try()) {
+            (Some(deadline), Some(hpet)) => hpet.counter() >= deadline,
+            _ => false
+        }
+    }
+
+    /// The offset of the comparator's value register.
+    fn value_offset(&self) -> u64 {
+        COMPARATOR_VALUE + self.comparator as u64 * COMPARATOR_STRIDE
+    }
+
+    /// Modifies the comparator's configuration register.
+    /// UNSAFE: The new configuration must make sense.
+    unsafe fn update_configuration<F: FnOnce(u64) -> u64>(&self, update: F) {
+        let hpet = HPET_DEVICE.try().expect("OneShotTimers can't exist without an HPET");
+        let offset = COMPARATOR_CONFIGURATION + self.comparator as u64 * COMPARATOR_STRIDE;
+
+        write_register(&hpet.registers, offset, update(read_register(&hpet.registers, offset)));
+    }
+}
+
+impl Drop for OneShotTimer {
+    fn drop(&mut self) {
+        self.disarm();
+        *COMPARATORS_IN_USE.lock() &= !(1 << self.comparator);
+    }
+}
+
+/// Reads one of the HPET's registers.
+/// UNSAFE: The registers must be the HPET's.
+unsafe fn read_register(registers: &Mmio, offset: u64) -> u64 {
+    registers.read(offset)
+}
+
+/// Writes one of the HPET's registers.
+/// UNSAFE: The registers must be the HPET's, and the write has whatever effect the register has.
+unsafe fn write_register(registers: &Mmio, offset: u64, value: u64) {
+    registers.write(offset, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// QEMU's HPET runs at 100MHz.
+    const QEMU_PERIOD: u64 = 10_000_000;
+
+    /// The period of the 14.31818MHz HPETs in Intel chipsets.
+    const INTEL_PERIOD: u64 = 69_841_279;
+
+    #[test]
+    fn converts_ticks_to_nanoseconds() {
+        assert_eq!(ticks_to_nanoseconds(0, QEMU_PERIOD), 0);
+        assert_eq!(ticks_to_nanoseconds(1, QEMU_PERIOD), 10);
+        assert_eq!(ticks_to_nanoseconds(14_318_180, INTEL_PERIOD), 1_000_000_004);
+
+        // A 64-bit counter which has run for a century, which would overflow done the simple way.
+        let century = 100 * 365 * 24 * 60 * 60 * 100_000_000;
+        assert_eq!(ticks_to_nanoseconds(century, QEMU_PERIOD), century * 10);
+    }
+
+    #[test]
+    fn converts_nanoseconds_to_ticks() {
+        assert_eq!(nanoseconds_to_ticks(10, QEMU_PERIOD), 1);
+        assert_eq!(nanoseconds_to_ticks(19, QEMU_PERIOD), 1);
+        assert_eq!(nanoseconds_to_ticks(1_000_000_000, INTEL_PERIOD), 14_318_179);
+
+        for &ticks in &[0, 1, 12345, 1 << 40] {
+            let nanoseconds = ticks_to_nanoseconds(ticks, QEMU_PERIOD);
+            assert_eq!(nanoseconds_to_ticks(nanoseconds, QEMU_PERIOD), ticks);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Provides the interrupt descriptor table, and dispatches interrupts to handlers registered at run time.
+//! Every vector enters through a stub in interrupts.s, which saves the interrupted state as an InterruptFrame
+//! and calls interrupt_dispatch() with it.
+//!
+//! Vectors 0-31 are the processor's exceptions; 32-47 are where the legacy PICs are moved to (they're masked,
+//! but can still raise spurious interrupts); the rest are handed out to devices behind the APICs. Breakpoints and
+//! NMIs are reported and then carried on from; any other exception without a handler is reported, along with the
+//! registers at the time, and halts. NMIs can't be masked, so they can arrive with anything locked; they never wait
+//! for a lock, and their report is skipped if the console is in use.
+
+use core::fmt;
+
+use spin::{Mutex, Once};
+
+use apic;
+use arch::x86_64::{gdt, instructions};
+
+/// The number of vectors in the IDT.
+pub const VECTOR_COUNT: usize = 256;
+
+/// The vectors of the exceptions which can be carried on from without a handler.
+pub const NMI_VECTOR: u8 = 2;
+pub const BREAKPOINT_VECTOR: u8 = 3;
+
+/// The double fault exception's vector, which runs on it's own stack (see gdt.rs), as the one in use may have
+/// overflowed.
+pub const DOUBLE_FAULT_VECTOR: u8 = 8;
+
+/// The first vector which isn't a processor exception.
+pub const FIRST_EXTERNAL_VECTOR: u8 = 32;
+
+/// The vectors the legacy PICs' IRQs 0-7 and 8-15 arrive on.
+pub const PIC_MASTER_VECTOR: u8 = 32;
+pub const PIC_SLAVE_VECTOR: u8 = 40;
+
+/// The first vector handed out by allocate(), after the PICs' vectors.
+pub const FIRST_APIC_VECTOR: u8 = 48;
+
+/// The vector the local APIC raises spurious interrupts on, which mustn't be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// The type of an interrupt gate which is present, and only usable from ring 0.
+const INTERRUPT_GATE: u8 = 0x8E;
+
+/// The state of the processor when it was interrupted, as saved by the entry stubs; changes made by a handler
+/// are restored when it returns.
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    /// The vector which was raised.
+    pub vector: u64,
+
+    /// The error code the processor pushed, for the exceptions which have one; zero otherwise.
+    pub error_code: u64,
+
+    /// Pushed by the processor: where to return to, and the stack and flags to return with.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64
+}
+
+impl fmt::Display for InterruptFrame {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(formatter, "    RAX {:016x} RBX {:016x} RCX {:016x} RDX {:016x}", self.rax, self.rbx, self.rcx,
+            self.rdx)?;
+        writeln!(formatter, "    RSI {:016x} RDI {:016x} RBP {:016x} RSP {:016x}", self.rsi, self.rdi, self.rbp,
+            self.rsp)?;
+        writeln!(formatter, "    R8  {:016x} R9  {:016x} R10 {:016x} R11 {:016x}", self.r8, self.r9, self.r10,
+            self.r11)?;
+        writeln!(formatter, "    R12 {:016x} R13 {:016x} R14 {:016x} R15 {:016x}", self.r12, self.r13, self.r14,
+            self.r15)?;
+        write!(formatter, "    RIP {:016x} RFLAGS {:08x} CS {:04x} SS {:04x}", self.rip, self.rflags, self.cs, self.ss)
+    }
+}
+
+/// The mnemonic and name of each of the processor's exceptions, by vector; the reserved ones (and the coprocessor
+/// segment overrun, which nothing since the 386 raises) have no mnemonic.
+const EXCEPTIONS: [(&str, &str); FIRST_EXTERNAL_VECTOR as usize] = [
+    ("#DE", "Divide error"),
+    ("#DB", "Debug"),
+    ("NMI", "Non-maskable interrupt"),
+    ("#BP", "Breakpoint"),
+    ("#OF", "Overflow"),
+    ("#BR", "Bound range exceeded"),
+    ("#UD", "Invalid opcode"),
+    ("#NM", "Device not available"),
+    ("#DF", "Double fault"),
+    ("", "Coprocessor segment overrun"),
+    ("#TS", "Invalid TSS"),
+    ("#NP", "Segment not present"),
+    ("#SS", "Stack fault"),
+    ("#GP", "General protection fault"),
+    ("#PF", "Page fault"),
+    ("", "Reserved"),
+    ("#MF", "x87 floating point error"),
+    ("#AC", "Alignment check"),
+    ("#MC", "Machine check"),
+    ("#XM", "SIMD floating point error"),
+    ("#VE", "Virtualization exception"),
+    ("#CP", "Control protection exception"),
+    ("", "Reserved"), ("", "Reserved"), ("", "Reserved"), ("", "Reserved"), ("", "Reserved"), ("", "Reserved"),
+    ("#HV", "Hypervisor injection exception"),
+    ("#VC", "VMM communication exception"),
+    ("#SX", "Security exception"),
+    ("", "Reserved")
+];
+
+/// True for the exceptions the processor pushes an error code for (the stubs in interrupts.s push a zero for the
+/// rest).
+pub fn has_error_code(vector: u8) -> bool {
+    match vector {
+        8 | 10 ..= 14 | 17 | 21 | 29 | 30 => true,
+        _ => false
+    }
+}
+
+/// The mnemonic and name of an exception, or None for vectors which aren't exceptions.
+pub fn exception_name(vector: u8) -> Option<(&'static str, &'static str)> {
+    EXCEPTIONS.get(vector as usize).cloned()
+}
+
+/// The error code pushed by the exceptions caused by a segment selector (#TS, #NP, #SS and #GP), which says which
+/// selector or gate was to blame; zero if it wasn't any of them in particular.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorError(pub u64);
+
+impl SelectorError {
+    /// True if the exception came from delivering an external interrupt, rather than from the code running.
+    pub fn is_external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    /// The descriptor table the index is into.
+    pub fn table(&self) -> &'static str {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT"
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        (self.0 >> 3) & 0x1FFF
+    }
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} entry {}{}", self.table(), self.index(),
+            if self.is_external() { ", delivering an external interrupt" } else { "" })
+    }
+}
+
+/// A function which handles an interrupt.
+pub type Handler = fn(&mut InterruptFrame);
+
+/// A single gate in the interrupt descriptor table.
+#[repr(packed)]
+#[derive(Debug, Clone, Copy)]
+struct IdtEntry {
+    /// Bits 0-15 of the handler's address.
+    offset_low: u16,
+
+    /// The code segment to run the handler in.
+    selector: u16,
+
+    /// The interrupt stack table entry to switch to (0 to stay on the current stack).
+    ist: u8,
+
+    /// The gate type, privilege level and present bit.
+    attributes: u8,
+
+    /// Bits 16-31 and 32-63 of the handler's address.
+    offset_middle: u16,
+    offset_high: u32,
+
+    _reserved: u32
+}
+
+impl IdtEntry {
+    /// Creates an interrupt gate which enters the given handler address.
+    fn new(handler: u64, selector: u16) -> IdtEntry {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector: selector,
+            ist: 0,
+            attributes: INTERRUPT_GATE,
+            offset_middle: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            _reserved: 0
+        }
+    }
+}
+
+extern "C" {
+    /// The address of each vector's entry stub, from interrupts.s.
+    static interrupt_stubs: [u64; VECTOR_COUNT];
+}
+
+/// The interrupt descriptor table, built by init().
+static IDT: Once<[IdtEntry; VECTOR_COUNT]> = Once::new();
+
+/// The handler registered for each vector. Interrupt handlers take this lock, so it must only be taken
+/// elsewhere with interrupts disabled.
+static HANDLERS: Mutex<[Option<Handler>; VECTOR_COUNT]> = Mutex::new([None; VECTOR_COUNT]);
+
+/// Loads the kernel's GDT (for the double fault stack), then builds the interrupt descriptor table and loads it on
+/// this processor. Interrupts stay disabled; they should be enabled once the interrupt controllers are set up.
+pub fn init() {
+    gdt::init();
+
+    let idt = IDT.call_once(|| {
+        let selector = instructions::code_segment();
+        let mut idt = [IdtEntry::new(0, 0); VECTOR_COUNT];
+
+        for (vector, entry) in idt.iter_mut().enumerate() {
+            // UNSAFE: Safe, as the stubs are never modified.
+            *entry = IdtEntry::new(unsafe { interrupt_stubs[vector] }, selector);
+        }
+
+        idt[DOUBLE_FAULT_VECTOR as usize].ist = gdt::DOUBLE_FAULT_IST;
+
+        idt
+    });
+
+    let base = idt.as_ptr() as u64;
+    let limit = (VECTOR_COUNT * 16 - 1) as u16;
+    let pointer = [limit, base as u16, (base >> 16) as u16, (base >> 32) as u16, (base >> 48) as u16];
+
+    // UNSAFE: Safe, as the table lives (unchanged) in a static from now on.
+    unsafe { instructions::load_idt(&pointer); }
+}
+
+/// Registers a handler for the given vector, replacing any existing one: an exception (taking over from the
+/// default report), or one of vectors 32-255.
+pub fn register(vector: u8, handler: Handler) {
+    instructions::without_interrupts(|| HANDLERS.lock()[vector as usize] = Some(handler));
+}
+
+/// Removes the handler for the given vector.
+pub fn unregister(vector: u8) {
+    instructions::without_interrupts(|| HANDLERS.lock()[vector as usize] = None);
+}
+
+/// Registers a handler on a free vector for a device interrupt, returning the vector. Returns None if every
+/// vector is in use.
+pub fn allocate(handler: Handler) -> Option<u8> {
+    instructions::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let vector = (FIRST_APIC_VECTOR .. SPURIOUS_VECTOR).find(|&vector| handlers[vector as usize].is_none())?;
+
+        handlers[vector as usize] = Some(handler);
+        Some(vector)
+    })
+}
+
+/// Called by the entry stubs for every interrupt, with interrupts disabled.
+#[no_mangle]
+pub extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    let vector = frame.vector as u8;
+    let handler = if vector == NMI_VECTOR {
+        HANDLERS.try_lock().and_then(|handlers| handlers[vector as usize])
+    } else {
+        HANDLERS.lock()[vector as usize]
+    };
+
+    match handler {
+        Some(handler) => handler(frame),
+        None if vector == NMI_VECTOR => report_nmi(frame),
+        None if vector == BREAKPOINT_VECTOR => report_exception(frame),
+        None if vector < FIRST_EXTERNAL_VECTOR => {
+            report_exception(frame);
+            loop { instructions::halt(); }
+        },
+
+        // A masked PIC or an unclaimed APIC interrupt; there's nothing to do but ignore it.
+        None => {}
+    }
+
+    // The PICs are never acknowledged, as they only ever raise spurious interrupts; nor are the local APIC's
+    // spurious interrupts.
+    if vector >= FIRST_APIC_VECTOR && vector != SPURIOUS_VECTOR {
+        apic::end_of_interrupt();
+    }
+}
+
+/// Reports an exception nobody handles: which one it was, the error code, and the registers at the time.
+fn report_exception(frame: &InterruptFrame) {
+    let vector = frame.vector as u8;
+    let (mnemonic, name) = exception_name(vector).unwrap_or(("", "Unknown"));
+    let color = if vector == BREAKPOINT_VECTOR { ::vga::Color::Yellow } else { ::vga::Color::Red };
+
+    color_println!(color, "{} (vector {}{}{}) at 0x{:x}", name, vector, if mnemonic.is_empty() { "" } else { ", " },
+        mnemonic, frame.rip);
+
+    match vector {
+        // The segment exceptions say which selector was to blame, if any.
+        10 ..= 13 if frame.error_code != 0 => color_println!(color, "    Error code 0x{:x}: {}", frame.error_code,
+            SelectorError(frame.error_code)),
+        _ if has_error_code(vector) => color_println!(color, "    Error code 0x{:x}", frame.error_code),
+        _ => {}
+    }
+
+    color_println!(color, "{}", frame);
+    color_println!(color, "    CR0 {:016x} CR2 {:016x} CR3 {:016x}", instructions::read_cr0(),
+        instructions::page_fault_address(), instructions::page_table_root());
+}
+
+/// Reports an NMI nobody handles, in one go so it's either all printed or not at all; if the console is in use
+/// (perhaps by whatever the NMI interrupted) the report is skipped rather than waiting forever.
+fn report_nmi(frame: &InterruptFrame) {
+    let (mnemonic, name) = exception_name(NMI_VECTOR).unwrap_or(("", "Unknown"));
+
+    ::vga::try_print(Some(::vga::Color::Red), format_args!("{} (vector {}, {}) at 0x{:x}\n{}\n", name, NMI_VECTOR,
+        mnemonic, frame.rip, frame));
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+    use super::*;
+
+    #[test]
+    fn names_exceptions() {
+        assert_eq!(exception_name(0), Some(("#DE", "Divide error")));
+        assert_eq!(exception_name(13), Some(("#GP", "General protection fault")));
+        assert_eq!(exception_name(DOUBLE_FAULT_VECTOR), Some(("#DF", "Double fault")));
+        assert_eq!(exception_name(30), Some(("#SX", "Security exception")));
+        assert_eq!(exception_name(FIRST_EXTERNAL_VECTOR), None);
+
+        assert!(has_error_code(DOUBLE_FAULT_VECTOR) && has_error_code(14) && !has_error_code(BREAKPOINT_VECTOR));
+    }
+
+    #[test]
+    fn describes_selector_errors() {
+        assert_eq!(format!("{}", SelectorError(0x18)), "GDT entry 3");
+        assert_eq!(format!("{}", SelectorError((13 << 3) | 0b011)), "IDT entry 13, delivering an external interrupt");
+        assert_eq!(format!("{}", SelectorError((2 << 3) | 0b100)), "LDT entry 2");
+    }
+
+    #[test]
+    fn prints_registers() {
+        // UNSAFE: Safe, as the frame is all integers.
+        let mut frame: InterruptFrame = unsafe { mem::zeroed() };
+        frame.rax = 0x1234;
+        frame.r15 = 0xFFFF_E000_0010_0000;
+        frame.rip = 0xFFFF_E000_0010_2345;
+        frame.cs = 0x08;
+
+        let printed = format!("{}", frame);
+        assert_eq!(printed.lines().count(), 5);
+        assert!(printed.starts_with("    RAX 0000000000001234 RBX"));
+        assert!(printed.contains("R15 ffffe00000100000\n"));
+        assert!(printed.ends_with("RIP ffffe00000102345 RFLAGS 00000000 CS 0008 SS 0000"));
+    }
+}
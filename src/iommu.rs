@@ -0,0 +1,845 @@
+//! Provides a driver for Intel VT-d IOMMUs (the DMA remapping hardware units the DMAR describes), which translate
+//! the addresses devices DMA to through per-device tables, so a device only reaches the memory it's handed.
+//!
+//! Devices are attached to domains, which are I/O address spaces: a device's DMA goes through it's domain's page
+//! tables, and map() and unmap() control what the domain can reach. Once init() turns translation on, devices which
+//! aren't attached to a domain can't DMA at all, apart from the reserved regions (RMRRs) the firmware says they
+//! need, which are identity mapped for them in a domain of their own.
+//!
+//! There's no frame allocator yet, so the tables come out of a fixed pool in the kernel image, and are never freed.
+
+
+use spin::{Mutex, Once};
+
+use acpi::{ACPI, DMAR, DeviceScope, PciPathEntry, SCOPE_PCI_ENDPOINT, SCOPE_PCI_SUB_HIERARCHY};
+use arch::x86_64::{instructions, kernel_physical_address, kernel_virtual_address};
+use memory::mmio::{self, Mmio};
+use memory::paging::MemoryType;
+use pci::{self, PciAddress, SECONDARY_BUS, SUBORDINATE_BUS};
+
+/// The offsets of the registers we use.
+const CAPABILITY: u64 = 0x08;
+const EXTENDED_CAPABILITY: u64 = 0x10;
+const GLOBAL_COMMAND: u64 = 0x18;
+const GLOBAL_STATUS: u64 = 0x1C;
+const ROOT_TABLE_ADDRESS: u64 = 0x20;
+const CONTEXT_COMMAND: u64 = 0x28;
+const FAULT_STATUS: u64 = 0x34;
+
+/// Bits of the global command and status registers: translation on, the root table pointer set, and the write
+/// buffer flushed.
+const GLOBAL_TRANSLATION_ENABLE: u32 = 1 << 31;
+const GLOBAL_SET_ROOT_TABLE: u32 = 1 << 30;
+const GLOBAL_WRITE_BUFFER_FLUSH: u32 = 1 << 27;
+
+/// The status bits which are safe to write back to the command register; the rest start one-shot operations.
+const GLOBAL_PERSISTENT_MASK: u32 = 0x96FFFFFF;
+
+/// Capability fields: the number of domains, whether the write buffer needs flushing, the supported page table
+/// depths, and where the fault recording registers are and how many there are.
+const CAPABILITY_DOMAINS_MASK: u64 = 0b111;
+const CAPABILITY_WRITE_BUFFER_FLUSH: u64 = 1 << 4;
+const CAPABILITY_3_LEVEL: u64 = 1 << 9;
+const CAPABILITY_4_LEVEL: u64 = 1 << 10;
+const CAPABILITY_FAULT_OFFSET_SHIFT: u64 = 24;
+const CAPABILITY_FAULT_COUNT_SHIFT: u64 = 40;
+
+/// Extended capability fields: whether table walks snoop the caches, and where the IOTLB registers are.
+const EXTENDED_COHERENT: u64 = 1;
+const EXTENDED_IOTLB_OFFSET_SHIFT: u64 = 8;
+
+/// The context command register: start a global invalidation.
+const CONTEXT_INVALIDATE: u64 = 1 << 63;
+const CONTEXT_GLOBAL: u64 = 1 << 61;
+
+/// The IOTLB invalidate register: start an invalidation, globally or for one domain, after draining DMA.
+const IOTLB_INVALIDATE: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 1 << 60;
+const IOTLB_DOMAIN: u64 = 2 << 60;
+const IOTLB_DRAIN: u64 = (1 << 49) | (1 << 48);
+const IOTLB_DOMAIN_SHIFT: u64 = 32;
+
+/// The fault status register: a primary fault is waiting in the recording registers.
+const FAULT_PENDING: u32 = 1 << 1;
+
+/// The high half of a fault recording register: set while it holds a fault, set for reads (clear for writes), and
+/// the reason.
+const FAULT_RECORDED: u64 = 1 << 63;
+const FAULT_READ: u64 = 1 << 62;
+const FAULT_REASON_SHIFT: u64 = 32;
+
+/// Bits of the root and context entries, and of the page table entries.
+const ENTRY_PRESENT: u64 = 1;
+const ENTRY_READ: u64 = 1;
+const ENTRY_WRITE: u64 = 1 << 1;
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The size of a page, and of a table (which is one page of 512 entries).
+pub const PAGE_SIZE: u64 = 0x1000;
+pub const TABLE_ENTRIES: usize = 512;
+
+/// How many times to check for the hardware finishing a command.
+const COMMAND_ATTEMPTS: usize = 1_000_000;
+
+/// The most hardware units, device scopes per unit, and domains we keep track of.
+const MAX_UNITS: usize = 8;
+const MAX_SCOPES: usize = 16;
+const MAX_DOMAINS: usize = 16;
+
+/// The number of tables in the pool.
+const POOL_TABLES: usize = 64;
+
+/// The reasons the IOMMUs can't be used, or can't do what was asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IommuError {
+    /// There's no DMAR (or no hardware unit in it), or init() hasn't been called.
+    NotPresent,
+
+    /// A unit's registers (at the given physical address) couldn't be mapped.
+    NotMapped(u64),
+
+    /// The units don't have a page table depth in common which we support.
+    UnsupportedAddressWidth,
+
+    /// A unit didn't finish a command.
+    Timeout,
+
+    /// The pool of tables is used up.
+    OutOfTables,
+
+    /// Every domain is in use.
+    OutOfDomains,
+
+    /// There's no such domain.
+    UnknownDomain,
+
+    /// No unit handles DMA from the device.
+    NoUnitForDevice(PciAddress),
+
+    /// An address or length isn't a multiple of the page size.
+    Misaligned,
+
+    /// The I/O address is beyond what the domain's tables can translate.
+    OutOfRange(u64),
+
+    /// The I/O address is already mapped.
+    AlreadyMapped(u64)
+}
+
+/// An I/O address space, which devices can be attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DomainId(u16);
+
+/// A DMA access which a unit blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// The device which made the access.
+    pub device: PciAddress,
+
+    /// The page of the I/O address it accessed.
+    pub address: u64,
+
+    /// True for a write, false for a read.
+    pub write: bool,
+
+    /// The reason code from the VT-d specification (like 0x05 for a write to a page that isn't writable).
+    pub reason: u8
+}
+
+/// Somewhere to get zeroed, 4KiB aligned tables from, which can then be reached by their physical addresses.
+pub trait TableMemory {
+    /// Allocates a zeroed table, returning it's physical address.
+    fn allocate(&mut self) -> Option<u64>;
+
+    /// The table at the given physical address.
+    fn table(&mut self, address: u64) -> &mut [u64; TABLE_ENTRIES];
+
+    /// Called after an entry of a table has been written, so it can be made visible to the hardware.
+    fn written(&mut self, _table: u64, _index: usize) {}
+}
+
+/// A domain's page tables, which translate I/O addresses to physical ones in the same format as the processor's
+/// (with just the read and write bits).
+#[derive(Debug, Clone, Copy)]
+pub struct IoPageTable {
+    /// The physical address of the top level table.
+    root: u64,
+
+    /// The number of levels of tables (3 for 39-bit I/O addresses, 4 for 48-bit).
+    levels: u8
+}
+
+impl IoPageTable {
+    /// Creates an empty set of tables with the given number of levels.
+    pub fn new<M: TableMemory>(memory: &mut M, levels: u8) -> Option<IoPageTable> {
+        Some(IoPageTable { root: memory.allocate()?, levels: levels })
+    }
+
+    /// The physical address of the top level table.
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// The first I/O address past what the tables can translate.
+    pub fn address_limit(&self) -> u64 {
+        1 << (12 + 9 * self.levels as u64)
+    }
+
+    /// Maps a page of I/O address space to a page of physical memory, readable and (optionally) writable.
+    pub fn map<M: TableMemory>(&self, memory: &mut M, io_address: u64, physical: u64, writable: bool)
+        -> Result<(), IommuError> {
+
+        if io_address % PAGE_SIZE != 0 || physical % PAGE_SIZE != 0 { return Err(IommuError::Misaligned); }
+        if io_address >= self.address_limit() { return Err(IommuError::OutOfRange(io_address)); }
+
+        let mut table = self.root;
+
+        for level in (2 .. self.levels + 1).rev() {
+            let index = table_index(io_address, level);
+            let entry = memory.table(table)[index];
+
+            table = if entry & (ENTRY_READ | ENTRY_WRITE) != 0 {
+                entry & ENTRY_ADDRESS_MASK
+            } else {
+                let next = memory.allocate().ok_or(IommuError::OutOfTables)?;
+
+                memory.table(table)[index] = next | ENTRY_READ | ENTRY_WRITE;
+                memory.written(table, index);
+                next
+            };
+        }
+
+        let index = table_index(io_address, 1);
+        if memory.table(table)[index] & (ENTRY_READ | ENTRY_WRITE) != 0 {
+            return Err(IommuError::AlreadyMapped(io_address));
+        }
+
+        memory.table(table)[index] = physical | ENTRY_READ | if writable { ENTRY_WRITE } else { 0 };
+        memory.written(table, index);
+        Ok(())
+    }
+
+    /// Unmaps a page of I/O address space. Returns false if it wasn't mapped. The (now possibly empty) tables on
+    /// the way to it are kept.
+    pub fn unmap<M: TableMemory>(&self, memory: &mut M, io_address: u64) -> bool {
+        match self.leaf(memory, io_address) {
+            Some((table, index)) => {
+                memory.table(table)[index] = 0;
+                memory.written(table, index);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Translates an I/O address to the physical address it's mapped to, and whether it's writable.
+    pub fn translate<M: TableMemory>(&self, memory: &mut M, io_address: u64) -> Option<(u64, bool)> {
+        let (table, index) = self.leaf(memory, io_address)?;
+        let entry = memory.table(table)[index];
+
+        Some(((entry & ENTRY_ADDRESS_MASK) + io_address % PAGE_SIZE, entry & ENTRY_WRITE != 0))
+    }
+
+    /// Finds the last level table and index of the entry mapping the given I/O address, if it's mapped.
+    fn leaf<M: TableMemory>(&self, memory: &mut M, io_address: u64) -> Option<(u64, usize)> {
+        if io_address >= self.address_limit() { return None; }
+
+        let mut table = self.root;
+
+        for level in (2 .. self.levels + 1).rev() {
+            let entry = memory.table(table)[table_index(io_address, level)];
+            if entry & (ENTRY_READ | ENTRY_WRITE) == 0 { return None; }
+
+            table = entry & ENTRY_ADDRESS_MASK;
+        }
+
+        let index = table_index(io_address, 1);
+        if memory.table(table)[index] & (ENTRY_READ | ENTRY_WRITE) == 0 { return None; }
+
+        Some((table, index))
+    }
+}
+
+/// The index into the table at the given level (1 being the last) for an I/O address.
+fn table_index(io_address: u64, level: u8) -> usize {
+    ((io_address >> (12 + 9 * (level as u64 - 1))) & 0x1FF) as usize
+}
+
+/// The two halves of a context entry, which points a device at a domain's page tables.
+fn context_entry(page_table: &IoPageTable, domain: DomainId) -> (u64, u64) {
+    // Translation type 0 (only untranslated requests, through the page tables), and the address width encoded as
+    // 1 for 3 levels and 2 for 4.
+    (page_table.root | ENTRY_PRESENT, (page_table.levels as u64 - 2) | (domain.0 as u64) << 8)
+}
+
+/// The source id the hardware identifies a device by: it's bus, device and function.
+fn source_id(device: PciAddress) -> u16 {
+    (device.bus as u16) << 8 | (device.device as u16 & 0x1F) << 3 | device.function as u16 & 0x7
+}
+
+/// The tables the hardware uses, which come out of the kernel image as there's no frame allocator yet.
+struct TablePool {
+    /// Enough memory for the tables, plus a page to line them up on a page boundary.
+    memory: [u8; (POOL_TABLES + 1) * PAGE_SIZE as usize],
+
+    /// The number of tables handed out.
+    used: usize,
+
+    /// True if every unit's table walks snoop the caches, so the tables don't need flushing.
+    coherent: bool
+}
+
+impl TableMemory for TablePool {
+    fn allocate(&mut self) -> Option<u64> {
+        if self.used == POOL_TABLES { return None; }
+
+        let start = self.memory.as_ptr() as u64;
+        let table = (start + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE + self.used as u64 * PAGE_SIZE;
+        self.used += 1;
+
+        // The pool is zeroed to start with, and tables are never given back, so the table is still clean.
+        Some(kernel_physical_address(table))
+    }
+
+    fn table(&mut self, address: u64) -> &mut [u64; TABLE_ENTRIES] {
+        // UNSAFE: Safe, as the only physical addresses we're given are the ones allocate() handed out.
+        unsafe { &mut *(kernel_virtual_address(address) as *mut [u64; TABLE_ENTRIES]) }
+    }
+
+    fn written(&mut self, table: u64, index: usize) {
+        if !self.coherent {
+            // UNSAFE: Safe, as flushing a line of our own table has no other effect.
+            unsafe { instructions::flush_cache_line(kernel_virtual_address(table) + index as u64 * 8); }
+        }
+    }
+}
+
+/// The devices a unit handles: a single function, or everything on a range of buses (below a bridge).
+#[derive(Debug, Clone, Copy)]
+enum Scope {
+    Device(PciAddress),
+    Buses(u8, u8)
+}
+
+/// A DMA remapping hardware unit.
+#[derive(Debug, Clone, Copy)]
+struct Unit {
+    /// The registers, mapped uncached.
+    registers: Mmio,
+
+    /// The segment group it handles, and whether it handles every device there which no other unit lists.
+    segment: u16,
+    includes_all: bool,
+
+    /// The devices it lists.
+    scopes: [Option<Scope>; MAX_SCOPES],
+
+    capability: u64,
+    extended_capability: u64,
+
+    /// The physical address of the root table, which points to a context table for each bus.
+    root_table: u64
+}
+
+/// A domain, and the units some device of it's is attached through.
+#[derive(Debug, Clone, Copy)]
+struct Domain {
+    page_table: IoPageTable,
+    units: u32
+}
+
+/// Everything that changes once the units are running; the units are only programmed with this locked.
+struct State {
+    pool: TablePool,
+    domains: [Option<Domain>; MAX_DOMAINS]
+}
+
+/// The hardware units, filled in by init(), and the page table depth every domain uses.
+static UNITS: Once<([Option<Unit>; MAX_UNITS], u8)> = Once::new();
+
+static STATE: Mutex<State> = Mutex::new(State {
+    pool: TablePool { memory: [0; (POOL_TABLES + 1) * PAGE_SIZE as usize], used: 0, coherent: false },
+    domains: [None; MAX_DOMAINS]
+});
+
+/// Finds the hardware units in the DMAR, gives each of them an empty root table, identity maps the reserved regions
+/// for the devices which need them, and turns translation on; from then on, devices can only DMA to what their
+/// domains map. PCI configuration space should be set up first, as the device paths go through bridges. Returns the
+/// number of units.
+/// UNSAFE: Devices which are in the middle of DMA to memory other than the reserved regions will fault.
+pub unsafe fn init(acpi: &ACPI) -> Result<usize, IommuError> {
+    let dmar = acpi.find_table::<DMAR>().ok_or(IommuError::NotPresent)?;
+    let mut state = STATE.lock();
+    let mut units = [None; MAX_UNITS];
+    let mut common_depths = CAPABILITY_3_LEVEL | CAPABILITY_4_LEVEL;
+    let mut coherent = true;
+
+    for (slot, drhd) in units.iter_mut().zip(dmar.hardware_units()) {
+        let base = drhd.register_base;
+        if base == 0 { return Err(IommuError::NotMapped(0)); }
+
+        let registers = mmio::ioremap(base, drhd.register_size(), MemoryType::Uncached)
+            .map_err(|_| IommuError::NotMapped(base))?;
+
+        let mut scopes = [None; MAX_SCOPES];
+        let resolved = drhd.scopes().filter_map(|scope| resolve(drhd.segment, scope));
+
+        for (scope_slot, scope) in scopes.iter_mut().zip(resolved) {
+            *scope_slot = Some(scope);
+        }
+
+        let capability = read_register(&registers, CAPABILITY);
+        let extended_capability = read_register(&registers, EXTENDED_CAPABILITY);
+
+        common_depths &= capability;
+        coherent &= extended_capability & EXTENDED_COHERENT != 0;
+
+        *slot = Some(Unit {
+            registers: registers,
+            segment: drhd.segment,
+            includes_all: drhd.includes_all(),
+            scopes: scopes,
+            capability: capability,
+            extended_capability: extended_capability,
+            root_table: 0
+        });
+    }
+
+    let levels = if common_depths & CAPABILITY_4_LEVEL != 0 {
+        4
+    } else if common_depths & CAPABILITY_3_LEVEL != 0 {
+        3
+    } else {
+        return Err(IommuError::UnsupportedAddressWidth);
+    };
+
+    let count = units.iter().filter(|unit| unit.is_some()).count();
+    if count == 0 { return Err(IommuError::NotPresent); }
+
+    state.pool.coherent = coherent;
+
+    for unit in units.iter_mut().filter_map(|unit| unit.as_mut()) {
+        unit.root_table = state.pool.allocate().ok_or(IommuError::OutOfTables)?;
+
+        write_register(&unit.registers, ROOT_TABLE_ADDRESS, unit.root_table);
+        global_command(unit, GLOBAL_SET_ROOT_TABLE, true)?;
+        invalidate_caches(unit, None)?;
+    }
+
+    let units = &UNITS.call_once(|| (units, levels)).0;
+
+    // The devices with reserved regions share an identity mapped domain. A region we can't map (or a device we
+    // can't attach) will just fault; there's not much else to do about it.
+    let mut reserved = None;
+
+    for rmrr in dmar.reserved_regions() {
+        let devices = rmrr.scopes().filter(|scope| scope.scope_type == SCOPE_PCI_ENDPOINT)
+            .filter_map(|scope| resolve_path(rmrr.segment, scope.start_bus, scope.path()));
+
+        for device in devices {
+            let domain = match reserved {
+                Some(domain) => domain,
+                None => {
+                    let domain = create_domain_locked(&mut state)?;
+                    reserved = Some(domain);
+                    domain
+                }
+            };
+
+            let mut address = rmrr.base_address & !(PAGE_SIZE - 1);
+            while address < rmrr.base_address + rmrr.length() {
+                match map_locked(&mut state, domain, address, address, PAGE_SIZE, true) {
+                    Ok(()) | Err(IommuError::AlreadyMapped(_)) => {},
+                    Err(_) => break
+                }
+
+                address += PAGE_SIZE;
+            }
+
+            attach_locked(&mut state, device, domain).ok();
+        }
+    }
+
+    for unit in units.iter().filter_map(|unit| unit.as_ref()) {
+        global_command(unit, GLOBAL_TRANSLATION_ENABLE, true)?;
+    }
+
+    Ok(count)
+}
+
+/// Puts the units back the way init() left them, after the machine wakes up from suspend-to-RAM (which loses their
+/// registers, though not the tables in memory). Does nothing if init() didn't find any.
+/// UNSAFE: Like init(), devices in the middle of DMA to memory their domains don't map will fault.
+pub unsafe fn resume() -> Result<(), IommuError> {
+    let units = match UNITS.try() {
+        Some(&(ref units, _)) => units,
+        None => return Ok(())
+    };
+
+    for unit in units.iter().filter_map(|unit| unit.as_ref()) {
+        write_register(&unit.registers, ROOT_TABLE_ADDRESS, unit.root_table);
+        global_command(unit, GLOBAL_SET_ROOT_TABLE, true)?;
+        invalidate_caches(unit, None)?;
+        global_command(unit, GLOBAL_TRANSLATION_ENABLE, true)?;
+    }
+
+    Ok(())
+}
+
+/// Works out the devices a DRHD device scope covers; I/O APIC and HPET scopes don't DMA, so are skipped.
+unsafe fn resolve(segment: u16, scope: &DeviceScope) -> Option<Scope> {
+    let device = resolve_path(segment, scope.start_bus, scope.path())?;
+
+    match scope.scope_type {
+        SCOPE_PCI_ENDPOINT => Some(Scope::Device(device)),
+        SCOPE_PCI_SUB_HIERARCHY => {
+            Some(Scope::Buses(pci::read_u8(device, SECONDARY_BUS)?, pci::read_u8(device, SUBORDINATE_BUS)?))
+        },
+        _ => None
+    }
+}
+
+/// Follows a device scope path from it's start bus, through the bridges along the way, to the device at the end.
+unsafe fn resolve_path(segment: u16, start_bus: u8, path: &[PciPathEntry]) -> Option<PciAddress> {
+    let (last, bridges) = path.split_last()?;
+    let mut bus = start_bus;
+
+    for bridge in bridges {
+        let bridge = PciAddress { segment: segment, bus: bus, device: bridge.device, function: bridge.function };
+        bus = pci::read_u8(bridge, SECONDARY_BUS)?;
+    }
+
+    Some(PciAddress { segment: segment, bus: bus, device: last.device, function: last.function })
+}
+
+/// True if init() found and enabled the hardware units.
+pub fn is_present() -> bool {
+    UNITS.try().is_some()
+}
+
+/// Creates an empty domain, which devices can be attached to.
+pub fn create_domain() -> Result<DomainId, IommuError> {
+    create_domain_locked(&mut STATE.lock())
+}
+
+fn create_domain_locked(state: &mut State) -> Result<DomainId, IommuError> {
+    let levels = UNITS.try().ok_or(IommuError::NotPresent)?.1;
+
+    // Domain id 0 is reserved on some hardware, so the ids start at 1.
+    let index = state.domains.iter().position(|domain| domain.is_none()).ok_or(IommuError::OutOfDomains)?;
+    let page_table = IoPageTable::new(&mut state.pool, levels).ok_or(IommuError::OutOfTables)?;
+
+    state.domains[index] = Some(Domain { page_table: page_table, units: 0 });
+    Ok(DomainId(index as u16 + 1))
+}
+
+/// Attaches a device to a domain, so all of it's DMA is translated through the domain's tables. A device can only
+/// be in one domain; attaching it again moves it.
+pub fn attach(device: PciAddress, domain: DomainId) -> Result<(), IommuError> {
+    attach_locked(&mut STATE.lock(), device, domain)
+}
+
+fn attach_locked(state: &mut State, device: PciAddress, domain: DomainId) -> Result<(), IommuError> {
+    let units = &UNITS.try().ok_or(IommuError::NotPresent)?.0;
+    let (unit_index, unit) = unit_for(units, device).ok_or(IommuError::NoUnitForDevice(device))?;
+    let entry = domain_entry(state, domain)?;
+
+    if domain.0 as u64 >= domain_count(unit) { return Err(IommuError::OutOfDomains); }
+
+    // The root table has an entry for each bus, pointing to a context table with an entry for each function.
+    let root_index = device.bus as usize * 2;
+    let root_entry = state.pool.table(unit.root_table)[root_index];
+
+    let context_table = if root_entry & ENTRY_PRESENT != 0 {
+        root_entry & ENTRY_ADDRESS_MASK
+    } else {
+        let table = state.pool.allocate().ok_or(IommuError::OutOfTables)?;
+
+        state.pool.table(unit.root_table)[root_index] = table | ENTRY_PRESENT;
+        state.pool.written(unit.root_table, root_index);
+        table
+    };
+
+    let (low, high) = context_entry(&entry.page_table, domain);
+    let context_index = (source_id(device) & 0xFF) as usize * 2;
+
+    // The present bit is in the low half, so that goes last.
+    state.pool.table(context_table)[context_index] = 0;
+    state.pool.table(context_table)[context_index + 1] = high;
+    state.pool.table(context_table)[context_index] = low;
+    state.pool.written(context_table, context_index);
+
+    state.domains[domain.0 as usize - 1].as_mut().map(|domain| domain.units |= 1 << unit_index);
+
+    // UNSAFE: Safe, as invalidating caches only makes the hardware read the tables again.
+    unsafe { invalidate_caches(unit, None) }
+}
+
+/// Maps a range of I/O addresses in a domain to physical memory, so the domain's devices can read (and optionally
+/// write) it. The range has to be page aligned, and not already mapped; if it is, the pages before the mapped one
+/// stay mapped.
+/// UNSAFE: The domain's devices can then DMA to the memory, so it has to stay put (and not be used for anything
+/// else) until it's unmapped.
+pub unsafe fn map(domain: DomainId, io_address: u64, physical: u64, length: u64, writable: bool)
+    -> Result<(), IommuError> {
+
+    map_locked(&mut STATE.lock(), domain, io_address, physical, length, writable)
+}
+
+fn map_locked(state: &mut State, domain: DomainId, io_address: u64, physical: u64, length: u64, writable: bool)
+    -> Result<(), IommuError> {
+
+    if length % PAGE_SIZE != 0 { return Err(IommuError::Misaligned); }
+
+    let entry = domain_entry(state, domain)?;
+    let mut offset = 0;
+
+    while offset < length {
+        entry.page_table.map(&mut state.pool, io_address + offset, physical + offset, writable)?;
+        offset += PAGE_SIZE;
+    }
+
+    // Units which cache not-present entries (as emulated ones do) need telling about new mappings too.
+    invalidate_domain(&entry, domain)
+}
+
+/// Unmaps a range of I/O addresses in a domain, and waits for the units to stop using the old translations, after
+/// which the memory is no longer reachable by the domain's devices. Pages in the range which aren't mapped are
+/// skipped.
+pub fn unmap(domain: DomainId, io_address: u64, length: u64) -> Result<(), IommuError> {
+    if io_address % PAGE_SIZE != 0 || length % PAGE_SIZE != 0 { return Err(IommuError::Misaligned); }
+
+    let mut state = STATE.lock();
+    let entry = domain_entry(&state, domain)?;
+    let mut offset = 0;
+
+    while offset < length {
+        entry.page_table.unmap(&mut state.pool, io_address + offset);
+        offset += PAGE_SIZE;
+    }
+
+    invalidate_domain(&entry, domain)
+}
+
+/// Translates an I/O address in a domain to the physical address it's mapped to, and whether it's writable.
+pub fn translate(domain: DomainId, io_address: u64) -> Option<(u64, bool)> {
+    let mut state = STATE.lock();
+    let entry = domain_entry(&state, domain).ok()?;
+
+    entry.page_table.translate(&mut state.pool, io_address)
+}
+
+/// Takes the next DMA access a unit blocked, if there is one, so it can be reported.
+pub fn take_fault() -> Option<Fault> {
+    let units = &UNITS.try()?.0;
+
+    for unit in units.iter().filter_map(|unit| unit.as_ref()) {
+        // UNSAFE: Safe, as the fault registers are write-one-to-clear, and we only clear the one we take.
+        unsafe {
+            if (read_register(&unit.registers, FAULT_STATUS) as u32) & FAULT_PENDING == 0 { continue; }
+
+            let offset = ((unit.capability >> CAPABILITY_FAULT_OFFSET_SHIFT) & 0x3FF) * 16;
+            let count = ((unit.capability >> CAPABILITY_FAULT_COUNT_SHIFT) & 0xFF) + 1;
+
+            for record in 0 .. count {
+                let low_register = offset + record * 16;
+                let high = read_register(&unit.registers, low_register + 8);
+                if high & FAULT_RECORDED == 0 { continue; }
+
+                let low = read_register(&unit.registers, low_register);
+                write_register(&unit.registers, low_register + 8, FAULT_RECORDED);
+
+                let source = high as u16;
+                return Some(Fault {
+                    device: PciAddress {
+                        segment: unit.segment,
+                        bus: (source >> 8) as u8,
+                        device: ((source >> 3) & 0x1F) as u8,
+                        function: (source & 0x7) as u8
+                    },
+                    address: low & !(PAGE_SIZE - 1),
+                    write: high & FAULT_READ == 0,
+                    reason: (high >> FAULT_REASON_SHIFT) as u8
+                });
+            }
+
+            write_register32(&unit.registers, FAULT_STATUS, FAULT_PENDING);
+        }
+    }
+
+    None
+}
+
+/// Finds a domain.
+fn domain_entry(state: &State, domain: DomainId) -> Result<Domain, IommuError> {
+    let index = (domain.0 as usize).wrapping_sub(1);
+
+    state.domains.get(index).and_then(|domain| *domain).ok_or(IommuError::UnknownDomain)
+}
+
+/// Finds the unit which handles DMA from a device: the one which lists it, or otherwise the one which takes
+/// everything else in it's segment group.
+fn unit_for(units: &[Option<Unit>; MAX_UNITS], device: PciAddress) -> Option<(usize, &Unit)> {
+    let listed = units.iter().enumerate()
+        .filter_map(|(index, unit)| unit.as_ref().map(|unit| (index, unit)))
+        .find(|&(_, unit)| unit.segment == device.segment && unit.scopes.iter().filter_map(|scope| *scope)
+            .any(|scope| match scope {
+                Scope::Device(address) => address == device,
+                Scope::Buses(first, last) => device.bus >= first && device.bus <= last
+            }));
+
+    listed.or_else(|| units.iter().enumerate()
+        .filter_map(|(index, unit)| unit.as_ref().map(|unit| (index, unit)))
+        .find(|&(_, unit)| unit.segment == device.segment && unit.includes_all))
+}
+
+/// The number of domain ids a unit supports.
+fn domain_count(unit: &Unit) -> u64 {
+    1 << (4 + 2 * (unit.capability & CAPABILITY_DOMAINS_MASK))
+}
+
+/// Makes every unit the domain is attached through forget it's cached translations.
+fn invalidate_domain(entry: &Domain, domain: DomainId) -> Result<(), IommuError> {
+    let units = &UNITS.try().ok_or(IommuError::NotPresent)?.0;
+
+    for (index, unit) in units.iter().enumerate().filter_map(|(index, unit)| unit.as_ref().map(|unit| (index, unit))) {
+        if entry.units & (1 << index) != 0 {
+            // UNSAFE: Safe, as invalidating caches only makes the hardware read the tables again.
+            unsafe { invalidate_caches(unit, Some(domain))?; }
+        }
+    }
+
+    Ok(())
+}
+
+/// Flushes the write buffer (if the unit has one), and invalidates the context cache and the IOTLB, either
+/// entirely or (for the IOTLB) for one domain.
+unsafe fn invalidate_caches(unit: &Unit, domain: Option<DomainId>) -> Result<(), IommuError> {
+    if unit.capability & CAPABILITY_WRITE_BUFFER_FLUSH != 0 {
+        let status = read_register32(&unit.registers, GLOBAL_STATUS) & GLOBAL_PERSISTENT_MASK;
+        write_register32(&unit.registers, GLOBAL_COMMAND, status | GLOBAL_WRITE_BUFFER_FLUSH);
+
+        wait(|| read_register32(&unit.registers, GLOBAL_STATUS) & GLOBAL_WRITE_BUFFER_FLUSH == 0)?;
+    }
+
+    if domain.is_none() {
+        write_register(&unit.registers, CONTEXT_COMMAND, CONTEXT_INVALIDATE | CONTEXT_GLOBAL);
+        wait(|| read_register(&unit.registers, CONTEXT_COMMAND) & CONTEXT_INVALIDATE == 0)?;
+    }
+
+    let iotlb = ((unit.extended_capability >> EXTENDED_IOTLB_OFFSET_SHIFT) & 0x3FF) * 16 + 8;
+    let granularity = match domain {
+        Some(domain) => IOTLB_DOMAIN | (domain.0 as u64) << IOTLB_DOMAIN_SHIFT,
+        None => IOTLB_GLOBAL
+    };
+
+    write_register(&unit.registers, iotlb, IOTLB_INVALIDATE | IOTLB_DRAIN | granularity);
+    wait(|| read_register(&unit.registers, iotlb) & IOTLB_INVALIDATE == 0)
+}
+
+/// Sets (or clears) a bit in the global command register, and waits for the status register to agree.
+unsafe fn global_command(unit: &Unit, bit: u32, set: bool) -> Result<(), IommuError> {
+    let status = read_register32(&unit.registers, GLOBAL_STATUS) & GLOBAL_PERSISTENT_MASK;
+    write_register32(&unit.registers, GLOBAL_COMMAND, if set { status | bit } else { status & !bit });
+
+    wait(|| (read_register32(&unit.registers, GLOBAL_STATUS) & bit != 0) == set)
+}
+
+/// Waits for the hardware to finish a command.
+fn wait<F: Fn() -> bool>(done: F) -> Result<(), IommuError> {
+    if (0 .. COMMAND_ATTEMPTS).any(|_| done()) { Ok(()) } else { Err(IommuError::Timeout) }
+}
+
+/// Reads one of a unit's 64-bit registers.
+unsafe fn read_register(registers: &Mmio, offset: u64) -> u64 {
+    registers.read(offset)
+}
+
+/// Writes one of a unit's 64-bit registers.
+unsafe fn write_register(registers: &Mmio, offset: u64, value: u64) {
+    registers.write(offset, value)
+}
+
+/// Reads one of a unit's 32-bit registers.
+unsafe fn read_register32(registers: &Mmio, offset: u64) -> u32 {
+    registers.read(offset)
+}
+
+/// Writes one of a unit's 32-bit registers.
+unsafe fn write_register32(registers: &Mmio, offset: u64, value: u32) {
+    registers.write(offset, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Tables kept in a vector, at made up physical addresses.
+    struct VecMemory {
+        tables: Vec<[u64; TABLE_ENTRIES]>
+    }
+
+    /// Where the made up physical addresses start.
+    const BASE: u64 = 0x40000;
+
+    impl TableMemory for VecMemory {
+        fn allocate(&mut self) -> Option<u64> {
+            if self.tables.len() == 8 { return None; }
+
+            self.tables.push([0; TABLE_ENTRIES]);
+            Some(BASE + (self.tables.len() as u64 - 1) * PAGE_SIZE)
+        }
+
+        fn table(&mut self, address: u64) -> &mut [u64; TABLE_ENTRIES] {
+            &mut self.tables[((address - BASE) / PAGE_SIZE) as usize]
+        }
+    }
+
+    #[test]
+    fn maps_and_translates_pages() {
+        let mut memory = VecMemory { tables: Vec::new() };
+        let tables = IoPageTable::new(&mut memory, 4).unwrap();
+
+        tables.map(&mut memory, 0x1234_5000, 0x9000, true).unwrap();
+        tables.map(&mut memory, 0x1234_6000, 0xA000, false).unwrap();
+
+        // Both pages share all but the root table's path, so that's four tables in all.
+        assert_eq!(memory.tables.len(), 4);
+        assert_eq!(tables.translate(&mut memory, 0x1234_5678), Some((0x9678, true)));
+        assert_eq!(tables.translate(&mut memory, 0x1234_6000), Some((0xA000, false)));
+        assert_eq!(tables.translate(&mut memory, 0x1234_7000), None);
+
+        assert_eq!(tables.map(&mut memory, 0x1234_5000, 0xB000, true), Err(IommuError::AlreadyMapped(0x1234_5000)));
+        assert!(tables.unmap(&mut memory, 0x1234_5000));
+        assert!(!tables.unmap(&mut memory, 0x1234_5000));
+        assert_eq!(tables.translate(&mut memory, 0x1234_5000), None);
+        assert_eq!(tables.translate(&mut memory, 0x1234_6000), Some((0xA000, false)));
+    }
+
+    #[test]
+    fn checks_alignment_and_range() {
+        let mut memory = VecMemory { tables: Vec::new() };
+        let tables = IoPageTable::new(&mut memory, 3).unwrap();
+
+        assert_eq!(tables.address_limit(), 1 << 39);
+        assert_eq!(tables.map(&mut memory, 0x1001, 0x2000, true), Err(IommuError::Misaligned));
+        assert_eq!(tables.map(&mut memory, 1 << 39, 0x2000, true), Err(IommuError::OutOfRange(1 << 39)));
+
+        // Pages far enough apart to need their own tables at every level run the memory out.
+        for index in 0 .. 3 {
+            tables.map(&mut memory, index << 30, 0x2000, true).unwrap();
+        }
+
+        assert_eq!(tables.map(&mut memory, 3 << 30, 0x2000, true), Err(IommuError::OutOfTables));
+    }
+
+    #[test]
+    fn encodes_context_entries() {
+        let tables = IoPageTable { root: 0x7000, levels: 4 };
+
+        assert_eq!(context_entry(&tables, DomainId(3)), (0x7001, 0x302));
+        assert_eq!(source_id(PciAddress::new(0x12, 0x1F, 7)), 0x12FF);
+    }
+}
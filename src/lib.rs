@@ -1,58 +1,322 @@
 #![feature(lang_items)]
-#![feature(step_by)]
-#![feature(unique)]
+#![feature(ptr_internals)]
 #![feature(const_fn)]
-#![no_std]
+#![feature(asm)]
+#![feature(alloc_error_handler)]
+// Unit tests run on the host, with the standard library; see the README.
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(not(test))]
 extern crate rlibc;
+#[cfg(test)]
+extern crate core;
 extern crate spin;
 extern crate volatile;
 extern crate multiboot2;
+extern crate alloc;
 
-pub mod acpi;
-
+// Declared first, so the printing macros are available to all of the other modules.
 #[macro_use]
 pub mod vga;
 
+pub mod arch;
+pub mod acpi;
+pub mod multiboot;
+pub mod boot_info;
+pub mod memory;
+pub mod cmdline;
+pub mod serial;
+pub mod power;
+pub mod hpet;
+pub mod pci;
+pub mod numa;
+pub mod interrupts;
+pub mod apic;
+pub mod sci;
+pub mod iommu;
+
 use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use arch::x86_64::instructions;
+
+/// Set when the power button is pressed, so the idle loop shuts the machine down.
+static POWER_BUTTON_PRESSED: AtomicBool = AtomicBool::new(false);
+
+/// Set when the sleep button is pressed, so the idle loop suspends the machine to RAM.
+static SLEEP_BUTTON_PRESSED: AtomicBool = AtomicBool::new(false);
+
+/// The size of the stack the kernel switches to from the bootstrap's, once paging is set up.
+const BOOT_STACK_SIZE: u64 = 0x10000;
 
 /// The rust entry point for the initial processor into the kernel.
 #[no_mangle]
-pub extern "C" fn rust_init(multiboot_header: *mut u8) {
+pub extern "C" fn rust_init(multiboot_header: *mut u8) -> ! {
+    // Any exceptions from here on get reported, rather than triple faulting. The GDT, TSS and IDT are all statics, so
+    // this needs nothing else set up first.
+    interrupts::init();
+
+    // Everything we need from the boot information is copied out first, so the memory it's in can be reused; the
+    // command line in it says where output should go, so that comes before anything is printed.
+    let boot_info = unsafe { boot_info::init(multiboot_header) };
+    let options = cmdline::init(boot_info.command_line());
+
+    let serial = options.console.serial && serial::init();
+    vga::set_enabled(options.console.vga || !serial);
+
     color_println!(vga::Color::Magenta, "AsyncOS Version {}\n", "0.0.1");
 
     color_println!(vga::Color::Magenta, "- Multiboot Metadata @ 0x{0:x}", multiboot_header as u64);
 
-    if let Some(acpi) = unsafe { acpi::ACPI::find_in_memory() } {
-        println!("- ACPI: Present");
-        println!("- ACPI: {} tables available:", acpi.raw_tables().count());
+    info_println!("- Boot loader: {}, command line \"{}\"", boot_info.loader_name().unwrap_or("Unknown"),
+        boot_info.command_line());
+
+    if options.console.serial && !serial {
+        warn_println!("- Console: No serial port found, staying on VGA");
+    }
+
+    for option in options.rejected() {
+        warn_println!("- Command line: Ignoring \"{}\", which has a bad value", option);
+    }
+
+    info_println!("- Memory: {} MiB available in {} areas, {} modules", boot_info.available_memory() >> 20,
+        boot_info.memory_areas().len(), boot_info.modules().len());
 
-        for table in acpi.raw_tables() {
-            let header = unsafe { &*table };
+    if boot_info.dropped_modules() > 0 {
+        warn_println!("- Memory: Only the first {} modules are kept, leaving out {}", boot_info::MAX_MODULES,
+            boot_info.dropped_modules());
+    }
 
-            println!("\t- {} @ {1:x}", str::from_utf8(&header.signature).unwrap(), table as u64);
+    for area in boot_info.memory_areas() {
+        debug_println!("\t- 0x{:x}-0x{:x}: {:?}", area.base, area.end(), area.kind);
+    }
+
+    for module in boot_info.modules() {
+        debug_println!("\t- Module {} @ 0x{:x}-0x{:x}", module.name(), module.start, module.end);
+    }
+
+    for section in boot_info.elf_sections().iter().filter(|section| section.is_allocated()) {
+        debug_println!("\t- Section {} @ 0x{:x}-0x{:x}, flags 0x{:x}", section.name(), section.start, section.end,
+            section.flags);
+    }
+
+    match memory::frame::init(boot_info).and_then(|_| memory::frame::hand_over()) {
+        Ok(stats) => info_println!("- Frames: {} MiB free of {} MiB", (stats.free * memory::FRAME_SIZE) >> 20,
+            (stats.total * memory::FRAME_SIZE) >> 20),
+        Err(error) => color_println!(vga::Color::Red, "- Frames: Unavailable ({:?})", error)
+    }
+
+    // UNSAFE: Safe, as nothing has used the bootstrap's mapping of all of memory at KERNEL_VIRTUAL.
+    match unsafe { memory::paging::init(boot_info) } {
+        Ok(space) => {
+            info_println!("- Paging: Kernel address space @ 0x{:x}", space.root());
+
+            if !memory::paging::no_execute_enabled() {
+                warn_println!("- Paging: No no-execute support, so the kernel's data stays executable");
+            }
+        },
+        Err(error) => color_println!(vga::Color::Red, "- Paging: Staying on the bootstrap's tables ({:?})", error)
+    }
+
+    // The bootstrap's stack has nothing below it to catch an overflow, so the rest of the kernel runs on one with a
+    // guard page.
+    match memory::stack::allocate("boot", BOOT_STACK_SIZE) {
+        // UNSAFE: Safe, as the stack was just allocated, and nothing on this one is used again.
+        Some(stack) => unsafe { instructions::switch_stack(stack.top(), kernel_main) },
+        None => {
+            color_println!(vga::Color::Red, "- Stack: Staying on the bootstrap's stack");
+            kernel_main()
         }
-    } else {
-        color_println!(vga::Color::Red, "- ACPI: Absent");
     }
+}
+
+/// The rest of the kernel's initialization, and then the idle loop, on the boot stack.
+extern "C" fn kernel_main() -> ! {
+    let boot_info = boot_info::get().expect("Boot information is copied out first thing");
+
+    // Page faults in demand-zero memory (like big heap allocations) get handled from here on.
+    memory::fault::init();
+
+    // With acpi=off, the tables aren't even looked for.
+    let acpi = if cmdline::acpi_enabled() { Some(unsafe { acpi::ACPI::find(boot_info) }) } else { None };
+
+    match acpi {
+        None => warn_println!("- ACPI: Disabled on the command line"),
+        Some(Ok(acpi)) => {
+            info_println!("- ACPI: Present");
+
+            // AML can access PCI configuration space, so this goes first.
+            match pci::init(&acpi) {
+                0 => info_println!("- PCI: Legacy configuration ports, {} functions", pci::functions().count()),
+                regions => info_println!("- PCI: {} ECAM regions, {} functions", regions, pci::functions().count())
+            }
+
+            match unsafe { acpi::aml::init(&acpi) } {
+                Ok(()) => info_println!("- AML: {} objects in the namespace", acpi::aml::object_count()),
+                Err(error) => color_println!(vga::Color::Red, "- AML: Failed to load the DSDT ({:?})", error)
+            }
+
+            power::init(&acpi);
+
+            match unsafe { apic::init(&acpi) } {
+                Ok(io_apics) => info_println!("- APIC: Local APIC {}, {} I/O APICs", apic::local_apic_id().unwrap_or(0),
+                    io_apics),
+                Err(error) => color_println!(vga::Color::Red, "- APIC: Unavailable ({:?})", error)
+            }
+
+            match unsafe { sci::init(&acpi) } {
+                Ok(interrupt) => {
+                    sci::subscribe(button_pressed);
+                    info_println!("- SCI: GSI {}", interrupt);
+                },
+                Err(error) => color_println!(vga::Color::Red, "- SCI: Unavailable ({:?})", error)
+            }
+
+            match unsafe { hpet::init(&acpi) } {
+                Ok(()) => info_println!("- HPET: {} Hz, {} comparators", hpet::frequency().unwrap_or(0),
+                    hpet::comparator_count().unwrap_or(0)),
+                Err(error) => color_println!(vga::Color::Red, "- HPET: Unavailable ({:?})", error)
+            }
 
-    // The OS HAS CONTROL NOW. No premature exiting for us.
-    loop { }
+            match unsafe { iommu::init(&acpi) } {
+                Ok(units) => info_println!("- IOMMU: {} remapping units, translation enabled", units),
+                Err(error) => color_println!(vga::Color::Red, "- IOMMU: Unavailable ({:?})", error)
+            }
+
+            info_println!("- ACPI: {} tables available:", acpi.raw_tables().count());
+
+            // Booting with acpi.dump prints everything we know about the tables, rather than just where they are.
+            if cmdline::flag("acpi.dump") {
+                let _ = acpi::dump(&acpi, &mut vga::Console);
+            } else if cmdline::logs(cmdline::LogLevel::Info) {
+                for address in acpi.raw_tables() {
+                    if let Some(header) = acpi.table_at(address) {
+                        info_println!("\t- {} @ {1:x}", str::from_utf8(&header.signature).unwrap(), address);
+                    }
+                }
+            }
+
+            match numa::init(&acpi) {
+                1 => info_println!("- NUMA: 1 node"),
+                nodes => info_println!("- NUMA: {} nodes", nodes)
+            }
+
+            if let Some(madt) = acpi.find_table::<acpi::MADT>() {
+                info_println!("- APIC: Local APIC @ 0x{:x}", madt.local_apic_address());
+
+                for cpu in madt.processors().filter(|cpu| cpu.enabled) {
+                    info_println!("\t- CPU {} (APIC {}, node {})", cpu.processor_id, cpu.apic_id,
+                        numa::node_of_cpu(cpu.apic_id));
+                }
+
+                for io_apic in madt.io_apics() {
+                    info_println!("\t- I/O APIC {} @ 0x{:x}, GSI base {}", io_apic.io_apic_id, { io_apic.address },
+                        { io_apic.global_system_interrupt_base });
+                }
+            } else {
+                color_println!(vga::Color::Red, "- APIC: No MADT present");
+            }
+        },
+        Some(Err(error)) => color_println!(vga::Color::Red, "- ACPI: Absent ({:?})", error)
+    }
+
+    // Booting with acpi.suspend goes straight to sleep once everything is set up, to try out suspend and resume.
+    if cmdline::flag("acpi.suspend") {
+        suspend_to_ram();
+    }
+
+    // Test runs have nothing left to do, so turn QEMU off so the run actually finishes.
+    if cmdline::test_mode() {
+        power::shutdown();
+    }
+
+    // The OS HAS CONTROL NOW. No premature exiting for us; just wait for something to happen.
+    loop {
+        unsafe { instructions::disable_interrupts(); }
+
+        // GPE methods (which can press the buttons, through Notify) can't run in the SCI handler, so they run here,
+        // with interrupts disabled so nothing latched after this is left waiting until the next interrupt.
+        sci::process_gpes();
+
+        if POWER_BUTTON_PRESSED.load(Ordering::SeqCst) {
+            info_println!("- Power button pressed, shutting down");
+            power::shutdown();
+        }
+
+        if SLEEP_BUTTON_PRESSED.swap(false, Ordering::SeqCst) {
+            info_println!("- Sleep button pressed");
+            suspend_to_ram();
+        }
+
+        unsafe { instructions::enable_interrupts_and_halt(); }
+    }
+}
+
+/// Listens for the power and sleep buttons, which are delivered in the SCI handler (or for control method buttons,
+/// by sci::process_gpes()); the shutdown or suspend happens in the idle loop.
+fn button_pressed(event: sci::Event) {
+    match event {
+        sci::Event::PowerButton => POWER_BUTTON_PRESSED.store(true, Ordering::SeqCst),
+        sci::Event::SleepButton => SLEEP_BUTTON_PRESSED.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+}
+
+/// Suspends the machine to RAM, and reports how it went once it wakes up.
+fn suspend_to_ram() {
+    info_println!("- Suspending to RAM");
+
+    match power::suspend() {
+        Ok(()) => info_println!("- Resumed from suspend-to-RAM"),
+        Err(error) => color_println!(vga::Color::Red, "- Suspend-to-RAM failed ({:?})", error)
+    }
+}
+
+/// Everything the `alloc` crate allocates comes from the kernel heap.
+#[cfg(not(test))]
+#[global_allocator]
+static ALLOCATOR: memory::heap::KernelHeap = memory::heap::KernelHeap;
+
+/// Reports an allocation the heap couldn't make, and stops.
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn out_of_memory(layout: core::alloc::Layout) -> ! {
+    color_println!(vga::Color::Red, "Out of memory: couldn't allocate {} bytes aligned to {}", layout.size(),
+        layout.align());
+
+    loop { instructions::halt(); }
 }
 
 /// Method used for the compilers personality, though I'm not sure what it is.
+#[cfg(not(test))]
 #[lang = "eh_personality"] 
 pub extern fn eh_personality() {}
 
-/// Formats panic messages; unused due to the kernel aborting on panic.
-#[lang = "panic_fmt"]
-#[no_mangle]
-pub extern fn panic_fmt() -> ! {
-    loop { /* :( */ }
+/// Set by the first panic, so a panic while reporting one doesn't try to report it again.
+#[cfg(not(test))]
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Reports a panic and reboots, or in test mode turns the machine off so the run finishes. A panic can happen with
+/// the console locked, so the report is given up on rather than waited for.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    unsafe { instructions::disable_interrupts(); }
+
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        vga::try_print(Some(vga::Color::Red), format_args!("Kernel panic: {}\n", info));
+    }
+
+    if cmdline::test_mode() {
+        power::shutdown();
+    }
+
+    power::reboot()
 }
 
 /// Some precompiled libaries assume the existence of this symbol, so we
 /// provide a diverging implementation.
+#[cfg(not(test))]
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern fn _Unwind_Resume() -> ! {
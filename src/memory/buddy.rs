@@ -0,0 +1,489 @@
+//! Provides the buddy allocator, which takes over the free frames from the early allocator in `frame` once it's been
+//! set up, and hands them out in blocks of a power of two frames (the block's order), aligned to their size. A free
+//! block is split in half (into two buddies) until it's the size wanted, and freed blocks are merged back with their
+//! buddies when those are free too, so big blocks stay available for as long as possible.
+//!
+//! Which blocks are free is kept in a bitmap for each order, rather than in lists threaded through the free frames,
+//! so frames outside the identity map can be managed before they're mapped anywhere.
+//!
+//! Single frames, which are most of what gets allocated, come from a small cache on each processor, which only it
+//! touches (with interrupts disabled); the allocator's lock is only taken to refill or empty a cache, a batch of
+//! frames at a time, so processors allocating at once don't all queue up on it.
+
+use core::cell::UnsafeCell;
+use core::cmp;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::{Mutex, Once};
+
+use apic;
+use arch::x86_64::{instructions, IDENTITY_MAP_SIZE};
+use numa::MAX_CPUS;
+use super::FRAME_SIZE;
+use super::frame::{FrameAllocator, FrameError, FrameStats};
+
+/// The largest order, of blocks of 2^18 frames (1GiB).
+pub const MAX_ORDER: usize = 18;
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// The number of blocks each word of the bitmap covers.
+const BLOCKS_PER_WORD: u64 = 64;
+
+/// The most frames each processor's cache holds, and how many are moved between it and the allocator at once.
+const CACHE_SIZE: usize = 32;
+const CACHE_BATCH: usize = CACHE_SIZE / 2;
+
+/// The order of the smallest block with room for the given number of frames.
+pub fn order_of(frames: u64) -> usize {
+    if frames <= 1 { 0 } else { 64 - (frames - 1).leading_zeros() as usize }
+}
+
+/// Keeps track of which blocks are free, in a bitmap for each order.
+#[derive(Debug)]
+pub struct BuddyAllocator<'a> {
+    /// For each order, a bit for each block of that size, set if the block is free (and isn't part of a bigger free
+    /// block). Each order's bits start at it's offset.
+    bitmap: &'a mut [u64],
+    offsets: [usize; ORDERS],
+
+    /// The number of frames covered.
+    frames: u64,
+
+    /// How many blocks of each order are free, and the first block of each order which might be.
+    counts: [u64; ORDERS],
+    hints: [u64; ORDERS],
+
+    /// The number of frames added, and the number which are free.
+    total: u64,
+    free: u64
+}
+
+impl<'a> BuddyAllocator<'a> {
+    /// The number of words of bitmap needed to cover the given number of frames.
+    pub fn bitmap_words(frames: u64) -> usize {
+        (0 .. ORDERS).map(|order| words(frames >> order)).sum()
+    }
+
+    /// Creates an allocator covering the given number of frames, none of which are free until they're added.
+    pub fn new(bitmap: &'a mut [u64], frames: u64) -> BuddyAllocator<'a> {
+        assert!(bitmap.len() >= Self::bitmap_words(frames), "The buddy allocator's bitmap is too small");
+
+        for word in bitmap.iter_mut() {
+            *word = 0;
+        }
+
+        let mut offsets = [0; ORDERS];
+        for order in 1 .. ORDERS {
+            offsets[order] = offsets[order - 1] + words(frames >> (order - 1));
+        }
+
+        BuddyAllocator {
+            bitmap: bitmap,
+            offsets: offsets,
+            frames: frames,
+            counts: [0; ORDERS],
+            hints: [0; ORDERS],
+            total: 0,
+            free: 0
+        }
+    }
+
+    /// The number of whole blocks of the given order which fit in the frames covered.
+    fn blocks(&self, order: usize) -> u64 {
+        self.frames >> order
+    }
+
+    /// The word of the bitmap with the given block's bit in.
+    fn word(&self, order: usize, index: u64) -> usize {
+        self.offsets[order] + (index / BLOCKS_PER_WORD) as usize
+    }
+
+    fn is_free(&self, order: usize, index: u64) -> bool {
+        index < self.blocks(order) && self.bitmap[self.word(order, index)] & 1 << (index % BLOCKS_PER_WORD) != 0
+    }
+
+    fn set_free(&mut self, order: usize, index: u64, free: bool) {
+        let word = self.word(order, index);
+        let word = &mut self.bitmap[word];
+
+        if free {
+            *word |= 1 << (index % BLOCKS_PER_WORD);
+            self.counts[order] += 1;
+            self.hints[order] = cmp::min(self.hints[order], index);
+        } else {
+            *word &= !(1 << (index % BLOCKS_PER_WORD));
+            self.counts[order] -= 1;
+        }
+    }
+
+    /// Finds the first free block of the given order with an index below the limit.
+    fn find_free(&mut self, order: usize, limit: u64) -> Option<u64> {
+        let limit = cmp::min(limit, self.blocks(order));
+        let mut index = self.hints[order];
+
+        while index < limit {
+            let word = self.bitmap[self.word(order, index)] >> (index % BLOCKS_PER_WORD);
+
+            if word == 0 {
+                index = (index / BLOCKS_PER_WORD + 1) * BLOCKS_PER_WORD;
+            } else {
+                // Nothing between the hint and this block is free, whether or not it's below the limit.
+                let found = index + word.trailing_zeros() as u64;
+
+                self.hints[order] = found;
+                return if found < limit { Some(found) } else { None };
+            }
+        }
+
+        self.hints[order] = cmp::max(self.hints[order], cmp::min(index, limit));
+        None
+    }
+
+    /// Allocates a block of the given order which ends at or below the given frame, returning it's first frame.
+    fn allocate_frames(&mut self, order: usize, limit: u64) -> Option<u64> {
+        if order > MAX_ORDER { return None; }
+
+        for larger in order .. ORDERS {
+            if self.counts[larger] == 0 { continue; }
+
+            if let Some(mut index) = self.find_free(larger, limit >> larger) {
+                self.set_free(larger, index, false);
+
+                // Split the block down to the order wanted, freeing the second half each time.
+                for smaller in (order .. larger).rev() {
+                    index *= 2;
+                    self.set_free(smaller, index + 1, true);
+                }
+
+                self.free -= 1 << order;
+                return Some(index << order);
+            }
+        }
+
+        None
+    }
+
+    /// Frees a block of the given order, merging it with it's buddy for as long as that's free too. Returns false
+    /// (and does nothing) if the block is out of range, misaligned, or is (or is part of) a free block.
+    fn free_frames(&mut self, frame: u64, order: usize) -> bool {
+        if order > MAX_ORDER || frame % (1 << order) != 0 { return false; }
+        if frame.checked_add(1 << order).map(|end| end > self.frames).unwrap_or(true) { return false; }
+        if (order .. ORDERS).any(|larger| self.is_free(larger, frame >> larger)) { return false; }
+
+        self.free += 1 << order;
+
+        let mut index = frame >> order;
+        let mut order = order;
+
+        while order < MAX_ORDER && self.is_free(order, index ^ 1) {
+            self.set_free(order, index ^ 1, false);
+            index /= 2;
+            order += 1;
+        }
+
+        self.set_free(order, index, true);
+        true
+    }
+
+    /// Frees a run of frames, as the biggest aligned blocks which fit it. Returns false if any of them couldn't be.
+    fn free_run(&mut self, frame: u64, count: u64) -> bool {
+        let (mut frame, mut count, mut freed) = (frame, count, true);
+
+        while count > 0 {
+            let order = cmp::min(cmp::min(frame.trailing_zeros(), 63 - count.leading_zeros()) as usize, MAX_ORDER);
+
+            freed &= self.free_frames(frame, order);
+            frame += 1 << order;
+            count -= 1 << order;
+        }
+
+        freed
+    }
+
+    /// Hands a run of free frames, starting at the given physical address, to the allocator.
+    pub fn add(&mut self, address: u64, count: u64) {
+        if self.free_run(address / FRAME_SIZE, count) {
+            self.total += count;
+        }
+    }
+
+    /// Allocates a block of the given order, returning it's physical address.
+    pub fn allocate(&mut self, order: usize) -> Option<u64> {
+        self.allocate_frames(order, self.frames).map(|frame| frame * FRAME_SIZE)
+    }
+
+    /// Allocates a run of contiguous frames, starting at a multiple of the given alignment (a power of two), which
+    /// all lie below the given physical address. The run is carved out of a block big enough for both the count and
+    /// the alignment, and the rest of the block is freed again.
+    pub fn allocate_contiguous(&mut self, count: u64, alignment: u64, limit: u64) -> Option<u64> {
+        if count == 0 { return None; }
+
+        let order = cmp::max(order_of(count), order_of(cmp::max(alignment, FRAME_SIZE) / FRAME_SIZE));
+        let frame = self.allocate_frames(order, limit / FRAME_SIZE)?;
+
+        self.free_run(frame + count, (1 << order) - count);
+        Some(frame * FRAME_SIZE)
+    }
+
+    /// Frees a block of the given order. Returns false (and does nothing) if it's already free.
+    pub fn free(&mut self, address: u64, order: usize) -> bool {
+        address % FRAME_SIZE == 0 && self.free_frames(address / FRAME_SIZE, order)
+    }
+
+    /// Frees a run of contiguous frames. Returns false if any of them were already free.
+    pub fn free_contiguous(&mut self, address: u64, count: u64) -> bool {
+        address % FRAME_SIZE == 0 && self.free_run(address / FRAME_SIZE, count)
+    }
+
+    /// How many frames there are, and how many are free.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats { total: self.total, free: self.free }
+    }
+}
+
+/// The number of words needed for a bit for each of the given number of blocks.
+fn words(blocks: u64) -> usize {
+    ((blocks + BLOCKS_PER_WORD - 1) / BLOCKS_PER_WORD) as usize
+}
+
+/// A processor's cache of free frames.
+#[derive(Clone, Copy)]
+struct Cache {
+    frames: [u64; CACHE_SIZE],
+    count: usize
+}
+
+impl Cache {
+    /// Fills the cache up to half full from the allocator, so there's room either way afterwards.
+    fn refill(&mut self) {
+        let mut buddy = BUDDY.lock();
+        let buddy = match buddy.as_mut() { Some(buddy) => buddy, None => return };
+
+        while self.count < CACHE_BATCH {
+            match buddy.allocate(0) {
+                Some(frame) => { self.frames[self.count] = frame; self.count += 1; },
+                None => break
+            }
+
+            CACHED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Empties the cache down to half full into the allocator.
+    fn drain(&mut self) {
+        let mut buddy = BUDDY.lock();
+        let buddy = match buddy.as_mut() { Some(buddy) => buddy, None => return };
+
+        while self.count > CACHE_BATCH {
+            self.count -= 1;
+            buddy.free(self.frames[self.count], 0);
+            CACHED.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Each processor's cache, indexed by it's local APIC id.
+struct Caches(UnsafeCell<[Cache; MAX_CPUS]>);
+
+// UNSAFE: Safe, as each processor only touches it's own cache, with interrupts disabled.
+unsafe impl Sync for Caches {}
+
+static CACHES: Caches = Caches(UnsafeCell::new([Cache { frames: [0; CACHE_SIZE], count: 0 }; MAX_CPUS]));
+
+/// The allocator, set up by init(). Only taken with interrupts disabled, so frames can be allocated in interrupt
+/// handlers.
+static BUDDY: Mutex<Option<BuddyAllocator<'static>>> = Mutex::new(None);
+
+/// The number of frames covered, once init() has set the allocator up.
+static FRAMES: Once<u64> = Once::new();
+
+/// The number of frames sitting in the processors' caches (which count as free).
+static CACHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Takes over from the early allocator: the bitmap is allocated from it, and then every frame it has free (and the
+/// frames of it's own bitmap) are handed over.
+pub fn init(early: FrameAllocator<'static>) -> Result<FrameStats, FrameError> {
+    let mut early = early;
+    let frames = early.frames();
+    let words = BuddyAllocator::bitmap_words(frames);
+    let pages = (words as u64 * 8 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+    let bitmap = early.allocate_contiguous_below(pages, FRAME_SIZE, IDENTITY_MAP_SIZE)
+        .ok_or(FrameError::NoRoomForBitmap)?;
+
+    // UNSAFE: Safe, as the frames were just allocated, and are identity mapped.
+    let bitmap = unsafe { slice::from_raw_parts_mut(bitmap as *mut u64, words) };
+    let mut buddy = BuddyAllocator::new(bitmap, frames);
+
+    for (address, count) in early.free_runs() {
+        buddy.add(address, count);
+    }
+
+    let (start, end) = early.bitmap_region();
+    drop(early);
+    buddy.add(start, (end - start) / FRAME_SIZE);
+
+    let stats = buddy.stats();
+
+    instructions::without_interrupts(|| *BUDDY.lock() = Some(buddy));
+    FRAMES.call_once(|| frames);
+    Ok(stats)
+}
+
+/// True once init() has set the allocator up.
+pub fn is_initialized() -> bool {
+    FRAMES.try().is_some()
+}
+
+/// Runs the closure on this processor's cache, with interrupts disabled.
+fn with_cache<T, F: FnOnce(&mut Cache) -> T>(f: F) -> T {
+    instructions::without_interrupts(|| {
+        // Before the APIC's been set up, only the bootstrap processor is running.
+        let cpu = apic::local_apic_id().unwrap_or(0) as usize % MAX_CPUS;
+
+        // UNSAFE: Safe, as only this processor touches it's cache, and nothing else on it can with interrupts
+        // disabled.
+        f(unsafe { &mut *(CACHES.0.get() as *mut Cache).offset(cpu as isize) })
+    })
+}
+
+/// Allocates a frame from this processor's cache, refilling it first if it's empty.
+pub fn allocate_frame() -> Option<u64> {
+    with_cache(|cache| {
+        if cache.count == 0 { cache.refill(); }
+        if cache.count == 0 { return None; }
+
+        cache.count -= 1;
+        CACHED.fetch_sub(1, Ordering::SeqCst);
+        Some(cache.frames[cache.count])
+    })
+}
+
+/// Frees a frame into this processor's cache, emptying half of it first if it's full. Returns false if the frame's
+/// out of range, or is already in the cache (frames freed twice which have since left the cache aren't caught).
+pub fn free_frame(address: u64) -> bool {
+    let frames = match FRAMES.try() { Some(&frames) => frames, None => return false };
+    if address % FRAME_SIZE != 0 || address / FRAME_SIZE >= frames { return false; }
+
+    with_cache(|cache| {
+        if cache.frames[.. cache.count].contains(&address) { return false; }
+
+        if cache.count == CACHE_SIZE { cache.drain(); }
+
+        cache.frames[cache.count] = address;
+        cache.count += 1;
+        CACHED.fetch_add(1, Ordering::SeqCst);
+        true
+    })
+}
+
+/// Runs the closure on the allocator, if it's been set up.
+fn with_allocator<T, F: FnOnce(&mut BuddyAllocator<'static>) -> Option<T>>(f: F) -> Option<T> {
+    instructions::without_interrupts(|| BUDDY.lock().as_mut().and_then(f))
+}
+
+/// Allocates a run of contiguous frames, starting at a multiple of the given alignment, which all lie below the
+/// given physical address.
+pub fn allocate_contiguous(count: u64, alignment: u64, limit: u64) -> Option<u64> {
+    with_allocator(|buddy| buddy.allocate_contiguous(count, alignment, limit))
+}
+
+/// Frees a run of contiguous frames. Returns false if any of them were already free.
+pub fn free_contiguous(address: u64, count: u64) -> bool {
+    with_allocator(|buddy| Some(buddy.free_contiguous(address, count))).unwrap_or(false)
+}
+
+/// How many frames there are, and how many are free (including the ones in the processors' caches).
+pub fn stats() -> Option<FrameStats> {
+    let stats = with_allocator(|buddy| Some(buddy.stats()))?;
+
+    Some(FrameStats { total: stats.total, free: stats.free + CACHED.load(Ordering::SeqCst) as u64 })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn allocator(bitmap: &mut Vec<u64>, frames: u64) -> BuddyAllocator {
+        *bitmap = vec![!0; BuddyAllocator::bitmap_words(frames)];
+        BuddyAllocator::new(bitmap, frames)
+    }
+
+    #[test]
+    fn splits_and_merges_blocks() {
+        let mut bitmap = Vec::new();
+        let mut buddy = allocator(&mut bitmap, 0x400);
+
+        buddy.add(0, 0x400);
+        assert_eq!(buddy.counts[10], 1);
+
+        assert_eq!(buddy.allocate(0), Some(0));
+        assert_eq!(buddy.allocate(0), Some(0x1000));
+        assert_eq!(buddy.allocate(2), Some(0x4000));
+        assert_eq!(buddy.allocate(0), Some(0x2000));
+        assert_eq!(buddy.stats(), FrameStats { total: 0x400, free: 0x400 - 7 });
+
+        assert!(buddy.free(0x1000, 0));
+        assert!(!buddy.free(0x1000, 0));
+        assert!(buddy.free(0, 0));
+        assert!(buddy.free(0x4000, 2));
+        assert!(buddy.free(0x2000, 0));
+
+        // Everything's merged back into the one block.
+        assert_eq!(buddy.counts[10], 1);
+        assert_eq!(buddy.stats().free, 0x400);
+        assert_eq!(buddy.allocate(10), Some(0));
+        assert_eq!(buddy.allocate(0), None);
+    }
+
+    #[test]
+    fn adds_unaligned_runs() {
+        let mut bitmap = Vec::new();
+        let mut buddy = allocator(&mut bitmap, 0x100);
+
+        buddy.add(0x3000, 0x1D);
+        assert_eq!(buddy.stats(), FrameStats { total: 0x1D, free: 0x1D });
+
+        // 3, 4-7, 8-15 and 16-31.
+        assert_eq!(&buddy.counts[.. 5], &[1, 0, 1, 1, 1]);
+        assert_eq!(buddy.allocate(3), Some(0x8000));
+        assert_eq!(buddy.allocate(5), None);
+
+        // The frames past the end of the bitmap's blocks can't be freed.
+        assert!(!buddy.free(0x100000, 0));
+    }
+
+    #[test]
+    fn allocates_aligned_runs_below_a_limit() {
+        let mut bitmap = Vec::new();
+        let mut buddy = allocator(&mut bitmap, 0x1000);
+
+        buddy.add(0x10000, 0xFF0);
+
+        assert_eq!(buddy.allocate_contiguous(3, 0x8000, !0), Some(0x10000));
+        assert_eq!(buddy.stats().free, 0xFF0 - 3);
+        assert_eq!(buddy.allocate_contiguous(1, 0x1000, !0), Some(0x13000));
+        assert_eq!(buddy.allocate_contiguous(0x200, 0x200000, 0x200000), None);
+        assert_eq!(buddy.allocate_contiguous(0x200, 0x200000, !0), Some(0x200000));
+        assert_eq!(buddy.allocate_contiguous(4, 0x1000, 0x18000), Some(0x14000));
+        assert_eq!(buddy.allocate_contiguous(0, 0x1000, !0), None);
+
+        assert!(buddy.free_contiguous(0x10000, 4));
+        assert!(!buddy.free_contiguous(0x10000, 1));
+        assert!(buddy.free_contiguous(0x200000, 0x200));
+        assert!(buddy.free_contiguous(0x14000, 4));
+        assert_eq!(buddy.stats().free, 0xFF0);
+    }
+
+    #[test]
+    fn finds_the_order_of_a_count() {
+        assert_eq!(order_of(1), 0);
+        assert_eq!(order_of(2), 1);
+        assert_eq!(order_of(3), 2);
+        assert_eq!(order_of(0x200), 9);
+        assert_eq!(order_of(0x201), 10);
+    }
+}
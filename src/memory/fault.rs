@@ -0,0 +1,180 @@
+//! Provides the page fault handler. Faults on pages which have been handed out from a demand-zero region of the
+//! layout (like big heap allocations) but not touched yet are expected: the page is backed by a newly allocated,
+//! zeroed, frame, and the faulting instruction runs again. Any other fault is a bug, so the handler reports
+//! everything it knows about it (the access, the address, where it came from, and the page table walk for the
+//! address) and halts.
+//!
+//! Also provides the double fault handler, which the processor raises when it can't deliver a page fault: most
+//! often because a kernel stack overflowed into it's guard page, leaving nowhere to push the page fault's frame.
+//! It runs on a stack of it's own, and reports which stack overflowed.
+
+use core::fmt;
+use core::ptr;
+
+use arch::x86_64::instructions;
+use interrupts::{self, InterruptFrame, DOUBLE_FAULT_VECTOR};
+use super::{FRAME_SIZE, align_down};
+use super::frame::{self, FrameSize};
+use super::layout::{self, Region};
+use super::paging::{self, WRITABLE};
+use super::stack;
+
+/// The page fault exception's vector.
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+
+/// The bits of the page fault error code.
+const ERROR_PRESENT: u64 = 1 << 0;
+const ERROR_WRITE: u64 = 1 << 1;
+const ERROR_USER: u64 = 1 << 2;
+const ERROR_RESERVED: u64 = 1 << 3;
+const ERROR_INSTRUCTION_FETCH: u64 = 1 << 4;
+
+/// The error code the processor pushes for a page fault, which says what kind of access faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultError(pub u64);
+
+impl FaultError {
+    /// True if the page was present, so the fault was a protection violation rather than a missing page.
+    pub fn is_present(&self) -> bool {
+        self.0 & ERROR_PRESENT != 0
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.0 & ERROR_WRITE != 0
+    }
+
+    /// True if the access came from user mode.
+    pub fn is_user(&self) -> bool {
+        self.0 & ERROR_USER != 0
+    }
+
+    /// True if a reserved bit was set in one of the entries on the way to the page.
+    pub fn is_reserved(&self) -> bool {
+        self.0 & ERROR_RESERVED != 0
+    }
+
+    pub fn is_instruction_fetch(&self) -> bool {
+        self.0 & ERROR_INSTRUCTION_FETCH != 0
+    }
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let access = match (self.is_instruction_fetch(), self.is_write()) {
+            (true, _) => "execute",
+            (false, true) => "write",
+            (false, false) => "read"
+        };
+
+        write!(formatter, "{} of {} page from {} mode{}", access,
+            if self.is_present() { "a present" } else { "a missing" },
+            if self.is_user() { "user" } else { "kernel" },
+            if self.is_reserved() { ", with a reserved bit set" } else { "" })
+    }
+}
+
+/// Installs the page fault and double fault handlers.
+pub fn init() {
+    interrupts::register(PAGE_FAULT_VECTOR, page_fault);
+    interrupts::register(DOUBLE_FAULT_VECTOR, double_fault);
+}
+
+/// Handles a page fault: backs the page if it's in a demand-zero range, and reports the fault and halts otherwise.
+fn page_fault(frame: &mut InterruptFrame) {
+    let address = instructions::page_fault_address();
+    let error = FaultError(frame.error_code);
+
+    if !error.is_present() && !error.is_user() && layout::is_demand_zero(address) && back_page(address) {
+        return;
+    }
+
+    report(frame, address, error);
+    loop { instructions::halt(); }
+}
+
+/// Maps the page holding the given address to a newly allocated, zeroed, frame. Returns false if there are no frames
+/// left (or the kernel's address space isn't set up).
+fn back_page(address: u64) -> bool {
+    let frame = match frame::allocate() {
+        Some(frame) => frame,
+        None => return false
+    };
+
+    // UNSAFE: Safe, as the frame was just allocated, and the page was handed out but never mapped.
+    unsafe {
+        match layout::phys_to_virt(frame) {
+            Some(zeroed) => ptr::write_bytes(zeroed as *mut u8, 0, FRAME_SIZE as usize),
+            None => {
+                frame::free(frame);
+                return false;
+            }
+        }
+
+        if paging::map(align_down(address, FRAME_SIZE), frame, FrameSize::Small, WRITABLE | paging::no_execute())
+            .is_err() {
+
+            frame::free(frame);
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Reports a double fault and halts; there's no coming back from one.
+fn double_fault(frame: &mut InterruptFrame) {
+    // A page fault which couldn't be delivered leaves the guard page's address in CR2; failing that, the stack
+    // pointer is left at the bottom of the stack which overflowed.
+    let address = instructions::page_fault_address();
+
+    match stack::overflowed(address).or_else(|| stack::overflowed(frame.rsp)) {
+        Some(overflowed) => color_println!(::vga::Color::Red,
+            "Kernel stack overflow: the {} stack (0x{:x}-0x{:x}) ran into it's guard page at 0x{:x}", overflowed.name(),
+            overflowed.bottom(), overflowed.top(), address),
+        None => color_println!(::vga::Color::Red, "Double fault (last page fault at 0x{:x})", address)
+    }
+
+    // The saved RIP and RSP are undefined for double faults, but are usually where the first fault happened.
+    color_println!(::vga::Color::Red, "{}", frame);
+    loop { instructions::halt(); }
+}
+
+/// Prints everything known about a fault which can't be handled.
+fn report(frame: &InterruptFrame, address: u64, error: FaultError) {
+    color_println!(::vga::Color::Red, "Page fault: {} at 0x{:x} (error code 0x{:x})", error, address, error.0);
+    color_println!(::vga::Color::Red, "{}", frame);
+
+    if let Some(region) = Region::of(address) {
+        let unused = region.is_demand_zero() && !layout::is_demand_zero(address);
+        color_println!(::vga::Color::Red, "    In the {:?} region{}", region,
+            if unused { ", outside of anything handed out from it" } else { "" });
+    } else if address < FRAME_SIZE {
+        color_println!(::vga::Color::Red, "    In the first page, so probably a null pointer");
+    }
+
+    if let Some(overflowed) = stack::overflowed(address) {
+        color_println!(::vga::Color::Red, "    In the guard page of the {} stack, so it overflowed", overflowed.name());
+    }
+
+    for (level, entry) in paging::walk(address).iter().enumerate() {
+        if let Some(entry) = *entry {
+            color_println!(::vga::Color::Red, "    Level {} entry {}: {:?}", 4 - level,
+                (address >> (39 - 9 * level)) & 0x1FF, entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_faults() {
+        assert_eq!(format!("{}", FaultError(0)), "read of a missing page from kernel mode");
+        assert_eq!(format!("{}", FaultError(ERROR_PRESENT | ERROR_WRITE)), "write of a present page from kernel mode");
+        assert_eq!(format!("{}", FaultError(ERROR_PRESENT | ERROR_INSTRUCTION_FETCH | ERROR_USER)),
+            "execute of a present page from user mode");
+        assert_eq!(format!("{}", FaultError(ERROR_RESERVED | ERROR_PRESENT)),
+            "read of a present page from kernel mode, with a reserved bit set");
+    }
+}
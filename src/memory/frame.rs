@@ -0,0 +1,518 @@
+//! Provides the physical frame allocator, which hands out the 4KiB frames of memory the boot loader's memory map
+//! says are available, apart from the ones already in use when the kernel starts: the real mode IVT and BIOS data
+//! and the low bootstrap region, the kernel image, the modules, and the boot information.
+//!
+//! Every frame up to the end of the highest available area has a bit in a bitmap (set for frames which are in use,
+//! or which aren't memory at all), which makes runs of contiguous and aligned frames easy to find. The bitmap itself
+//! goes in the first available memory below the identity map with room for it.
+//!
+//! This is only the early allocator: once hand_over() is called, every free frame moves to the buddy allocator in
+//! `buddy`, and the functions here allocate from that instead.
+
+use core::{cmp, slice};
+
+use spin::Mutex;
+
+use arch::x86_64::{self, instructions, IDENTITY_MAP_SIZE};
+use boot_info::{BootInfo, MemoryArea, MemoryKind};
+use super::{FRAME_SIZE, LARGE_FRAME_SIZE, HUGE_FRAME_SIZE, align_up, align_down};
+use super::buddy;
+
+/// The most regions init() keeps out of the allocator.
+const MAX_RESERVED: usize = 16;
+
+/// The number of frames each word of the bitmap covers.
+const FRAMES_PER_WORD: u64 = 64;
+
+/// The sizes of frame which can be allocated on their own, each aligned to it's size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameSize {
+    /// A 4KiB frame.
+    Small,
+
+    /// A 2MiB frame, which a large page can map.
+    Large,
+
+    /// A 1GiB frame, which a huge page can map.
+    Huge
+}
+
+impl FrameSize {
+    /// The size in bytes.
+    pub fn bytes(&self) -> u64 {
+        match *self {
+            FrameSize::Small => FRAME_SIZE,
+            FrameSize::Large => LARGE_FRAME_SIZE,
+            FrameSize::Huge => HUGE_FRAME_SIZE
+        }
+    }
+}
+
+/// The reasons the frame allocator can't be set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The boot loader gave us no memory map, or it has no available memory.
+    NoMemoryMap,
+
+    /// There's no available memory below the identity map big enough for the bitmap.
+    NoRoomForBitmap
+}
+
+/// How many frames there are, and how many are free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// The frames of available memory which weren't in use when the kernel started.
+    pub total: u64,
+
+    pub free: u64
+}
+
+impl FrameStats {
+    /// The number of frames handed out.
+    pub fn used(&self) -> u64 {
+        self.total - self.free
+    }
+}
+
+/// Keeps track of which frames are free, in a bitmap.
+#[derive(Debug)]
+pub struct FrameAllocator<'a> {
+    /// A bit for each frame, set if it's in use (or isn't available memory).
+    bitmap: &'a mut [u64],
+
+    /// The number of frames the bitmap covers.
+    frames: u64,
+
+    /// The number of frames which were free to start with, and which are free now.
+    total: u64,
+    free: u64,
+
+    /// No frame before this one is free.
+    next_free: u64
+}
+
+impl<'a> FrameAllocator<'a> {
+    /// The number of words of bitmap needed to cover every available area of the memory map.
+    pub fn bitmap_words(areas: &[MemoryArea]) -> usize {
+        let frames = areas.iter().filter(|area| area.kind == MemoryKind::Available)
+            .map(|area| align_down(area.end(), FRAME_SIZE) / FRAME_SIZE).max().unwrap_or(0);
+
+        ((frames + FRAMES_PER_WORD - 1) / FRAMES_PER_WORD) as usize
+    }
+
+    /// Creates an allocator over the available areas of the memory map, apart from the given reserved regions (as
+    /// pairs of start and end addresses). Only the frames which lie entirely within an available area are used.
+    pub fn new(bitmap: &'a mut [u64], areas: &[MemoryArea], reserved: &[(u64, u64)]) -> FrameAllocator<'a> {
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let frames = bitmap.len() as u64 * FRAMES_PER_WORD;
+        let mut allocator = FrameAllocator { bitmap: bitmap, frames: frames, total: 0, free: 0, next_free: 0 };
+
+        for area in areas.iter().filter(|area| area.kind == MemoryKind::Available) {
+            let first = align_up(area.base, FRAME_SIZE) / FRAME_SIZE;
+            let end = cmp::min(align_down(area.end(), FRAME_SIZE) / FRAME_SIZE, frames);
+
+            for frame in first .. cmp::max(first, end) {
+                allocator.set_used(frame, false);
+            }
+        }
+
+        for &(start, end) in reserved {
+            let first = align_down(start, FRAME_SIZE) / FRAME_SIZE;
+            let end = cmp::min(align_up(end, FRAME_SIZE) / FRAME_SIZE, frames);
+
+            for frame in first .. cmp::max(first, end) {
+                allocator.set_used(frame, true);
+            }
+        }
+
+        allocator.free = allocator.bitmap.iter().map(|word| word.count_zeros() as u64).sum();
+        allocator.total = allocator.free;
+        allocator
+    }
+
+    /// True if the frame is in use (or isn't available memory).
+    fn is_used(&self, frame: u64) -> bool {
+        self.bitmap[(frame / FRAMES_PER_WORD) as usize] & (1 << (frame % FRAMES_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, frame: u64, used: bool) {
+        let word = &mut self.bitmap[(frame / FRAMES_PER_WORD) as usize];
+
+        if used { *word |= 1 << (frame % FRAMES_PER_WORD); } else { *word &= !(1 << (frame % FRAMES_PER_WORD)); }
+    }
+
+    /// Allocates a frame, returning it's physical address.
+    pub fn allocate(&mut self) -> Option<u64> {
+        let first_word = (self.next_free / FRAMES_PER_WORD) as usize;
+        let (index, word) = self.bitmap[first_word ..].iter().enumerate().find(|&(_, &word)| word != !0)?;
+
+        let frame = (first_word + index) as u64 * FRAMES_PER_WORD + (!word).trailing_zeros() as u64;
+
+        self.set_used(frame, true);
+        self.free -= 1;
+        self.next_free = frame + 1;
+
+        Some(frame * FRAME_SIZE)
+    }
+
+    /// Allocates a frame below the given physical address (like one the identity map reaches).
+    pub fn allocate_below(&mut self, limit: u64) -> Option<u64> {
+        self.allocate_contiguous_below(1, FRAME_SIZE, limit)
+    }
+
+    /// Allocates a frame of the given size, aligned to it's size.
+    pub fn allocate_sized(&mut self, size: FrameSize) -> Option<u64> {
+        self.allocate_contiguous(size.bytes() / FRAME_SIZE, size.bytes())
+    }
+
+    /// Allocates a run of contiguous frames, starting at a multiple of the given alignment (a power of two, and at
+    /// least the frame size). Returns the physical address of the first.
+    pub fn allocate_contiguous(&mut self, count: u64, alignment: u64) -> Option<u64> {
+        self.allocate_contiguous_below(count, alignment, !0)
+    }
+
+    /// Allocates a run of contiguous, aligned frames like allocate_contiguous(), which all lie below the given
+    /// physical address.
+    pub fn allocate_contiguous_below(&mut self, count: u64, alignment: u64, limit: u64) -> Option<u64> {
+        if count == 0 || count > self.free { return None; }
+
+        let alignment = cmp::max(alignment, FRAME_SIZE) / FRAME_SIZE;
+        let limit = cmp::min(limit / FRAME_SIZE, self.frames);
+        let mut start = align_up(self.next_free, alignment);
+
+        while start.checked_add(count).map(|end| end <= limit).unwrap_or(false) {
+            match (start .. start + count).rev().find(|&frame| self.is_used(frame)) {
+                // Nothing up to the used frame can start a long enough run, so skip past it.
+                Some(used) => start = align_up(used + 1, alignment),
+                None => {
+                    for frame in start .. start + count {
+                        self.set_used(frame, true);
+                    }
+
+                    self.free -= count;
+                    if start == self.next_free { self.next_free = start + count; }
+
+                    return Some(start * FRAME_SIZE);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Frees a frame. Returns false (and does nothing) if it's already free.
+    pub fn free(&mut self, address: u64) -> bool {
+        self.free_contiguous(address, 1)
+    }
+
+    /// Frees a run of contiguous frames. Returns false (and frees none of them) if any of them are already free.
+    ///
+    /// The bitmap doesn't tell allocated frames from ones which were never available, so those must not be freed.
+    pub fn free_contiguous(&mut self, address: u64, count: u64) -> bool {
+        if address % FRAME_SIZE != 0 { return false; }
+
+        let first = address / FRAME_SIZE;
+        if first.checked_add(count).map(|end| end > self.frames).unwrap_or(true) { return false; }
+        if (first .. first + count).any(|frame| !self.is_used(frame)) { return false; }
+
+        for frame in first .. first + count {
+            self.set_used(frame, false);
+        }
+
+        self.free += count;
+        self.next_free = cmp::min(self.next_free, first);
+        true
+    }
+
+    /// How many frames there are, and how many are free.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats { total: self.total, free: self.free }
+    }
+
+    /// The number of frames the bitmap covers.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The start and end addresses of the bitmap.
+    pub fn bitmap_region(&self) -> (u64, u64) {
+        let start = self.bitmap.as_ptr() as u64;
+        (start, start + self.bitmap.len() as u64 * 8)
+    }
+
+    /// The runs of free frames, as their physical address and the number of frames.
+    pub fn free_runs<'b>(&'b self) -> FreeRuns<'b, 'a> {
+        FreeRuns { allocator: self, next: 0 }
+    }
+}
+
+/// An iterator over the runs of free frames in a frame allocator.
+pub struct FreeRuns<'b, 'a: 'b> {
+    allocator: &'b FrameAllocator<'a>,
+
+    /// The frame to carry on looking from.
+    next: u64
+}
+
+impl<'b, 'a> Iterator for FreeRuns<'b, 'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let frames = self.allocator.frames;
+
+        while self.next < frames && self.allocator.is_used(self.next) {
+            // Skip whole words of used frames at once.
+            if self.next % FRAMES_PER_WORD == 0 && self.allocator.bitmap[(self.next / FRAMES_PER_WORD) as usize] == !0 {
+                self.next += FRAMES_PER_WORD;
+            } else {
+                self.next += 1;
+            }
+        }
+
+        if self.next >= frames { return None; }
+
+        let start = self.next;
+        while self.next < frames && !self.allocator.is_used(self.next) {
+            self.next += 1;
+        }
+
+        Some((start * FRAME_SIZE, self.next - start))
+    }
+}
+
+/// The allocator, set up by init() and given up by hand_over(). Only taken with interrupts disabled, so frames can
+/// be allocated in interrupt handlers.
+static FRAMES: Mutex<Option<FrameAllocator<'static>>> = Mutex::new(None);
+
+/// Sets up the allocator from the memory map in the boot information, keeping out everything already in use.
+pub fn init(boot_info: &BootInfo) -> Result<FrameStats, FrameError> {
+    let areas = boot_info.memory_areas();
+    if !areas.iter().any(|area| area.kind == MemoryKind::Available) { return Err(FrameError::NoMemoryMap); }
+
+    let mut reserved = [(0, 0); MAX_RESERVED];
+    let mut reserved_count = 0;
+
+    // The real mode IVT and BIOS data area sit below the bootstrap region, and are kept along with it.
+    reserved[0] = (0, x86_64::bootstrap_end_address());
+    reserved[1] = x86_64::kernel_image();
+    reserved[2] = boot_info.info_region();
+    reserved_count += 3;
+
+    // Every module is kept out, not just the ones the boot information copied; once there's only room left for the
+    // bitmap, the last module's range grows to cover the rest.
+    // UNSAFE: Safe, as nothing has been allocated yet, so the boot information is still where the loader left it.
+    for (start, end) in unsafe { boot_info.module_ranges() } {
+        if reserved_count < MAX_RESERVED - 1 {
+            reserved[reserved_count] = (start, end);
+            reserved_count += 1;
+        } else {
+            let last = &mut reserved[reserved_count - 1];
+            *last = (cmp::min(last.0, start), cmp::max(last.1, end));
+        }
+    }
+
+    let words = FrameAllocator::bitmap_words(areas);
+    let size = words as u64 * 8;
+    let bitmap = place_bitmap(areas, &reserved[.. reserved_count], size).ok_or(FrameError::NoRoomForBitmap)?;
+
+    reserved[reserved_count] = (bitmap, bitmap + size);
+    reserved_count += 1;
+
+    // UNSAFE: Safe, as the bitmap is in available, identity mapped memory, which nothing else is using.
+    let bitmap = unsafe { slice::from_raw_parts_mut(bitmap as *mut u64, words) };
+    let allocator = FrameAllocator::new(bitmap, areas, &reserved[.. reserved_count]);
+    let stats = allocator.stats();
+
+    instructions::without_interrupts(|| *FRAMES.lock() = Some(allocator));
+    Ok(stats)
+}
+
+/// Moves every free frame to the buddy allocator, which the functions here use from then on.
+pub fn hand_over() -> Result<FrameStats, FrameError> {
+    let allocator = instructions::without_interrupts(|| FRAMES.lock().take()).ok_or(FrameError::NoMemoryMap)?;
+
+    buddy::init(allocator)
+}
+
+/// Finds room for the bitmap in an available area below the identity map, which doesn't overlap a reserved region.
+fn place_bitmap(areas: &[MemoryArea], reserved: &[(u64, u64)], size: u64) -> Option<u64> {
+    for area in areas.iter().filter(|area| area.kind == MemoryKind::Available) {
+        let mut start = align_up(area.base, FRAME_SIZE);
+
+        loop {
+            let end = start.checked_add(size)?;
+            if end > area.end() || end > IDENTITY_MAP_SIZE { break; }
+
+            match reserved.iter().find(|&&(first, last)| first < end && start < last) {
+                Some(&(_, last)) => start = align_up(last, FRAME_SIZE),
+                None => return Some(start)
+            }
+        }
+    }
+
+    None
+}
+
+/// Runs the closure on the allocator, if it's been set up.
+fn with_allocator<T, F: FnOnce(&mut FrameAllocator<'static>) -> Option<T>>(f: F) -> Option<T> {
+    instructions::without_interrupts(|| FRAMES.lock().as_mut().and_then(f))
+}
+
+/// Allocates a frame, returning it's physical address.
+pub fn allocate() -> Option<u64> {
+    if buddy::is_initialized() { return buddy::allocate_frame(); }
+
+    with_allocator(|frames| frames.allocate())
+}
+
+/// Allocates a frame below the given physical address (like one the identity map reaches).
+pub fn allocate_below(limit: u64) -> Option<u64> {
+    if buddy::is_initialized() { return buddy::allocate_contiguous(1, FRAME_SIZE, limit); }
+
+    with_allocator(|frames| frames.allocate_below(limit))
+}
+
+/// Allocates a frame of the given size, aligned to it's size.
+pub fn allocate_sized(size: FrameSize) -> Option<u64> {
+    if buddy::is_initialized() { return buddy::allocate_contiguous(size.bytes() / FRAME_SIZE, size.bytes(), !0); }
+
+    with_allocator(|frames| frames.allocate_sized(size))
+}
+
+/// Allocates a run of contiguous frames, starting at a multiple of the given alignment.
+pub fn allocate_contiguous(count: u64, alignment: u64) -> Option<u64> {
+    if buddy::is_initialized() { return buddy::allocate_contiguous(count, alignment, !0); }
+
+    with_allocator(|frames| frames.allocate_contiguous(count, alignment))
+}
+
+/// Frees a frame. Returns false if it's already free.
+/// UNSAFE: The frame must have been allocated, and nothing may use it afterwards.
+pub unsafe fn free(address: u64) -> bool {
+    if buddy::is_initialized() { return buddy::free_frame(address); }
+
+    with_allocator(|frames| Some(frames.free(address))).unwrap_or(false)
+}
+
+/// Frees a run of contiguous frames. Returns false if any of them are already free.
+/// UNSAFE: The frames must have been allocated, and nothing may use them afterwards.
+pub unsafe fn free_contiguous(address: u64, count: u64) -> bool {
+    if buddy::is_initialized() { return buddy::free_contiguous(address, count); }
+
+    with_allocator(|frames| Some(frames.free_contiguous(address, count))).unwrap_or(false)
+}
+
+/// How many frames there are, and how many are free, if init() has been called.
+pub fn stats() -> Option<FrameStats> {
+    if buddy::is_initialized() { return buddy::stats(); }
+
+    with_allocator(|frames| Some(frames.stats()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn available(base: u64, length: u64) -> MemoryArea {
+        MemoryArea { base: base, length: length, kind: MemoryKind::Available }
+    }
+
+    /// A memory map like QEMU's for a machine with 64MiB (with the space under 1MiB cut short, and a hole).
+    fn areas() -> Vec<MemoryArea> {
+        vec![
+            available(0, 0x9FC00),
+            MemoryArea { base: 0x9FC00, length: 0x400, kind: MemoryKind::Reserved(2) },
+            available(0x100000, 0x1F00000),
+            MemoryArea { base: 0x2000000, length: 0x100000, kind: MemoryKind::AcpiNvs },
+            available(0x2100000, 0x1EFF800)
+        ]
+    }
+
+    #[test]
+    fn skips_reserved_and_partial_frames() {
+        let areas = areas();
+        let mut bitmap = vec![0; FrameAllocator::bitmap_words(&areas)];
+
+        // The last area ends part way through a frame, which isn't used.
+        assert_eq!(bitmap.len(), 0x3FFF000 / FRAME_SIZE as usize / 64 + 1);
+
+        let frames = FrameAllocator::new(&mut bitmap, &areas, &[(0, 0x10000), (0x100000, 0x180000)]);
+        let expected = (0x9F000 - 0x10000) / FRAME_SIZE + (0x1F00000 - 0x80000) / FRAME_SIZE +
+            0x1EFF000 / FRAME_SIZE;
+
+        assert_eq!(frames.stats(), FrameStats { total: expected, free: expected });
+        assert!(frames.is_used(0x9F));
+        assert!(!frames.is_used(0x9E));
+        assert!(frames.is_used(0x2000));
+        assert!(frames.is_used(0x3FFF));
+    }
+
+    #[test]
+    fn allocates_and_frees_frames() {
+        let areas = areas();
+        let mut bitmap = vec![0; FrameAllocator::bitmap_words(&areas)];
+        let mut frames = FrameAllocator::new(&mut bitmap, &areas, &[(0, 0x10000)]);
+        let total = frames.stats().total;
+
+        assert_eq!(frames.allocate(), Some(0x10000));
+        assert_eq!(frames.allocate(), Some(0x11000));
+        assert_eq!(frames.stats().used(), 2);
+
+        assert!(frames.free(0x10000));
+        assert!(!frames.free(0x10000));
+        assert!(!frames.free(0x10800));
+        assert_eq!(frames.allocate(), Some(0x10000));
+        assert_eq!(frames.allocate(), Some(0x12000));
+
+        assert!(frames.free_contiguous(0x10000, 3));
+        assert_eq!(frames.stats(), FrameStats { total: total, free: total });
+    }
+
+    #[test]
+    fn allocates_aligned_runs() {
+        let areas = areas();
+        let mut bitmap = vec![0; FrameAllocator::bitmap_words(&areas)];
+        let mut frames = FrameAllocator::new(&mut bitmap, &areas, &[(0, 0x10000), (0x100000, 0x180000)]);
+
+        // The first 2MiB aligned frame with 2MiB free after it is past the reserved region at 1MiB.
+        assert_eq!(frames.allocate_sized(FrameSize::Large), Some(0x200000));
+        assert_eq!(frames.allocate_contiguous(4, 0x10000), Some(0x10000));
+        assert_eq!(frames.allocate_contiguous(0x20, FRAME_SIZE), Some(0x14000));
+
+        // Runs can't span the hole at 32MiB.
+        assert_eq!(frames.allocate_contiguous_below(0x100, FRAME_SIZE, 0x2000000).map(|address| address < 0x2000000),
+            Some(true));
+        assert_eq!(frames.allocate_contiguous(0x1D00, LARGE_FRAME_SIZE), Some(0x2200000));
+
+        // There's no 1GiB of memory at all, and nothing left below 64KiB.
+        assert_eq!(frames.allocate_sized(FrameSize::Huge), None);
+        assert_eq!(frames.allocate_below(0x10000), None);
+        assert_eq!(frames.allocate_contiguous(0, FRAME_SIZE), None);
+    }
+
+    #[test]
+    fn lists_free_runs() {
+        let areas = areas();
+        let mut bitmap = vec![0; FrameAllocator::bitmap_words(&areas)];
+        let mut frames = FrameAllocator::new(&mut bitmap, &areas, &[(0, 0x10000), (0x100000, 0x180000)]);
+
+        assert_eq!(frames.allocate_contiguous(2, 0x20000), Some(0x20000));
+
+        let runs: Vec<_> = frames.free_runs().collect();
+        assert_eq!(runs, vec![(0x10000, 0x10), (0x22000, 0x7D), (0x180000, 0x1E80), (0x2100000, 0x1EFF)]);
+    }
+
+    #[test]
+    fn places_the_bitmap_around_reserved_regions() {
+        let areas = areas();
+
+        assert_eq!(place_bitmap(&areas, &[(0, 0x9000)], 0x1000), Some(0x9000));
+        assert_eq!(place_bitmap(&areas, &[(0, 0x9000), (0x9800, 0x9F000)], 0x1000), Some(0x100000));
+        assert_eq!(place_bitmap(&areas, &[(0, 0x9000)], 0x10000000), None);
+    }
+}
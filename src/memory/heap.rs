@@ -0,0 +1,162 @@
+//! Provides the kernel heap, which backs the `alloc` crate (so Box, Vec, BTreeMap and the rest work). Small
+//! allocations come from slabs: pages carved up into objects of one of a few power of two size classes, with the
+//! free objects of each class kept on a list threaded through them. Anything bigger than the biggest class gets
+//! pages of it's own. Slabs are mapped (writable and no-execute) into the layout's heap region as they're needed;
+//! big allocations only reserve their part of it, and their pages are backed by frames when they're first touched,
+//! by the page fault handler.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::ptr;
+
+use spin::Mutex;
+
+use arch::x86_64::instructions;
+use super::{FRAME_SIZE, align_up};
+use super::layout::{self, Region};
+use super::paging::{self, Flags, WRITABLE};
+
+/// The sizes of the objects slabs are carved into. Each is a power of two, and slabs are page aligned, so objects
+/// are aligned to their size.
+const CLASSES: usize = 8;
+const SIZE_CLASSES: [usize; CLASSES] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The size class an allocation fits in, if it's small enough for one.
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = cmp::max(layout.size(), layout.align());
+
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+/// How much of the heap is in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The bytes of heap region handed out, for slabs and for big allocations (whose pages aren't backed by frames
+    /// until they're touched).
+    pub reserved: u64,
+
+    /// The bytes handed out by allocations which are still live (rounded up to their size class or to pages).
+    pub allocated: u64
+}
+
+/// The heap's state.
+struct Heap {
+    /// The first free object of each size class; each free object holds the address of the next (or 0 for the
+    /// last).
+    free_objects: [u64; CLASSES],
+
+    stats: HeapStats
+}
+
+impl Heap {
+    /// Allocates memory for the layout, returning it's address.
+    fn allocate(&mut self, layout: &Layout) -> Option<u64> {
+        match size_class(layout) {
+            Some(class) => {
+                if self.free_objects[class] == 0 { self.add_slab(class)?; }
+
+                let object = self.free_objects[class];
+
+                // UNSAFE: Safe, as free objects are mapped, and hold the address of the next.
+                self.free_objects[class] = unsafe { *(object as *const u64) };
+                self.stats.allocated += SIZE_CLASSES[class] as u64;
+                Some(object)
+            },
+            None => {
+                // Nothing can back the pages until the kernel's address space is set up.
+                if paging::kernel_space().is_none() { return None; }
+
+                let size = align_up(layout.size() as u64, FRAME_SIZE);
+                let start = layout::allocate(Region::Heap, size, layout.align() as u64)?;
+
+                self.stats.reserved += size;
+                self.stats.allocated += size;
+                Some(start)
+            }
+        }
+    }
+
+    /// Frees memory allocated for the layout.
+    fn free(&mut self, address: u64, layout: &Layout) {
+        match size_class(layout) {
+            Some(class) => {
+                // UNSAFE: Safe, as the object was handed out by allocate(), and isn't in use any more.
+                unsafe { *(address as *mut u64) = self.free_objects[class]; }
+
+                self.free_objects[class] = address;
+                self.stats.allocated -= SIZE_CLASSES[class] as u64;
+            },
+            None => {
+                let size = align_up(layout.size() as u64, FRAME_SIZE);
+
+                // UNSAFE: Safe, as the allocation was handed out by allocate(), and isn't in use any more.
+                unsafe { layout::free_mapped(Region::Heap, address, size); }
+                self.stats.reserved -= size;
+                self.stats.allocated -= size;
+            }
+        }
+    }
+
+    /// Maps a new page, and carves it up into free objects of the size class.
+    fn add_slab(&mut self, class: usize) -> Option<()> {
+        let slab = layout::allocate_mapped(Region::Heap, FRAME_SIZE, FRAME_SIZE, heap_flags())?;
+        let size = SIZE_CLASSES[class] as u64;
+
+        self.stats.reserved += FRAME_SIZE;
+
+        // Pushed from the end, so objects are handed out in address order.
+        for object in (0 .. FRAME_SIZE / size).rev().map(|index| slab + index * size) {
+            // UNSAFE: Safe, as the slab was just mapped, and nothing else is using it.
+            unsafe { *(object as *mut u64) = self.free_objects[class]; }
+            self.free_objects[class] = object;
+        }
+
+        Some(())
+    }
+}
+
+/// The flags heap pages are mapped with.
+fn heap_flags() -> Flags {
+    WRITABLE | paging::no_execute()
+}
+
+/// The heap. Only taken with interrupts disabled, so interrupt handlers can allocate.
+static HEAP: Mutex<Heap> = Mutex::new(Heap {
+    free_objects: [0; CLASSES],
+    stats: HeapStats { reserved: 0, allocated: 0 }
+});
+
+/// The global allocator, which allocates from the heap. Nothing can be allocated until the kernel's address space
+/// has been set up.
+pub struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        instructions::without_interrupts(|| HEAP.lock().allocate(&layout))
+            .map(|address| address as *mut u8).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        instructions::without_interrupts(|| HEAP.lock().free(pointer as u64, &layout));
+    }
+}
+
+/// How much of the heap is in use.
+pub fn stats() -> HeapStats {
+    instructions::without_interrupts(|| HEAP.lock().stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_size_classes() {
+        assert_eq!(size_class(&Layout::from_size_align(1, 1).unwrap()), Some(0));
+        assert_eq!(size_class(&Layout::from_size_align(24, 8).unwrap()), Some(1));
+        assert_eq!(size_class(&Layout::from_size_align(8, 256).unwrap()), Some(4));
+        assert_eq!(size_class(&Layout::from_size_align(2048, 8).unwrap()), Some(7));
+        assert_eq!(size_class(&Layout::from_size_align(2049, 8).unwrap()), None);
+        assert_eq!(size_class(&Layout::from_size_align(8, 4096).unwrap()), None);
+    }
+}
@@ -0,0 +1,423 @@
+//! Provides the layout of the kernel's half of the address space, which is carved up into named regions at fixed
+//! places, and hands out ranges of virtual memory from them without overlaps.
+//!
+//! | Region        | Start                 | Size   |
+//! |---------------|-----------------------|--------|
+//! | Physical map  | 0xFFFF_8000_0000_0000 | 64TiB  |
+//! | Kernel image  | 0xFFFF_E000_0000_0000 | 4GiB   |
+//! | Heap          | 0xFFFF_E001_0000_0000 | 64GiB  |
+//! | Per-CPU areas | 0xFFFF_E020_0000_0000 | 64GiB  |
+//! | Kernel stacks | 0xFFFF_E030_0000_0000 | 64GiB  |
+//! | MMIO mappings | 0xFFFF_E040_0000_0000 | 1TiB   |
+//! | vmalloc       | 0xFFFF_E200_0000_0000 | 8TiB   |
+//!
+//! The physical map is a window onto all of physical memory (phys_to_virt() and virt_to_phys() convert between the
+//! two), which paging::init() sets up. Before then, physical memory is reached through the bootstrap's identity map
+//! of the first few gigabytes, which stays in place afterwards for the trampolines that run from low memory.
+
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use arch::x86_64::{instructions, kernel_physical_address, IDENTITY_MAP_SIZE};
+use boot_info::BootInfo;
+use super::{FRAME_SIZE, HUGE_FRAME_SIZE, align_up};
+use super::frame::{self, FrameSize};
+use super::paging::{self, Flags};
+
+const PHYSICAL_MAP_START: u64 = 0xFFFF_8000_0000_0000;
+const PHYSICAL_MAP_SIZE: u64 = 64 << 40;
+const KERNEL_IMAGE_START: u64 = 0xFFFF_E000_0000_0000;
+const KERNEL_IMAGE_SIZE: u64 = 4 << 30;
+const HEAP_START: u64 = 0xFFFF_E001_0000_0000;
+const HEAP_SIZE: u64 = 64 << 30;
+const PER_CPU_START: u64 = 0xFFFF_E020_0000_0000;
+const PER_CPU_SIZE: u64 = 64 << 30;
+const KERNEL_STACKS_START: u64 = 0xFFFF_E030_0000_0000;
+const KERNEL_STACKS_SIZE: u64 = 64 << 30;
+const MMIO_START: u64 = 0xFFFF_E040_0000_0000;
+const MMIO_SIZE: u64 = 1 << 40;
+const VMALLOC_START: u64 = 0xFFFF_E200_0000_0000;
+const VMALLOC_SIZE: u64 = 8 << 40;
+
+/// The most separate free ranges kept track of in each region.
+const MAX_FREE_RANGES: usize = 64;
+
+/// The regions of the kernel's half of the address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// A window onto all of physical memory.
+    PhysicalMap,
+
+    /// Where the kernel image is mapped, at `KERNEL_VIRTUAL`.
+    KernelImage,
+
+    /// The kernel heap, which the global allocator maps pages into.
+    Heap,
+
+    /// Each processor's own data.
+    PerCpu,
+
+    /// Kernel stacks, with unmapped guard pages between them.
+    KernelStacks,
+
+    /// Device registers, mapped uncached.
+    Mmio,
+
+    /// Big allocations which only need to be contiguous in virtual memory.
+    Vmalloc
+}
+
+/// Every region, in address order.
+pub const REGIONS: [Region; 7] = [Region::PhysicalMap, Region::KernelImage, Region::Heap, Region::PerCpu,
+    Region::KernelStacks, Region::Mmio, Region::Vmalloc];
+
+impl Region {
+    pub fn start(&self) -> u64 {
+        match *self {
+            Region::PhysicalMap => PHYSICAL_MAP_START,
+            Region::KernelImage => KERNEL_IMAGE_START,
+            Region::Heap => HEAP_START,
+            Region::PerCpu => PER_CPU_START,
+            Region::KernelStacks => KERNEL_STACKS_START,
+            Region::Mmio => MMIO_START,
+            Region::Vmalloc => VMALLOC_START
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match *self {
+            Region::PhysicalMap => PHYSICAL_MAP_SIZE,
+            Region::KernelImage => KERNEL_IMAGE_SIZE,
+            Region::Heap => HEAP_SIZE,
+            Region::PerCpu => PER_CPU_SIZE,
+            Region::KernelStacks => KERNEL_STACKS_SIZE,
+            Region::Mmio => MMIO_SIZE,
+            Region::Vmalloc => VMALLOC_SIZE
+        }
+    }
+
+    /// The first address past the region.
+    pub fn end(&self) -> u64 {
+        self.start() + self.size()
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.start() && address - self.start() < self.size()
+    }
+
+    /// The region the given address is in, if any.
+    pub fn of(address: u64) -> Option<Region> {
+        REGIONS.iter().cloned().find(|region| region.contains(address))
+    }
+
+    /// True for the regions whose ranges are backed by frames on demand: a page fault on a page of a range
+    /// handed out by allocate() maps it to a newly allocated, zeroed, frame. Kernel stacks are mapped up front, as
+    /// a page fault on the stack in use can't be delivered.
+    pub fn is_demand_zero(&self) -> bool {
+        match *self {
+            Region::Heap => true,
+            _ => false
+        }
+    }
+
+    /// The index of the region's free ranges, for the regions which ranges are handed out from (the physical map
+    /// and kernel image have fixed contents).
+    fn allocator_index(&self) -> Option<usize> {
+        match *self {
+            Region::PhysicalMap | Region::KernelImage => None,
+            Region::Heap => Some(0),
+            Region::PerCpu => Some(1),
+            Region::KernelStacks => Some(2),
+            Region::Mmio => Some(3),
+            Region::Vmalloc => Some(4)
+        }
+    }
+}
+
+/// The parts of a region of virtual memory which aren't in use, as ranges sorted by address.
+struct FreeRanges {
+    ranges: [(u64, u64); MAX_FREE_RANGES],
+    count: usize
+}
+
+impl FreeRanges {
+    /// Starts with the whole region free.
+    const fn new(start: u64, end: u64) -> FreeRanges {
+        // Only the first count ranges mean anything.
+        FreeRanges { ranges: [(start, end); MAX_FREE_RANGES], count: 1 }
+    }
+
+    /// Takes the given number of bytes, starting at a multiple of the given alignment, from the first range with
+    /// room for them.
+    fn take(&mut self, size: u64, alignment: u64) -> Option<u64> {
+        for index in 0 .. self.count {
+            let (start, end) = self.ranges[index];
+            let first = align_up(start, alignment);
+
+            if first.checked_add(size).map(|last| last > end).unwrap_or(true) { continue; }
+
+            match (first == start, first + size == end) {
+                (true, true) => self.remove(index),
+                (true, false) => self.ranges[index].0 = first + size,
+                (false, true) => self.ranges[index].1 = first,
+                (false, false) => {
+                    // Taking from the middle splits the range in two, which there might not be room for.
+                    if self.count == MAX_FREE_RANGES { continue; }
+
+                    self.ranges[index].1 = first;
+                    self.insert(index + 1, (first + size, end));
+                }
+            }
+
+            return Some(first);
+        }
+
+        None
+    }
+
+    /// Gives back the given number of bytes, merging them with the ranges either side. Returns false if they can't
+    /// be kept track of, in which case they're lost.
+    fn give(&mut self, start: u64, size: u64) -> bool {
+        let end = start + size;
+        let index = self.ranges[.. self.count].iter().position(|&(first, _)| first >= start).unwrap_or(self.count);
+
+        let after_previous = index > 0 && self.ranges[index - 1].1 == start;
+        let before_next = index < self.count && self.ranges[index].0 == end;
+
+        match (after_previous, before_next) {
+            (true, true) => {
+                self.ranges[index - 1].1 = self.ranges[index].1;
+                self.remove(index);
+            },
+            (true, false) => self.ranges[index - 1].1 = end,
+            (false, true) => self.ranges[index].0 = start,
+            (false, false) => {
+                if self.count == MAX_FREE_RANGES { return false; }
+
+                self.insert(index, (start, end));
+            }
+        }
+
+        true
+    }
+
+    /// True if the address is in one of the free ranges.
+    fn contains(&self, address: u64) -> bool {
+        self.ranges[.. self.count].iter().any(|&(start, end)| address >= start && address < end)
+    }
+
+    fn insert(&mut self, index: usize, range: (u64, u64)) {
+        for moved in (index .. self.count).rev() {
+            self.ranges[moved + 1] = self.ranges[moved];
+        }
+
+        self.ranges[index] = range;
+        self.count += 1;
+    }
+
+    fn remove(&mut self, index: usize) {
+        for moved in index .. self.count - 1 {
+            self.ranges[moved] = self.ranges[moved + 1];
+        }
+
+        self.count -= 1;
+    }
+}
+
+/// The free ranges of the regions which hand them out, by Region::allocator_index(). Only taken with interrupts
+/// disabled.
+static FREE_RANGES: Mutex<[FreeRanges; 5]> = Mutex::new([
+    FreeRanges::new(HEAP_START, HEAP_START + HEAP_SIZE),
+    FreeRanges::new(PER_CPU_START, PER_CPU_START + PER_CPU_SIZE),
+    FreeRanges::new(KERNEL_STACKS_START, KERNEL_STACKS_START + KERNEL_STACKS_SIZE),
+    FreeRanges::new(MMIO_START, MMIO_START + MMIO_SIZE),
+    FreeRanges::new(VMALLOC_START, VMALLOC_START + VMALLOC_SIZE)
+]);
+
+/// Takes a range of the given size (rounded up to whole pages) from a region, starting at a multiple of the given
+/// alignment (at least a page). Nothing is mapped there.
+pub fn allocate(region: Region, size: u64, alignment: u64) -> Option<u64> {
+    let index = region.allocator_index()?;
+    let size = align_up(size, FRAME_SIZE);
+
+    instructions::without_interrupts(|| FREE_RANGES.lock()[index].take(size, cmp::max(alignment, FRAME_SIZE)))
+}
+
+/// Gives a range back to a region. Returns false if the region doesn't hand out ranges, or if the range can't be
+/// kept track of (in which case it's lost, though nothing else can be given it).
+pub fn free(region: Region, start: u64, size: u64) -> bool {
+    let index = match region.allocator_index() { Some(index) => index, None => return false };
+    let size = align_up(size, FRAME_SIZE);
+
+    instructions::without_interrupts(|| FREE_RANGES.lock()[index].give(start, size))
+}
+
+/// True if the address is in a range handed out from a demand-zero region, so a page fault on it should be
+/// handled by backing it's page with a zeroed frame.
+pub fn is_demand_zero(address: u64) -> bool {
+    let index = match Region::of(address) {
+        Some(region) if region.is_demand_zero() => region.allocator_index(),
+        _ => None
+    };
+
+    index.map(|index| instructions::without_interrupts(|| !FREE_RANGES.lock()[index].contains(address)))
+        .unwrap_or(false)
+}
+
+/// Takes a range from a region like allocate(), and maps every page of it to a newly allocated frame with the given
+/// flags.
+pub fn allocate_mapped(region: Region, size: u64, alignment: u64, flags: Flags) -> Option<u64> {
+    let size = align_up(size, FRAME_SIZE);
+    let start = allocate(region, size, alignment)?;
+
+    if !back(start, size, flags) {
+        free(region, start, size);
+        return None;
+    }
+
+    Some(start)
+}
+
+/// Maps every page of part of a range handed out by allocate() to a newly allocated frame with the given flags.
+/// Returns false, with nothing left mapped, if there aren't enough frames (or the kernel's address space isn't set
+/// up).
+pub fn back(start: u64, size: u64, flags: Flags) -> bool {
+    for page in (0 .. align_up(size, FRAME_SIZE) / FRAME_SIZE).map(|index| start + index * FRAME_SIZE) {
+        let mapped = match frame::allocate() {
+            // UNSAFE: Safe, as the frame was just allocated, and the page was handed out but never mapped.
+            Some(frame) => unsafe { paging::map(page, frame, FrameSize::Small, flags) }.is_ok() || {
+                unsafe { frame::free(frame); }
+                false
+            },
+            None => false
+        };
+
+        if !mapped {
+            // UNSAFE: Safe, as nothing has been given the pages yet.
+            unsafe { unmap_and_free(start, page - start); }
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Unmaps a range handed out by allocate_mapped() (or by allocate() from a demand-zero region, where only the pages
+/// which were touched are mapped), frees it's frames, and gives it back to the region.
+/// UNSAFE: Nothing may use the range afterwards.
+pub unsafe fn free_mapped(region: Region, start: u64, size: u64) {
+    let size = align_up(size, FRAME_SIZE);
+
+    unmap_and_free(start, size);
+    free(region, start, size);
+}
+
+/// Unmaps the pages of a range (skipping ones which aren't mapped), and frees their frames.
+unsafe fn unmap_and_free(start: u64, size: u64) {
+    for page in (0 .. size / FRAME_SIZE).map(|index| start + index * FRAME_SIZE) {
+        if let Ok((frame, _)) = paging::unmap(page) {
+            frame::free(frame);
+        }
+    }
+}
+
+/// The amount of physical memory the physical map covers, once paging::init() has set it up.
+static PHYSICAL_MAPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// The amount of physical memory the physical map should cover: everything in the memory map (and at least what
+/// the identity map covers, for the devices in the gap below 4GiB), in whole gigabytes.
+pub fn physical_map_size(boot_info: &BootInfo) -> u64 {
+    let end = boot_info.memory_areas().iter().map(|area| area.end()).max().unwrap_or(0);
+
+    cmp::min(align_up(cmp::max(end, IDENTITY_MAP_SIZE), HUGE_FRAME_SIZE), PHYSICAL_MAP_SIZE)
+}
+
+/// Records that the physical map covers the given amount of physical memory, so phys_to_virt() uses it from then
+/// on; called by paging::init() once it's switched to the tables with the physical map in.
+pub fn set_physical_map_size(size: u64) {
+    PHYSICAL_MAPPED.store(size as usize, Ordering::SeqCst);
+}
+
+/// The virtual address physical memory can be reached at: it's place in the physical map, or before that's set up,
+/// in the identity map. Returns None for memory neither covers.
+pub fn phys_to_virt(physical: u64) -> Option<u64> {
+    match PHYSICAL_MAPPED.load(Ordering::SeqCst) as u64 {
+        0 if physical < IDENTITY_MAP_SIZE => Some(physical),
+        0 => None,
+        mapped if physical < mapped => Some(PHYSICAL_MAP_START + physical),
+        _ => None
+    }
+}
+
+/// The physical address a virtual address in the kernel's half of the address space (or in the identity map) is
+/// mapped to, if it's mapped.
+pub fn virt_to_phys(virtual_address: u64) -> Option<u64> {
+    if Region::PhysicalMap.contains(virtual_address) {
+        let physical = virtual_address - PHYSICAL_MAP_START;
+        return if physical < PHYSICAL_MAPPED.load(Ordering::SeqCst) as u64 { Some(physical) } else { None };
+    }
+
+    if let Some((physical, _)) = paging::translate(virtual_address) {
+        return Some(physical);
+    }
+
+    // Before the kernel's tables are set up, the bootstrap maps low memory twice: at 0 and for the kernel image.
+    match Region::of(virtual_address) {
+        Some(Region::KernelImage) if paging::kernel_space().is_none() => Some(kernel_physical_address(virtual_address)),
+        None if virtual_address < IDENTITY_MAP_SIZE && paging::kernel_space().is_none() => Some(virtual_address),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_out_regions_in_order() {
+        for pair in REGIONS.windows(2) {
+            assert!(pair[0].end() <= pair[1].start(), "{:?} overlaps {:?}", pair[0], pair[1]);
+        }
+
+        assert!(Region::KernelImage.contains(::arch::x86_64::KERNEL_VIRTUAL));
+        assert_eq!(Region::of(0xFFFF_E001_2345_6000), Some(Region::Heap));
+        assert_eq!(Region::of(0xFFFF_C000_0000_0000), None);
+        assert_eq!(Region::of(0x1000), None);
+    }
+
+    #[test]
+    fn takes_and_gives_ranges() {
+        let mut ranges = FreeRanges::new(0x10000, 0x20000);
+
+        assert_eq!(ranges.take(0x1000, 0x1000), Some(0x10000));
+        assert_eq!(ranges.take(0x2000, 0x4000), Some(0x14000));
+        assert_eq!(&ranges.ranges[.. ranges.count], &[(0x11000, 0x14000), (0x16000, 0x20000)]);
+        assert_eq!(ranges.take(0x10000, 0x1000), None);
+
+        assert!(ranges.give(0x14000, 0x2000));
+        assert_eq!(&ranges.ranges[.. ranges.count], &[(0x11000, 0x20000)]);
+        assert!(ranges.give(0x10000, 0x1000));
+        assert_eq!(&ranges.ranges[.. ranges.count], &[(0x10000, 0x20000)]);
+    }
+
+    #[test]
+    fn keeps_ranges_apart() {
+        let mut ranges = FreeRanges::new(0, 0x10000);
+
+        for page in 0 .. 0x10 {
+            assert_eq!(ranges.take(0x1000, 0x1000), Some(page * 0x1000));
+        }
+
+        assert!(ranges.give(0x3000, 0x1000));
+        assert!(ranges.give(0x1000, 0x1000));
+        assert!(ranges.give(0x8000, 0x1000));
+        assert_eq!(&ranges.ranges[.. ranges.count], &[(0x1000, 0x2000), (0x3000, 0x4000), (0x8000, 0x9000)]);
+
+        assert!(ranges.give(0x2000, 0x1000));
+        assert_eq!(&ranges.ranges[.. ranges.count], &[(0x1000, 0x4000), (0x8000, 0x9000)]);
+        assert!(ranges.contains(0x3FFF) && !ranges.contains(0x4000) && ranges.contains(0x8000));
+        assert_eq!(ranges.take(0x2000, 0x1000), Some(0x1000));
+    }
+}
@@ -0,0 +1,166 @@
+//! Provides mappings of device registers (and other device memory, like framebuffers) into the layout's MMIO region,
+//! with the memory type the device needs: uncached for registers, or write-combining for framebuffers, rather than
+//! the write-back everything else is mapped with. Registers are read and written through the `volatile` crate, so
+//! none of the accesses are optimized away.
+
+use core::mem;
+
+use volatile::Volatile;
+
+use arch::x86_64::IDENTITY_MAP_SIZE;
+use super::{FRAME_SIZE, align_down, align_up};
+use super::frame::FrameSize;
+use super::layout::{self, Region};
+use super::paging::{self, MemoryType, PagingError, WRITABLE};
+
+/// The reasons device memory can't be mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioError {
+    /// The kernel's address space isn't set up, and the memory isn't in the bootstrap's identity map either.
+    NotMapped(u64),
+
+    /// The MMIO region has no room left.
+    OutOfAddressSpace,
+
+    /// Mapping a page failed.
+    Paging(PagingError)
+}
+
+/// A mapping of a device's memory, made by ioremap(). Copies of it share the mapping.
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    /// Where the device's memory starts in virtual memory, and in physical memory.
+    address: u64,
+    physical: u64,
+    size: u64,
+
+    /// False for device memory reached through the identity map, which isn't unmapped by iounmap().
+    mapped: bool
+}
+
+impl Mmio {
+    /// The virtual address the device's memory starts at.
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// The physical address the device's memory starts at.
+    pub fn physical_address(&self) -> u64 {
+        self.physical
+    }
+
+    /// The number of bytes of device memory mapped.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads the register of the given type at the given offset.
+    /// UNSAFE: Reading hardware registers can have side effects.
+    pub unsafe fn read<T: Copy>(&self, offset: u64) -> T {
+        (&*(self.register::<T>(offset) as *const Volatile<T>)).read()
+    }
+
+    /// Writes the register of the given type at the given offset.
+    /// UNSAFE: Writing hardware registers can have arbitrary side effects.
+    pub unsafe fn write<T: Copy>(&self, offset: u64, value: T) {
+        (&mut *(self.register::<T>(offset) as *mut Volatile<T>)).write(value)
+    }
+
+    /// The address of the register of the given type at the given offset. Panics if it's not all inside the
+    /// mapping, or isn't aligned to it's size.
+    fn register<T>(&self, offset: u64) -> u64 {
+        let size = mem::size_of::<T>() as u64;
+
+        assert!(offset.checked_add(size).map(|end| end <= self.size).unwrap_or(false),
+            "MMIO register at 0x{:x} outside of mapping of 0x{:x} bytes", offset, self.size);
+        assert!((self.address + offset) % size == 0, "MMIO register at 0x{:x} is misaligned", offset);
+
+        self.address + offset
+    }
+}
+
+/// Maps the given number of bytes of device memory, starting at the given physical address, into the MMIO region
+/// with the given memory type (writable and no-execute).
+///
+/// Before the kernel's address space is set up, device memory in the bootstrap's identity map is reached through
+/// that instead; it's write-back there as far as the page tables go, but the firmware's MTRRs keep device memory
+/// below 4GiB uncached anyway.
+/// UNSAFE: The memory mustn't be ordinary memory in use for something else, as memory mapped with more than one
+/// memory type can end up corrupted.
+pub unsafe fn ioremap(physical: u64, size: u64, memory_type: MemoryType) -> Result<Mmio, MmioError> {
+    let end = physical.checked_add(size).ok_or(MmioError::NotMapped(physical))?;
+
+    if paging::kernel_space().is_none() {
+        return if physical != 0 && end <= IDENTITY_MAP_SIZE {
+            Ok(Mmio { address: physical, physical: physical, size: size, mapped: false })
+        } else {
+            Err(MmioError::NotMapped(physical))
+        };
+    }
+
+    let first = align_down(physical, FRAME_SIZE);
+    let length = align_up(end, FRAME_SIZE) - first;
+    let start = layout::allocate(Region::Mmio, length, FRAME_SIZE).ok_or(MmioError::OutOfAddressSpace)?;
+    let flags = WRITABLE | paging::no_execute() | memory_type.flags();
+
+    for offset in (0 .. length / FRAME_SIZE).map(|page| page * FRAME_SIZE) {
+        if let Err(error) = paging::map(start + offset, first + offset, FrameSize::Small, flags) {
+            unmap_pages(start, offset);
+            layout::free(Region::Mmio, start, length);
+            return Err(MmioError::Paging(error));
+        }
+    }
+
+    Ok(Mmio { address: start + (physical - first), physical: physical, size: size, mapped: true })
+}
+
+/// Unmaps device memory mapped by ioremap(), and gives it's virtual memory back.
+/// UNSAFE: Nothing may use the mapping (or any copy of it) afterwards.
+pub unsafe fn iounmap(mmio: Mmio) {
+    if !mmio.mapped { return; }
+
+    let start = align_down(mmio.address, FRAME_SIZE);
+    let length = align_up(mmio.address + mmio.size, FRAME_SIZE) - start;
+
+    unmap_pages(start, length);
+    layout::free(Region::Mmio, start, length);
+}
+
+/// Unmaps the pages of a range; the frames are the device's, so aren't freed.
+unsafe fn unmap_pages(start: u64, length: u64) {
+    for page in (0 .. length / FRAME_SIZE).map(|page| start + page * FRAME_SIZE) {
+        paging::unmap(page).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let mut registers = [0u64; 4];
+        let address = registers.as_mut_ptr() as u64;
+        let mmio = Mmio { address: address, physical: 0xFED0_0000, size: 32, mapped: false };
+
+        unsafe {
+            mmio.write(8, 0x1122_3344_5566_7788u64);
+            mmio.write(16, 0xAABB_CCDDu32);
+
+            assert_eq!(mmio.read::<u64>(8), 0x1122_3344_5566_7788);
+            assert_eq!(mmio.read::<u32>(12), 0x1122_3344);
+            assert_eq!(mmio.read::<u8>(16), 0xDD);
+        }
+
+        assert_eq!(registers[2], 0xAABB_CCDD);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_registers_outside_the_mapping() {
+        let registers = [0u64; 4];
+        let mmio = Mmio { address: registers.as_ptr() as u64, physical: 0xFED0_0000, size: 32, mapped: false };
+
+        unsafe { mmio.read::<u64>(28); }
+    }
+}
@@ -0,0 +1,27 @@
+//! Provides management of the machine's memory: which physical frames are free, and (on top of that) how they're
+//! mapped into the kernel's address space.
+
+pub mod frame;
+pub mod buddy;
+pub mod paging;
+pub mod heap;
+pub mod layout;
+pub mod mmio;
+pub mod fault;
+pub mod stack;
+
+/// The size of a frame (and of a page), and of the large and huge pages which map 2MiB and 1GiB at once.
+pub const FRAME_SIZE: u64 = 0x1000;
+pub const LARGE_FRAME_SIZE: u64 = 0x200000;
+pub const HUGE_FRAME_SIZE: u64 = 0x40000000;
+
+/// Rounds an address down to a multiple of the given alignment, which has to be a power of two.
+pub fn align_down(address: u64, alignment: u64) -> u64 {
+    address & !(alignment - 1)
+}
+
+/// Rounds an address up to a multiple of the given alignment, which has to be a power of two. Addresses too close
+/// to the top of the address space to round up are rounded down instead.
+pub fn align_up(address: u64, alignment: u64) -> u64 {
+    align_down(address.saturating_add(alignment - 1), alignment)
+}
@@ -0,0 +1,835 @@
+//! Provides the processor's page tables: typed tables and entries, and address spaces built out of them which pages
+//! can be mapped into, unmapped from and translated through. Tables are reached by their physical addresses through
+//! a `TableMemory`, which for the kernel is the layout's physical map (or the identity map of the first few
+//! gigabytes before that's set up, so tables are always allocated below it).
+//!
+//! The bootstrap maps the first few gigabytes twice over with 2MiB pages, writable and executable, at 0 and at
+//! `KERNEL_VIRTUAL`. init() replaces that with a fresh address space: an identity map of the same memory (which
+//! device registers and the wakeup trampoline are reached through), a window onto all of physical memory at the
+//! start of the layout's physical map, but only the kernel image's own sections at `KERNEL_VIRTUAL`, each with the
+//! permissions the ELF section tags give it. No page is both writable and executable: the kernel's code and the
+//! wakeup trampoline's are read only, and everything else is no-execute (where the processor supports it). The
+//! kernel image's frames are read only in the identity map and physical map, and the first page isn't mapped at
+//! all, so null pointers fault. Write protect is turned on too, so the kernel faults on a stray write to it's own
+//! code rather than corrupting it. The PAT is programmed too, so pages can be given any MemoryType (like uncached or
+//! write-combining for device memory; see memory::mmio). The identity map and physical map only cache RAM: anything
+//! the memory map doesn't call RAM, like the hole device registers live in, is mapped uncached there, so it isn't
+//! aliased write-back behind ioremap()'s back.
+
+use core::{cmp, fmt};
+use core::iter::once;
+use core::ops::{BitOr, Index, IndexMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use arch::x86_64::{self, instructions, kernel_physical_address, IDENTITY_MAP_SIZE, KERNEL_VIRTUAL};
+use boot_info::{BootInfo, ElfSection, MemoryArea, MemoryKind};
+use super::{FRAME_SIZE, align_up, align_down};
+use super::frame::{self, FrameSize};
+use super::layout::{self, Region};
+
+pub const TABLE_ENTRIES: usize = 512;
+
+/// The physical address bits of an entry.
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The CPUID leaf and EDX bit which say whether the processor supports no-execute, and the EFER bit which turns it
+/// on.
+const CPUID_EXTENDED_FEATURES: u32 = 0x80000001;
+const CPUID_NO_EXECUTE: u32 = 1 << 20;
+
+/// The EDX bit of the same leaf which says whether the processor supports 1GiB pages.
+const CPUID_HUGE_PAGES: u32 = 1 << 26;
+const EFER: u32 = 0xC0000080;
+const EFER_NO_EXECUTE: u64 = 1 << 11;
+
+/// The page attribute table MSR, and what we program it with (see MemoryType): the first four entries are the
+/// power-on defaults (write-back, write-through, uncached-minus and uncached), so entries without PAGE_ATTRIBUTE
+/// mean what they always have, and the other four add write-combining and write-protected.
+const PAT_MSR: u32 = 0x277;
+const PAT_ENTRIES: u64 = 0x0007_0501_0007_0406;
+
+/// The CR0 bit which stops the kernel writing to read only pages, as well as user code.
+const CR0_WRITE_PROTECT: u64 = 1 << 16;
+
+/// The flags of a page table entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u64);
+
+pub const NONE: Flags = Flags(0);
+pub const PRESENT: Flags = Flags(1);
+pub const WRITABLE: Flags = Flags(1 << 1);
+pub const USER: Flags = Flags(1 << 2);
+pub const WRITE_THROUGH: Flags = Flags(1 << 3);
+pub const NO_CACHE: Flags = Flags(1 << 4);
+pub const ACCESSED: Flags = Flags(1 << 5);
+pub const DIRTY: Flags = Flags(1 << 6);
+
+/// Set in a level 2 or 3 entry which maps a large or huge page, rather than pointing at a table.
+pub const HUGE: Flags = Flags(1 << 7);
+
+/// The same bit in a level 1 entry, which picks the memory type from the upper half of the PAT.
+pub const PAGE_ATTRIBUTE: Flags = Flags(1 << 7);
+
+/// Kept in the TLB when CR3 changes, once global pages are turned on in CR4.
+pub const GLOBAL: Flags = Flags(1 << 8);
+
+/// Only honoured once no-execute is turned on in EFER; before then, the bit is reserved.
+pub const NO_EXECUTE: Flags = Flags(1 << 63);
+
+impl Flags {
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// True if every flag set in the other flags is set here too.
+    pub fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// These flags, without the other flags.
+    pub fn without(&self, other: Flags) -> Flags {
+        Flags(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
+/// A page table entry: the physical address of a page or of the next level's table, and it's flags.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Entry(u64);
+
+impl Entry {
+    pub fn new(address: u64, flags: Flags) -> Entry {
+        Entry(address & ADDRESS_MASK | flags.0)
+    }
+
+    pub fn unused() -> Entry {
+        Entry(0)
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.flags().contains(PRESENT)
+    }
+
+    /// True for a level 2 or 3 entry which maps a large or huge page.
+    pub fn is_huge(&self) -> bool {
+        self.flags().contains(HUGE)
+    }
+
+    pub fn address(&self) -> u64 {
+        self.0 & ADDRESS_MASK
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags(self.0 & !ADDRESS_MASK)
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Entry(0x{:x}, {:?})", self.address(), self.flags())
+    }
+}
+
+/// A page table, of any level.
+#[repr(C)]
+pub struct PageTable {
+    entries: [Entry; TABLE_ENTRIES]
+}
+
+impl PageTable {
+    /// Clears every entry.
+    pub fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            *entry = Entry::unused();
+        }
+    }
+}
+
+impl Index<usize> for PageTable {
+    type Output = Entry;
+
+    fn index(&self, index: usize) -> &Entry {
+        &self.entries[index]
+    }
+}
+
+impl IndexMut<usize> for PageTable {
+    fn index_mut(&mut self, index: usize) -> &mut Entry {
+        &mut self.entries[index]
+    }
+}
+
+/// The reasons pages can't be mapped, unmapped or changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// init() hasn't set up the kernel's address space yet.
+    NotInitialized,
+
+    /// The virtual or physical address isn't aligned to the page size.
+    Misaligned,
+
+    /// The virtual address isn't canonical (bits 47 and up aren't all the same).
+    NonCanonical(u64),
+
+    /// The virtual address (or the large or huge page it's in) is already mapped.
+    AlreadyMapped(u64),
+
+    /// The virtual address isn't mapped.
+    NotMapped(u64),
+
+    /// There are no frames left for tables.
+    OutOfTables
+}
+
+/// The ways memory can be cached, which the PAT entry a page's flags pick gives it (the MTRRs can make it
+/// stricter). Ordinary memory is write-back; device registers need to be uncached, and framebuffers are best
+/// write-combining.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    WriteBack,
+    WriteThrough,
+    WriteCombining,
+    WriteProtected,
+    Uncached
+}
+
+impl MemoryType {
+    /// The flags which pick the memory type, for a level 1 entry; large and huge pages keep the PAT bit elsewhere,
+    /// so are always mapped with the default types.
+    pub fn flags(&self) -> Flags {
+        match *self {
+            MemoryType::WriteBack => NONE,
+            MemoryType::WriteThrough => WRITE_THROUGH,
+            MemoryType::Uncached => NO_CACHE | WRITE_THROUGH,
+            MemoryType::WriteCombining => PAGE_ATTRIBUTE,
+            MemoryType::WriteProtected => PAGE_ATTRIBUTE | WRITE_THROUGH
+        }
+    }
+}
+
+/// Somewhere to get zeroed tables from, which can then be reached by their physical addresses.
+pub trait TableMemory {
+    /// Allocates a zeroed table, returning it's physical address.
+    fn allocate(&mut self) -> Option<u64>;
+
+    /// The table at the given physical address.
+    fn table(&mut self, address: u64) -> &mut PageTable;
+}
+
+/// A set of page tables, from the top level (level 4) table down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressSpace {
+    /// The physical address of the level 4 table.
+    root: u64
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped.
+    pub fn new<M: TableMemory>(memory: &mut M) -> Option<AddressSpace> {
+        Some(AddressSpace { root: memory.allocate()? })
+    }
+
+    /// The address space the processor is using.
+    pub fn active() -> AddressSpace {
+        AddressSpace { root: instructions::page_table_root() }
+    }
+
+    /// The physical address of the level 4 table, for CR3.
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Maps a page of the given size to a frame, with the given flags (present is added). Missing tables on the way
+    /// are allocated, and are writable (and user accessible if the page is), leaving the page's own entry to decide.
+    pub fn map<M: TableMemory>(&self, memory: &mut M, virtual_address: u64, physical: u64, size: FrameSize,
+        flags: Flags) -> Result<(), PagingError> {
+
+        if virtual_address % size.bytes() != 0 || physical % size.bytes() != 0 { return Err(PagingError::Misaligned); }
+        if !is_canonical(virtual_address) { return Err(PagingError::NonCanonical(virtual_address)); }
+
+        let leaf_level = level_of(size);
+        let mut table = self.root;
+
+        for level in (leaf_level + 1 .. 5).rev() {
+            let index = table_index(virtual_address, level);
+            let entry = memory.table(table)[index];
+
+            table = if !entry.is_present() {
+                let next = memory.allocate().ok_or(PagingError::OutOfTables)?;
+                let user = if flags.contains(USER) { USER } else { NONE };
+
+                memory.table(table)[index] = Entry::new(next, PRESENT | WRITABLE | user);
+                next
+            } else if entry.is_huge() {
+                return Err(PagingError::AlreadyMapped(virtual_address));
+            } else {
+                if flags.contains(USER) && !entry.flags().contains(USER) {
+                    memory.table(table)[index] = Entry::new(entry.address(), entry.flags() | USER);
+                }
+
+                entry.address()
+            };
+        }
+
+        let index = table_index(virtual_address, leaf_level);
+        if memory.table(table)[index].is_present() { return Err(PagingError::AlreadyMapped(virtual_address)); }
+
+        let huge = if leaf_level > 1 { HUGE } else { NONE };
+        memory.table(table)[index] = Entry::new(physical, flags | PRESENT | huge);
+        Ok(())
+    }
+
+    /// Unmaps the page at the given virtual address, returning the frame it was mapped to and it's size. The (now
+    /// possibly empty) tables on the way to it are kept. The TLB isn't flushed.
+    pub fn unmap<M: TableMemory>(&self, memory: &mut M, virtual_address: u64)
+        -> Result<(u64, FrameSize), PagingError> {
+
+        let (table, index, size) = self.leaf(memory, virtual_address)?;
+        if virtual_address % size.bytes() != 0 { return Err(PagingError::Misaligned); }
+
+        let entry = memory.table(table)[index];
+        memory.table(table)[index] = Entry::unused();
+
+        Ok((entry.address() & !(size.bytes() - 1), size))
+    }
+
+    /// Changes the flags of the page at the given virtual address (present, and huge for large and huge pages, are
+    /// kept). The TLB isn't flushed.
+    pub fn update<M: TableMemory>(&self, memory: &mut M, virtual_address: u64, flags: Flags)
+        -> Result<(), PagingError> {
+
+        let (table, index, size) = self.leaf(memory, virtual_address)?;
+        let entry = memory.table(table)[index];
+        let huge = if size == FrameSize::Small { NONE } else { HUGE };
+
+        memory.table(table)[index] = Entry::new(entry.address(), flags | PRESENT | huge);
+        Ok(())
+    }
+
+    /// Translates a virtual address to the physical address it's mapped to, and the flags of the page it's in.
+    pub fn translate<M: TableMemory>(&self, memory: &mut M, virtual_address: u64) -> Option<(u64, Flags)> {
+        let (table, index, size) = self.leaf(memory, virtual_address).ok()?;
+        let entry = memory.table(table)[index];
+        let offset = virtual_address & (size.bytes() - 1);
+
+        Some(((entry.address() & !(size.bytes() - 1)) + offset, entry.flags()))
+    }
+
+    /// The entries the processor goes through to translate the given virtual address, from the level 4 table's
+    /// down; the walk stops at the first entry which isn't present or maps a page, so the rest are None.
+    pub fn walk<M: TableMemory>(&self, memory: &mut M, virtual_address: u64) -> [Option<Entry>; 4] {
+        let mut entries = [None; 4];
+        if !is_canonical(virtual_address) { return entries; }
+
+        let mut table = self.root;
+
+        for level in (1 .. 5).rev() {
+            let entry = memory.table(table)[table_index(virtual_address, level)];
+            entries[4 - level as usize] = Some(entry);
+
+            if !entry.is_present() || level == 1 || entry.is_huge() { break; }
+            table = entry.address();
+        }
+
+        entries
+    }
+
+    /// Finds the table and index of the entry mapping the given virtual address, and the size of the page it maps.
+    fn leaf<M: TableMemory>(&self, memory: &mut M, virtual_address: u64)
+        -> Result<(u64, usize, FrameSize), PagingError> {
+
+        if !is_canonical(virtual_address) { return Err(PagingError::NonCanonical(virtual_address)); }
+
+        let mut table = self.root;
+
+        for level in (1 .. 5).rev() {
+            let index = table_index(virtual_address, level);
+            let entry = memory.table(table)[index];
+
+            if !entry.is_present() { break; }
+
+            match level {
+                1 => return Ok((table, index, FrameSize::Small)),
+                2 if entry.is_huge() => return Ok((table, index, FrameSize::Large)),
+                3 if entry.is_huge() => return Ok((table, index, FrameSize::Huge)),
+                _ => table = entry.address()
+            }
+        }
+
+        Err(PagingError::NotMapped(virtual_address))
+    }
+}
+
+/// True if bits 47 and up of the address are all the same, as the processor requires.
+pub fn is_canonical(address: u64) -> bool {
+    let top = address >> 47;
+    top == 0 || top == 0x1FFFF
+}
+
+/// The level of the table (1 being the last) whose entries map pages of the given size.
+fn level_of(size: FrameSize) -> u8 {
+    match size {
+        FrameSize::Small => 1,
+        FrameSize::Large => 2,
+        FrameSize::Huge => 3
+    }
+}
+
+/// The index into the table at the given level (1 being the last) for a virtual address.
+fn table_index(virtual_address: u64, level: u8) -> usize {
+    ((virtual_address >> (12 + 9 * (level as u64 - 1))) & 0x1FF) as usize
+}
+
+/// The kernel's tables, which are allocated below the identity map, and reached through the physical map once
+/// that's set up (or through the identity map until then).
+struct KernelTables;
+
+impl TableMemory for KernelTables {
+    fn allocate(&mut self) -> Option<u64> {
+        let table = frame::allocate_below(IDENTITY_MAP_SIZE)?;
+
+        self.table(table).zero();
+        Some(table)
+    }
+
+    fn table(&mut self, address: u64) -> &mut PageTable {
+        let table = layout::phys_to_virt(address).expect("Page table outside the physical map");
+
+        // UNSAFE: Safe, as the only physical addresses we're given are tables, which are always reachable.
+        unsafe { &mut *(table as *mut PageTable) }
+    }
+}
+
+/// The kernel's address space, once init() has switched to it.
+static KERNEL: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+/// Builds the kernel's address space, and switches to it.
+/// UNSAFE: The frame allocator must be set up, and nothing may rely on the bootstrap's mapping of everything at
+/// `KERNEL_VIRTUAL` beyond the kernel image.
+pub unsafe fn init(boot_info: &BootInfo) -> Result<AddressSpace, PagingError> {
+    let mut memory = KernelTables;
+    let space = AddressSpace::new(&mut memory).ok_or(PagingError::OutOfTables)?;
+
+    // The bits are reserved until no-execute is on, so it has to be before any are set.
+    enable_no_execute();
+    program_pat();
+
+    let ranges = PhysicalRanges::new(boot_info.memory_areas());
+    let huge_pages = instructions::cpuid(CPUID_EXTENDED_FEATURES)[3] & CPUID_HUGE_PAGES != 0;
+
+    map_window(&space, &mut memory, 0, IDENTITY_MAP_SIZE, &ranges, Window::Identity, false)?;
+
+    let physical_map_size = layout::physical_map_size(boot_info);
+    map_window(&space, &mut memory, Region::PhysicalMap.start(), physical_map_size, &ranges, Window::Physical,
+        huge_pages)?;
+
+    let sections = boot_info.elf_sections().iter()
+        .filter(|section| section.is_allocated() && section.start >= KERNEL_VIRTUAL && section.end > section.start);
+    let mut mapped = false;
+
+    for section in sections {
+        map_kernel_range(&space, &mut memory, section.start, section.end, section_flags(section))?;
+        mapped = true;
+    }
+
+    // Without the section tags, the whole image stays writable and executable, like it was.
+    if !mapped {
+        let (start, end) = x86_64::kernel_image();
+        map_kernel_range(&space, &mut memory, x86_64::kernel_virtual_address(start),
+            x86_64::kernel_virtual_address(end), WRITABLE)?;
+    }
+
+    instructions::without_interrupts(|| {
+        instructions::set_page_table_root(space.root());
+        instructions::write_cr0(instructions::read_cr0() | CR0_WRITE_PROTECT);
+        *KERNEL.lock() = Some(space);
+    });
+
+    layout::set_physical_map_size(physical_map_size);
+    Ok(space)
+}
+
+/// The two mappings of physical memory init() makes: the identity map, and the layout's physical map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Window {
+    Identity,
+    Physical
+}
+
+/// The physical memory the identity map and physical map don't map like the rest, rounded out to whole pages.
+#[derive(Debug, Clone, Copy)]
+struct PhysicalRanges<'a> {
+    /// The kernel image, which is only writable through the kernel's own mappings of it's sections.
+    image: (u64, u64),
+
+    /// The wakeup trampoline's code, which runs from the identity map.
+    trampoline: (u64, u64),
+
+    /// The memory map; only the pages wholly inside RAM are write-back.
+    areas: &'a [MemoryArea]
+}
+
+impl<'a> PhysicalRanges<'a> {
+    fn new(areas: &'a [MemoryArea]) -> PhysicalRanges<'a> {
+        let pages = |(start, end): (u64, u64)| (align_down(start, FRAME_SIZE), align_up(end, FRAME_SIZE));
+
+        PhysicalRanges { image: pages(x86_64::kernel_image()), trampoline: pages(x86_64::wakeup_code()), areas: areas }
+    }
+
+    /// The pages of RAM in the memory map, rounded in to whole pages.
+    fn ram<'b>(&'b self) -> impl Iterator<Item = (u64, u64)> + 'b {
+        self.areas.iter().filter(|area| match area.kind { MemoryKind::Reserved(_) => false, _ => true })
+            .map(|area| (align_up(area.base, FRAME_SIZE), align_down(area.end(), FRAME_SIZE)))
+    }
+
+    /// The flags the page of physical memory at the given address is mapped with in the given window, or None if
+    /// it isn't mapped there.
+    fn flags(&self, physical: u64, window: Window) -> Option<Flags> {
+        let within = |(start, end): (u64, u64)| physical >= start && physical < end;
+        let memory_type = if self.ram().any(within) { MemoryType::WriteBack } else { MemoryType::Uncached };
+
+        match window {
+            Window::Identity if physical < FRAME_SIZE => None,
+            Window::Identity if within(self.trampoline) => Some(memory_type.flags()),
+            _ if within(self.image) => Some(memory_type.flags() | no_execute()),
+            _ => Some(memory_type.flags() | WRITABLE | no_execute())
+        }
+    }
+
+    /// The first address above the given one where flags() might change.
+    fn next_boundary(&self, physical: u64) -> u64 {
+        let ram = self.ram().flat_map(|(start, end)| once(start).chain(once(end)));
+
+        [FRAME_SIZE, self.image.0, self.image.1, self.trampoline.0, self.trampoline.1].iter().cloned().chain(ram)
+            .filter(|&boundary| boundary > physical).min().unwrap_or(u64::max_value())
+    }
+}
+
+/// Maps physical memory from 0 up to the given size into one of the windows onto it, starting at the given virtual
+/// address, with the biggest pages which fit between the places the flags change (1GiB pages only if huge_pages is
+/// set).
+fn map_window<M: TableMemory>(space: &AddressSpace, memory: &mut M, start: u64, size: u64, ranges: &PhysicalRanges,
+    window: Window, huge_pages: bool) -> Result<(), PagingError> {
+
+    let mut physical = 0;
+
+    while physical < size {
+        let limit = cmp::min(ranges.next_boundary(physical), size);
+        let page_size = [FrameSize::Huge, FrameSize::Large].iter().cloned()
+            .filter(|&page_size| huge_pages || page_size != FrameSize::Huge)
+            .find(|page_size| physical % page_size.bytes() == 0 && physical + page_size.bytes() <= limit)
+            .unwrap_or(FrameSize::Small);
+
+        if let Some(flags) = ranges.flags(physical, window) {
+            space.map(memory, start + physical, physical, page_size, flags)?;
+        }
+
+        physical += page_size.bytes();
+    }
+
+    Ok(())
+}
+
+/// Set once enable_no_execute() has turned no-execute on.
+static NO_EXECUTE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns no-execute on, if the processor supports it.
+fn enable_no_execute() {
+    if instructions::cpuid(CPUID_EXTENDED_FEATURES)[3] & CPUID_NO_EXECUTE == 0 { return; }
+
+    // UNSAFE: Safe, as EFER exists on every processor with long mode, and no-execute is supported.
+    unsafe { instructions::write_msr(EFER, instructions::read_msr(EFER) | EFER_NO_EXECUTE); }
+    NO_EXECUTE_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// True if no-execute is on, so pages can be mapped no-execute.
+pub fn no_execute_enabled() -> bool {
+    NO_EXECUTE_ENABLED.load(Ordering::SeqCst)
+}
+
+/// The no-execute flag if it's on, or no flags otherwise; for pages which should be no-execute where possible.
+pub fn no_execute() -> Flags {
+    if no_execute_enabled() { NO_EXECUTE } else { NONE }
+}
+
+/// Programs the PAT with the memory types MemoryType picks between. Nothing is mapped with PAGE_ATTRIBUTE yet,
+/// and the other entries don't change, so nothing has to be flushed. Every processor with long mode has a PAT.
+fn program_pat() {
+    // UNSAFE: Safe, as only the entries no page uses yet change.
+    unsafe { instructions::write_msr(PAT_MSR, PAT_ENTRIES); }
+}
+
+/// The flags a kernel section's pages are mapped with: writable or executable (or neither), but never both.
+fn section_flags(section: &ElfSection) -> Flags {
+    match (section.is_writable(), section.is_executable()) {
+        (true, _) => WRITABLE | no_execute(),
+        (false, true) => NONE,
+        (false, false) => no_execute()
+    }
+}
+
+/// The flags of a page shared by two sections, which needs the permissions of both.
+fn merge_flags(first: Flags, second: Flags) -> Flags {
+    let no_execute = if first.contains(NO_EXECUTE) && second.contains(NO_EXECUTE) { NO_EXECUTE } else { NONE };
+
+    (first | second).without(NO_EXECUTE) | no_execute
+}
+
+/// Maps the pages of the kernel image between the given virtual addresses to where the image was loaded. A page
+/// shared with a section mapped before gets the flags of both.
+fn map_kernel_range<M: TableMemory>(space: &AddressSpace, memory: &mut M, start: u64, end: u64, flags: Flags)
+    -> Result<(), PagingError> {
+
+    let pages = align_down(start, FRAME_SIZE) / FRAME_SIZE .. align_up(end, FRAME_SIZE) / FRAME_SIZE;
+
+    for page in pages.map(|page| page * FRAME_SIZE) {
+        match space.map(memory, page, kernel_physical_address(page), FrameSize::Small, flags) {
+            Err(PagingError::AlreadyMapped(_)) => {
+                let (_, existing) = space.translate(memory, page).ok_or(PagingError::NotMapped(page))?;
+                space.update(memory, page, merge_flags(existing, flags))?;
+            },
+            result => result?
+        }
+    }
+
+    Ok(())
+}
+
+/// The kernel's address space, once init() has switched to it.
+pub fn kernel_space() -> Option<AddressSpace> {
+    instructions::without_interrupts(|| *KERNEL.lock())
+}
+
+/// Runs the closure on the kernel's address space, with it locked.
+fn with_kernel_space<T, F: FnOnce(&AddressSpace, &mut KernelTables) -> Result<T, PagingError>>(f: F)
+    -> Result<T, PagingError> {
+
+    instructions::without_interrupts(|| {
+        let space = KERNEL.lock();
+        f(space.as_ref().ok_or(PagingError::NotInitialized)?, &mut KernelTables)
+    })
+}
+
+/// Maps a page of the given size into the kernel's address space.
+/// UNSAFE: The frame mustn't be in use for anything else, unless that's what's wanted.
+pub unsafe fn map(virtual_address: u64, physical: u64, size: FrameSize, flags: Flags) -> Result<(), PagingError> {
+    with_kernel_space(|space, memory| space.map(memory, virtual_address, physical, size, flags))
+}
+
+/// Unmaps a page from the kernel's address space, and flushes it from this processor's TLB. Returns the frame it
+/// was mapped to, which isn't freed, and it's size.
+/// UNSAFE: Nothing may use the page afterwards.
+pub unsafe fn unmap(virtual_address: u64) -> Result<(u64, FrameSize), PagingError> {
+    let unmapped = with_kernel_space(|space, memory| space.unmap(memory, virtual_address))?;
+
+    instructions::flush_tlb_page(virtual_address);
+    Ok(unmapped)
+}
+
+/// Changes the flags of a page of the kernel's address space, and flushes it from this processor's TLB.
+/// UNSAFE: Nothing may rely on the old flags (like writing to a page which becomes read only).
+pub unsafe fn update(virtual_address: u64, flags: Flags) -> Result<(), PagingError> {
+    with_kernel_space(|space, memory| space.update(memory, virtual_address, flags))?;
+
+    instructions::flush_tlb_page(virtual_address);
+    Ok(())
+}
+
+/// Translates a virtual address through the kernel's address space.
+pub fn translate(virtual_address: u64) -> Option<(u64, Flags)> {
+    with_kernel_space(|space, memory| Ok(space.translate(memory, virtual_address))).ok().and_then(|found| found)
+}
+
+/// The entries the processor goes through to translate a virtual address in the active address space (which
+/// needn't be the kernel's), without taking any locks, so it's safe to use when something has gone wrong.
+pub fn walk(virtual_address: u64) -> [Option<Entry>; 4] {
+    AddressSpace::active().walk(&mut KernelTables, virtual_address)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Tables kept in a vector, at made up physical addresses.
+    struct VecMemory {
+        tables: Vec<Box<PageTable>>
+    }
+
+    /// Where the made up physical addresses start.
+    const BASE: u64 = 0x40000;
+
+    impl TableMemory for VecMemory {
+        fn allocate(&mut self) -> Option<u64> {
+            if self.tables.len() == 8 { return None; }
+
+            self.tables.push(Box::new(PageTable { entries: [Entry::unused(); TABLE_ENTRIES] }));
+            Some(BASE + (self.tables.len() as u64 - 1) * FRAME_SIZE)
+        }
+
+        fn table(&mut self, address: u64) -> &mut PageTable {
+            &mut self.tables[((address - BASE) / FRAME_SIZE) as usize]
+        }
+    }
+
+    #[test]
+    fn maps_and_translates_pages() {
+        let mut memory = VecMemory { tables: Vec::new() };
+        let space = AddressSpace::new(&mut memory).unwrap();
+
+        space.map(&mut memory, 0xFFFF_8000_1234_5000, 0x9000, FrameSize::Small, WRITABLE).unwrap();
+        space.map(&mut memory, 0xFFFF_8000_1234_6000, 0xA000, FrameSize::Small, NO_EXECUTE).unwrap();
+
+        // Both pages share all but the root table's path, so that's four tables in all.
+        assert_eq!(memory.tables.len(), 4);
+        assert_eq!(space.translate(&mut memory, 0xFFFF_8000_1234_5678), Some((0x9678, PRESENT | WRITABLE)));
+        assert_eq!(space.translate(&mut memory, 0xFFFF_8000_1234_6000), Some((0xA000, PRESENT | NO_EXECUTE)));
+        assert_eq!(space.translate(&mut memory, 0xFFFF_8000_1234_7000), None);
+
+        assert_eq!(space.map(&mut memory, 0xFFFF_8000_1234_5000, 0xB000, FrameSize::Small, NONE),
+            Err(PagingError::AlreadyMapped(0xFFFF_8000_1234_5000)));
+        assert_eq!(space.map(&mut memory, 0x8000_0000_0000, 0xB000, FrameSize::Small, NONE),
+            Err(PagingError::NonCanonical(0x8000_0000_0000)));
+        assert_eq!(space.map(&mut memory, 0x1000, 0xB800, FrameSize::Small, NONE), Err(PagingError::Misaligned));
+
+        assert_eq!(space.unmap(&mut memory, 0xFFFF_8000_1234_5000), Ok((0x9000, FrameSize::Small)));
+        assert_eq!(space.translate(&mut memory, 0xFFFF_8000_1234_5000), None);
+        assert_eq!(space.unmap(&mut memory, 0xFFFF_8000_1234_5000),
+            Err(PagingError::NotMapped(0xFFFF_8000_1234_5000)));
+    }
+
+    #[test]
+    fn maps_large_and_huge_pages() {
+        let mut memory = VecMemory { tables: Vec::new() };
+        let space = AddressSpace::new(&mut memory).unwrap();
+
+        space.map(&mut memory, 0x4000_0000, 0x8000_0000, FrameSize::Huge, WRITABLE).unwrap();
+        space.map(&mut memory, 0x20_0000, 0x60_0000, FrameSize::Large, NONE).unwrap();
+
+        assert_eq!(memory.tables.len(), 3);
+        assert_eq!(space.translate(&mut memory, 0x4123_4567), Some((0x8123_4567, PRESENT | WRITABLE | HUGE)));
+        assert_eq!(space.translate(&mut memory, 0x21_0000), Some((0x61_0000, PRESENT | HUGE)));
+
+        // Pages can't go inside a huge page, and a huge page can't be unmapped from the middle.
+        assert_eq!(space.map(&mut memory, 0x4000_1000, 0x1000, FrameSize::Small, NONE),
+            Err(PagingError::AlreadyMapped(0x4000_1000)));
+        assert_eq!(space.unmap(&mut memory, 0x4000_1000), Err(PagingError::Misaligned));
+
+        space.update(&mut memory, 0x20_0000, WRITABLE | NO_EXECUTE).unwrap();
+        assert_eq!(space.translate(&mut memory, 0x20_0000),
+            Some((0x60_0000, PRESENT | WRITABLE | HUGE | NO_EXECUTE)));
+        assert_eq!(space.unmap(&mut memory, 0x4000_0000), Ok((0x8000_0000, FrameSize::Huge)));
+    }
+
+    #[test]
+    fn walks_the_tables() {
+        let mut memory = VecMemory { tables: Vec::new() };
+        let space = AddressSpace::new(&mut memory).unwrap();
+
+        space.map(&mut memory, 0x4000_0000, 0x8000_0000, FrameSize::Huge, WRITABLE).unwrap();
+        space.map(&mut memory, 0x1000, 0x5000, FrameSize::Small, NONE).unwrap();
+
+        let walk = space.walk(&mut memory, 0x4123_4567);
+        assert!(walk[0].unwrap().is_present() && walk[1].unwrap().is_huge());
+        assert_eq!(walk[1].unwrap().address(), 0x8000_0000);
+        assert_eq!((walk[2], walk[3]), (None, None));
+
+        let walk = space.walk(&mut memory, 0x1000);
+        assert_eq!(walk[3], Some(Entry::new(0x5000, PRESENT)));
+
+        // The walk ends at the entry which isn't present.
+        let walk = space.walk(&mut memory, 0x2000);
+        assert_eq!(walk[3], Some(Entry::unused()));
+        assert_eq!(space.walk(&mut memory, 0x8000_0000_0000), [None; 4]);
+    }
+
+    #[test]
+    fn keeps_physical_windows_from_being_writable_and_executable() {
+        let mut memory = VecMemory { tables: Vec::new() };
+        let space = AddressSpace::new(&mut memory).unwrap();
+        let areas = [
+            MemoryArea { base: 0, length: 0x9_F000, kind: MemoryKind::Available },
+            MemoryArea { base: 0x10_0000, length: 0x50_0000, kind: MemoryKind::Available },
+            MemoryArea { base: 0x60_0000, length: 0x20_0000, kind: MemoryKind::Reserved(2) }
+        ];
+        let ranges = PhysicalRanges { image: (0x10_0000, 0x34_5000), trampoline: (0x8000, 0x9000), areas: &areas };
+
+        map_window(&space, &mut memory, 0, 0x80_0000, &ranges, Window::Identity, false).unwrap();
+
+        // Small pages up to the end of the image, which takes a table for each of the first two large pages.
+        assert_eq!(memory.tables.len(), 5);
+        assert_eq!(space.translate(&mut memory, 0), None);
+        assert_eq!(space.translate(&mut memory, 0x1000), Some((0x1000, PRESENT | WRITABLE | no_execute())));
+        assert_eq!(space.translate(&mut memory, 0x8000), Some((0x8000, PRESENT)));
+        assert_eq!(space.translate(&mut memory, 0x9000), Some((0x9000, PRESENT | WRITABLE | no_execute())));
+        assert_eq!(space.translate(&mut memory, 0x34_4000), Some((0x34_4000, PRESENT | no_execute())));
+        assert_eq!(space.translate(&mut memory, 0x34_5000), Some((0x34_5000, PRESENT | WRITABLE | no_execute())));
+        assert_eq!(space.translate(&mut memory, 0x40_0000),
+            Some((0x40_0000, PRESENT | WRITABLE | HUGE | no_execute())));
+
+        // Nothing outside RAM is cached, from the legacy video memory to the reserved area.
+        let uncached = MemoryType::Uncached.flags();
+        assert_eq!(space.translate(&mut memory, 0xB_8000),
+            Some((0xB_8000, PRESENT | WRITABLE | uncached | no_execute())));
+        assert_eq!(space.translate(&mut memory, 0x60_0000),
+            Some((0x60_0000, PRESENT | WRITABLE | HUGE | uncached | no_execute())));
+
+        // The physical map has the first page, and the trampoline is as writable as the rest of it there.
+        let mut memory = VecMemory { tables: Vec::new() };
+        let space = AddressSpace::new(&mut memory).unwrap();
+
+        map_window(&space, &mut memory, 0x4000_0000, 0x80_0000, &ranges, Window::Physical, false).unwrap();
+        assert_eq!(space.translate(&mut memory, 0x4000_0000), Some((0, PRESENT | WRITABLE | no_execute())));
+        assert_eq!(space.translate(&mut memory, 0x4000_8000), Some((0x8000, PRESENT | WRITABLE | no_execute())));
+        assert_eq!(space.translate(&mut memory, 0x4010_0000), Some((0x10_0000, PRESENT | no_execute())));
+    }
+
+    #[test]
+    fn merges_the_flags_of_shared_pages() {
+        assert_eq!(merge_flags(PRESENT | NO_EXECUTE, WRITABLE | NO_EXECUTE), PRESENT | WRITABLE | NO_EXECUTE);
+        assert_eq!(merge_flags(PRESENT, WRITABLE | NO_EXECUTE), PRESENT | WRITABLE);
+        assert_eq!(merge_flags(NO_EXECUTE, NONE), NONE);
+    }
+
+    #[test]
+    fn runs_out_of_tables() {
+        let mut memory = VecMemory { tables: Vec::new() };
+        let space = AddressSpace::new(&mut memory).unwrap();
+
+        for table in 0 .. 2 {
+            space.map(&mut memory, table << 39, 0, FrameSize::Small, NONE).unwrap();
+        }
+
+        assert_eq!(space.map(&mut memory, 2 << 39, 0, FrameSize::Small, NONE), Err(PagingError::OutOfTables));
+    }
+
+    #[test]
+    fn picks_pat_entries_for_memory_types() {
+        // The PAT entry a level 1 entry picks is it's PAT, PCD and PWT bits, in that order.
+        let entry = |memory_type: MemoryType| {
+            let flags = memory_type.flags();
+            let index = (flags.contains(PAGE_ATTRIBUTE) as u64) << 2 | (flags.contains(NO_CACHE) as u64) << 1
+                | flags.contains(WRITE_THROUGH) as u64;
+
+            (PAT_ENTRIES >> (index * 8)) & 0xFF
+        };
+
+        assert_eq!(entry(MemoryType::WriteBack), 0x06);
+        assert_eq!(entry(MemoryType::WriteThrough), 0x04);
+        assert_eq!(entry(MemoryType::Uncached), 0x00);
+        assert_eq!(entry(MemoryType::WriteCombining), 0x01);
+        assert_eq!(entry(MemoryType::WriteProtected), 0x05);
+    }
+}
@@ -0,0 +1,119 @@
+//! Provides kernel stacks, taken from the layout's kernel stacks region with an unmapped guard page below each one.
+//! Running off the end of a stack faults on it's guard page rather than quietly overwriting whatever is below it;
+//! the page fault can't be delivered on the stack which overflowed, so it becomes a double fault, whose handler runs
+//! on a stack of it's own and uses overflowed() to say which stack it was.
+
+use spin::Mutex;
+
+use arch::x86_64::instructions;
+use super::{FRAME_SIZE, align_up};
+use super::layout::{self, Region};
+use super::paging::{self, WRITABLE};
+
+/// The size of the unmapped guard page below each stack.
+pub const GUARD_SIZE: u64 = FRAME_SIZE;
+
+/// The most stacks which can be allocated at once.
+const MAX_STACKS: usize = 64;
+
+/// A kernel stack, mapped from it's bottom up to (but not including) it's top, with a guard page below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    /// What the stack is for, for reporting overflows.
+    name: &'static str,
+
+    /// The start of the guard page, where the stack's range starts.
+    guard: u64,
+    top: u64
+}
+
+impl KernelStack {
+    /// The address the stack pointer starts at; it's aligned to a page, so to 16 bytes as the System V ABI expects.
+    pub fn top(&self) -> u64 {
+        self.top
+    }
+
+    /// The lowest address of the stack which is mapped.
+    pub fn bottom(&self) -> u64 {
+        self.guard + GUARD_SIZE
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// True if the address is in the stack's guard page.
+    pub fn guards(&self, address: u64) -> bool {
+        address >= self.guard && address < self.bottom()
+    }
+}
+
+/// The stacks which have been allocated, so overflows can be put down to one. Only taken with interrupts disabled.
+static STACKS: Mutex<[Option<KernelStack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// Allocates a stack with room for the given number of bytes (rounded up to whole pages), with every page mapped
+/// up front. Returns None if the region or the physical memory has run out, or too many stacks are in use.
+pub fn allocate(name: &'static str, size: u64) -> Option<KernelStack> {
+    let size = align_up(size, FRAME_SIZE);
+    let guard = layout::allocate(Region::KernelStacks, GUARD_SIZE + size, FRAME_SIZE)?;
+    let stack = KernelStack { name: name, guard: guard, top: guard + GUARD_SIZE + size };
+
+    if !layout::back(stack.bottom(), size, WRITABLE | paging::no_execute()) {
+        layout::free(Region::KernelStacks, guard, GUARD_SIZE + size);
+        return None;
+    }
+
+    let registered = instructions::without_interrupts(|| {
+        match STACKS.lock().iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(stack);
+                true
+            },
+            None => false
+        }
+    });
+
+    if !registered {
+        // UNSAFE: Safe, as nothing has been given the stack yet.
+        unsafe { layout::free_mapped(Region::KernelStacks, guard, GUARD_SIZE + size); }
+        return None;
+    }
+
+    Some(stack)
+}
+
+/// Unmaps a stack, frees it's frames, and gives it back to the region.
+/// UNSAFE: Nothing may be running on the stack, or use anything on it, afterwards.
+pub unsafe fn free(stack: KernelStack) {
+    instructions::without_interrupts(|| {
+        for slot in STACKS.lock().iter_mut().filter(|slot| **slot == Some(stack)) {
+            *slot = None;
+        }
+    });
+
+    // The guard page was never mapped, so unmapping it does nothing.
+    layout::free_mapped(Region::KernelStacks, stack.guard, stack.top - stack.guard);
+}
+
+/// The stack whose guard page the address is in, if any. Called from the double fault handler, which can interrupt
+/// anything, so if the stacks are locked this gives up rather than waiting forever.
+pub fn overflowed(address: u64) -> Option<KernelStack> {
+    let stacks = STACKS.try_lock()?;
+    let found = stacks.iter().filter_map(|&stack| stack).find(|stack| stack.guards(address));
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guards_the_page_below_a_stack() {
+        let stack = KernelStack { name: "test", guard: 0xFFFF_E030_0000_0000, top: 0xFFFF_E030_0000_5000 };
+
+        assert_eq!(stack.bottom(), 0xFFFF_E030_0000_1000);
+        assert!(stack.guards(0xFFFF_E030_0000_0000) && stack.guards(0xFFFF_E030_0000_0FF8));
+        assert!(!stack.guards(stack.bottom()) && !stack.guards(0xFFFF_E02F_FFFF_FFF8));
+    }
+}
@@ -0,0 +1,109 @@
+//! Provides minimal access to the raw tags of the multiboot2 boot information structure, for the tags
+//! which the multiboot2 crate doesn't know about (like the ACPI RSDP copies).
+
+use core::{mem, slice, str};
+
+/// The type of the tag which terminates the tag list.
+pub const TAG_END: u32 = 0;
+
+/// The type of the tag containing the kernel command line, as a null-terminated string.
+pub const TAG_COMMAND_LINE: u32 = 1;
+
+/// The type of the tag containing a copy of the ACPI 1.0 RSDP.
+pub const TAG_ACPI_OLD_RSDP: u32 = 14;
+
+/// The type of the tag containing a copy of the ACPI 2.0+ XSDP.
+pub const TAG_ACPI_NEW_RSDP: u32 = 15;
+
+/// Every tag starts on an 8-byte boundary.
+const TAG_ALIGNMENT: usize = 8;
+
+/// The fixed header of the boot information structure, which is followed directly by the tags.
+#[repr(C)]
+#[derive(Debug)]
+pub struct InfoHeader {
+    /// The total size of the boot information, including this header and the end tag.
+    pub total_size: u32,
+
+    /// Reserved, should be zero.
+    _reserved: u32
+}
+
+/// The header shared by every tag in the boot information structure.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TagHeader {
+    /// The type of the tag, which determines how the data after it should be interpreted.
+    pub tag_type: u32,
+
+    /// The size of the tag, including this header but excluding any padding at the end.
+    pub size: u32
+}
+
+impl TagHeader {
+    /// Obtains a pointer to the data which follows this header.
+    pub fn data(&self) -> *const u8 {
+        // UNSAFE: Safe, as the data directly follows the header.
+        unsafe { (self as *const TagHeader as *const u8).offset(mem::size_of::<TagHeader>() as isize) }
+    }
+
+    /// The number of bytes of data which follow this header.
+    pub fn data_size(&self) -> usize {
+        (self.size as usize).saturating_sub(mem::size_of::<TagHeader>())
+    }
+}
+
+/// Provides iteration over the tags of the boot information structure.
+#[derive(Debug)]
+pub struct TagIter {
+    /// The location of the next tag to return.
+    location: usize,
+
+    /// The end of the boot information structure; no tag may extend past this.
+    end: usize
+}
+
+impl Iterator for TagIter {
+    type Item = &'static TagHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.location + mem::size_of::<TagHeader>() > self.end { return None; }
+
+        // UNSAFE: Safe, as we checked the header is within the boot information.
+        let tag: &'static TagHeader = unsafe { &*(self.location as *const TagHeader) };
+        if tag.tag_type == TAG_END || (tag.size as usize) < mem::size_of::<TagHeader>() { return None; }
+
+        // Tags are padded out so the next one starts 8-byte aligned.
+        let size = (tag.size as usize + TAG_ALIGNMENT - 1) & !(TAG_ALIGNMENT - 1);
+        self.location += size;
+
+        Some(tag)
+    }
+}
+
+/// Returns an iterator over the tags of the boot information structure at the given address.
+/// UNSAFE: The address must point to a valid (mapped) multiboot2 boot information structure.
+pub unsafe fn tags(info: *const u8) -> TagIter {
+    let header = &*(info as *const InfoHeader);
+    let start = info as usize;
+
+    TagIter { location: start + mem::size_of::<InfoHeader>(), end: start + header.total_size as usize }
+}
+
+/// Finds the first tag of the given type in the boot information structure at the given address.
+/// UNSAFE: The address must point to a valid (mapped) multiboot2 boot information structure.
+pub unsafe fn find_tag(info: *const u8, tag_type: u32) -> Option<&'static TagHeader> {
+    tags(info).find(|tag| tag.tag_type == tag_type)
+}
+
+/// Obtains the kernel command line from the boot information structure at the given address, if the loader
+/// gave us one (and it's valid UTF-8).
+/// UNSAFE: The address must point to a valid (mapped) multiboot2 boot information structure.
+pub unsafe fn command_line(info: *const u8) -> Option<&'static str> {
+    let tag = find_tag(info, TAG_COMMAND_LINE)?;
+    let bytes = slice::from_raw_parts(tag.data(), tag.data_size());
+
+    // The string is null-terminated within the tag, but don't trust the loader to have done so.
+    let length = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[.. length]).ok()
+}
@@ -0,0 +1,314 @@
+//! Provides the NUMA topology of the machine: which node (proximity domain) each processor and each range of
+//! physical memory belongs to, and how far apart the nodes are. This comes from the SRAT and SLIT; machines
+//! without an SRAT are treated as a single node containing everything.
+//!
+//! Proximity domains are arbitrary 32-bit numbers, so nodes are renumbered densely (in the order their
+//! domains first appear in the SRAT), which lets per-node state live in plain arrays.
+
+use spin::Once;
+
+use acpi::{ACPI, SRAT, SLIT, SratEntry, LOCAL_DISTANCE, AFFINITY_ENABLED, MEMORY_HOT_PLUGGABLE,
+    MEMORY_NON_VOLATILE};
+
+/// A node, numbered densely from 0.
+pub type NodeId = usize;
+
+/// The most nodes we keep track of; domains beyond these are folded into node 0.
+pub const MAX_NODES: usize = 64;
+
+/// The most processors and memory ranges we keep track of.
+pub const MAX_CPUS: usize = 256;
+pub const MAX_MEMORY_RANGES: usize = 64;
+
+/// The distance assumed between two different nodes when there's no SLIT, as the specification suggests.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// A processor, and the node it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cpu {
+    /// The processor's (x2)APIC id.
+    pub apic_id: u32,
+
+    pub node: NodeId
+}
+
+/// A range of physical memory, and the node it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub base: u64,
+    pub length: u64,
+    pub node: NodeId,
+
+    /// True if the memory may be hot-plugged, and so might not be present (yet).
+    pub hot_pluggable: bool,
+
+    /// True if the memory is non-volatile.
+    pub non_volatile: bool
+}
+
+impl MemoryRange {
+    /// True if the address lies within this range.
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.base && address - self.base < self.length
+    }
+}
+
+/// The NUMA topology of the machine.
+pub struct Topology {
+    /// The proximity domain of each node.
+    domains: [u32; MAX_NODES],
+    node_count: usize,
+
+    cpus: [Cpu; MAX_CPUS],
+    cpu_count: usize,
+
+    memory: [MemoryRange; MAX_MEMORY_RANGES],
+    memory_count: usize,
+
+    /// The distance between each pair of nodes.
+    distances: [[u8; MAX_NODES]; MAX_NODES]
+}
+
+/// The topology, filled in by init().
+static TOPOLOGY: Once<Topology> = Once::new();
+
+/// Works out the NUMA topology from the SRAT and SLIT. Returns the number of nodes.
+pub fn init(acpi: &ACPI) -> usize {
+    TOPOLOGY.call_once(|| Topology::new(acpi.find_table::<SRAT>(), acpi.find_table::<SLIT>())).node_count
+}
+
+/// The topology found by init(); before then, everything is treated as a single node.
+fn topology() -> Option<&'static Topology> {
+    TOPOLOGY.try()
+}
+
+/// The number of nodes in the machine.
+pub fn node_count() -> usize {
+    topology().map(|topology| topology.node_count()).unwrap_or(1)
+}
+
+/// The node of the processor with the given (x2)APIC id; node 0 if it isn't in the SRAT.
+pub fn node_of_cpu(apic_id: u32) -> NodeId {
+    topology().map(|topology| topology.node_of_cpu(apic_id)).unwrap_or(0)
+}
+
+/// The node which the given physical address belongs to; node 0 if it isn't in the SRAT.
+pub fn node_of_address(address: u64) -> NodeId {
+    topology().map(|topology| topology.node_of_address(address)).unwrap_or(0)
+}
+
+/// The relative distance between two nodes, where LOCAL_DISTANCE (10) is the distance from a node to itself.
+pub fn distance(from: NodeId, to: NodeId) -> u8 {
+    match topology() {
+        Some(topology) => topology.distance(from, to),
+        None => if from == to { LOCAL_DISTANCE } else { REMOTE_DISTANCE }
+    }
+}
+
+/// The processors in the SRAT.
+pub fn cpus() -> &'static [Cpu] {
+    topology().map(|topology| topology.cpus()).unwrap_or(&[])
+}
+
+/// The memory ranges in the SRAT.
+pub fn memory_ranges() -> &'static [MemoryRange] {
+    topology().map(|topology| topology.memory_ranges()).unwrap_or(&[])
+}
+
+impl Topology {
+    /// Builds the topology from the (enabled) entries of an SRAT and the distances in a SLIT.
+    pub fn new(srat: Option<&SRAT>, slit: Option<&SLIT>) -> Topology {
+        let mut topology = Topology {
+            domains: [0; MAX_NODES],
+            node_count: 1,
+            cpus: [Cpu { apic_id: 0, node: 0 }; MAX_CPUS],
+            cpu_count: 0,
+            memory: [MemoryRange { base: 0, length: 0, node: 0, hot_pluggable: false, non_volatile: false };
+                MAX_MEMORY_RANGES],
+            memory_count: 0,
+            distances: [[LOCAL_DISTANCE; MAX_NODES]; MAX_NODES]
+        };
+
+        if let Some(srat) = srat {
+            // Node 0 is whichever domain comes first, rather than always being there.
+            topology.node_count = 0;
+
+            for entry in srat.entries() {
+                match entry {
+                    SratEntry::ProcessorAffinity(affinity) if affinity.flags & AFFINITY_ENABLED != 0 => {
+                        let node = topology.node_of_domain(affinity.proximity_domain());
+                        topology.add_cpu(affinity.apic_id as u32, node);
+                    },
+                    SratEntry::X2ApicAffinity(affinity) if affinity.flags & AFFINITY_ENABLED != 0 => {
+                        let node = topology.node_of_domain(affinity.proximity_domain);
+                        topology.add_cpu(affinity.x2apic_id, node);
+                    },
+                    SratEntry::MemoryAffinity(affinity) if affinity.flags & AFFINITY_ENABLED != 0 => {
+                        let node = topology.node_of_domain(affinity.proximity_domain);
+                        let flags = affinity.flags;
+
+                        if topology.memory_count < MAX_MEMORY_RANGES && affinity.length() != 0 {
+                            topology.memory[topology.memory_count] = MemoryRange {
+                                base: affinity.base_address(),
+                                length: affinity.length(),
+                                node: node,
+                                hot_pluggable: flags & MEMORY_HOT_PLUGGABLE != 0,
+                                non_volatile: flags & MEMORY_NON_VOLATILE != 0
+                            };
+                            topology.memory_count += 1;
+                        }
+                    },
+                    _ => {}
+                }
+            }
+
+            topology.node_count = if topology.node_count == 0 { 1 } else { topology.node_count };
+        }
+
+        // The SLIT is indexed by proximity domain, not by our node numbers.
+        for from in 0 .. topology.node_count {
+            for to in 0 .. topology.node_count {
+                let distance = slit.and_then(|slit| {
+                    slit.distance(topology.domains[from] as usize, topology.domains[to] as usize)
+                });
+
+                topology.distances[from][to] = match distance {
+                    Some(distance) => distance,
+                    None if from == to => LOCAL_DISTANCE,
+                    None => REMOTE_DISTANCE
+                };
+            }
+        }
+
+        topology
+    }
+
+    /// Finds (or assigns) the node for the given proximity domain.
+    fn node_of_domain(&mut self, domain: u32) -> NodeId {
+        if let Some(node) = self.domains[.. self.node_count].iter().position(|&known| known == domain) {
+            return node;
+        }
+
+        if self.node_count == MAX_NODES { return 0; }
+
+        self.domains[self.node_count] = domain;
+        self.node_count += 1;
+        self.node_count - 1
+    }
+
+    /// Records a processor, unless there are already too many to keep track of.
+    fn add_cpu(&mut self, apic_id: u32, node: NodeId) {
+        if self.cpu_count == MAX_CPUS { return; }
+
+        self.cpus[self.cpu_count] = Cpu { apic_id: apic_id, node: node };
+        self.cpu_count += 1;
+    }
+
+    /// The number of nodes.
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    /// The proximity domain the given node stands for.
+    pub fn proximity_domain(&self, node: NodeId) -> Option<u32> {
+        if node < self.node_count { Some(self.domains[node]) } else { None }
+    }
+
+    /// The node of the processor with the given (x2)APIC id, or node 0 if it isn't known.
+    pub fn node_of_cpu(&self, apic_id: u32) -> NodeId {
+        self.cpus().iter().find(|cpu| cpu.apic_id == apic_id).map(|cpu| cpu.node).unwrap_or(0)
+    }
+
+    /// The node the given physical address belongs to, or node 0 if it isn't known.
+    pub fn node_of_address(&self, address: u64) -> NodeId {
+        self.memory_ranges().iter().find(|range| range.contains(address)).map(|range| range.node).unwrap_or(0)
+    }
+
+    /// The relative distance between two nodes.
+    pub fn distance(&self, from: NodeId, to: NodeId) -> u8 {
+        if from < self.node_count && to < self.node_count {
+            self.distances[from][to]
+        } else if from == to {
+            LOCAL_DISTANCE
+        } else {
+            REMOTE_DISTANCE
+        }
+    }
+
+    /// The enabled processors, in the order the SRAT lists them.
+    pub fn cpus(&self) -> &[Cpu] {
+        &self.cpus[.. self.cpu_count]
+    }
+
+    /// The enabled memory ranges, in the order the SRAT lists them.
+    pub fn memory_ranges(&self) -> &[MemoryRange] {
+        &self.memory[.. self.memory_count]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use acpi::{SRAT, SLIT, AFFINITY_ENABLED};
+    use acpi::test_support::{qemu_numa_tables, processor_affinity, memory_affinity, table};
+    use super::*;
+
+    /// Reinterprets a table built in a buffer.
+    fn as_table<T>(bytes: &[u8]) -> &T {
+        unsafe { &*(bytes.as_ptr() as *const T) }
+    }
+
+    #[test]
+    fn maps_cpus_and_memory_to_nodes() {
+        let (srat, slit) = qemu_numa_tables();
+        let topology = Topology::new(Some(as_table::<SRAT>(&srat)), Some(as_table::<SLIT>(&slit)));
+
+        assert_eq!(topology.node_count(), 2);
+
+        // The disabled processor is left out.
+        let cpus: Vec<(u32, NodeId)> = topology.cpus().iter().map(|cpu| (cpu.apic_id, cpu.node)).collect();
+        assert_eq!(cpus, vec![(0, 0), (1, 0), (2, 1), (3, 1)]);
+        assert_eq!(topology.node_of_cpu(4), 0);
+
+        assert_eq!(topology.node_of_address(0x1000), 0);
+        assert_eq!(topology.node_of_address(0x1FFFFFFF), 0);
+        assert_eq!(topology.node_of_address(0x20000000), 1);
+        assert_eq!(topology.node_of_address(0x120000000), 1);
+        assert!(topology.memory_ranges()[3].hot_pluggable);
+
+        assert_eq!(topology.distance(0, 0), 10);
+        assert_eq!(topology.distance(0, 1), 21);
+        assert_eq!(topology.distance(1, 1), 10);
+    }
+
+    #[test]
+    fn numbers_sparse_domains_densely() {
+        let mut body = vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        body.extend_from_slice(&processor_affinity(0, 7, AFFINITY_ENABLED));
+        body.extend_from_slice(&processor_affinity(1, 3, AFFINITY_ENABLED));
+        body.extend_from_slice(&memory_affinity(0, 0x40000000, 3, AFFINITY_ENABLED));
+        let srat = table(b"SRAT", 1, &body);
+
+        let topology = Topology::new(Some(as_table::<SRAT>(&srat)), None);
+
+        assert_eq!(topology.node_count(), 2);
+        assert_eq!((topology.proximity_domain(0), topology.proximity_domain(1)), (Some(7), Some(3)));
+        assert_eq!((topology.node_of_cpu(0), topology.node_of_cpu(1)), (0, 1));
+        assert_eq!(topology.node_of_address(0x1000), 1);
+
+        // Without a SLIT, the default distances are used.
+        assert_eq!(topology.distance(0, 1), REMOTE_DISTANCE);
+        assert_eq!(topology.distance(1, 1), LOCAL_DISTANCE);
+    }
+
+    #[test]
+    fn treats_machines_without_an_srat_as_one_node() {
+        let topology = Topology::new(None, None);
+
+        assert_eq!(topology.node_count(), 1);
+        assert_eq!(topology.node_of_cpu(3), 0);
+        assert_eq!(topology.node_of_address(0x123456789), 0);
+        assert_eq!(topology.distance(0, 0), LOCAL_DISTANCE);
+    }
+}
@@ -0,0 +1,258 @@
+//! Provides access to PCI configuration space. When the firmware describes memory-mapped (ECAM)
+//! configuration regions in the MCFG, those are used, which gives access to every segment group and to the
+//! 4KiB extended configuration space of PCI Express functions; otherwise, the legacy 0xCF8/0xCFC ports are
+//! used, which only reach the first 256 bytes of each function in segment group 0.
+
+use core::ptr;
+
+use spin::{Mutex, Once};
+
+use acpi::{ACPI, MCFG, McfgEntry};
+use arch::x86_64::{port, IDENTITY_MAP_SIZE};
+
+/// The legacy configuration mechanism's address and data ports.
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+
+/// Set in the address port to enable the configuration cycle.
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+/// The size of the configuration space of a function, through the legacy ports and through ECAM.
+pub const LEGACY_CONFIG_SIZE: u16 = 0x100;
+pub const EXTENDED_CONFIG_SIZE: u16 = 0x1000;
+
+/// The most ECAM regions we keep track of; machines normally have one per segment group.
+const MAX_ECAM_REGIONS: usize = 16;
+
+/// The offsets of the standard header registers every function has.
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION_ID: u16 = 0x08;
+pub const CLASS_CODE: u16 = 0x09;
+pub const HEADER_TYPE: u16 = 0x0E;
+
+/// The offsets of a PCI-to-PCI bridge's bus numbers: the bus right behind it, and the highest bus below it.
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const SUBORDINATE_BUS: u16 = 0x1A;
+
+/// The vendor id read back from functions which don't exist.
+pub const INVALID_VENDOR_ID: u16 = 0xFFFF;
+
+/// Set in the header type of function 0 if the device has functions other than 0.
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+
+/// A PCI function, identified by it's segment group, bus, device and function numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8
+}
+
+impl PciAddress {
+    /// Creates the address of a function in segment group 0.
+    pub fn new(bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress { segment: 0, bus: bus, device: device, function: function }
+    }
+
+    /// The value to write to the legacy address port to select the dword at the given offset.
+    fn legacy_config_address(&self, offset: u16) -> u32 {
+        CONFIG_ADDRESS_ENABLE | (self.bus as u32) << 16 | (self.device as u32 & 0x1F) << 11
+            | (self.function as u32 & 0x7) << 8 | (offset as u32 & 0xFC)
+    }
+}
+
+/// The ECAM regions, filled in by init(); empty if there's no (usable) MCFG.
+static ECAM_REGIONS: Once<([Option<McfgEntry>; MAX_ECAM_REGIONS], usize)> = Once::new();
+
+/// Serializes use of the legacy ports, as selecting a register and accessing it takes two steps.
+static LEGACY_PORTS: Mutex<()> = Mutex::new(());
+
+/// Picks up the ECAM regions from the MCFG, if there is one. Regions outside of the identity mapped part of
+/// physical memory are ignored, so accesses to them fall back to the legacy ports (or fail, for segment
+/// groups other than 0). Returns the number of regions in use.
+pub fn init(acpi: &ACPI) -> usize {
+    let regions = ECAM_REGIONS.call_once(|| {
+        let mut regions = [None; MAX_ECAM_REGIONS];
+        let mut count = 0;
+
+        if let Some(mcfg) = acpi.find_table::<MCFG>() {
+            for entry in mcfg.entries() {
+                if count == MAX_ECAM_REGIONS { break; }
+                if entry.start_bus > entry.end_bus || entry.end() > IDENTITY_MAP_SIZE {
+                    continue;
+                }
+
+                regions[count] = Some(entry);
+                count += 1;
+            }
+        }
+
+        (regions, count)
+    });
+
+    regions.1
+}
+
+/// Finds the memory-mapped address of the given offset in a function's configuration space.
+fn ecam_address(function: PciAddress, offset: u16) -> Option<u64> {
+    let &(ref regions, count) = ECAM_REGIONS.try()?;
+
+    regions[.. count].iter()
+        .filter_map(|region| region.and_then(|region| {
+            region.function_address(function.segment, function.bus, function.device, function.function)
+        }))
+        .next()
+        .map(|address| address + offset as u64)
+}
+
+/// Reads a register of the given width (1, 2 or 4 bytes) from a function's configuration space. The
+/// offset must be aligned to the width. Returns None if the register can't be reached (it's past the first
+/// 256 bytes, or in another segment group, and there's no ECAM region for the function).
+/// UNSAFE: Reading some registers has side effects.
+pub unsafe fn read(function: PciAddress, offset: u16, width: usize) -> Option<u32> {
+    if offset >= EXTENDED_CONFIG_SIZE || offset as usize % width != 0 { return None; }
+
+    if let Some(address) = ecam_address(function, offset) {
+        return Some(match width {
+            1 => ptr::read_volatile(address as *const u8) as u32,
+            2 => ptr::read_volatile(address as *const u16) as u32,
+            _ => ptr::read_volatile(address as *const u32)
+        });
+    }
+
+    if function.segment != 0 || offset >= LEGACY_CONFIG_SIZE { return None; }
+
+    let _ports = LEGACY_PORTS.lock();
+    let data_port = CONFIG_DATA_PORT + (offset & 0b11);
+    port::outl(CONFIG_ADDRESS_PORT, function.legacy_config_address(offset));
+
+    Some(match width {
+        1 => port::inb(data_port) as u32,
+        2 => port::inw(data_port) as u32,
+        _ => port::inl(data_port)
+    })
+}
+
+/// Writes a register of the given width (1, 2 or 4 bytes) in a function's configuration space. Returns
+/// false if the register can't be reached; see read().
+/// UNSAFE: Writing configuration registers can reconfigure the device arbitrarily.
+pub unsafe fn write(function: PciAddress, offset: u16, width: usize, value: u32) -> bool {
+    if offset >= EXTENDED_CONFIG_SIZE || offset as usize % width != 0 { return false; }
+
+    if let Some(address) = ecam_address(function, offset) {
+        match width {
+            1 => ptr::write_volatile(address as *mut u8, value as u8),
+            2 => ptr::write_volatile(address as *mut u16, value as u16),
+            _ => ptr::write_volatile(address as *mut u32, value)
+        }
+
+        return true;
+    }
+
+    if function.segment != 0 || offset >= LEGACY_CONFIG_SIZE { return false; }
+
+    let _ports = LEGACY_PORTS.lock();
+    let data_port = CONFIG_DATA_PORT + (offset & 0b11);
+    port::outl(CONFIG_ADDRESS_PORT, function.legacy_config_address(offset));
+
+    match width {
+        1 => port::outb(data_port, value as u8),
+        2 => port::outw(data_port, value as u16),
+        _ => port::outl(data_port, value)
+    }
+
+    true
+}
+
+/// Reads a byte from a function's configuration space.
+pub unsafe fn read_u8(function: PciAddress, offset: u16) -> Option<u8> {
+    read(function, offset, 1).map(|value| value as u8)
+}
+
+/// Reads a word from a function's configuration space.
+pub unsafe fn read_u16(function: PciAddress, offset: u16) -> Option<u16> {
+    read(function, offset, 2).map(|value| value as u16)
+}
+
+/// Reads a dword from a function's configuration space.
+pub unsafe fn read_u32(function: PciAddress, offset: u16) -> Option<u32> {
+    read(function, offset, 4)
+}
+
+/// Writes a byte to a function's configuration space.
+pub unsafe fn write_u8(function: PciAddress, offset: u16, value: u8) -> bool {
+    write(function, offset, 1, value as u32)
+}
+
+/// Writes a word to a function's configuration space.
+pub unsafe fn write_u16(function: PciAddress, offset: u16, value: u16) -> bool {
+    write(function, offset, 2, value as u32)
+}
+
+/// Writes a dword to a function's configuration space.
+pub unsafe fn write_u32(function: PciAddress, offset: u16, value: u32) -> bool {
+    write(function, offset, 4, value)
+}
+
+/// True if the function exists.
+pub fn exists(function: PciAddress) -> bool {
+    // UNSAFE: Safe, as the vendor id has no side effects.
+    unsafe { read_u16(function, VENDOR_ID) }.map(|vendor| vendor != INVALID_VENDOR_ID).unwrap_or(false)
+}
+
+/// True if the function has access to the extended (PCI Express) configuration space.
+pub fn has_extended_config(function: PciAddress) -> bool {
+    ecam_address(function, 0).is_some()
+}
+
+/// An iterator over every function which exists in segment group 0, found by checking every device on
+/// every bus.
+#[derive(Debug)]
+pub struct FunctionIter {
+    /// The next function to check, or None once we've run out of buses.
+    next: Option<PciAddress>
+}
+
+/// Iterates over every function in segment group 0.
+pub fn functions() -> FunctionIter {
+    FunctionIter { next: Some(PciAddress::new(0, 0, 0)) }
+}
+
+impl FunctionIter {
+    /// Moves on to the next device (or bus).
+    fn next_device(current: PciAddress) -> Option<PciAddress> {
+        match (current.bus, current.device) {
+            (255, 31) => None,
+            (bus, 31) => Some(PciAddress::new(bus + 1, 0, 0)),
+            (bus, device) => Some(PciAddress::new(bus, device + 1, 0))
+        }
+    }
+}
+
+impl Iterator for FunctionIter {
+    type Item = PciAddress;
+
+    fn next(&mut self) -> Option<PciAddress> {
+        while let Some(current) = self.next {
+            let exists = exists(current);
+
+            // Functions 1-7 only need checking if function 0 says it's a multi-function device.
+            let multi_function = exists && current.function == 0
+                && unsafe { read_u8(current, HEADER_TYPE) }.unwrap_or(0) & HEADER_TYPE_MULTI_FUNCTION != 0;
+
+            self.next = if multi_function || (current.function != 0 && current.function < 7) {
+                Some(PciAddress { function: current.function + 1, .. current })
+            } else {
+                Self::next_device(current)
+            };
+
+            if exists { return Some(current); }
+        }
+
+        None
+    }
+}
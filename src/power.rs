@@ -0,0 +1,265 @@
+//! Provides system-wide power control: turning the machine off, resetting it, and suspending it to RAM. All are
+//! driven by the registers the firmware describes in the FADT, with the traditional PC fallbacks for the first two
+//! when those are missing.
+
+use spin::Once;
+
+use acpi::{ACPI, FADT, FACS, GenericAddress, ADDRESS_SPACE_PCI_CONFIG};
+use acpi::aml::{self, AmlValue};
+use arch::x86_64::{gdt, port, instructions, wakeup};
+use pci::{self, PciAddress};
+use sci::{self, SciError};
+use apic;
+use hpet;
+use iommu;
+use serial;
+
+/// The bit in the PM1 control register which starts the sleep transition selected by SLP_TYP.
+const SLP_EN: u64 = 1 << 13;
+
+/// The position of the SLP_TYP field in the PM1 control register.
+const SLP_TYP_SHIFT: u64 = 10;
+
+/// The mask of the SLP_TYP field in the PM1 control register (after shifting).
+const SLP_TYP_MASK: u64 = 0b111;
+
+/// The numbers of the suspend-to-RAM and soft-off sleep states, as passed to \_PTS and \_WAK.
+const S3: u64 = 3;
+const S5: u64 = 5;
+
+/// The 8042 keyboard controller's command/status port.
+const KEYBOARD_CONTROLLER_PORT: u16 = 0x64;
+
+/// Set in the 8042 status register while the controller hasn't consumed the last command yet.
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+
+/// The 8042 command which pulses the CPU reset line.
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xFE;
+
+/// The PM1a control ports used by emulators (QEMU, Bochs/old QEMU, VirtualBox), and the value which turns them
+/// off; used if we couldn't figure out how to enter S5 from the firmware.
+const EMULATOR_SHUTDOWN_PORTS: [u16; 3] = [0x604, 0xB004, 0x4004];
+const EMULATOR_SHUTDOWN_VALUE: u16 = 0x2000;
+
+/// Everything we need from the firmware to change the power state of the machine.
+#[derive(Debug)]
+struct PowerControl {
+    /// The PM1a control register, which every ACPI machine has.
+    pm1a_control: Option<GenericAddress>,
+
+    /// The PM1b control register, which some machines split their PM1 registers into.
+    pm1b_control: Option<GenericAddress>,
+
+    /// The SLP_TYPa and SLP_TYPb values which select the S3 (suspend-to-RAM) state.
+    s3_sleep_type: Option<(u8, u8)>,
+
+    /// The SLP_TYPa and SLP_TYPb values which select the S5 (soft-off) state.
+    s5_sleep_type: Option<(u8, u8)>,
+
+    /// The (mapped) address of the FACS, which holds the waking vector.
+    facs: Option<u64>,
+
+    /// The reset register and the value to write to it.
+    reset: Option<(GenericAddress, u8)>,
+
+    /// True if we can fall back to resetting through the 8042 keyboard controller.
+    has_8042: bool
+}
+
+/// The power control information, filled in by init().
+static POWER_CONTROL: Once<PowerControl> = Once::new();
+
+/// The reasons the machine can't be suspended to RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspendError {
+    /// There's no FADT with a PM1 control register, or init() hasn't been called.
+    NotPresent,
+
+    /// The firmware has no \_S3 package, so the machine can't be suspended to RAM.
+    NotSupported,
+
+    /// There's no FACS to put the waking vector in.
+    NoFacs,
+
+    /// The SCI isn't set up, so there's no way to tell when the machine wakes up.
+    NoWakeEvents,
+
+    /// The machine woke up, but the event registers couldn't be set up again.
+    ResumeFailed(SciError)
+}
+
+/// Gathers the power control registers from the FADT (and the sleep values from the AML namespace, which
+/// should be loaded first), so shutdown(), reboot() and suspend() can use them later. If there is no FADT (or this is
+/// never called), only the legacy fallbacks are used.
+pub fn init(acpi: &ACPI) {
+    let fadt = match acpi.find_table::<FADT>() {
+        Some(fadt) => fadt,
+        None => return
+    };
+
+    POWER_CONTROL.call_once(|| PowerControl {
+        pm1a_control: fadt.pm1a_control_block(),
+        pm1b_control: fadt.pm1b_control_block(),
+        s3_sleep_type: find_sleep_type("\\_S3"),
+        s5_sleep_type: find_sleep_type("\\_S5"),
+        facs: acpi.facs().map(|facs| facs as *const FACS as u64),
+        reset: fadt.reset_register(),
+        has_8042: fadt.has_8042()
+    });
+}
+
+/// Turns the machine off, by entering the ACPI S5 (soft-off) state.
+pub fn shutdown() -> ! {
+    unsafe {
+        instructions::disable_interrupts();
+
+        if let Some(control) = POWER_CONTROL.try() {
+            // Give the firmware a chance to prepare for the transition, if it wants one. This can be shutting down
+            // after a panic part way through an evaluation, so it's skipped if the namespace is in use.
+            aml::try_evaluate("\\_PTS", &[AmlValue::Integer(S5)]);
+
+            if let (Some(pm1a), Some((typ_a, typ_b))) = (control.pm1a_control, control.s5_sleep_type) {
+                enter_sleep_state(&pm1a, typ_a);
+
+                if let Some(pm1b) = control.pm1b_control {
+                    enter_sleep_state(&pm1b, typ_b);
+                }
+            }
+        }
+
+        // If we're still here, either ACPI isn't there or it didn't work; try the emulator-specific ports.
+        for &shutdown_port in &EMULATOR_SHUTDOWN_PORTS {
+            port::outw(shutdown_port, EMULATOR_SHUTDOWN_VALUE);
+        }
+    }
+
+    // Nothing worked; the best we can do is stop doing anything.
+    loop { instructions::halt(); }
+}
+
+/// Suspends the machine to RAM (the ACPI S3 state), returning once it wakes up again. Everything but memory loses
+/// power in the meantime, so the processor's state and the interrupt controllers, HPET and event registers are
+/// saved first and put back on the way out, and the IOMMUs are set up again. Interrupts are disabled while this
+/// runs, and only enabled again afterwards if they were enabled before.
+pub fn suspend() -> Result<(), SuspendError> {
+    let control = POWER_CONTROL.try().ok_or(SuspendError::NotPresent)?;
+    let pm1a = control.pm1a_control.ok_or(SuspendError::NotPresent)?;
+    let sleep_type = control.s3_sleep_type.ok_or(SuspendError::NotSupported)?;
+    let facs = control.facs.ok_or(SuspendError::NoFacs)?;
+
+    // UNSAFE: Safe, as init() found a valid FACS there, in memory which stays mapped.
+    let facs = unsafe { &*(facs as *const FACS) };
+    let interrupts_enabled = instructions::interrupts_enabled();
+
+    unsafe {
+        instructions::disable_interrupts();
+
+        if !sci::suspend() {
+            if interrupts_enabled { instructions::enable_interrupts(); }
+            return Err(SuspendError::NoWakeEvents);
+        }
+
+        // Give the firmware a chance to prepare for the transition, if it wants one.
+        if aml::exists("\\_PTS") {
+            aml::evaluate("\\_PTS", &[AmlValue::Integer(S3)]).ok();
+        }
+
+        hpet::suspend();
+        apic::suspend();
+        facs.set_waking_vector(wakeup::trampoline_address());
+
+        sleep(&pm1a, control.pm1b_control, sleep_type);
+
+        facs.set_waking_vector(0);
+        gdt::resume();
+        serial::resume();
+        apic::resume();
+        hpet::resume();
+
+        // A unit which won't come back up is left with translation off, which lets DMA through unchecked; there's
+        // nothing better to do about it here.
+        iommu::resume().ok();
+        let result = sci::resume();
+
+        if aml::exists("\\_WAK") {
+            aml::evaluate("\\_WAK", &[AmlValue::Integer(S3)]).ok();
+        }
+
+        if interrupts_enabled { instructions::enable_interrupts(); }
+        result.map_err(SuspendError::ResumeFailed)
+    }
+}
+
+/// Saves the processor's state and enters the given sleep state, returning once the machine wakes up (or if it
+/// never went to sleep at all, in which case nothing was lost). Kept out of line, so the saved state only refers to
+/// this frame, which doesn't change until the machine is asleep.
+#[inline(never)]
+unsafe fn sleep(pm1a_control: &GenericAddress, pm1b_control: Option<GenericAddress>, sleep_type: (u8, u8)) {
+    if wakeup::save_state() { return; }
+
+    // The caches lose power along with everything else.
+    instructions::write_back_and_invalidate_caches();
+
+    enter_sleep_state(pm1a_control, sleep_type.0);
+
+    if let Some(pm1b) = pm1b_control {
+        enter_sleep_state(&pm1b, sleep_type.1);
+    }
+
+    // Going to sleep takes a moment; if we get past this, the machine never went to sleep.
+    while !sci::has_woken() { }
+}
+
+/// Resets the machine, using the ACPI reset register if there is one, then the keyboard controller, and
+/// finally a triple fault if all else fails.
+pub fn reboot() -> ! {
+    unsafe {
+        instructions::disable_interrupts();
+
+        let control = POWER_CONTROL.try();
+
+        if let Some(&(register, value)) = control.and_then(|control| control.reset.as_ref()) {
+            if register.address_space == ADDRESS_SPACE_PCI_CONFIG {
+                write_pci_config_byte(register.address, value);
+            } else {
+                register.write(value as u64);
+            }
+        }
+
+        // No FADT means a legacy machine, which will have a keyboard controller.
+        if control.map(|control| control.has_8042).unwrap_or(true) {
+            while port::inb(KEYBOARD_CONTROLLER_PORT) & KEYBOARD_CONTROLLER_INPUT_FULL != 0 { }
+
+            port::outb(KEYBOARD_CONTROLLER_PORT, KEYBOARD_CONTROLLER_RESET);
+        }
+
+        instructions::triple_fault()
+    }
+}
+
+/// Writes the given sleep type to a PM1 control register along with SLP_EN, starting the transition.
+unsafe fn enter_sleep_state(pm1_control: &GenericAddress, sleep_type: u8) {
+    let current = pm1_control.read().unwrap_or(0);
+    let cleared = current & !(SLP_TYP_MASK << SLP_TYP_SHIFT);
+
+    pm1_control.write(cleared | ((sleep_type as u64 & SLP_TYP_MASK) << SLP_TYP_SHIFT) | SLP_EN);
+}
+
+/// Writes a byte to PCI configuration space on bus 0, given an ACPI PCI configuration space address
+/// (device in bits 32-47, function in bits 16-31, offset in bits 0-15).
+unsafe fn write_pci_config_byte(address: u64, value: u8) {
+    let function = PciAddress::new(0, (address >> 32) as u8, (address >> 16) as u8);
+
+    pci::write_u8(function, address as u16, value);
+}
+
+/// Finds the SLP_TYPa/SLP_TYPb values for a sleep state by evaluating it's package (like \_S5), which needs the
+/// AML namespace to have been loaded.
+fn find_sleep_type(package: &str) -> Option<(u8, u8)> {
+    let package = aml::evaluate(package, &[]).ok()?;
+
+    let typ_a = aml::package_integer(package, 0).ok()?;
+    let typ_b = aml::package_integer(package, 1).unwrap_or(0);
+
+    Some((typ_a as u8, typ_b as u8))
+}
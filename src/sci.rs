@@ -0,0 +1,586 @@
+//! Handles the System Control Interrupt (SCI), through which the ACPI hardware reports events: the fixed
+//! events (the power and sleep buttons) in the PM1 registers, and the general-purpose events (GPEs) in the
+//! GPE blocks, which the firmware handles with the \_GPE._Lxx and _Exx methods. Events are delivered to
+//! subscribers, along with any Notify operations the GPE methods perform.
+//!
+//! GPE methods can't run in the interrupt handler: whatever it interrupted may be holding the namespace. So the
+//! handler masks and latches pending GPEs, and process_gpes() (called from the idle loop) runs their methods and
+//! enables them again. Fixed events are still delivered from the handler, so subscribers should just record the
+//! event and leave the actual work for later.
+
+use core::{mem, str};
+
+use spin::{Mutex, Once};
+
+use acpi::{ACPI, FADT, MADT, GenericAddress, Polarity, TriggerMode, FLAG_PWR_BUTTON, FLAG_SLP_BUTTON,
+    ACCESS_SIZE_UNDEFINED};
+use acpi::aml::{self, AmlValue, NodeId};
+use arch::x86_64::{port, instructions};
+use apic;
+use interrupts::{self, InterruptFrame};
+
+/// Fixed event bits, in both the PM1 status and enable registers.
+const PWRBTN: u64 = 1 << 8;
+const SLPBTN: u64 = 1 << 9;
+
+/// Set in the PM1 status register once the machine has woken up from a sleep state.
+const WAK_STS: u64 = 1 << 15;
+
+/// Set in the PM1 control register once the machine is in ACPI mode, and so raises SCIs rather than SMIs.
+const SCI_EN: u64 = 1;
+
+/// How many times to check for SCI_EN after asking the firmware to switch to ACPI mode.
+const ACPI_ENABLE_ATTEMPTS: usize = 1_000_000;
+
+/// The most subscribers which can be listening for events.
+const MAX_SUBSCRIBERS: usize = 8;
+
+/// The most enable bytes a GPE block can have, as it's length (of both halves) is only 8 bits.
+const MAX_GPE_BYTES: usize = 128;
+
+/// The hardware ids (as EISA ids) of the control method power and sleep buttons, PNP0C0C and PNP0C0E.
+const POWER_BUTTON_HID: u64 = 0x0C0CD041;
+const SLEEP_BUTTON_HID: u64 = 0x0E0CD041;
+
+/// The notification value buttons are notified with when they're pressed.
+const BUTTON_PRESSED: u64 = 0x80;
+
+/// An event raised through the SCI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The power button was pressed (the fixed feature button, or a control method one).
+    PowerButton,
+
+    /// The sleep button was pressed.
+    SleepButton,
+
+    /// A general-purpose event, after it's \_GPE method (if any) has run.
+    Gpe(u32),
+
+    /// A GPE method notified an object (other than the buttons) with the given value.
+    Notify { node: NodeId, value: u64 }
+}
+
+/// The reasons the SCI can't be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SciError {
+    /// There's no FADT, or it has no PM1 event block.
+    NotPresent,
+
+    /// The platform is hardware-reduced, and reports events through a GED device instead.
+    HardwareReduced,
+
+    /// The firmware didn't switch to ACPI mode when asked to.
+    EnableFailed,
+
+    /// Every interrupt vector is in use.
+    NoFreeVector,
+
+    /// No I/O APIC handles the SCI's global system interrupt.
+    Unroutable(u32)
+}
+
+/// One of the (up to two) GPE blocks, which are split into status bytes followed by enable bytes.
+#[derive(Debug, Clone, Copy)]
+struct GpeBlock {
+    block: GenericAddress,
+
+    /// The number of status (and enable) bytes.
+    length: u64,
+
+    /// The number of the first GPE in this block.
+    base: u32
+}
+
+impl GpeBlock {
+    /// Describes the block at the given address, which is the given number of bytes long.
+    fn new(block: GenericAddress, byte_length: u8, base: u32) -> GpeBlock {
+        GpeBlock { block: block, length: byte_length as u64 / 2, base: base }
+    }
+
+    /// The status byte holding the given GPE's bit.
+    fn status(&self, index: u64) -> GenericAddress {
+        byte_register(&self.block, index)
+    }
+
+    /// The enable byte holding the given GPE's bit.
+    fn enable(&self, index: u64) -> GenericAddress {
+        byte_register(&self.block, self.length + index)
+    }
+}
+
+/// The event registers from the FADT, filled in by init().
+#[derive(Debug)]
+struct EventRegisters {
+    /// The PM1a control register, and the SMI command port and value which switch to ACPI mode (if there are any).
+    pm1a_control: GenericAddress,
+    acpi_enable: Option<(u16, u8)>,
+
+    /// The PM1a and PM1b status and enable registers.
+    pm1_status: [Option<GenericAddress>; 2],
+    pm1_enable: [Option<GenericAddress>; 2],
+
+    gpe_blocks: [Option<GpeBlock>; 2],
+
+    /// The fixed events which are enabled.
+    fixed_events: u64
+}
+
+static REGISTERS: Once<EventRegisters> = Once::new();
+
+/// The functions events are delivered to. Taken in the interrupt handler, so only taken elsewhere with
+/// interrupts disabled.
+static SUBSCRIBERS: Mutex<[Option<fn(Event)>; MAX_SUBSCRIBERS]> = Mutex::new([None; MAX_SUBSCRIBERS]);
+
+/// Each GPE block's GPEs which the handler has masked, waiting for process_gpes(). Taken in the interrupt handler,
+/// so only taken elsewhere with interrupts disabled.
+static PENDING_GPES: Mutex<[[u8; MAX_GPE_BYTES]; 2]> = Mutex::new([[0; MAX_GPE_BYTES]; 2]);
+
+/// Each GPE block's enable bytes, saved by suspend() as they're lost in sleep states.
+static SAVED_GPE_ENABLES: Mutex<[[u8; MAX_GPE_BYTES]; 2]> = Mutex::new([[0; MAX_GPE_BYTES]; 2]);
+
+/// Switches the machine into ACPI mode, routes the SCI to a handler, and enables the fixed button events and
+/// every GPE the firmware has a method for. The AML namespace and the APICs should be set up first. Returns the
+/// global system interrupt the SCI arrives on.
+/// UNSAFE: Reconfigures the power management hardware.
+pub unsafe fn init(acpi: &ACPI) -> Result<u32, SciError> {
+    let fadt = acpi.find_table::<FADT>().ok_or(SciError::NotPresent)?;
+    if fadt.is_hardware_reduced() { return Err(SciError::HardwareReduced); }
+
+    let pm1a_event = fadt.pm1a_event_block().ok_or(SciError::NotPresent)?;
+    let pm1a_control = fadt.pm1a_control_block().ok_or(SciError::NotPresent)?;
+    let acpi_enable = if fadt.smi_command != 0 && fadt.acpi_enable != 0 {
+        Some((fadt.smi_command as u16, fadt.acpi_enable))
+    } else {
+        None
+    };
+
+    enable_acpi_mode(&pm1a_control, acpi_enable)?;
+
+    let mut fixed_events = 0;
+    if fadt.flags & FLAG_PWR_BUTTON == 0 { fixed_events |= PWRBTN; }
+    if fadt.flags & FLAG_SLP_BUTTON == 0 { fixed_events |= SLPBTN; }
+
+    let pm1b_event = fadt.pm1b_event_block();
+    // The GPE block lengths come from the FADT's byte lengths, as a block of 32 bytes or more is too long for a
+    // generic address' bit width (which firmware often leaves at 0 for them anyway).
+    let gpe0 = fadt.gpe0_block().map(|block| GpeBlock::new(block, fadt.gpe0_block_length, 0));
+    let gpe1 = fadt.gpe1_block().map(|block| GpeBlock::new(block, fadt.gpe1_block_length, fadt.gpe1_base as u32));
+
+    let (interrupt, polarity, trigger) = sci_route(acpi, fadt);
+    let registers = REGISTERS.call_once(|| EventRegisters {
+        pm1a_control: pm1a_control,
+        acpi_enable: acpi_enable,
+        pm1_status: [Some(pm1_half(&pm1a_event, false)), pm1b_event.map(|block| pm1_half(&block, false))],
+        pm1_enable: [Some(pm1_half(&pm1a_event, true)), pm1b_event.map(|block| pm1_half(&block, true))],
+        gpe_blocks: [gpe0, gpe1],
+        fixed_events: fixed_events
+    });
+
+    // Start from a clean slate: nothing enabled, and nothing left over from the firmware.
+    clear_events(registers);
+
+    let vector = interrupts::allocate(handle_sci).ok_or(SciError::NoFreeVector)?;
+
+    if !apic::route(interrupt, vector, polarity, trigger) {
+        interrupts::unregister(vector);
+        return Err(SciError::Unroutable(interrupt));
+    }
+
+    for enable in registers.pm1_enable.iter().filter_map(|enable| *enable) {
+        enable.write(registers.fixed_events);
+    }
+
+    for block in registers.gpe_blocks.iter().filter_map(|block| *block) {
+        for index in 0 .. block.length * 8 {
+            let gpe = block.base + index as u32;
+
+            if aml::exists(gpe_method(b'L', gpe).as_str()) || aml::exists(gpe_method(b'E', gpe).as_str()) {
+                set_gpe_enabled(&block, index, true);
+            }
+        }
+    }
+
+    Ok(interrupt)
+}
+
+/// Asks the firmware to hand the power management hardware over to us (by writing the value to the SMI command
+/// port), unless it already has.
+unsafe fn enable_acpi_mode(control: &GenericAddress, acpi_enable: Option<(u16, u8)>) -> Result<(), SciError> {
+    let enabled = || control.read().map(|value| value & SCI_EN != 0).unwrap_or(false);
+
+    if enabled() { return Ok(()); }
+
+    // Without an SMI command port, the machine is always in ACPI mode, so SCI_EN should have been set.
+    let (smi_command, value) = acpi_enable.ok_or(SciError::EnableFailed)?;
+    port::outb(smi_command, value);
+
+    if (0 .. ACPI_ENABLE_ATTEMPTS).any(|_| enabled()) { Ok(()) } else { Err(SciError::EnableFailed) }
+}
+
+/// Disables every fixed event and GPE, and clears their status.
+unsafe fn clear_events(registers: &EventRegisters) {
+    for (status, enable) in registers.pm1_status.iter().zip(registers.pm1_enable.iter()) {
+        if let (&Some(status), &Some(enable)) = (status, enable) {
+            enable.write(0);
+            status.write(!0);
+        }
+    }
+
+    for block in registers.gpe_blocks.iter().filter_map(|block| *block) {
+        for index in 0 .. block.length {
+            block.enable(index).write(0);
+            block.status(index).write(0xFF);
+        }
+    }
+}
+
+/// Gets the event registers ready for a sleep state: saves which GPEs are enabled (which are the ones that can wake
+/// the machine up), and clears the wake status. Returns false if init() hasn't been called, as then there's no way
+/// to tell when the machine wakes up.
+/// UNSAFE: Has to be called with interrupts disabled.
+pub unsafe fn suspend() -> bool {
+    let registers = match REGISTERS.try() {
+        Some(registers) => registers,
+        None => return false
+    };
+
+    let mut saved = SAVED_GPE_ENABLES.lock();
+    let pending = PENDING_GPES.lock();
+
+    // GPEs waiting for process_gpes() are only masked for now, so they're saved as enabled.
+    for ((block, enables), pending) in registers.gpe_blocks.iter().zip(saved.iter_mut()).zip(pending.iter()) {
+        if let Some(block) = *block {
+            for (index, enable) in enables.iter_mut().enumerate().take(block.length as usize) {
+                *enable = block.enable(index as u64).read().unwrap_or(0) as u8 | pending[index];
+            }
+        }
+    }
+
+    for status in registers.pm1_status.iter().filter_map(|status| *status) {
+        status.write(WAK_STS);
+    }
+
+    true
+}
+
+/// True once the machine has woken up from the sleep state suspend() got ready for.
+pub fn has_woken() -> bool {
+    REGISTERS.try().map(|registers| {
+        // UNSAFE: Safe, as reading the status registers has no side effects.
+        registers.pm1_status.iter().filter_map(|status| *status)
+            .any(|status| unsafe { status.read() }.map(|value| value & WAK_STS != 0).unwrap_or(false))
+    }).unwrap_or(false)
+}
+
+/// Puts the event registers back after the machine wakes up: back to ACPI mode if the firmware didn't already
+/// switch, with the fixed events and the GPEs suspend() saved enabled again, and whatever happened while it was
+/// asleep (including whatever woke it up) cleared.
+/// UNSAFE: Has to be called with interrupts disabled, after suspend().
+pub unsafe fn resume() -> Result<(), SciError> {
+    let registers = REGISTERS.try().ok_or(SciError::NotPresent)?;
+
+    enable_acpi_mode(&registers.pm1a_control, registers.acpi_enable)?;
+    clear_events(registers);
+
+    for enable in registers.pm1_enable.iter().filter_map(|enable| *enable) {
+        enable.write(registers.fixed_events);
+    }
+
+    let saved = SAVED_GPE_ENABLES.lock();
+
+    for (block, enables) in registers.gpe_blocks.iter().zip(saved.iter()) {
+        if let Some(block) = *block {
+            for (index, &enable) in enables.iter().enumerate().take(block.length as usize) {
+                block.enable(index as u64).write(enable as u64);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Works out which global system interrupt the SCI arrives on, and how it's signalled. The SCI is given as an ISA
+/// IRQ, which the MADT may override; unlike other ISA interrupts, it's level triggered and active low by default.
+fn sci_route(acpi: &ACPI, fadt: &FADT) -> (u32, Polarity, TriggerMode) {
+    let irq = fadt.sci_interrupt;
+    let over = acpi.find_table::<MADT>().and_then(|madt| {
+        if irq < 16 { madt.interrupt_source_override(irq as u8).map(|over| (over.global_system_interrupt,
+            over.polarity(), over.trigger_mode())) } else { None }
+    });
+
+    let (interrupt, polarity, trigger) = over.unwrap_or((irq as u32, Polarity::ConformsToBus,
+        TriggerMode::ConformsToBus));
+
+    let polarity = match polarity {
+        Polarity::ConformsToBus => Polarity::ActiveLow,
+        other => other
+    };
+
+    let trigger = match trigger {
+        TriggerMode::ConformsToBus => TriggerMode::Level,
+        other => other
+    };
+
+    (interrupt, polarity, trigger)
+}
+
+/// Subscribes to events. Returns false if there are too many subscribers already.
+pub fn subscribe(subscriber: fn(Event)) -> bool {
+    instructions::without_interrupts(|| {
+        let mut subscribers = SUBSCRIBERS.lock();
+
+        match subscribers.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(subscriber);
+                true
+            },
+            None => false
+        }
+    })
+}
+
+/// Enables or disables a general-purpose event, so a driver can listen for one the firmware has no method for.
+/// Returns false if there's no such GPE.
+pub fn enable_gpe(gpe: u32, enabled: bool) -> bool {
+    let registers = match REGISTERS.try() {
+        Some(registers) => registers,
+        None => return false
+    };
+
+    for block in registers.gpe_blocks.iter().filter_map(|block| *block) {
+        if gpe >= block.base && ((gpe - block.base) as u64) < block.length * 8 {
+            // UNSAFE: Safe, as GPEs with no method just get delivered to subscribers.
+            unsafe { set_gpe_enabled(&block, (gpe - block.base) as u64, enabled); }
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Sets or clears a GPE's bit in the enable register. The handler masks GPEs in the same registers, so interrupts
+/// are kept out while the register is changed.
+unsafe fn set_gpe_enabled(block: &GpeBlock, index: u64, enabled: bool) {
+    let register = block.enable(index / 8);
+    let bit = 1 << (index % 8);
+
+    instructions::without_interrupts(|| {
+        let current = register.read().unwrap_or(0);
+        register.write(if enabled { current | bit } else { current & !bit });
+    });
+}
+
+/// Runs the methods of the GPEs the handler has latched, delivers them, and enables them again. AML can only run
+/// outside of interrupt handlers, so this is called from the idle loop.
+pub fn process_gpes() {
+    let registers = match REGISTERS.try() {
+        Some(registers) => registers,
+        None => return
+    };
+
+    let pending = instructions::without_interrupts(|| {
+        mem::replace(&mut *PENDING_GPES.lock(), [[0; MAX_GPE_BYTES]; 2])
+    });
+
+    for (block, pending) in registers.gpe_blocks.iter().zip(pending.iter()) {
+        if let Some(block) = *block {
+            for (byte, &pending) in pending.iter().enumerate().take(block.length as usize) {
+                for bit in (0 .. 8).filter(|&bit| pending & (1 << bit) != 0) {
+                    // UNSAFE: Safe, as the GPE was enabled until the handler masked it.
+                    unsafe { handle_gpe(&block, byte as u64 * 8 + bit); }
+                }
+            }
+        }
+    }
+}
+
+/// Handles the SCI: delivers pending fixed events, and masks and latches pending GPEs for process_gpes().
+fn handle_sci(_frame: &mut InterruptFrame) {
+    let registers = match REGISTERS.try() {
+        Some(registers) => registers,
+        None => return
+    };
+
+    // UNSAFE: Safe, as the status registers are write-one-to-clear, so we only clear what we handle.
+    unsafe {
+        let status = registers.pm1_status.iter().filter_map(|status| *status)
+            .fold(0, |status, register| status | register.read().unwrap_or(0));
+        let pending = status & registers.fixed_events;
+
+        for &(bit, event) in &[(PWRBTN, Event::PowerButton), (SLPBTN, Event::SleepButton)] {
+            if pending & bit == 0 { continue; }
+
+            for status in registers.pm1_status.iter().filter_map(|status| *status) {
+                status.write(bit);
+            }
+
+            deliver(event);
+        }
+
+        // Masking a GPE stops it raising the SCI, even though it's status is left for it's method to deal with.
+        let mut latched = PENDING_GPES.lock();
+
+        for (block, latched) in registers.gpe_blocks.iter().zip(latched.iter_mut()) {
+            if let Some(block) = *block {
+                for byte in 0 .. block.length {
+                    let enable = block.enable(byte);
+                    let enabled = enable.read().unwrap_or(0);
+                    let pending = block.status(byte).read().unwrap_or(0) & enabled;
+
+                    if pending != 0 {
+                        enable.write(enabled & !pending);
+                        latched[byte as usize] |= pending as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs a GPE's method, delivers it, and enables it again. Edge triggered GPEs are cleared before the method runs,
+/// so another edge isn't lost; level triggered ones after, once the method has dealt with the cause.
+unsafe fn handle_gpe(block: &GpeBlock, index: u64) {
+    let gpe = block.base + index as u32;
+    let status = block.status(index / 8);
+    let bit = 1 << (index % 8);
+
+    let edge = gpe_method(b'E', gpe);
+    let level = gpe_method(b'L', gpe);
+
+    if aml::exists(edge.as_str()) {
+        status.write(bit);
+        aml::evaluate(edge.as_str(), &[]).ok();
+    } else {
+        aml::evaluate(level.as_str(), &[]).ok();
+        status.write(bit);
+    }
+
+    set_gpe_enabled(block, index, true);
+    deliver(Event::Gpe(gpe));
+
+    while let Some((node, value)) = aml::take_notification() {
+        deliver(match (hardware_id(node), value) {
+            (Some(POWER_BUTTON_HID), BUTTON_PRESSED) => Event::PowerButton,
+            (Some(SLEEP_BUTTON_HID), BUTTON_PRESSED) => Event::SleepButton,
+            _ => Event::Notify { node: node, value: value }
+        });
+    }
+}
+
+/// The hardware id of a device, as an EISA id; string ids are compressed to match.
+fn hardware_id(node: NodeId) -> Option<u64> {
+    match aml::evaluate_relative(node, "_HID", &[]).ok()? {
+        AmlValue::Integer(id) => Some(id),
+        string @ AmlValue::String { .. } => {
+            let mut id = [0; 7];
+            if aml::buffer_bytes(string, &mut id).ok()? != 7 { return None; }
+
+            eisa_id(&id)
+        },
+        _ => None
+    }
+}
+
+/// Compresses a seven character id like "PNP0C0C" into the 32-bit EISA id form EisaId() produces.
+fn eisa_id(id: &[u8; 7]) -> Option<u64> {
+    let letter = |byte: u8| if byte >= b'A' && byte <= b'Z' { Some((byte - b'@') as u32) } else { None };
+    let digit = |byte: u8| (byte as char).to_digit(16);
+
+    let vendor = letter(id[0])? << 10 | letter(id[1])? << 5 | letter(id[2])?;
+    let mut product = 0;
+    for &byte in &id[3 ..] {
+        product = product << 4 | digit(byte)?;
+    }
+
+    // The vendor and product are big endian, but the id is stored as a little endian integer.
+    Some((vendor << 16 | product).swap_bytes() as u64)
+}
+
+/// Hands an event to every subscriber.
+fn deliver(event: Event) {
+    let subscribers = instructions::without_interrupts(|| *SUBSCRIBERS.lock());
+
+    for subscriber in subscribers.iter().filter_map(|subscriber| *subscriber) {
+        subscriber(event);
+    }
+}
+
+/// The path of a GPE's method, "\_GPE._Lxx" or "\_GPE._Exx", where xx is the GPE number in hex.
+struct GpeMethod([u8; 10]);
+
+impl GpeMethod {
+    fn as_str(&self) -> &str {
+        // UNSAFE: Safe, as the path is built from ASCII.
+        unsafe { str::from_utf8_unchecked(&self.0) }
+    }
+}
+
+/// Builds the path of the edge ('E') or level ('L') triggered method for a GPE.
+fn gpe_method(kind: u8, gpe: u32) -> GpeMethod {
+    const HEX: &'static [u8] = b"0123456789ABCDEF";
+
+    let mut path = *b"\\_GPE._L00";
+    path[7] = kind;
+    path[8] = HEX[(gpe as usize >> 4) & 0xF];
+    path[9] = HEX[gpe as usize & 0xF];
+
+    GpeMethod(path)
+}
+
+/// The number of bytes in each half of a register block, which is split into status and enable registers.
+fn half_length(block: &GenericAddress) -> u64 {
+    block.bit_width as u64 / 16
+}
+
+/// The status (first) or enable (second) half of a PM1 event block.
+fn pm1_half(block: &GenericAddress, enable: bool) -> GenericAddress {
+    let length = half_length(block);
+
+    GenericAddress {
+        address: block.address + if enable { length } else { 0 },
+        bit_width: (length * 8) as u8,
+        access_size: ACCESS_SIZE_UNDEFINED,
+        .. *block
+    }
+}
+
+/// A single byte of a register block, which GPE registers are accessed as.
+fn byte_register(block: &GenericAddress, offset: u64) -> GenericAddress {
+    GenericAddress {
+        address: block.address + offset,
+        bit_width: 8,
+        bit_offset: 0,
+        access_size: ACCESS_SIZE_UNDEFINED,
+        .. *block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_eisa_ids() {
+        assert_eq!(eisa_id(b"PNP0C0C"), Some(POWER_BUTTON_HID));
+        assert_eq!(eisa_id(b"PNP0C0E"), Some(SLEEP_BUTTON_HID));
+        assert_eq!(eisa_id(b"pnp0c0c"), None);
+    }
+
+    #[test]
+    fn splits_gpe_blocks_by_their_byte_length() {
+        // An Intel PCH's 32 byte GPE0 block, whose extended address has no bit width.
+        let address = GenericAddress { address_space: 1, bit_width: 0, bit_offset: 0, access_size: 0, address: 0x1860 };
+        let block = GpeBlock::new(address, 0x20, 0);
+
+        assert_eq!(block.length, 16);
+        assert_eq!((block.status(15).address, block.enable(0).address), (0x186F, 0x1870));
+        assert_eq!(block.enable(15).bit_width, 8);
+    }
+
+    #[test]
+    fn builds_gpe_method_paths() {
+        assert_eq!(gpe_method(b'L', 0x0A).as_str(), "\\_GPE._L0A");
+        assert_eq!(gpe_method(b'E', 0x1F).as_str(), "\\_GPE._E1F");
+    }
+}
@@ -0,0 +1,144 @@
+//! Provides a driver for the 16550 UART behind the first serial port (COM1), which the kernel's output can be sent
+//! to as well as (or instead of) the VGA buffer, with console=serial on the command line. Under QEMU, that's the
+//! terminal `make run` was started from.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use arch::x86_64::port;
+
+/// The I/O port base of COM1.
+const COM1: u16 = 0x3F8;
+
+/// The offsets of the UART's registers. With the divisor latch bit set in the line control register, the first
+/// two are the low and high bytes of the baud rate divisor instead.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+/// Line control: access the divisor latch, or 8 data bits, no parity and 1 stop bit.
+const LINE_DIVISOR_LATCH: u8 = 0x80;
+const LINE_8N1: u8 = 0x03;
+
+/// Turn the FIFOs on, clear them, and interrupt at 14 bytes (though the interrupts stay off).
+const FIFO_ENABLE_AND_CLEAR: u8 = 0xC7;
+
+/// Raise DTR and RTS.
+const MODEM_READY: u8 = 0x03;
+
+/// Set in the line status register when the transmit holding register can take another byte.
+const LINE_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// The divisor of the UART's 115200 baud clock; 1 runs at the full rate.
+const BAUD_DIVISOR: u16 = 1;
+
+/// How many times to check the transmitter is free before dropping a byte, so a UART which never is can't hang
+/// the kernel.
+const TRANSMIT_ATTEMPTS: usize = 100_000;
+
+/// A 16550 UART.
+pub struct SerialPort {
+    /// The first I/O port of the UART's registers.
+    base: u16
+}
+
+impl SerialPort {
+    /// Checks there's a UART at the port base (by writing to the scratch register and reading it back), and sets it
+    /// up for 115200 baud, 8N1, with interrupts off. Returns false if there's nothing there.
+    /// UNSAFE: Nothing else may be using the ports.
+    unsafe fn init(&mut self) -> bool {
+        port::outb(self.base + SCRATCH, 0xAE);
+        if port::inb(self.base + SCRATCH) != 0xAE { return false; }
+
+        port::outb(self.base + INTERRUPT_ENABLE, 0);
+
+        port::outb(self.base + LINE_CONTROL, LINE_DIVISOR_LATCH);
+        port::outb(self.base + DATA, BAUD_DIVISOR as u8);
+        port::outb(self.base + INTERRUPT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+        port::outb(self.base + LINE_CONTROL, LINE_8N1);
+
+        port::outb(self.base + FIFO_CONTROL, FIFO_ENABLE_AND_CLEAR);
+        port::outb(self.base + MODEM_CONTROL, MODEM_READY);
+
+        true
+    }
+
+    /// Sends a byte, once the transmitter is free.
+    pub fn write_byte(&mut self, byte: u8) {
+        // UNSAFE: Safe, as the port is locked, and only the line status and data registers are touched.
+        unsafe {
+            for _ in 0 .. TRANSMIT_ATTEMPTS {
+                if port::inb(self.base + LINE_STATUS) & LINE_TRANSMIT_EMPTY != 0 {
+                    port::outb(self.base + DATA, byte);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        for byte in string.bytes() {
+            // Terminals want a carriage return to go back to the start of the line.
+            if byte == b'\n' { self.write_byte(b'\r'); }
+
+            self.write_byte(byte);
+        }
+
+        Ok(())
+    }
+}
+
+/// COM1.
+static SERIAL: Mutex<SerialPort> = Mutex::new(SerialPort { base: COM1 });
+
+/// Set once init() has found and set up COM1, after which everything printed is sent to it.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up COM1, and sends everything printed from then on to it. Returns false if there's no UART there.
+pub fn init() -> bool {
+    // UNSAFE: Safe, as the port is locked, and nothing else uses COM1.
+    let present = unsafe { SERIAL.lock().init() };
+
+    ENABLED.store(present, Ordering::SeqCst);
+    present
+}
+
+/// Sets COM1 up again after the machine wakes up from suspend-to-RAM, if init() set it up before.
+pub fn resume() {
+    if ENABLED.load(Ordering::SeqCst) {
+        init();
+    }
+}
+
+/// True if init() set up COM1.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Writes formatted text to COM1, if it's been set up.
+pub fn print(args: fmt::Arguments) {
+    if is_enabled() {
+        let _ = SERIAL.lock().write_fmt(args);
+    }
+}
+
+/// Like print(), but returns false rather than waiting if COM1 is in use.
+pub fn try_print(args: fmt::Arguments) -> bool {
+    if !is_enabled() { return true; }
+
+    match SERIAL.try_lock() {
+        Some(mut serial) => {
+            let _ = serial.write_fmt(args);
+            true
+        },
+        None => false
+    }
+}
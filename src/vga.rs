@@ -3,10 +3,14 @@
 //! in the README.
 
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use volatile::Volatile;
 use core::ptr::Unique;
 use spin::Mutex;
 
+#[cfg(not(test))]
+use serial;
+
 /// The default VGA text buffer width, in characters.
 const BUFFER_WIDTH: usize = 80;
 
@@ -23,9 +27,12 @@ pub static VGA_WRITER: Mutex<VGAWriter> = Mutex::new(VGAWriter {
     row: 0,
     column: 0,
     color: ColorCode::new(Color::Green, Color::Black),
-    buffer: unsafe { Unique::new(0xB8000 as *mut _) }
+    buffer: unsafe { Unique::new_unchecked(0xB8000 as *mut _) }
 });
 
+/// Cleared when output should only go to the serial port (with console=serial).
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
 /// Represents the possible VGA text colors.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
@@ -180,7 +187,7 @@ impl VGAWriter {
     fn buffer(&mut self) -> &mut TextBuffer {
         // UNSAFE: Safe, as this text writer uniquely owns this buffer
         // and this method is private.
-        unsafe { self.buffer.get_mut() }
+        unsafe { self.buffer.as_mut() }
     }
 }
 
@@ -209,26 +216,106 @@ macro_rules! color_println {
     ($color:expr, $fmt:expr, $($arg:tt)*) => (color_print!($color, concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Prints a line like println!, if the log level given on the command line is info (the default) or above.
+macro_rules! info_println {
+    ($($arg:tt)*) => (if $crate::cmdline::logs($crate::cmdline::LogLevel::Info) { println!($($arg)*) });
+}
+
+/// Prints a line like println!, if the log level given on the command line is debug.
+macro_rules! debug_println {
+    ($($arg:tt)*) => (if $crate::cmdline::logs($crate::cmdline::LogLevel::Debug) { println!($($arg)*) });
+}
+
+/// Prints a line in yellow, if the log level given on the command line is warn or above.
+macro_rules! warn_println {
+    ($($arg:tt)*) => (if $crate::cmdline::logs($crate::cmdline::LogLevel::Warning) {
+        color_println!($crate::vga::Color::Yellow, $($arg)*)
+    });
+}
+
 /// Prints characters to the VGA buffer. Uses the default output color.
 macro_rules! print {
-    ($($arg:tt)*) => ({
-        use core::fmt::Write;
-        let mut writer = $crate::vga::VGA_WRITER.lock();
-        writer.write_fmt(format_args!($($arg)*)).unwrap();
-    });
+    ($($arg:tt)*) => ($crate::vga::print(None, format_args!($($arg)*)));
 }
 
 /// Prints characters to the VGA buffer. Uses the provided foreground color.
 macro_rules! color_print {
-    ($color:expr, $($arg:tt)*) => ({
-        use core::fmt::Write;
-        use $crate::vga;
-        let mut writer = vga::VGA_WRITER.lock();
+    ($color:expr, $($arg:tt)*) => ($crate::vga::print(Some($color), format_args!($($arg)*)));
+}
 
-        let old_color = writer.color();
+/// Sets whether anything is written to the VGA buffer; the serial port (if it's set up) gets everything either
+/// way.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
 
-        writer.set_color(vga::ColorCode::new($color, vga::Color::Black));
-        writer.write_fmt(format_args!($($arg)*)).unwrap();
-        writer.set_color(old_color);
-    });
-}
\ No newline at end of file
+/// A writer for everything the printing macros print to, for code which writes to a fmt::Write.
+pub struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        print(None, format_args!("{}", string));
+        Ok(())
+    }
+}
+
+/// Writes formatted text to the VGA buffer, in the given foreground color or the current one, and to the serial
+/// port if it's set up; this is what the printing macros expand to.
+#[cfg(not(test))]
+pub fn print(color: Option<Color>, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    serial::print(args);
+    if !ENABLED.load(Ordering::SeqCst) { return; }
+
+    let mut writer = VGA_WRITER.lock();
+
+    let old_color = writer.color();
+
+    if let Some(color) = color {
+        writer.set_color(ColorCode::new(color, Color::Black));
+    }
+
+    writer.write_fmt(args).unwrap();
+    writer.set_color(old_color);
+}
+
+/// There's no VGA buffer when running unit tests on the host, so anything printed goes to stdout instead.
+#[cfg(test)]
+pub fn print(_color: Option<Color>, args: fmt::Arguments) {
+    use std::io::Write;
+
+    let _ = ::std::io::stdout().write_fmt(args);
+}
+
+/// Like print(), but gives up (returning false) rather than waiting if the VGA buffer or serial port is in use; for
+/// handlers like the NMI's, which can interrupt code that's printing.
+#[cfg(not(test))]
+pub fn try_print(color: Option<Color>, args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    if !serial::try_print(args) { return false; }
+    if !ENABLED.load(Ordering::SeqCst) { return true; }
+
+    let mut writer = match VGA_WRITER.try_lock() {
+        Some(writer) => writer,
+        None => return false
+    };
+
+    let old_color = writer.color();
+
+    if let Some(color) = color {
+        writer.set_color(ColorCode::new(color, Color::Black));
+    }
+
+    writer.write_fmt(args).unwrap();
+    writer.set_color(old_color);
+    true
+}
+
+/// Nothing is ever locked when printing to stdout.
+#[cfg(test)]
+pub fn try_print(color: Option<Color>, args: fmt::Arguments) -> bool {
+    print(color, args);
+    true
+}